@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// `prev` of the first record
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What happened to an entry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOp {
+    /// The entry was already stored when the log was started
+    Baseline,
+    Add,
+    Delete,
+    /// Moved out of the database by `fsck --quarantine`
+    Quarantine,
+}
+
+/// One line of the audit log
+///
+/// Only IDs and content hashes are recorded, never content. `chain` covers the
+/// other fields and the previous record's `chain`, so changing, removing or
+/// reordering records breaks every later link.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    pub op: AuditOp,
+    pub id: String,
+    pub hash: String,
+    pub prev: String,
+    pub chain: String,
+}
+
+impl AuditRecord {
+    /// The record following `prev` (or the first record, without one)
+    pub fn next(prev: Option<&AuditHead>, op: AuditOp, id: &str, hash: &str) -> Self {
+        let mut record = Self {
+            seq: prev.map_or(0, |head| head.seq + 1),
+            time: Utc::now(),
+            op,
+            id: id.to_string(),
+            hash: hash.to_string(),
+            prev: prev.map_or_else(|| GENESIS.to_string(), |head| head.chain.clone()),
+            chain: String::new(),
+        };
+        record.chain = record.compute_chain();
+        record
+    }
+
+    fn compute_chain(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev.as_bytes());
+        // Unambiguous since none of the fields can contain a newline
+        hasher.update(format!(
+            "\n{}\n{}\n{:?}\n{}\n{}",
+            self.seq,
+            self.time.to_rfc3339(),
+            self.op,
+            self.id,
+            self.hash
+        ));
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn head(&self) -> AuditHead {
+        AuditHead {
+            seq: self.seq,
+            chain: self.chain.clone(),
+        }
+    }
+}
+
+/// The last record of the log, kept in the database so that records cut off the
+/// end of the log are noticed too
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub chain: String,
+}
+
+/// Append a record to the log and sync it to disk
+pub fn append(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .context("Failed to write audit log")?;
+    file.sync_data().context("Failed to sync audit log")?;
+    Ok(())
+}
+
+/// Outcome of [`verify`]
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub records: u64,
+    /// First place the chain doesn't hold, as (line number, reason)
+    pub broken: Option<(usize, String)>,
+    /// Entries the log says are stored, by ID, with their content hashes
+    pub live: HashMap<String, String>,
+}
+
+/// Check every link of the log, and that it ends at `head`
+pub fn verify(path: &Path, head: &AuditHead) -> Result<AuditReport> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+    let mut report = AuditReport::default();
+    let mut last: Option<AuditHead> = None;
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.context("Failed to read audit log")?;
+        let broken = |reason: &str| Some((line_number, reason.to_string()));
+
+        let record: AuditRecord = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(_) => {
+                report.broken = broken("record can't be parsed");
+                return Ok(report);
+            }
+        };
+        let expected_seq = last.as_ref().map_or(0, |head| head.seq + 1);
+        let expected_prev = last.as_ref().map_or(GENESIS, |head| head.chain.as_str());
+        if record.seq != expected_seq {
+            report.broken = broken("records are missing or out of order");
+            return Ok(report);
+        }
+        if record.prev != expected_prev {
+            report.broken = broken("doesn't link to the previous record");
+            return Ok(report);
+        }
+        if record.chain != record.compute_chain() {
+            report.broken = broken("record was modified");
+            return Ok(report);
+        }
+
+        match record.op {
+            // The anchor of a log started on an empty database isn't an entry
+            AuditOp::Baseline if record.id.is_empty() => {}
+            AuditOp::Baseline | AuditOp::Add => {
+                report.live.insert(record.id.clone(), record.hash.clone());
+            }
+            AuditOp::Delete | AuditOp::Quarantine => {
+                report.live.remove(&record.id);
+            }
+        }
+        report.records += 1;
+        last = Some(record.head());
+    }
+
+    if last.as_ref() != Some(head) {
+        report.broken = Some((
+            report.records as usize,
+            "log doesn't end at the last record the database knows of".to_string(),
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(path: &Path, ops: &[(AuditOp, &str)]) -> AuditHead {
+        let mut head = None;
+        for (op, id) in ops {
+            let record = AuditRecord::next(head.as_ref(), *op, id, &format!("hash-{}", id));
+            append(path, &record).unwrap();
+            head = Some(record.head());
+        }
+        head.unwrap()
+    }
+
+    #[test]
+    fn test_verify_intact_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let head = write_log(
+            &path,
+            &[
+                (AuditOp::Baseline, "a"),
+                (AuditOp::Add, "b"),
+                (AuditOp::Add, "c"),
+                (AuditOp::Delete, "a"),
+            ],
+        );
+
+        let report = verify(&path, &head).unwrap();
+        assert_eq!(report.broken, None);
+        assert_eq!(report.records, 4);
+        assert_eq!(report.live.len(), 2);
+        assert_eq!(report.live["b"], "hash-b");
+    }
+
+    #[test]
+    fn test_verify_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let head = write_log(
+            &path,
+            &[
+                (AuditOp::Add, "a"),
+                (AuditOp::Add, "b"),
+                (AuditOp::Delete, "a"),
+            ],
+        );
+        let original = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+
+        // Hiding the deletion of "a"
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        let report = verify(&path, &head).unwrap();
+        assert_eq!(report.broken.unwrap().0, 2);
+
+        // Dropping a record from the middle
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert_eq!(verify(&path, &head).unwrap().broken.unwrap().0, 2);
+
+        // Changing a recorded hash
+        let edited = original.replacen("hash-b", "hash-x", 1);
+        fs::write(&path, edited).unwrap();
+        let (line, reason) = verify(&path, &head).unwrap().broken.unwrap();
+        assert_eq!((line, reason.as_str()), (2, "record was modified"));
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::{self, ClipboardDatabase};
+
+/// Operations the whole database is backed up before (`--backup-before`)
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupTrigger {
+    /// `clear`
+    Clear,
+    /// `change-password` and `rotate-salt`, which re-encrypt every entry
+    #[value(alias = "rekey")]
+    #[serde(alias = "rekey")]
+    Passwd,
+    /// Upgrading a database written by an older clpd to the current format
+    Migrate,
+}
+
+impl BackupTrigger {
+    /// Part of the names of the backups taken before this operation
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Clear => "clear",
+            Self::Passwd => "passwd",
+            Self::Migrate => "migrate",
+        }
+    }
+}
+
+/// A backup in the backup directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+    /// `<YYYYmmdd-HHMMSS>-<reason>`, in local time
+    pub name: String,
+    pub path: PathBuf,
+    /// Bytes on disk
+    pub size: u64,
+}
+
+/// Copies of the whole database, taken before destructive operations
+///
+/// Kept next to the database in `<database>.backups`, one sled database per
+/// backup, named after the time it was taken and why. Nothing removes them;
+/// old ones are deleted by removing their directory.
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    /// The backups of the database at `db_path`
+    pub fn for_database(db_path: &Path) -> Self {
+        let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".backups");
+        Self {
+            dir: db_path.with_file_name(name),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Back up `db`, returning the new backup's name
+    ///
+    /// `reason` (e.g. a [`BackupTrigger::label`]) ends the name, so the list
+    /// shows what each backup was taken before.
+    pub fn create(&self, db: &ClipboardDatabase, reason: &str) -> Result<String> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let stem = format!(
+            "{}-{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            reason
+        );
+        // Two backups within the same second, e.g. before a restore and a clear
+        let mut name = stem.clone();
+        let mut n = 1;
+        while self.dir.join(&name).exists() {
+            n += 1;
+            name = format!("{}-{}", stem, n);
+        }
+        db.snapshot_to(&self.dir.join(&name))
+            .context("Failed to back up the database")?;
+        Ok(name)
+    }
+
+    /// Every backup, oldest first
+    pub fn list(&self) -> Result<Vec<BackupInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()));
+            }
+        };
+        let mut backups = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            backups.push(BackupInfo {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: database::dir_size(&path)?,
+                path,
+            });
+        }
+        // The names start with the time, so this is chronological
+        backups.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(backups)
+    }
+
+    /// The backup named `name`, or the only one whose name starts with it
+    /// (e.g. just the timestamp)
+    pub fn find(&self, name: &str) -> Result<BackupInfo> {
+        let backups = self.list()?;
+        if let Some(backup) = backups.iter().find(|backup| backup.name == name) {
+            return Ok(backup.clone());
+        }
+        let matches: Vec<_> = backups
+            .into_iter()
+            .filter(|backup| backup.name.starts_with(name))
+            .collect();
+        match matches.as_slice() {
+            [backup] => Ok(backup.clone()),
+            [] => anyhow::bail!(
+                "No backup named '{}' in {} (see 'clpd backups list')",
+                name,
+                self.dir.display()
+            ),
+            _ => anyhow::bail!(
+                "'{}' matches {} backups: {}",
+                name,
+                matches.len(),
+                matches
+                    .iter()
+                    .map(|backup| backup.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// Replace the database at `db_path` with a copy of `backup`
+    ///
+    /// The current database is backed up first, so a restore can be undone by
+    /// restoring that; its name is returned. The backup itself is kept.
+    pub fn restore(&self, backup: &BackupInfo, db_path: &Path) -> Result<Option<String>> {
+        let current = if db_path.exists() {
+            let db = ClipboardDatabase::open(db_path.to_path_buf()).context(
+                "Failed to open the current database to back it up (is another clpd process using it?)",
+            )?;
+            Some(self.create(&db, "restore")?)
+        } else {
+            None
+        };
+        ClipboardDatabase::replace_with_copy(db_path, &backup.path)?;
+        Ok(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ClipboardContentType, ClipboardEntry};
+
+    fn entry(n: u8) -> ClipboardEntry {
+        ClipboardEntry::new(ClipboardContentType::Text, vec![n], format!("hash{}", n))
+    }
+
+    #[test]
+    fn test_backup_before_clear_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db");
+        let backups = Backups::for_database(&db_path);
+        assert_eq!(backups.dir(), dir.path().join("db.backups"));
+        assert!(backups.list().unwrap().is_empty());
+
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        db.initialize(&[1; 16], &[1, 2, 3]).unwrap();
+        for n in 0..3 {
+            db.insert_entry(&entry(n)).unwrap();
+        }
+        let name = backups.create(&db, BackupTrigger::Clear.label()).unwrap();
+        assert!(name.ends_with("-clear"), "{}", name);
+        for entry in db.list_entries().unwrap() {
+            db.delete_entry(&entry.id).unwrap();
+        }
+        assert_eq!(db.count_entries().unwrap(), 0);
+        drop(db);
+
+        // By its timestamp alone
+        let timestamp = name.trim_end_matches("-clear");
+        let backup = backups.find(timestamp).unwrap();
+        assert_eq!(backup.name, name);
+        assert!(backup.size > 0);
+        let before_restore = backups.restore(&backup, &db_path).unwrap().unwrap();
+
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        assert_eq!(db.count_entries().unwrap(), 3);
+        assert!(db.hash_exists("hash1").unwrap());
+        assert_eq!(db.get_salt().unwrap(), [1; 16]);
+        drop(db);
+
+        // The backup is kept, and the cleared database was saved before the restore
+        let names: Vec<_> = backups
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|backup| backup.name)
+            .collect();
+        assert_eq!(names, [name.clone(), before_restore.clone()]);
+        assert!(before_restore.ends_with("-restore"), "{}", before_restore);
+        backups
+            .restore(&backups.find(&before_restore).unwrap(), &db_path)
+            .unwrap();
+        assert_eq!(
+            ClipboardDatabase::open(db_path)
+                .unwrap()
+                .count_entries()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_find_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = Backups::for_database(&dir.path().join("db"));
+        for name in ["20260101-120000-clear", "20260101-120000-passwd"] {
+            fs::create_dir_all(backups.dir().join(name)).unwrap();
+        }
+
+        assert_eq!(
+            backups.find("20260101-120000-clear").unwrap().name,
+            "20260101-120000-clear"
+        );
+        let err = backups.find("20260101-12").unwrap_err().to_string();
+        assert!(err.contains("matches 2 backups"), "{}", err);
+        let err = backups.find("2025").unwrap_err().to_string();
+        assert!(err.contains("No backup named"), "{}", err);
+    }
+}
@@ -1,6 +1,14 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
 use std::path::PathBuf;
 
+use crate::backups::BackupTrigger;
+use crate::database::SledTree;
+use crate::models::{ClipboardContentType, ClipboardEntry, ClipboardSelection};
+use crate::tui::ImageOpen;
+use crate::watcher::{CaptureRate, ImageMode, Selections, TextNormalization};
+
 #[derive(Parser)]
 #[command(name = "clpd")]
 #[command(about = "Encrypted clipboard history manager", long_about = None)]
@@ -11,6 +19,22 @@ pub struct Cli {
     #[arg(short, long, global = true)]
     pub database: Option<PathBuf>,
 
+    /// Answer yes to every confirmation prompt, for scripts (also CLPD_ASSUME_YES=1)
+    #[arg(long, visible_alias = "force", global = true)]
+    pub assume_yes: bool,
+
+    /// Copy the whole database aside before these operations: clear, passwd
+    /// (change-password and rotate-salt) or migrate (an upgrade of its format);
+    /// comma-separated (default: backup_before from the config file)
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "OPS",
+        global = true
+    )]
+    pub backup_before: Vec<BackupTrigger>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -18,24 +42,235 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     /// Initialize the database with a master password
-    Init,
+    Init {
+        /// Length of the random key derivation salt in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = crate::crypto::DEFAULT_SALT_LEN,
+              value_parser = parse_salt_length)]
+        salt_length: usize,
+
+        /// Encrypt each entry under its own key, derived from the master key and the entry ID
+        #[arg(long)]
+        per_entry_keys: bool,
+
+        /// Memory the Argon2id key derivation uses, in MiB; more slows down guessing
+        #[arg(long, value_name = "MIB", default_value_t = 19,
+              value_parser = clap::value_parser!(u32).range(19..=4096))]
+        kdf_memory: u32,
+
+        /// Passes the Argon2id key derivation makes over its memory
+        #[arg(long, value_name = "N", default_value_t = 2,
+              value_parser = clap::value_parser!(u32).range(2..=64))]
+        kdf_iterations: u32,
+    },
+
+    /// Change the master password, re-encrypting every entry
+    #[command(visible_alias = "passwd")]
+    ChangePassword {
+        /// Check that every entry re-encrypts cleanly, without changing anything
+        #[arg(long)]
+        dry_run: bool,
 
-    NetListen,
+        /// Skip confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Replace the key derivation salt, keeping the password, re-encrypting every entry
+    RotateSalt {
+        /// Check that every entry re-encrypts cleanly, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Skip confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Run the network clipboard server
+    NetListen {
+        /// Allow cross-origin requests from this origin (can be repeated)
+        #[arg(long, value_name = "ORIGIN", conflicts_with = "allow_any_origin")]
+        allow_origin: Vec<String>,
+
+        /// Allow cross-origin requests from any origin (not recommended)
+        #[arg(long)]
+        allow_any_origin: bool,
+
+        /// Address and port to listen on, e.g. 0.0.0.0:2573 to be reachable from
+        /// other machines (default: 127.0.0.1 and listen_port from the config
+        /// file, or 2573)
+        #[arg(long, value_name = "ADDR:PORT")]
+        bind: Option<std::net::SocketAddr>,
+
+        /// Refuse requests larger than this many MiB, e.g. entries with big images
+        #[arg(long, value_name = "MIB", default_value_t = 50)]
+        max_payload_mb: usize,
+
+        /// Only store entries of these types (e.g. text to keep the server small)
+        #[arg(
+            long,
+            value_name = "TYPES",
+            value_delimiter = ',',
+            default_value = "text,image"
+        )]
+        accept: Vec<EntryType>,
+    },
 
     NetStart {
         /// Maximum number of entries to keep (oldest entries are pruned)
         #[arg(short, long)]
         max_entries: Option<usize>,
+
+        /// URL of the clpd server (as started with `net-listen`; default:
+        /// server_url from the config file, or http://localhost:2573)
+        #[arg(long, visible_alias = "server-url", value_name = "URL", value_parser = parse_server_url)]
+        server: Option<reqwest::Url>,
+
+        /// Skip entries whose request would be larger than this many MiB; keep
+        /// it at or below the server's --max-payload-mb
+        #[arg(long, value_name = "MIB", default_value_t = 50)]
+        max_payload_mb: usize,
     },
 
     /// Start the clipboard watcher daemon
+    // `storing` holds every option that affects capturing or storing entries,
+    // which `--print-only` doesn't do
+    #[command(visible_alias = "watch", group(
+        clap::ArgGroup::new("storing").multiple(true).args([
+            "max_entries", "max_age", "dedupe_window", "events", "watch_interval_jitter",
+            "notify", "quiet_hours", "strip_ansi", "normalize", "max_image_mb", "images",
+            "swap_rb", "canonical_image_hash", "delta", "skip_initial", "ignore_app",
+            "max_rate", "no_max_rate", "verbose", "on_capture", "once", "json",
+        ])
+    ))]
     Start {
         /// Maximum number of entries to keep (oldest entries are pruned)
         #[arg(short, long)]
         max_entries: Option<usize>,
+
+        /// Delete entries older than this (e.g. 30d; units s, m, h, d, w)
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        max_age: Option<TimeDelta>,
+
+        /// Number of recently seen clipboard values to ignore as duplicates
+        #[arg(long, default_value_t = crate::watcher::DEFAULT_DEDUPE_WINDOW)]
+        dedupe_window: usize,
+
+        /// How often the clipboard is read, in milliseconds; lower catches copies
+        /// in quick succession, higher saves battery (default: poll_interval_ms
+        /// from the config file, or 500)
+        #[arg(long, value_name = "MS", value_parser = parse_poll_interval)]
+        poll_interval: Option<u64>,
+
+        /// Read the clipboard when the OS reports a change instead of on every
+        /// poll (Windows, X11, and Wayland with wl-paste); polls where unavailable
+        #[arg(long)]
+        events: bool,
+
+        /// Randomly vary each poll interval by up to this percentage
+        #[arg(long, value_name = "PERCENT", default_value_t = 0,
+              value_parser = clap::value_parser!(u8).range(0..=100))]
+        watch_interval_jitter: u8,
+
+        #[command(flatten)]
+        watch_selection: WatchSelection,
+
+        /// Show a desktop notification when an entry is captured
+        #[arg(long)]
+        notify: bool,
+
+        /// Don't show notifications during this daily time range (e.g. 22:00-07:00)
+        #[arg(long, value_name = "HH:MM-HH:MM", requires = "notify",
+              value_parser = crate::notify::QuietHours::parse)]
+        quiet_hours: Option<crate::notify::QuietHours>,
+
+        /// Remove ANSI escape codes (e.g. terminal colors) from captured text
+        #[arg(long)]
+        strip_ansi: bool,
+
+        /// Normalize text before comparing it with stored entries, so that
+        /// equivalent text isn't stored twice (the text is stored as copied)
+        #[arg(long, value_enum, value_name = "FORM")]
+        normalize: Option<TextNormalization>,
+
+        /// Skip clipboard images larger than this many MiB of raw RGBA data
+        #[arg(long, value_name = "MIB", default_value_t = 256)]
+        max_image_mb: usize,
+
+        /// How captured images are stored: in full, or only as a small thumbnail
+        /// to recognize them by, which takes far less space
+        #[arg(long, value_enum, value_name = "MODE", default_value_t = ImageMode::Full)]
+        images: ImageMode,
+
+        /// Swap the red and blue channels of captured images, for clipboards
+        /// that hand out BGRA pixels as RGBA (red and blue appear swapped)
+        #[arg(long)]
+        swap_rb: bool,
+
+        /// Deduplicate images by their pixels alone: unused alpha and row padding
+        /// don't make a screenshot a new image (the original is stored)
+        #[arg(long)]
+        canonical_image_hash: bool,
+
+        /// Experimental: store text that closely matches the previous text entry
+        /// as a diff against it
+        #[arg(long)]
+        delta: bool,
+
+        /// Only read the clipboard when its change counter moves (Windows and
+        /// macOS; other platforms read it on every poll)
+        #[arg(long)]
+        on_change_only: bool,
+
+        /// Don't store what is already on the clipboard at startup, only new copies
+        #[arg(long)]
+        skip_initial: bool,
+
+        /// Don't store anything copied while an application whose name contains
+        /// NAME (ignoring case) has the focus, e.g. a password manager; repeat for
+        /// more applications (default: ignore_apps from the config file)
+        #[arg(long, value_name = "NAME")]
+        ignore_app: Vec<String>,
+
+        /// Pause capture for a minute when more than N entries are stored
+        /// within the given time, e.g. from an application rewriting the
+        /// clipboard in a loop
+        #[arg(long, value_name = "N/AGE", default_value = "50/10s",
+              value_parser = parse_capture_rate)]
+        max_rate: CaptureRate,
+
+        /// Never pause capture, however fast entries are stored
+        #[arg(long, conflicts_with = "max_rate")]
+        no_max_rate: bool,
+
+        /// Report failures to read the clipboard (e.g. another application
+        /// holding it open), which otherwise look like an empty clipboard
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Run this shell command after each stored entry, with the entry's ID,
+        /// type and size (never its content) in CLPD_ENTRY_ID, CLPD_CONTENT_TYPE
+        /// and CLPD_SIZE
+        #[arg(long, value_name = "COMMAND")]
+        on_capture: Option<String>,
+
+        /// Check the clipboard once and exit, printing the ID of the stored entry;
+        /// nothing is printed when the content was empty, skipped or already stored
+        #[arg(long, conflicts_with_all = ["skip_initial", "print_only"])]
+        once: bool,
+
+        /// With --once, print the stored entry's metadata as JSON instead of its ID
+        #[arg(long, requires = "once")]
+        json: bool,
+
+        /// Only print clipboard changes (type, size, preview) without storing
+        /// them; needs no database or password
+        #[arg(long, conflicts_with_all = ["storing", "database", "backup_before"])]
+        print_only: bool,
     },
 
     /// List all stored clipboard entries
+    #[command(visible_alias = "ls")]
     List {
         /// Show full timestamps
         #[arg(short, long)]
@@ -44,21 +279,110 @@ pub enum Commands {
         /// Limit number of entries to display
         #[arg(short = 'n', long)]
         limit: Option<usize>,
+
+        /// Only entries captured at or after this time (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only entries captured at or before this time (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_time)]
+        until: Option<DateTime<Utc>>,
+
+        /// Print entry metadata as a JSON array
+        #[arg(long)]
+        json: bool,
+
+        /// Only show these fields, in this order (e.g. id,timestamp,hash)
+        #[arg(long, value_delimiter = ',')]
+        columns: Option<Vec<ListColumn>>,
+
+        /// Show a one-line preview of each entry's content (needs the password)
+        #[arg(long, conflicts_with_all = ["json", "columns"])]
+        preview: bool,
+
+        /// Include at most this many lines of content in each preview
+        #[arg(long, value_name = "N", default_value_t = 3, requires = "preview")]
+        preview_lines: usize,
+
+        /// Cut previews off after this many characters
+        #[arg(long, value_name = "CHARS", default_value_t = 80, requires = "preview")]
+        preview_width: usize,
+
+        /// Copy the newest listed entry back to the clipboard after listing
+        #[arg(long, conflicts_with_all = ["json", "columns"])]
+        copy_first: bool,
+
+        #[command(flatten)]
+        types: TypeFilter,
+
+        #[command(flatten)]
+        selection: SelectionFilter,
     },
 
     /// Show (decrypt and display) a specific entry
+    #[command(visible_aliases = ["cat", "view"])]
     Show {
         /// Entry ID to show
         id: String,
+
+        /// How to print the content: `pretty` for reading, `base64` for scripts
+        /// (text as UTF-8, images as PNG)
+        #[arg(long, value_enum, default_value_t = ShowFormat::Pretty)]
+        format: ShowFormat,
     },
 
     /// Copy a specific entry back to the clipboard
+    #[command(visible_alias = "cp")]
     Copy {
         /// Entry ID to copy
         id: String,
+
+        /// Print the result as a JSON object, including failures
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        target: CopyTarget,
+    },
+
+    /// Store text read from stdin as a new entry (e.g. `cat notes.txt | clpd paste-from-stdin`)
+    PasteFromStdin,
+
+    /// Write an entry's raw content to stdout (images as PNG)
+    Print {
+        /// Entry ID to print
+        id: String,
+    },
+
+    /// Find text entries containing a string
+    #[command(visible_alias = "find")]
+    Search {
+        /// Text to look for
+        query: String,
+
+        /// Ignore differences in case
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Stop after this many matches, without decrypting the remaining entries
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Copy all matches to the clipboard, one after another
+        #[arg(long, conflicts_with = "copy_ids")]
+        copy: bool,
+
+        /// Only print the IDs of matching entries, one per line
+        #[arg(long)]
+        copy_ids: bool,
+
+        /// List (and concatenate) matches oldest first instead of newest first
+        #[arg(long)]
+        oldest_first: bool,
     },
 
     /// Delete a specific entry
+    #[command(visible_alias = "rm")]
     Delete {
         /// Entry ID to delete
         id: String,
@@ -68,15 +392,67 @@ pub enum Commands {
         yes: bool,
     },
 
-    /// Clear all entries from the database
+    /// Move an entry to the top of the history, as if it had just been copied
+    Touch {
+        /// Entry ID to move
+        id: String,
+    },
+
+    /// Keep an entry when pruning by --max-entries, --max-age or `prune`
+    Pin {
+        /// Entry ID to pin
+        id: String,
+    },
+
+    /// Let pruning delete a pinned entry again
+    Unpin {
+        /// Entry ID to unpin
+        id: String,
+    },
+
+    /// Clear all entries from the database (or all entries of one type)
     Clear {
         /// Skip confirmation prompt
         #[arg(short, long)]
         yes: bool,
+
+        #[command(flatten)]
+        types: TypeFilter,
     },
 
     /// Show database statistics
-    Stats,
+    Stats {
+        /// Show how often entries were captured, copied and searched instead
+        ///
+        /// Counting is off until switched on with --enable. The counts stay in the
+        /// database and are never sent anywhere.
+        #[arg(long, conflicts_with = "storage")]
+        usage: bool,
+        /// Show how much disk the database takes, per tree, instead; needs no password
+        #[arg(long)]
+        storage: bool,
+        /// Start counting captures, copies and searches
+        #[arg(long, requires = "usage", conflicts_with = "disable")]
+        enable: bool,
+        /// Stop counting and forget the counts
+        #[arg(long, requires = "usage")]
+        disable: bool,
+    },
+
+    /// Delete entries older than a given age, once
+    Prune {
+        /// Age as a number and a unit: s, m, h, d or w (e.g. 30d)
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: TimeDelta,
+
+        /// Skip confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Rewrite the database to give back the disk space of deleted entries
+    /// (no password needed)
+    Compact,
 
     /// Dump all entries to a directory (images as files, text as CSV)
     Dump {
@@ -86,17 +462,808 @@ pub enum Commands {
         /// Skip confirmation prompt
         #[arg(short, long)]
         yes: bool,
+
+        /// Dump at most this many entries, newest first
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Only entries captured at or after this time (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+
+        #[command(flatten)]
+        types: TypeFilter,
+
+        #[command(flatten)]
+        content: DumpContent,
+    },
+
+    /// Merge entries from another clpd database into this one
+    Merge {
+        /// Path to the other database
+        other: PathBuf,
+
+        /// Re-encrypt entries from a database with a different password
+        #[arg(long)]
+        reencrypt: bool,
     },
 
+    /// Write all entries to a single backup file (no password needed), or
+    /// restore one with --restore
+    Backup {
+        /// Backup file to write, or to read with --restore
+        file: PathBuf,
+
+        /// Add the backup's entries that aren't stored yet, initializing an
+        /// empty database from the backup
+        #[arg(long)]
+        restore: bool,
+    },
+
+    /// List or restore the copies of the database taken by --backup-before
+    Backups {
+        #[command(subcommand)]
+        command: BackupsCommand,
+    },
+
+    /// Check that encryption, compression and serialization round-trip on this
+    /// machine, with a throwaway key (no database or password needed)
+    Selftest,
+
+    /// Check the database structure for corruption (no password needed)
+    Fsck {
+        /// Move unreadable entries to a quarantine tree and rebuild the indexes
+        #[arg(long)]
+        quarantine: bool,
+    },
+
+    /// Verify the tamper-evident audit log against the database (no password needed)
+    Audit {
+        /// Start keeping the audit log, recording the current entries as its baseline
+        #[arg(long)]
+        enable: bool,
+    },
+
+    /// Developer tools for inspecting the database; unstable, may change or go away
+    #[command(hide = true)]
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommand,
+    },
+
+    /// Print a short fingerprint of the derived key to compare across machines
+    Fingerprint,
+
+    /// List the formats the clipboard currently offers, to see why a copy isn't
+    /// captured (no password needed)
+    Formats,
+
+    /// Cache the derived key in the OS keyring so other commands don't prompt
+    Unlock {
+        /// Minutes until the cached key expires
+        #[arg(long, value_name = "MINUTES", default_value_t = 15)]
+        ttl: u64,
+    },
+
+    /// Remove the key cached by `unlock`
+    Lock,
+
+    /// Run list, show, copy, delete and search at a prompt, entering the
+    /// password only once
+    Shell,
+
     /// Browse clipboard history with an interactive TUI
-    Browse,
+    Browse {
+        /// Keep capturing the clipboard while browsing; new entries appear live
+        #[arg(short, long)]
+        watch: bool,
+
+        /// Longest pause between copies that still counts as one session, when
+        /// grouping entries by session (`g` in the TUI)
+        #[arg(long, value_name = "MINUTES", default_value_t = 5)]
+        session_gap: u64,
+
+        #[command(flatten)]
+        selection: SelectionFilter,
+
+        /// Where `o` shows images (default: open_images from the config file, or auto)
+        #[arg(long, value_enum, value_name = "WHERE")]
+        open_images: Option<ImageOpen>,
+    },
 
-    NetBrowse,
+    NetBrowse {
+        /// URL of the clpd server (as started with `net-listen`; default:
+        /// server_url from the config file, or http://localhost:2573)
+        #[arg(long, visible_alias = "server-url", value_name = "URL", value_parser = parse_server_url)]
+        server: Option<reqwest::Url>,
+
+        /// Where `o` shows images (default: open_images from the config file, or auto)
+        #[arg(long, value_enum, value_name = "WHERE")]
+        open_images: Option<ImageOpen>,
+    },
+
+    /// Step through the history like a clipboard ring, copying one entry at a
+    /// time (for hotkeys)
+    Cycle {
+        #[command(subcommand)]
+        command: CycleCommand,
+    },
+
+    /// Show or change settings in the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
 
     /// Install clpd binary to default location and add to PATH
     Install,
 }
 
+/// Subcommands of `clpd config`
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the value of a setting
+    Get {
+        /// database_path, max_entries, max_age, poll_interval_ms, server_url, listen_port, open_images, ignore_apps or backup_before
+        key: String,
+    },
+
+    /// Change a setting, checking the value before it's written
+    Set {
+        /// database_path, max_entries, max_age, poll_interval_ms, server_url, listen_port, open_images, ignore_apps or backup_before
+        key: String,
+        value: String,
+    },
+
+    /// Print where the config file is read from
+    Path,
+}
+
+/// Subcommands of `clpd backups`
+#[derive(Subcommand)]
+pub enum BackupsCommand {
+    /// List the backups, oldest first
+    List,
+
+    /// Replace the database with a backup; the current database is backed up
+    /// first
+    Restore {
+        /// Backup to restore, or the start of its name (e.g. its timestamp)
+        name: String,
+
+        /// Skip confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+/// Subcommands of `clpd cycle`
+#[derive(Subcommand)]
+pub enum CycleCommand {
+    /// Copy the next older entry
+    Prev,
+
+    /// Copy the next newer entry
+    Next,
+
+    /// Start over from the newest entry, without copying anything
+    Reset,
+}
+
+/// Subcommands of the hidden `clpd debug`
+#[derive(Subcommand)]
+pub enum DebugCommand {
+    /// Print the keys of a sled tree and the sizes of their values, without
+    /// decrypting anything (no password needed)
+    DumpTree {
+        /// The tree to print
+        #[arg(value_enum)]
+        tree: SledTree,
+
+        /// Print every key as hex, even when it's readable text
+        #[arg(long)]
+        hex: bool,
+    },
+}
+
+/// Content type filter for commands that select entries
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum EntryType {
+    Text,
+    Image,
+}
+
+impl EntryType {
+    pub fn matches(self, content_type: &ClipboardContentType) -> bool {
+        matches!(
+            (self, content_type),
+            (EntryType::Text, ClipboardContentType::Text)
+                | (EntryType::Image, ClipboardContentType::Image)
+        )
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EntryType::Text => "text",
+            EntryType::Image => "image",
+        }
+    }
+}
+
+/// Output format of `show`
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ShowFormat {
+    /// Entry details and content for reading in a terminal
+    Pretty,
+    /// Only the content, base64-encoded
+    Base64,
+}
+
+/// How `dump` writes text to its CSV, for exports that can be shared
+#[derive(Debug, Clone, Default, Args)]
+pub struct DumpContent {
+    /// Replace text matching this regular expression with `***` (can be repeated)
+    #[arg(long, value_name = "REGEX")]
+    pub redact: Vec<Regex>,
+
+    /// Write the SHA-256 hash of each text instead of the text itself
+    #[arg(long, conflicts_with = "redact")]
+    pub hash_content: bool,
+}
+
+/// `--watch-clipboard-selection` of `start`, only on Linux where there is a
+/// primary selection
+#[derive(Debug, Clone, Copy, Args)]
+pub struct WatchSelection {
+    /// Which selections to capture: the clipboard, the primary selection
+    /// (selected text, pasted with the middle mouse button) or both
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long = "watch-clipboard-selection",
+        group = "storing",
+        value_enum,
+        value_name = "SELECTION",
+        default_value = "clipboard"
+    )]
+    selections: Selections,
+}
+
+impl WatchSelection {
+    pub fn selections(&self) -> Selections {
+        #[cfg(target_os = "linux")]
+        {
+            self.selections
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Selections::Clipboard
+        }
+    }
+}
+
+/// `--selection` filter of `list` and `browse` (Linux)
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct SelectionFilter {
+    /// Only entries captured from this selection
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long = "selection",
+        value_enum,
+        value_name = "SELECTION",
+        default_value = "both"
+    )]
+    selections: Selections,
+}
+
+impl SelectionFilter {
+    /// Whether the option narrows the entries at all
+    pub fn is_set(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            self.selections != Selections::Both
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            false
+        }
+    }
+
+    /// Entries that don't record a selection count as from the clipboard
+    pub fn matches(&self, entry: &ClipboardEntry) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            let selection = entry.selection.unwrap_or(ClipboardSelection::Clipboard);
+            self.selections.includes(selection)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = entry;
+            true
+        }
+    }
+}
+
+/// `--to` of `copy` (Linux)
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct CopyTarget {
+    /// Copy to this selection instead of the one the entry was captured from
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum, value_name = "SELECTION")]
+    to: Option<ClipboardSelection>,
+}
+
+impl CopyTarget {
+    /// Where to copy `entry`: where it came from, unless overridden
+    pub fn selection_for(&self, entry: &ClipboardEntry) -> ClipboardSelection {
+        #[cfg(target_os = "linux")]
+        {
+            self.to
+                .or(entry.selection)
+                .unwrap_or(ClipboardSelection::Clipboard)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = entry;
+            ClipboardSelection::Clipboard
+        }
+    }
+}
+
+/// `--type` / `--exclude-type` options shared by commands that select entries
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct TypeFilter {
+    /// Only entries of this type
+    #[arg(long = "type", value_name = "TYPE", conflicts_with = "exclude_type")]
+    pub only: Option<EntryType>,
+
+    /// Skip entries of this type
+    #[arg(long, value_name = "TYPE")]
+    pub exclude_type: Option<EntryType>,
+}
+
+impl TypeFilter {
+    /// Whether either option was given
+    pub fn is_set(&self) -> bool {
+        self.only.is_some() || self.exclude_type.is_some()
+    }
+
+    pub fn matches(&self, content_type: &ClipboardContentType) -> bool {
+        self.only.is_none_or(|only| only.matches(content_type))
+            && !self
+                .exclude_type
+                .is_some_and(|excluded| excluded.matches(content_type))
+    }
+
+    /// Describes the selected entries, e.g. "image" in "Delete all 3 image entries?"
+    pub fn label(&self) -> Option<String> {
+        match (self.only, self.exclude_type) {
+            (Some(only), _) => Some(only.name().to_string()),
+            (None, Some(excluded)) => Some(format!("non-{}", excluded.name())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// A field of an entry's metadata that `list` can show
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ListColumn {
+    Id,
+    Timestamp,
+    #[value(name = "content_type")]
+    ContentType,
+    Size,
+    Hash,
+    Image,
+}
+
+impl ListColumn {
+    /// Key of this field in JSON output
+    pub fn key(self) -> &'static str {
+        match self {
+            ListColumn::Id => "id",
+            ListColumn::Timestamp => "timestamp",
+            ListColumn::ContentType => "content_type",
+            ListColumn::Size => "size",
+            ListColumn::Hash => "hash",
+            ListColumn::Image => "image",
+        }
+    }
+}
+
+/// Parse a server URL, requiring an http(s) scheme and a host
+pub fn parse_server_url(s: &str) -> Result<reqwest::Url, String> {
+    let url = reqwest::Url::parse(s).map_err(|e| format!("invalid URL: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "unsupported scheme '{}', use http:// or https://",
+            url.scheme()
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("the URL has no host".to_string());
+    }
+    Ok(url)
+}
+
+/// Parse a poll interval in milliseconds, from 50 ms to a minute
+pub fn parse_poll_interval(s: &str) -> Result<u64, String> {
+    let ms: u64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(50..=60_000).contains(&ms) {
+        return Err("the poll interval must be between 50 and 60000 ms".to_string());
+    }
+    Ok(ms)
+}
+
+/// Parse a salt length within what Argon2 accepts
+fn parse_salt_length(s: &str) -> Result<usize, String> {
+    let len: usize = s.parse().map_err(|e| format!("{}", e))?;
+    let range = crate::crypto::DEFAULT_SALT_LEN..=crate::crypto::MAX_SALT_LEN;
+    if !range.contains(&len) {
+        return Err(format!(
+            "salt length must be between {} and {} bytes",
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(len)
+}
+
+/// Age units in `parse_age` and `format_age`, largest first
+const AGE_UNITS: [(char, i64); 5] = [
+    ('w', 7 * 24 * 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('h', 60 * 60),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Parse an age given as a number and a unit, e.g. `30d` or `12h`
+pub fn parse_age(s: &str) -> Result<TimeDelta, String> {
+    let invalid = || {
+        format!(
+            "invalid age '{}', expected e.g. 30d (units s, m, h, d, w)",
+            s
+        )
+    };
+    let unit = s.chars().last().ok_or_else(invalid)?;
+    let seconds = AGE_UNITS
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .map(|(_, seconds)| *seconds)
+        .ok_or_else(invalid)?;
+    let count: i64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    if count <= 0 {
+        return Err("age must be greater than zero".to_string());
+    }
+    count
+        .checked_mul(seconds)
+        .and_then(TimeDelta::try_seconds)
+        .ok_or_else(|| format!("age '{}' is too large", s))
+}
+
+/// Parse a capture rate given as a count and an age, e.g. `50/10s`
+fn parse_capture_rate(s: &str) -> Result<CaptureRate, String> {
+    let invalid = || format!("invalid rate '{}', expected e.g. 50/10s", s);
+    let (count, window) = s.split_once('/').ok_or_else(invalid)?;
+    let count: usize = count.trim().parse().map_err(|_| invalid())?;
+    if count == 0 {
+        return Err("the number of entries must be greater than zero".to_string());
+    }
+    let window = parse_age(window.trim())?.to_std().map_err(|_| invalid())?;
+    Ok(CaptureRate { count, window })
+}
+
+/// Format an age in the largest unit that divides it, as accepted by `parse_age`
+pub fn format_age(age: TimeDelta) -> String {
+    let seconds = age.num_seconds();
+    let (unit, size) = AGE_UNITS
+        .iter()
+        .find(|(_, size)| seconds % size == 0)
+        .copied()
+        .unwrap_or(('s', 1));
+    format!("{}{}", seconds / size, unit)
+}
+
+/// Parse a point in time given as RFC 3339 or as a local date (start of that day)
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let invalid = || format!("invalid time '{}', expected YYYY-MM-DD or RFC 3339", s);
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| invalid())?;
+    let midnight = date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(invalid)
+}
+
 pub fn parse_args() -> Cli {
     Cli::parse()
 }
+
+/// Parse a line typed at the `clpd shell` prompt, split into words like a shell would
+pub fn parse_shell_line(line: &str) -> Result<Commands, clap::Error> {
+    let words = shlex::split(line).ok_or_else(|| {
+        clap::Error::raw(clap::error::ErrorKind::InvalidValue, "unbalanced quotes\n")
+    })?;
+    let cli = Cli::try_parse_from(std::iter::once("clpd".to_string()).chain(words))?;
+    if cli.database.is_some() {
+        return Err(clap::Error::raw(
+            clap::error::ErrorKind::ArgumentConflict,
+            "the database can't be changed within the shell\n",
+        ));
+    }
+    if !cli.backup_before.is_empty() {
+        return Err(clap::Error::raw(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--backup-before can't be changed within the shell\n",
+        ));
+    }
+    if cli.assume_yes {
+        return Err(clap::Error::raw(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--assume-yes applies to the whole shell (clpd --assume-yes shell); use the command's --yes\n",
+        ));
+    }
+    Ok(cli.command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump_types(args: &[&str]) -> Result<TypeFilter, clap::Error> {
+        let cli = Cli::try_parse_from(["clpd", "dump", "out"].iter().chain(args))?;
+        match cli.command {
+            Commands::Dump { types, .. } => Ok(types),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn type_filter_matches() {
+        let text = ClipboardContentType::Text;
+        let image = ClipboardContentType::Image;
+
+        let all = TypeFilter::default();
+        assert!(!all.is_set());
+        assert!(all.matches(&text) && all.matches(&image));
+
+        let only_images = dump_types(&["--type", "image"]).unwrap();
+        assert!(!only_images.matches(&text));
+        assert!(only_images.matches(&image));
+        assert_eq!(only_images.label().as_deref(), Some("image"));
+
+        let no_images = dump_types(&["--exclude-type", "image"]).unwrap();
+        assert!(no_images.is_set());
+        assert!(no_images.matches(&text));
+        assert!(!no_images.matches(&image));
+        assert_eq!(no_images.label().as_deref(), Some("non-image"));
+    }
+
+    fn dump_content(args: &[&str]) -> Result<DumpContent, clap::Error> {
+        let cli = Cli::try_parse_from(["clpd", "dump", "out"].iter().chain(args))?;
+        match cli.command {
+            Commands::Dump { content, .. } => Ok(content),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn dump_content_args() {
+        let redacted = dump_content(&["--redact", "a", "--redact", "b"]).unwrap();
+        assert_eq!(redacted.redact.len(), 2);
+        assert!(dump_content(&["--hash-content"]).unwrap().hash_content);
+
+        assert!(dump_content(&["--redact", "("]).is_err());
+        let err = dump_content(&["--hash-content", "--redact", "x"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn parse_and_format_age() {
+        assert_eq!(parse_age("30d"), Ok(TimeDelta::days(30)));
+        assert_eq!(parse_age("12h"), Ok(TimeDelta::hours(12)));
+        assert_eq!(parse_age("2w"), Ok(TimeDelta::weeks(2)));
+        assert_eq!(parse_age("90m"), Ok(TimeDelta::minutes(90)));
+        for invalid in ["", "d", "30", "30x", "-1d", "0d", "1.5h", "99999999999999w"] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(format_age(TimeDelta::days(30)), "30d");
+        assert_eq!(format_age(TimeDelta::days(14)), "2w");
+        assert_eq!(format_age(TimeDelta::minutes(90)), "90m");
+        assert_eq!(format_age(TimeDelta::seconds(61)), "61s");
+
+        assert_eq!(
+            parse_capture_rate("50/10s"),
+            Ok(CaptureRate {
+                count: 50,
+                window: std::time::Duration::from_secs(10)
+            })
+        );
+        for invalid in ["50", "0/10s", "50/10", "x/1m"] {
+            assert!(parse_capture_rate(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn shell_lines() {
+        match parse_shell_line(r#"search "two words" --oldest-first"#).unwrap() {
+            Commands::Search {
+                query,
+                oldest_first,
+                ..
+            } => {
+                assert_eq!(query, "two words");
+                assert!(oldest_first);
+            }
+            _ => panic!("expected a search"),
+        }
+        assert!(matches!(
+            parse_shell_line("delete 123 -y").unwrap(),
+            Commands::Delete { yes: true, .. }
+        ));
+
+        assert!(parse_shell_line(r#"search "unbalanced"#).is_err());
+        assert!(parse_shell_line("list --database other.db").is_err());
+        assert!(parse_shell_line("frobnicate").is_err());
+    }
+
+    #[test]
+    fn net_listen_bind() {
+        let bind = |args: &[&str]| -> Result<Option<std::net::SocketAddr>, clap::Error> {
+            let cli = Cli::try_parse_from(["clpd", "net-listen"].iter().chain(args))?;
+            match cli.command {
+                Commands::NetListen { bind, .. } => Ok(bind),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(bind(&[]).unwrap(), None);
+        assert_eq!(
+            bind(&["--bind", "0.0.0.0:8080"]).unwrap(),
+            Some("0.0.0.0:8080".parse().unwrap())
+        );
+        assert_eq!(
+            bind(&["--bind", "[::1]:2573"]).unwrap().unwrap().port(),
+            2573
+        );
+        // The port is required, and a host name isn't an address
+        assert!(bind(&["--bind", "0.0.0.0"]).is_err());
+        assert!(bind(&["--bind", "localhost:2573"]).is_err());
+    }
+
+    #[test]
+    fn command_aliases() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once(&"clpd").chain(args))
+                .unwrap()
+                .command
+        };
+        assert!(matches!(
+            parse(&["ls", "-n", "3"]),
+            Commands::List { limit: Some(3), .. }
+        ));
+        assert!(matches!(parse(&["cat", "abc"]), Commands::Show { id, .. } if id == "abc"));
+        assert!(matches!(parse(&["view", "abc"]), Commands::Show { .. }));
+        assert!(matches!(parse(&["cp", "abc"]), Commands::Copy { .. }));
+        assert!(matches!(
+            parse(&["rm", "abc", "-y"]),
+            Commands::Delete { yes: true, .. }
+        ));
+        assert!(matches!(parse(&["find", "todo"]), Commands::Search { .. }));
+        assert!(matches!(
+            parse_shell_line("rm abc").unwrap(),
+            Commands::Delete { .. }
+        ));
+
+        // Global flags work on either side of an alias
+        let cli =
+            Cli::try_parse_from(["clpd", "--database", "x.db", "ls", "--assume-yes"]).unwrap();
+        assert_eq!(cli.database, Some(PathBuf::from("x.db")));
+        assert!(cli.assume_yes);
+        assert!(matches!(cli.command, Commands::List { .. }));
+
+        let cli = Cli::try_parse_from(["clpd", "net-start", "--server-url", "http://box.lan:2573"])
+            .unwrap();
+        let Commands::NetStart { server, .. } = cli.command else {
+            unreachable!()
+        };
+        assert_eq!(server.unwrap().host_str(), Some("box.lan"));
+
+        let help = Cli::command().render_help().to_string();
+        assert!(help.contains("[aliases: cat, view]"), "{}", help);
+        assert!(help.contains("[alias: ls]"), "{}", help);
+    }
+
+    #[test]
+    fn print_only_conflicts_with_storing_options() {
+        for args in [
+            &["--max-entries", "5"][..],
+            &["--images", "thumbnail"],
+            &["--ignore-app", "vault"],
+            &["--no-max-rate"],
+            &["--dedupe-window", "2"],
+        ] {
+            let args = ["clpd", "start", "--print-only"].iter().chain(args);
+            let Err(err) = Cli::try_parse_from(args) else {
+                panic!("accepted");
+            };
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+        for global in [&["--database", "x.db"][..], &["--backup-before", "clear"]] {
+            let args = ["clpd", "start", "--print-only"].iter().chain(global);
+            let Err(err) = Cli::try_parse_from(args) else {
+                panic!("accepted");
+            };
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+
+        let cli =
+            Cli::try_parse_from(["clpd", "start", "--print-only", "--on-change-only"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Start {
+                print_only: true,
+                ..
+            }
+        ));
+        // The storing options' defaults aren't a conflict
+        assert!(Cli::try_parse_from(["clpd", "start", "--max-entries", "5", "--once"]).is_ok());
+    }
+
+    #[test]
+    fn type_and_exclude_type_conflict() {
+        let err = dump_types(&["--type", "text", "--exclude-type", "image"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        for command in ["list", "clear"] {
+            let args = ["clpd", command, "--type", "text", "--exclude-type", "text"];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn selection_filter_and_copy_target() {
+        let entry_from = |selection| {
+            let mut entry =
+                ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), String::new());
+            entry.selection = selection;
+            entry
+        };
+        let old = entry_from(None);
+        let primary = entry_from(Some(ClipboardSelection::Primary));
+
+        let cli = Cli::try_parse_from(["clpd", "list", "--selection", "primary"]).unwrap();
+        let Commands::List { selection, .. } = cli.command else {
+            unreachable!()
+        };
+        assert!(selection.is_set());
+        assert!(selection.matches(&primary));
+        assert!(!selection.matches(&old));
+        assert!(SelectionFilter::default().matches(&old));
+
+        let cli = Cli::try_parse_from(["clpd", "copy", "1"]).unwrap();
+        let Commands::Copy { target, .. } = cli.command else {
+            unreachable!()
+        };
+        assert_eq!(target.selection_for(&primary), ClipboardSelection::Primary);
+        assert_eq!(target.selection_for(&old), ClipboardSelection::Clipboard);
+
+        let cli = Cli::try_parse_from(["clpd", "copy", "1", "--to", "clipboard"]).unwrap();
+        let Commands::Copy { target, .. } = cli.command else {
+            unreachable!()
+        };
+        assert_eq!(
+            target.selection_for(&primary),
+            ClipboardSelection::Clipboard
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use anyhow::{Context, Result};
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::backups::BackupTrigger;
+use crate::tui::ImageOpen;
+
+/// Settings read from the config file, used where no command-line flag is given
+///
+/// The serde derive is the schema: unknown keys and values of the wrong type are
+/// rejected both when the file is loaded and by `clpd config set`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Database location, instead of the platform's data directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_path: Option<PathBuf>,
+    /// Entries kept by `start` and `net-start`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<usize>,
+    /// Age after which `start` deletes entries, e.g. `30d`
+    #[serde(skip_serializing_if = "Option::is_none", with = "age")]
+    pub max_age: Option<TimeDelta>,
+    /// How often `start` reads the clipboard, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none", with = "poll_interval")]
+    pub poll_interval_ms: Option<u64>,
+    /// Server used by `net-start` and `net-browse`
+    #[serde(skip_serializing_if = "Option::is_none", with = "server_url")]
+    pub server_url: Option<reqwest::Url>,
+    /// Port `net-listen` serves on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_port: Option<NonZeroU16>,
+    /// Where `o` in the TUI shows images: auto, inline or external
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_images: Option<ImageOpen>,
+    /// Applications `start` doesn't capture from while they have the focus
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore_apps: Vec<String>,
+    /// Operations the database is backed up before
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backup_before: Vec<BackupTrigger>,
+}
+
+/// Every key of [`Config`], in file order
+pub const KEYS: &[&str] = &[
+    "database_path",
+    "max_entries",
+    "max_age",
+    "poll_interval_ms",
+    "server_url",
+    "listen_port",
+    "open_images",
+    "ignore_apps",
+    "backup_before",
+];
+
+mod age {
+    use chrono::TimeDelta;
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(age: &Option<TimeDelta>, s: S) -> Result<S::Ok, S::Error> {
+        match age {
+            Some(age) => s.serialize_str(&crate::cli::format_age(*age)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<TimeDelta>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| crate::cli::parse_age(&s).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+mod poll_interval {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+    pub fn serialize<S: Serializer>(ms: &Option<u64>, s: S) -> Result<S::Ok, S::Error> {
+        ms.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+        Option::<u64>::deserialize(d)?
+            .map(|ms| crate::cli::parse_poll_interval(&ms.to_string()).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+mod server_url {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(url: &Option<reqwest::Url>, s: S) -> Result<S::Ok, S::Error> {
+        match url {
+            Some(url) => s.serialize_str(url.as_str()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<reqwest::Url>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| crate::cli::parse_server_url(&s).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+impl Config {
+    /// Where the config file is looked for
+    pub fn default_path() -> Result<PathBuf> {
+        let mut path = dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not determine config directory"))?;
+        path.push("clpd");
+        path.push("config.toml");
+        Ok(path)
+    }
+
+    /// Read the config file; a missing file is an empty config
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|e| anyhow::anyhow!("not valid TOML: {}", e))?;
+        Self::from_document(document)
+    }
+
+    fn from_document(document: DocumentMut) -> Result<Self> {
+        Ok(toml_edit::de::from_document(document)?)
+    }
+
+    fn to_document(&self) -> Result<DocumentMut> {
+        Ok(toml_edit::ser::to_document(self)?)
+    }
+
+    fn check_key(key: &str) -> Result<()> {
+        if !KEYS.contains(&key) {
+            anyhow::bail!("Unknown setting '{}'. Settings: {}", key, KEYS.join(", "));
+        }
+        Ok(())
+    }
+
+    /// The value of a setting as it would be written to the file, if it's set
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        Self::check_key(key)?;
+        Ok(self
+            .to_document()?
+            .get(key)
+            .and_then(Item::as_value)
+            .map(|value| match value {
+                Value::String(s) => s.value().clone(),
+                value => value.clone().decorated("", "").to_string(),
+            }))
+    }
+
+    /// Change a setting, failing without changes if the value isn't valid for it
+    ///
+    /// The value is taken as a number or boolean when it reads as one and the
+    /// setting accepts it, as a string otherwise, and as a comma-separated list
+    /// for list settings.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        Self::check_key(key)?;
+
+        let mut candidates = Vec::new();
+        if let Ok(n) = value.parse::<i64>() {
+            candidates.push(Value::from(n));
+        } else if let Ok(b) = value.parse::<bool>() {
+            candidates.push(Value::from(b));
+        }
+        candidates.push(Value::from(value));
+        candidates.push(Value::from(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<toml_edit::Array>(),
+        ));
+
+        let mut errors = Vec::new();
+        for candidate in candidates {
+            let mut document = self.to_document()?;
+            document.insert(key, Item::Value(candidate));
+            match Self::from_document(document) {
+                Ok(config) => {
+                    *self = config;
+                    return Ok(());
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        // A type mismatch says less than why a value of the right type was refused
+        let error = errors
+            .iter()
+            .find(|e| !e.starts_with("invalid type"))
+            .unwrap_or(&errors[0]);
+        anyhow::bail!(
+            "Invalid value '{}' for {}: {}",
+            value,
+            key,
+            error.trim_end()
+        )
+    }
+}
+
+/// The config file as written, for reading or changing one setting whatever the
+/// others hold
+///
+/// `config set` goes through this so that it can fix a file [`Config::load`]
+/// refuses, and keeps the file's comments and layout.
+pub struct ConfigFile {
+    path: PathBuf,
+    document: DocumentMut,
+}
+
+impl ConfigFile {
+    /// What a config file starts with
+    const HEADER: &str = "# clpd settings, change them with `clpd config set <key> <value>`\n";
+
+    /// Read the config file; a missing file is an empty one
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::empty(path)),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        let document = text
+            .parse::<DocumentMut>()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            document,
+        })
+    }
+
+    /// A config file with no settings, replacing whatever is at `path` when saved
+    pub fn empty(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            document: Self::HEADER.parse().expect("the header is valid TOML"),
+        }
+    }
+
+    /// The value of a setting, failing if it's set to something invalid
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        Config::check_key(key)?;
+        let Some(item) = self.document.get(key) else {
+            return Ok(None);
+        };
+        // Read on its own, so that only this setting has to be valid
+        let mut document = DocumentMut::new();
+        document.insert(key, item.clone());
+        Config::from_document(document)
+            .with_context(|| format!("Invalid {} in {}", key, self.path.display()))?
+            .get(key)
+    }
+
+    /// Change a setting, failing without changes if the value isn't valid for it
+    ///
+    /// See [`Config::set`] for how the value is read; returns it as stored.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<String> {
+        let mut config = Config::default();
+        config.set(key, value)?;
+        // An empty list is the default, which isn't written
+        match config.to_document()?.remove(key) {
+            Some(Item::Value(mut new)) => {
+                // Comments on the line stay where they were
+                if let Some(old) = self.document.get(key).and_then(Item::as_value) {
+                    *new.decor_mut() = old.decor().clone();
+                }
+                self.document[key] = Item::Value(new);
+            }
+            _ => {
+                self.document.remove(key);
+            }
+        }
+        Ok(config.get(key)?.unwrap_or_default())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Failed to create config directory")?;
+        }
+        fs::write(&self.path, self.document.to_string())
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_get_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clpd").join("config.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        let mut config = Config::default();
+        let mut file = ConfigFile::open(&path).unwrap();
+        for (key, value) in [
+            ("database_path", r#"C:\Users\me\clpd "db""#),
+            ("max_entries", "1000"),
+            ("max_age", "30d"),
+            ("poll_interval_ms", "250"),
+            ("server_url", "http://10.0.0.2:2573"),
+            ("listen_port", "8080"),
+            ("open_images", "inline"),
+            ("ignore_apps", "KeePassXC, 1password"),
+            ("backup_before", "clear,rekey"),
+        ] {
+            config.set(key, value).unwrap();
+            file.set(key, value).unwrap();
+        }
+        file.save().unwrap();
+
+        let loaded = Config::load(&path).unwrap();
+        assert_eq!(loaded, config);
+        assert_eq!(loaded.max_age, Some(TimeDelta::days(30)));
+        assert_eq!(loaded.poll_interval_ms, Some(250));
+        assert_eq!(loaded.listen_port.map(NonZeroU16::get), Some(8080));
+        assert_eq!(loaded.open_images, Some(ImageOpen::Inline));
+        assert_eq!(loaded.ignore_apps, ["KeePassXC", "1password"]);
+        assert_eq!(
+            loaded.backup_before,
+            [BackupTrigger::Clear, BackupTrigger::Passwd]
+        );
+        for key in KEYS {
+            assert!(loaded.get(key).unwrap().is_some(), "{} not saved", key);
+        }
+        assert_eq!(loaded.get("max_age").unwrap().as_deref(), Some("30d"));
+        assert_eq!(
+            loaded.get("database_path").unwrap().as_deref(),
+            Some(r#"C:\Users\me\clpd "db""#)
+        );
+        assert_eq!(Config::default().get("max_entries").unwrap(), None);
+        assert_eq!(
+            ConfigFile::open(&path)
+                .unwrap()
+                .get("backup_before")
+                .unwrap()
+                .as_deref(),
+            Some(r#"["clear", "passwd"]"#)
+        );
+    }
+
+    #[test]
+    fn test_config_file_with_invalid_setting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "# mine\nmax_age = \"30\" # a month\nmax_entries = 10\n",
+        )
+        .unwrap();
+        assert!(Config::load(&path).is_err());
+
+        // Only the setting asked about has to be valid
+        let mut file = ConfigFile::open(&path).unwrap();
+        assert_eq!(file.get("max_entries").unwrap().as_deref(), Some("10"));
+        assert_eq!(file.get("listen_port").unwrap(), None);
+        let err = format!("{:#}", file.get("max_age").unwrap_err());
+        assert!(err.contains("Invalid max_age in"), "{}", err);
+        assert!(err.contains("expected e.g. 30d"), "{}", err);
+
+        // Setting it fixes the file, keeping the rest as written
+        assert!(file.set("max_age", "30").is_err());
+        assert_eq!(file.set("max_age", "30d").unwrap(), "30d");
+        file.save().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# mine\nmax_age = \"30d\" # a month\nmax_entries = 10\n"
+        );
+        assert_eq!(
+            Config::load(&path).unwrap().max_age,
+            Some(TimeDelta::days(30))
+        );
+
+        fs::write(&path, "max_entries = \n").unwrap();
+        let err = ConfigFile::open(&path).err().unwrap();
+        assert!(err.downcast_ref::<toml_edit::TomlError>().is_some());
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        let mut config = Config::default();
+        config.set("max_entries", "50").unwrap();
+
+        for (key, value) in [
+            ("max_entries", "many"),
+            ("max_entries", "-1"),
+            ("max_age", "30"),
+            ("max_age", "0d"),
+            ("server_url", "ftp://example.com"),
+            ("poll_interval_ms", "10"),
+            ("poll_interval_ms", "1m"),
+            ("listen_port", "0"),
+            ("listen_port", "70000"),
+            ("open_images", "sixel"),
+            ("backup_before", "delete"),
+        ] {
+            assert!(config.set(key, value).is_err(), "{} = {}", key, value);
+        }
+        let err = config.set("max_age", "30").unwrap_err();
+        assert!(err.to_string().contains("expected e.g. 30d"), "{}", err);
+        assert!(
+            config
+                .set("max_entry", "5")
+                .unwrap_err()
+                .to_string()
+                .contains("Unknown setting")
+        );
+        assert!(config.get("colour").is_err());
+        // Nothing changed by the failed attempts
+        assert_eq!(config.max_entries, Some(50));
+
+        assert!(Config::parse("max_entries = 10\nshow_images = true\n").is_err());
+        assert!(Config::parse("max_entries = \"10\"\n").is_err());
+        assert!(Config::parse("max_entries = [10]\n").is_err());
+        assert!(Config::parse("ignore_apps = \"keepass\"\n").is_err());
+        assert!(Config::parse("ignore_apps = [\"keepass\", 1]\n").is_err());
+        assert_eq!(
+            Config::parse("ignore_apps = [\"keepass\"]\n")
+                .unwrap()
+                .ignore_apps,
+            ["keepass"]
+        );
+        assert!(Config::parse("max_entries = \n").is_err());
+        assert_eq!(
+            Config::parse("# comment\nmax_entries = 10\n")
+                .unwrap()
+                .max_entries,
+            Some(10)
+        );
+    }
+}
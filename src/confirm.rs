@@ -0,0 +1,71 @@
+use anyhow::Result;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Environment variable with the same effect as the global `--assume-yes`
+pub const ASSUME_YES_ENV: &str = "CLPD_ASSUME_YES";
+
+/// Answer every confirmation with yes, set once from `--assume-yes` or the environment
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::Relaxed);
+}
+
+/// Whether confirmations are switched off globally, for prompts asked some other way
+/// than [`confirm`] (e.g. in the TUI)
+pub fn assume_yes() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+}
+
+/// Whether `CLPD_ASSUME_YES` asks to skip confirmations (`1`, `true`, `yes` or `y`)
+pub fn assume_yes_from_env(value: Option<&str>) -> bool {
+    value.is_some_and(|value| {
+        let value = value.trim();
+        ["1", "true", "yes", "y"]
+            .iter()
+            .any(|yes| value.eq_ignore_ascii_case(yes))
+    })
+}
+
+/// Ask a yes/no question on the terminal; anything but "y" counts as no
+///
+/// Always yes without asking when confirmations are switched off globally.
+pub fn confirm(question: &str) -> Result<bool> {
+    confirm_with(assume_yes(), question, &mut io::stdin().lock())
+}
+
+fn confirm_with(assume_yes: bool, question: &str, input: &mut impl BufRead) -> Result<bool> {
+    if assume_yes {
+        return Ok(true);
+    }
+
+    print!("{} (y/N): ", question);
+    io::stdout().flush()?;
+
+    let mut response = String::new();
+    input.read_line(&mut response)?;
+    Ok(response.trim().eq_ignore_ascii_case("y"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assume_yes_skips_prompt() {
+        // Nothing is read: a closed stdin would otherwise count as no
+        let mut input = io::empty();
+        assert!(confirm_with(true, "⚠ Delete entry 'x'?", &mut input).unwrap());
+        assert!(!confirm_with(false, "⚠ Delete entry 'x'?", &mut input).unwrap());
+
+        assert!(confirm_with(false, "Continue?", &mut "Y\n".as_bytes()).unwrap());
+        assert!(!confirm_with(false, "Continue?", &mut "yes\n".as_bytes()).unwrap());
+
+        assert!(assume_yes_from_env(Some("1")));
+        assert!(assume_yes_from_env(Some("True")));
+        assert!(!assume_yes_from_env(Some("0")));
+        assert!(!assume_yes_from_env(Some("")));
+        assert!(!assume_yes_from_env(None));
+    }
+}
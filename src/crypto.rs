@@ -1,6 +1,6 @@
 use anyhow::Result;
 use argon2::{
-    Argon2,
+    Algorithm, Argon2, Params, Version,
     password_hash::{PasswordHasher, SaltString},
 };
 use chacha20poly1305::{
@@ -54,34 +54,151 @@ impl MasterKey {
         &self.0
     }
 
-    #[allow(dead_code)]
+    /// Derive the key from the master password (slow, see [`derive_key`])
+    pub fn from_password(password: &str, salt: &[u8], kdf: KdfParams) -> Result<Self> {
+        derive_key(password, salt, kdf)
+    }
+
     pub fn from_bytes(bytes: [u8; 32]) -> Self {
         Self(bytes)
     }
 
-    pub fn hash(&self) -> [u8; 32] {
+    /// Derive an independent subkey for one use of the key (e.g. one entry)
+    ///
+    /// Uses HKDF-SHA256 with `info` as context, so learning a subkey reveals
+    /// nothing about the master key or any other subkey.
+    pub fn subkey(&self, info: &[u8]) -> MasterKey {
+        use sha2::Sha256;
+
+        let mut key = [0u8; 32];
+        hkdf::Hkdf::<Sha256>::new(Some(b"clpd-subkey"), &self.0)
+            .expand(info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        MasterKey(key)
+    }
+
+    /// Bearer token for the network server, `hex(subkey("clpd-server-token"))`
+    ///
+    /// Proves knowledge of the key to `clpd net-listen` without revealing it.
+    pub fn auth_token(&self) -> String {
+        hex::encode(self.subkey(b"clpd-server-token").as_bytes())
+    }
+
+    /// Short, non-reversible fingerprint of the key for comparing keys across machines
+    ///
+    /// The key is hashed with a domain separation prefix so the fingerprint can't be
+    /// confused with any other value derived from the key.
+    pub fn fingerprint(&self) -> String {
         use sha2::{Digest, Sha256};
         let mut hasher = Sha256::new();
-        hasher.update(&self.0);
+        hasher.update(b"clpd-fingerprint");
+        hasher.update(self.0);
         let result = hasher.finalize();
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&result);
-        hash
+        result[..8]
+            .chunks(2)
+            .map(hex::encode)
+            .collect::<Vec<_>>()
+            .join(":")
     }
 }
 
-/// Generate a random 16-byte salt
-pub fn generate_salt() -> [u8; 16] {
-    let mut salt = [0u8; 16];
+/// Salt length used by `clpd init` unless told otherwise
+pub const DEFAULT_SALT_LEN: usize = 16;
+/// Longest salt Argon2's encoded salt strings can hold
+pub const MAX_SALT_LEN: usize = 48;
+
+/// Generate a random salt of the given length in bytes
+pub fn generate_salt(len: usize) -> Vec<u8> {
+    let mut salt = vec![0u8; len];
     OsRng.fill_bytes(&mut salt);
     salt
 }
 
+/// Argon2id costs a database's key is derived with, stored in it at `init`
+///
+/// Stored rather than taken from the argon2 crate's defaults, which could change
+/// between versions and leave existing databases unopenable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    /// Passes over the memory
+    pub t_cost: u32,
+    /// Lanes
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// What keys were derived with before the parameters were stored: the
+    /// defaults of argon2 0.5
+    pub const LEGACY: Self = Self {
+        m_cost: 19 * 1024,
+        t_cost: 2,
+        p_cost: 1,
+    };
+
+    /// Stronger parameters for `clpd init --kdf-memory/--kdf-iterations`
+    pub fn new(memory_mib: u32, iterations: u32) -> Self {
+        Self {
+            m_cost: memory_mib.saturating_mul(1024),
+            t_cost: iterations,
+            p_cost: Self::LEGACY.p_cost,
+        }
+    }
+
+    /// The parameters as argon2 takes them, failing if they're out of its range
+    fn argon2_params(self) -> Result<Params> {
+        Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters {}: {}", self, e))
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
+/// `m=<KiB>,t=<passes>,p=<lanes>`, as in Argon2's encoded hashes
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "m={},t={},p={}", self.m_cost, self.t_cost, self.p_cost)
+    }
+}
+
+impl std::str::FromStr for KdfParams {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut costs = [None; 3];
+        for part in s.trim().split(',') {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected name=value, got '{}'", part))?;
+            let index = match name {
+                "m" => 0,
+                "t" => 1,
+                "p" => 2,
+                _ => anyhow::bail!("unknown key derivation parameter '{}'", name),
+            };
+            costs[index] = Some(value.parse::<u32>()?);
+        }
+        let [Some(m_cost), Some(t_cost), Some(p_cost)] = costs else {
+            anyhow::bail!("expected m=<KiB>,t=<passes>,p=<lanes>, got '{}'", s);
+        };
+        let params = Self {
+            m_cost,
+            t_cost,
+            p_cost,
+        };
+        params.argon2_params()?;
+        Ok(params)
+    }
+}
+
 /// Derive a 256-bit master key from password and salt using Argon2id
-pub fn derive_key(password: &str, salt: &[u8]) -> Result<MasterKey> {
-    // Configure Argon2id with reasonable parameters
-    // m_cost: 64 MiB, t_cost: 3 iterations, p_cost: 4 parallelism
-    let argon2 = Argon2::default();
+pub fn derive_key(password: &str, salt: &[u8], kdf: KdfParams) -> Result<MasterKey> {
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, kdf.argon2_params()?);
 
     // Create a SaltString from our bytes
     let salt_string = SaltString::encode_b64(salt)
@@ -103,6 +220,9 @@ pub fn derive_key(password: &str, salt: &[u8]) -> Result<MasterKey> {
     Ok(MasterKey(key))
 }
 
+/// Bytes `encrypt` adds to the plaintext: the nonce and the authentication tag
+pub const ENCRYPTION_OVERHEAD: usize = 24 + 16;
+
 /// Encrypted data format: 24-byte nonce || ciphertext
 pub fn encrypt(key: &MasterKey, plaintext: &[u8]) -> Result<Vec<u8>> {
     let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
@@ -152,8 +272,8 @@ mod tests {
     #[test]
     fn test_encrypt_decrypt() {
         let password = "test_password_123";
-        let salt = generate_salt();
-        let key = derive_key(password, &salt).unwrap();
+        let salt = generate_salt(DEFAULT_SALT_LEN);
+        let key = derive_key(password, &salt, KdfParams::LEGACY).unwrap();
 
         let plaintext = b"Hello, World! This is a test message.";
         let encrypted = encrypt(&key, plaintext).unwrap();
@@ -162,11 +282,61 @@ mod tests {
         assert_eq!(plaintext, decrypted.as_slice());
     }
 
+    #[test]
+    fn test_subkeys() {
+        let key = MasterKey::from_bytes([9u8; 32]);
+        let a = key.subkey(b"entry-a");
+        // Entries already stored with their own key depend on this never changing
+        assert_eq!(
+            hex::encode(a.as_bytes()),
+            "53d8339baa132b4fd56d592da190a205828e20366291c3bc490ef8337dbfb40c"
+        );
+        let b = key.subkey(b"entry-b");
+        assert_eq!(a.as_bytes(), key.subkey(b"entry-a").as_bytes());
+        assert_ne!(a.as_bytes(), b.as_bytes());
+        assert_ne!(a.as_bytes(), key.as_bytes());
+
+        let encrypted = encrypt(&a, b"secret").unwrap();
+        assert_eq!(decrypt(&a, &encrypted).unwrap(), b"secret");
+        assert!(decrypt(&b, &encrypted).is_err());
+        assert!(decrypt(&key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_salt_lengths() {
+        for len in [DEFAULT_SALT_LEN, 32, MAX_SALT_LEN] {
+            let salt = generate_salt(len);
+            assert_eq!(salt.len(), len);
+            assert!(derive_key("password", &salt, KdfParams::LEGACY).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_kdf_params() {
+        // Keys of databases without stored parameters must never change
+        let key = derive_key("correct horse", b"0123456789abcdef", KdfParams::LEGACY).unwrap();
+        assert_eq!(
+            hex::encode(key.as_bytes()),
+            "ae4d8c8b713876044c8347c76986a9b5655944aaacfffe9bea4dff9e7b6a1899"
+        );
+
+        let stronger = KdfParams::new(32, 3);
+        assert_eq!(stronger.m_cost, 32 * 1024);
+        let other = derive_key("correct horse", b"0123456789abcdef", stronger).unwrap();
+        assert_ne!(other.as_bytes(), key.as_bytes());
+
+        assert_eq!(KdfParams::LEGACY.to_string(), "m=19456,t=2,p=1");
+        assert_eq!("m=32768,t=3,p=1".parse::<KdfParams>().unwrap(), stronger);
+        for invalid in ["m=32768,t=3", "m=32768,t=0,p=1", "m=1,t=2,p=1", "x=1", ""] {
+            assert!(invalid.parse::<KdfParams>().is_err(), "{}", invalid);
+        }
+    }
+
     #[test]
     fn test_wrong_password() {
-        let salt = generate_salt();
-        let key1 = derive_key("password1", &salt).unwrap();
-        let key2 = derive_key("password2", &salt).unwrap();
+        let salt = generate_salt(DEFAULT_SALT_LEN);
+        let key1 = derive_key("password1", &salt, KdfParams::LEGACY).unwrap();
+        let key2 = derive_key("password2", &salt, KdfParams::LEGACY).unwrap();
 
         let plaintext = b"Secret data";
         let encrypted = encrypt(&key1, plaintext).unwrap();
@@ -178,8 +348,8 @@ mod tests {
     #[test]
     fn test_nonce_uniqueness() {
         let password = "test_password";
-        let salt = generate_salt();
-        let key = derive_key(password, &salt).unwrap();
+        let salt = generate_salt(DEFAULT_SALT_LEN);
+        let key = derive_key(password, &salt, KdfParams::LEGACY).unwrap();
 
         let plaintext = b"Same message";
         let encrypted1 = encrypt(&key, plaintext).unwrap();
@@ -188,4 +358,19 @@ mod tests {
         // Encrypted versions should be different due to random nonces
         assert_ne!(encrypted1, encrypted2);
     }
+
+    #[test]
+    fn test_fingerprint() {
+        let key1 = MasterKey::from_bytes([7u8; 32]);
+        let key2 = MasterKey::from_bytes([7u8; 32]);
+        let key3 = MasterKey::from_bytes([8u8; 32]);
+
+        // Same key gives the same fingerprint, different keys differ
+        assert_eq!(key1.fingerprint(), key2.fingerprint());
+        assert_ne!(key1.fingerprint(), key3.fingerprint());
+
+        // Four groups of two bytes, none of which are the raw key bytes
+        assert_eq!(key1.fingerprint().len(), 19);
+        assert!(!key1.fingerprint().contains("0707"));
+    }
 }
@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which way `clpd cycle` steps through the history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Older,
+    Newer,
+}
+
+/// Where `clpd cycle` is in the history, kept in a small file next to the
+/// database between invocations
+///
+/// The cursor is the ID of the entry copied last, so entries captured in the
+/// meantime don't shift it.
+pub struct CycleCursor {
+    path: PathBuf,
+}
+
+impl CycleCursor {
+    /// The cursor of the database at `db_path`
+    pub fn for_database(db_path: &Path) -> Self {
+        let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".cycle");
+        Self {
+            path: db_path.with_file_name(name),
+        }
+    }
+
+    /// ID of the entry copied last, if cycling has started
+    pub fn load(&self) -> Option<String> {
+        let id = fs::read_to_string(&self.path).ok()?;
+        let id = id.trim();
+        (!id.is_empty()).then(|| id.to_string())
+    }
+
+    pub fn save(&self, id: &str) -> Result<()> {
+        fs::write(&self.path, format!("{}\n", id))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// Go back to the newest entry, returning whether cycling had started
+    pub fn reset(&self) -> Result<bool> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove {}", self.path.display())),
+        }
+    }
+}
+
+/// Index of the entry to copy when stepping from `cursor` through `ids`
+/// (newest first), wrapping around at either end
+///
+/// Without a cursor, or when its entry is gone, the ring starts at the newest
+/// entry, which is usually what's on the clipboard already; so the first step
+/// older copies the one before it.
+pub fn step(ids: &[&str], cursor: Option<&str>, step: Step) -> Option<usize> {
+    if ids.is_empty() {
+        return None;
+    }
+    let current = cursor
+        .and_then(|cursor| ids.iter().position(|id| *id == cursor))
+        .unwrap_or(0);
+    Some(match step {
+        Step::Older => (current + 1) % ids.len(),
+        Step::Newer => (current + ids.len() - 1) % ids.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_wraps_around() {
+        let ids = ["c", "b", "a"];
+        assert_eq!(step(&ids, None, Step::Older), Some(1));
+        assert_eq!(step(&ids, Some("b"), Step::Older), Some(2));
+        assert_eq!(step(&ids, Some("a"), Step::Older), Some(0));
+        assert_eq!(step(&ids, Some("b"), Step::Newer), Some(0));
+        assert_eq!(step(&ids, None, Step::Newer), Some(2));
+        // A deleted entry starts over from the newest
+        assert_eq!(step(&ids, Some("gone"), Step::Older), Some(1));
+        assert_eq!(step(&[], None, Step::Older), None);
+        assert_eq!(step(&["only"], Some("only"), Step::Older), Some(0));
+    }
+
+    #[test]
+    fn test_cursor_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cursor = CycleCursor::for_database(&dir.path().join("db"));
+        assert_eq!(cursor.load(), None);
+        assert!(!cursor.reset().unwrap());
+
+        cursor.save("1700000000-1").unwrap();
+        assert!(dir.path().join("db.cycle").exists());
+        assert_eq!(cursor.load().as_deref(), Some("1700000000-1"));
+        assert!(cursor.reset().unwrap());
+        assert_eq!(cursor.load(), None);
+    }
+}
@@ -1,39 +1,239 @@
+use crate::audit::{self, AuditHead, AuditOp, AuditRecord, AuditReport};
+use crate::cli::EntryType;
 use crate::crypto::encrypt;
-use crate::crypto::{MasterKey, decrypt, derive_key};
-use crate::watcher::LocalClipboardWatcher;
+use crate::crypto::{KdfParams, MasterKey, decrypt};
+use crate::middleware::{AuthToken, CheckAuthorization};
+use crate::watcher::{CaptureOutcome, LocalClipboardWatcher, WriteInProgress};
 // use crate::database::ClipboardDatabase;
-use crate::models::ClipboardEntry;
-use crate::models::{ClipboardContentType, ImageData};
+use crate::models::{ClipboardContentType, DecryptedEntry, ImageData, TextEntry, match_ranges};
+use crate::models::{
+    ClipboardEntry, DEFAULT_MAX_IMAGE_BYTES, EntryMetadata, MonotonicClock, check_image_size,
+};
 use actix_cors::Cors;
 use anyhow::{Context, Result};
 use parking_lot::RwLock;
 use reqwest::ClientBuilder;
-use reqwest::header::{AUTHORIZATION, HeaderValue};
 use sha2::{Digest, Sha256};
-use sled::{Db, Tree};
-// use std::default;
-use actix_web::{
-    App, HttpRequest, HttpResponse, HttpServer, Responder, Scope, get, middleware, post, web,
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
 };
+use sled::{Db, IVec, Transactional, Tree};
+// use std::default;
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, Scope, get, post, web};
 use arboard::Clipboard;
-use std::path::PathBuf;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const META_TREE: &str = "meta";
 const CLIPS_TREE: &str = "clips";
+const TIMESTAMPS_TREE: &str = "timestamps";
+const HASHES_TREE: &str = "hashes";
+const PINNED_TREE: &str = "pinned";
+const DELTAS_TREE: &str = "deltas";
+const QUARANTINE_TREE: &str = "quarantine";
+const USAGE_TREE: &str = "usage";
 const SALT_KEY: &[u8] = b"meta:salt";
 const VERSION_KEY: &[u8] = b"meta:version";
 const PAYLOAD_KEY: &[u8] = b"meta:payload";
+const ENTRY_KEYS_KEY: &[u8] = b"meta:entry_keys";
+const AUDIT_HEAD_KEY: &[u8] = b"meta:audit_head";
+/// Argon2 parameters of the key, `m=<KiB>,t=<passes>,p=<lanes>`; databases
+/// created before they were stored lack it and use [`KdfParams::LEGACY`]
+const KDF_KEY: &[u8] = b"meta:kdf";
+/// Number of stored entries, and of pinned ones, kept up to date with them
+/// (little-endian `u64`s) so that counting doesn't read every entry
+const ENTRY_COUNT_KEY: &[u8] = b"meta:entry_count";
+const PINNED_COUNT_KEY: &[u8] = b"meta:pinned_count";
+/// Present in the usage tree while usage counting is switched on
+const USAGE_ENABLED_KEY: &[u8] = b"usage:enabled";
+/// Encrypted and stored at init; decrypting it proves the password is right
+const VERIFY_PLAINTEXT: &[u8] = b"clpd_test";
+/// Server used by `net-start` and `net-browse` unless `--server` is given
+pub const DEFAULT_SERVER_URL: &str = "http://localhost:2573";
+/// Port `net-listen` serves on unless `--bind` or the config file says otherwise
+pub const DEFAULT_PORT: u16 = 2573;
+/// Largest request `net-listen` accepts and `net-start` sends, unless told otherwise (50 MiB)
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 50 * 1024 * 1024;
+/// Version of the on-disk format written by this build
+const SCHEMA_VERSION: u32 = 4;
+/// First format version with the hash index; older databases get it built on open
+const HASH_INDEX_VERSION: u32 = 2;
+/// First format version with the delta index, likewise
+const DELTA_INDEX_VERSION: u32 = 3;
+/// First format version whose entries are written in one transaction with their
+/// index keys and the entry counts; older databases get the timestamp index
+/// rebuilt and the entries counted once
+const COUNTED_VERSION: u32 = 4;
+/// First line of a file written by `clpd backup`
+const BACKUP_HEADER: &str = "clpd-backup 2";
+/// Header of backups written before they recorded the key derivation parameters
+const BACKUP_HEADER_V1: &str = "clpd-backup 1";
+
+/// A database shared between threads, e.g. the server's workers or a
+/// background watcher and the TUI
+pub type SharedDatabase = Arc<RwLock<ClipboardDatabase>>;
 
+/// Clones share the same open database
+#[derive(Clone)]
 pub struct ClipboardDatabase {
     pub db: Db,
     meta_tree: Tree,
     clips_tree: Tree,
+    // Secondary index: timestamp key -> entry ID, for ordered and range scans
+    timestamps_tree: Tree,
+    // Secondary index: hash key -> entry ID, for duplicate checks
+    hashes_tree: Tree,
+    // Secondary index: IDs of pinned entries, which pruning keeps
+    pinned_tree: Tree,
+    // Secondary index: delta key -> empty, to find the deltas stored against an entry
+    deltas_tree: Tree,
+    path: PathBuf,
+    // Serializes audit log appends, which read and update the head
+    audit_lock: Arc<parking_lot::Mutex<()>>,
+    // The per-entry keys setting, read once rather than on every capture
+    per_entry_keys: Arc<AtomicBool>,
+    // Stamps new entries, never before the newest one stored
+    clock: Arc<MonotonicClock>,
+}
+
+/// Key for the timestamp index: big-endian millis (sign bit flipped so negative
+/// timestamps sort first) followed by the entry ID to keep keys unique
+fn timestamp_key(timestamp: &DateTime<Utc>, id: &str) -> Vec<u8> {
+    let mut key = timestamp_prefix(timestamp).to_vec();
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn timestamp_prefix(timestamp: &DateTime<Utc>) -> [u8; 8] {
+    ((timestamp.timestamp_millis() as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Key for the hash index: the hash, a 0 byte, then the entry ID, so entries
+/// sharing a hash each have their own key
+fn hash_key(hash: &str, id: &str) -> Vec<u8> {
+    let mut key = hash_prefix(hash);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn hash_prefix(hash: &str) -> Vec<u8> {
+    let mut prefix = hash.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Key for the delta index: the base's ID, a 0 byte, then the delta entry's ID
+fn delta_key(base: &str, id: &str) -> Vec<u8> {
+    let mut key = delta_prefix(base);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn delta_prefix(base: &str) -> Vec<u8> {
+    let mut prefix = base.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Hash index keys of an entry: one for its hash, and one for its dedupe hash
+/// when it has a different one
+fn hash_keys(entry: &ClipboardEntry) -> Vec<Vec<u8>> {
+    let mut keys = vec![hash_key(&entry.hash, &entry.id)];
+    if let Some(dedupe_hash) = &entry.dedupe_hash
+        && *dedupe_hash != entry.hash
+    {
+        keys.push(hash_key(dedupe_hash, &entry.id));
+    }
+    keys
+}
+
+/// A count stored under [`ENTRY_COUNT_KEY`] or [`PINNED_COUNT_KEY`], 0 before
+/// there is one
+fn count_from(value: Option<&IVec>) -> u64 {
+    value
+        .and_then(|value| <[u8; 8]>::try_from(value.as_ref()).ok())
+        .map_or(0, u64::from_le_bytes)
+}
+
+/// The trees an entry is written to, within a transaction
+struct EntryTrees<'a> {
+    clips: &'a TransactionalTree,
+    timestamps: &'a TransactionalTree,
+    hashes: &'a TransactionalTree,
+    pinned: &'a TransactionalTree,
+    deltas: &'a TransactionalTree,
+    meta: &'a TransactionalTree,
+}
+
+impl EntryTrees<'_> {
+    fn add_to_count(&self, key: &[u8], change: i64) -> ConflictableTransactionResult<()> {
+        let count = count_from(self.meta.get(key)?.as_ref()).saturating_add_signed(change);
+        self.meta.insert(key, &count.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Add the index keys of `entry`
+    fn index(&self, entry: &ClipboardEntry) -> ConflictableTransactionResult<()> {
+        let id = entry.id.as_bytes();
+        self.timestamps
+            .insert(timestamp_key(&entry.timestamp, &entry.id), id)?;
+        for key in hash_keys(entry) {
+            self.hashes.insert(key, id)?;
+        }
+        if entry.pinned && self.pinned.insert(id, &[])?.is_none() {
+            self.add_to_count(PINNED_COUNT_KEY, 1)?;
+        }
+        if let Some(base) = &entry.delta_base {
+            self.deltas.insert(delta_key(base, &entry.id), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Remove the index keys of the entry stored as `value` under `id`,
+    /// returning its hash if it's readable
+    fn unindex(&self, id: &[u8], value: &[u8]) -> ConflictableTransactionResult<Option<String>> {
+        if self.pinned.remove(id)?.is_some() {
+            self.add_to_count(PINNED_COUNT_KEY, -1)?;
+        }
+        // Unreadable entries have no other keys, they're left out of the indexes
+        let Ok(entry) = ClipboardEntry::from_stored(value) else {
+            return Ok(None);
+        };
+        self.timestamps
+            .remove(timestamp_key(&entry.timestamp, &entry.id))?;
+        for key in hash_keys(&entry) {
+            self.hashes.remove(key)?;
+        }
+        if let Some(base) = &entry.delta_base {
+            self.deltas.remove(delta_key(base, &entry.id))?;
+        }
+        Ok(Some(entry.hash))
+    }
+}
+
+/// The timestamp a [`timestamp_key`] starts with
+fn timestamp_from_key(key: &[u8]) -> Option<DateTime<Utc>> {
+    let prefix: [u8; 8] = key.get(..8)?.try_into().ok()?;
+    DateTime::from_timestamp_millis((u64::from_be_bytes(prefix) ^ (1 << 63)) as i64)
 }
 
 impl ClipboardDatabase {
     /// Open or create a database at the given path
     pub fn open(path: PathBuf) -> Result<Self> {
+        Self::open_with(path, |_| Ok(()))
+    }
+
+    /// Open or create a database, calling `before_migration` with it before a
+    /// database in an older format is upgraded (e.g. to back it up)
+    pub fn open_with(
+        path: PathBuf,
+        before_migration: impl FnOnce(&Self) -> Result<()>,
+    ) -> Result<Self> {
         // Create parent directories if they don't exist
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent).context("Failed to create database directory")?;
@@ -45,15 +245,176 @@ impl ClipboardDatabase {
             .open_tree(META_TREE)
             .context("Failed to open meta tree")?;
 
+        // Refuse before touching anything else, a newer format may not be readable here
+        let version = match meta_tree.get(VERSION_KEY)? {
+            Some(version) => Some(
+                <[u8; 4]>::try_from(version.as_ref())
+                    .map(u32::from_le_bytes)
+                    .map_err(|_| anyhow::anyhow!("Database has an unrecognized version marker"))?,
+            ),
+            None => None,
+        };
+        if let Some(version) = version
+            && version > SCHEMA_VERSION
+        {
+            anyhow::bail!(
+                "Database was created by a newer version of clpd (format version {}, this \
+                 build supports up to {}). Please upgrade clpd.",
+                version,
+                SCHEMA_VERSION
+            );
+        }
+
         let clips_tree = db
             .open_tree(CLIPS_TREE)
             .context("Failed to open clips tree")?;
 
-        Ok(Self {
+        let timestamps_tree = db
+            .open_tree(TIMESTAMPS_TREE)
+            .context("Failed to open timestamps tree")?;
+
+        let hashes_tree = db
+            .open_tree(HASHES_TREE)
+            .context("Failed to open hashes tree")?;
+
+        let pinned_tree = db
+            .open_tree(PINNED_TREE)
+            .context("Failed to open pinned tree")?;
+
+        let deltas_tree = db
+            .open_tree(DELTAS_TREE)
+            .context("Failed to open deltas tree")?;
+
+        let per_entry_keys = meta_tree
+            .get(ENTRY_KEYS_KEY)?
+            .is_some_and(|value| value.as_ref() == [1]);
+
+        let database = Self {
             db,
             meta_tree,
             clips_tree,
-        })
+            timestamps_tree,
+            hashes_tree,
+            pinned_tree,
+            deltas_tree,
+            path,
+            audit_lock: Arc::new(parking_lot::Mutex::new(())),
+            per_entry_keys: Arc::new(AtomicBool::new(per_entry_keys)),
+            clock: Arc::new(MonotonicClock::new()),
+        };
+
+        if version.is_some_and(|version| version < SCHEMA_VERSION) {
+            before_migration(&database)?;
+        }
+
+        // Databases written before the hash index existed get it built once.
+        // Uninitialized databases are marked when `init` writes the version.
+        if version.unwrap_or(0) < HASH_INDEX_VERSION {
+            database.rebuild_hash_index()?;
+            if version.is_some() {
+                database
+                    .meta_tree
+                    .insert(VERSION_KEY, &HASH_INDEX_VERSION.to_le_bytes())?;
+                database.meta_tree.flush()?;
+            }
+        }
+        if version.unwrap_or(0) < DELTA_INDEX_VERSION {
+            database.rebuild_delta_index()?;
+            if version.is_some() {
+                database
+                    .meta_tree
+                    .insert(VERSION_KEY, &DELTA_INDEX_VERSION.to_le_bytes())?;
+                database.meta_tree.flush()?;
+            }
+        }
+        // Before, an interrupted write could leave an entry out of the index
+        if version.unwrap_or(0) < COUNTED_VERSION {
+            database.rebuild_timestamp_index()?;
+            database.recount()?;
+            if version.is_some() {
+                database
+                    .meta_tree
+                    .insert(VERSION_KEY, &COUNTED_VERSION.to_le_bytes())?;
+            }
+            database.meta_tree.flush()?;
+        }
+
+        // A clock that went back since the last capture mustn't put new entries
+        // before the stored ones
+        if let Some((key, _)) = database.timestamps_tree.last()?
+            && let Some(newest) = timestamp_from_key(&key)
+        {
+            database.clock.observe(&newest);
+        }
+
+        Ok(database)
+    }
+
+    /// Count the stored and pinned entries, after they were changed other than
+    /// through [`Self::store_entry`] and [`Self::remove_entry`]
+    fn recount(&self) -> Result<()> {
+        self.meta_tree.insert(
+            ENTRY_COUNT_KEY,
+            &(self.clips_tree.len() as u64).to_le_bytes(),
+        )?;
+        self.meta_tree.insert(
+            PINNED_COUNT_KEY,
+            &(self.pinned_tree.len() as u64).to_le_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Rebuild the timestamp index from the stored entries
+    fn rebuild_timestamp_index(&self) -> Result<()> {
+        self.timestamps_tree.clear()?;
+        for item in self.clips_tree.iter() {
+            let (_, value) = item?;
+            // Unreadable entries can't be ordered, leave them out of the index
+            if let Ok(entry) = ClipboardEntry::from_stored(&value) {
+                self.timestamps_tree.insert(
+                    timestamp_key(&entry.timestamp, &entry.id),
+                    entry.id.as_bytes(),
+                )?;
+            }
+        }
+        self.timestamps_tree.flush()?;
+        Ok(())
+    }
+
+    /// Rebuild the hash index from the stored entries
+    fn rebuild_hash_index(&self) -> Result<()> {
+        self.hashes_tree.clear()?;
+        for item in self.clips_tree.iter() {
+            let (_, value) = item?;
+            // Unreadable entries have no hash to find them by
+            if let Ok(entry) = ClipboardEntry::from_stored(&value) {
+                for key in hash_keys(&entry) {
+                    self.hashes_tree.insert(key, entry.id.as_bytes())?;
+                }
+            }
+        }
+        self.hashes_tree.flush()?;
+        Ok(())
+    }
+
+    /// Rebuild the delta index from the stored entries
+    fn rebuild_delta_index(&self) -> Result<()> {
+        self.deltas_tree.clear()?;
+        for item in self.clips_tree.iter() {
+            let (_, value) = item?;
+            if let Ok(entry) = ClipboardEntry::from_stored(&value)
+                && let Some(base) = &entry.delta_base
+            {
+                self.deltas_tree.insert(delta_key(base, &entry.id), &[])?;
+            }
+        }
+        self.deltas_tree.flush()?;
+        Ok(())
+    }
+
+    /// Path the database was opened from
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     /// Get the default database path
@@ -75,12 +436,225 @@ impl ClipboardDatabase {
         self.meta_tree.insert(SALT_KEY, salt)?;
         // while `sled` prefers big endian when needing ordering, here we just need a fixed
         // representation, so little endian is fine
-        self.meta_tree.insert(VERSION_KEY, &1u32.to_le_bytes())?;
+        self.meta_tree
+            .insert(VERSION_KEY, &SCHEMA_VERSION.to_le_bytes())?;
         self.meta_tree.insert(PAYLOAD_KEY, payload)?;
         self.meta_tree.flush()?;
         Ok(())
     }
 
+    /// Initialize the database for `key`, derived with `kdf`, making sure it's on
+    /// disk before returning
+    ///
+    /// The salt, key derivation parameters and verification payload are flushed
+    /// and read back, and the payload must decrypt with the key; otherwise an
+    /// error is returned.
+    pub fn initialize_with_key(&self, salt: &[u8], key: &MasterKey, kdf: KdfParams) -> Result<()> {
+        let payload = encrypt(key, VERIFY_PLAINTEXT)?;
+        // Before the salt, which marks the database as initialized
+        self.set_kdf_params(kdf)?;
+        self.initialize(salt, &payload)?;
+        self.db
+            .flush()
+            .context("Failed to write the database to disk")?;
+
+        if self.get_salt()? != salt || self.get_payload()? != payload || self.kdf_params()? != kdf {
+            anyhow::bail!("Initialization data read back from the database doesn't match");
+        }
+        if !self.verify_password(key)? {
+            anyhow::bail!("The stored password check doesn't decrypt with the new key");
+        }
+        Ok(())
+    }
+
+    /// The Argon2 parameters the key is derived with
+    pub fn kdf_params(&self) -> Result<KdfParams> {
+        match self.meta_tree.get(KDF_KEY)? {
+            Some(value) => String::from_utf8_lossy(&value)
+                .parse()
+                .context("Invalid key derivation parameters in the database"),
+            None => Ok(KdfParams::LEGACY),
+        }
+    }
+
+    /// Record the Argon2 parameters the key is derived with
+    pub fn set_kdf_params(&self, kdf: KdfParams) -> Result<()> {
+        self.meta_tree.insert(KDF_KEY, kdf.to_string().as_bytes())?;
+        self.meta_tree.flush()?;
+        Ok(())
+    }
+
+    /// Choose whether new entries are encrypted under per-entry subkeys
+    pub fn set_per_entry_keys(&self, enabled: bool) -> Result<()> {
+        self.meta_tree
+            .insert(ENTRY_KEYS_KEY, &[u8::from(enabled)])?;
+        self.meta_tree.flush()?;
+        self.per_entry_keys.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether new entries are encrypted under per-entry subkeys
+    ///
+    /// Each entry records how it was encrypted, so this only affects new entries.
+    /// Read when the database is opened, as `init` is the only thing setting it.
+    pub fn per_entry_keys(&self) -> bool {
+        self.per_entry_keys.load(Ordering::Relaxed)
+    }
+
+    /// The clock new entries are stamped with (see [`ClipboardEntry::stamp`]),
+    /// seeded with the newest stored entry when the database is opened
+    pub fn clock(&self) -> &MonotonicClock {
+        &self.clock
+    }
+
+    /// Path of the audit log, next to the database directory
+    pub fn audit_log_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".audit.log");
+        self.path.with_file_name(name)
+    }
+
+    /// The last record of the audit log, if it's enabled
+    fn audit_head(&self) -> Result<Option<AuditHead>> {
+        self.meta_tree
+            .get(AUDIT_HEAD_KEY)?
+            .map(|value| bincode::deserialize(&value).context("Audit log head is malformed"))
+            .transpose()
+    }
+
+    pub fn audit_enabled(&self) -> Result<bool> {
+        Ok(self.meta_tree.contains_key(AUDIT_HEAD_KEY)?)
+    }
+
+    /// Start the audit log, recording the entries stored so far as its baseline
+    ///
+    /// Returns the number of baseline records.
+    pub fn enable_audit_log(&self) -> Result<usize> {
+        if self.audit_enabled()? {
+            anyhow::bail!("The audit log is already enabled");
+        }
+        let path = self.audit_log_path();
+        if path.exists() {
+            anyhow::bail!(
+                "{} already exists; move it away to start a new audit log",
+                path.display()
+            );
+        }
+
+        let _guard = self.audit_lock.lock();
+        let mut entries = self.list_entries()?;
+        entries.reverse();
+        let mut head = None;
+        for entry in &entries {
+            let record =
+                AuditRecord::next(head.as_ref(), AuditOp::Baseline, &entry.id, &entry.hash);
+            audit::append(&path, &record)?;
+            head = Some(record.head());
+        }
+        // An empty database still needs a record to anchor the chain
+        let head = match head {
+            Some(head) => head,
+            None => {
+                let record = AuditRecord::next(None, AuditOp::Baseline, "", "");
+                audit::append(&path, &record)?;
+                record.head()
+            }
+        };
+        self.meta_tree
+            .insert(AUDIT_HEAD_KEY, bincode::serialize(&head)?)?;
+        self.meta_tree.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Append a record to the audit log, if it's enabled
+    fn log_audit(&self, op: AuditOp, id: &str, hash: &str) -> Result<()> {
+        let _guard = self.audit_lock.lock();
+        let Some(head) = self.audit_head()? else {
+            return Ok(());
+        };
+        let record = AuditRecord::next(Some(&head), op, id, hash);
+        audit::append(&self.audit_log_path(), &record)?;
+        self.meta_tree
+            .insert(AUDIT_HEAD_KEY, bincode::serialize(&record.head())?)?;
+        self.meta_tree.flush()?;
+        Ok(())
+    }
+
+    /// Verify the audit log's chain and compare it with the stored entries
+    pub fn audit(&self) -> Result<AuditCheck> {
+        let Some(head) = self.audit_head()? else {
+            anyhow::bail!("The audit log isn't enabled. Run 'clpd audit --enable' to start it.");
+        };
+        let log = audit::verify(&self.audit_log_path(), &head)?;
+
+        let mut check = AuditCheck::default();
+        let mut logged = log.live.clone();
+        for entry in self.list_entries()? {
+            match logged.remove(&entry.id) {
+                Some(hash) if hash == entry.hash => {}
+                Some(_) => check.changed.push(entry.id),
+                None => check.unlogged.push(entry.id),
+            }
+        }
+        check.missing = logged.into_keys().collect();
+        check.missing.sort();
+        check.log = log;
+        Ok(check)
+    }
+
+    /// Switch usage counting on or off; switching it off also forgets the counts
+    pub fn set_usage_counting(&self, enabled: bool) -> Result<()> {
+        let tree = self.db.open_tree(USAGE_TREE)?;
+        if enabled {
+            tree.insert(USAGE_ENABLED_KEY, &[1])?;
+        } else {
+            tree.clear()?;
+        }
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Add one to a usage counter, if usage counting is on
+    ///
+    /// Counting never gets in the way of the action being counted, so failures
+    /// are ignored. The counts stay in the database and are never sent anywhere.
+    pub fn count_usage(&self, counter: UsageCounter) {
+        let Ok(tree) = self.db.open_tree(USAGE_TREE) else {
+            return;
+        };
+        if !tree.contains_key(USAGE_ENABLED_KEY).unwrap_or(false) {
+            return;
+        }
+        let _ = tree.update_and_fetch(counter.key(), |old| {
+            let count = old
+                .and_then(|bytes| <[u8; 8]>::try_from(bytes).ok())
+                .map_or(0, u64::from_be_bytes);
+            Some((count + 1).to_be_bytes().to_vec())
+        });
+    }
+
+    /// Every usage counter with its count, or None while counting is off
+    pub fn usage_counts(&self) -> Result<Option<Vec<(UsageCounter, u64)>>> {
+        let tree = self.db.open_tree(USAGE_TREE)?;
+        if !tree.contains_key(USAGE_ENABLED_KEY)? {
+            return Ok(None);
+        }
+        let mut counts = Vec::new();
+        for counter in UsageCounter::ALL {
+            let count = match tree.get(counter.key())? {
+                Some(bytes) => u64::from_be_bytes(
+                    bytes
+                        .as_ref()
+                        .try_into()
+                        .context("Usage counter is malformed")?,
+                ),
+                None => 0,
+            };
+            counts.push((counter, count));
+        }
+        Ok(Some(counts))
+    }
+
     /// Get the stored salt
     pub fn get_salt(&self) -> Result<Vec<u8>> {
         self.meta_tree
@@ -101,25 +675,76 @@ impl ClipboardDatabase {
     pub fn verify_password(&self, key: &MasterKey) -> Result<bool> {
         let payload = self.get_payload()?;
         match decrypt(key, &payload) {
-            Ok(plaintext) => Ok(plaintext == b"clpd_test"),
+            Ok(plaintext) => Ok(plaintext == VERIFY_PLAINTEXT),
             Err(_) => Ok(false),
         }
     }
 
     /// Insert a clipboard entry
     pub fn insert_entry(&self, entry: &ClipboardEntry) -> Result<()> {
+        self.store_entry(entry)?;
+        self.log_audit(AuditOp::Add, &entry.id, &entry.hash)
+    }
+
+    /// Write an entry without recording it in the audit log, for rewrites
+    ///
+    /// The entry, its index keys and the counts are written in one transaction,
+    /// replacing the index keys of the version it overwrites.
+    fn store_entry(&self, entry: &ClipboardEntry) -> Result<()> {
         let serialized = bincode::serialize(entry).context("Failed to serialize entry")?;
 
-        self.clips_tree.insert(entry.id.as_bytes(), serialized)?;
+        self.entry_transaction(|trees| {
+            match trees
+                .clips
+                .insert(entry.id.as_bytes(), serialized.as_slice())?
+            {
+                Some(old) => {
+                    trees.unindex(entry.id.as_bytes(), &old)?;
+                }
+                None => trees.add_to_count(ENTRY_COUNT_KEY, 1)?,
+            }
+            trees.index(entry)
+        })?;
+        self.meta_tree.flush()?;
         self.clips_tree.flush()?;
+        self.timestamps_tree.flush()?;
+        self.hashes_tree.flush()?;
+        self.pinned_tree.flush()?;
+        self.deltas_tree.flush()?;
         Ok(())
     }
 
+    /// Move an entry to the top of the history by giving it the current time
+    ///
+    /// The ID stays the same, so delta entries and registers still find it.
+    /// Returns the entry as updated, or `None` if there's no such entry.
+    pub fn touch_entry(&self, id: &str) -> Result<Option<ClipboardEntry>> {
+        let Some(mut entry) = self.get_entry(id)? else {
+            return Ok(None);
+        };
+        entry.timestamp = self.clock.stamp(Utc::now());
+        self.store_entry(&entry)?;
+        Ok(Some(entry))
+    }
+
+    /// Pin or unpin an entry, so pruning keeps it or may delete it again
+    ///
+    /// Returns the entry as updated, or `None` if there's no such entry.
+    pub fn set_pinned(&self, id: &str, pinned: bool) -> Result<Option<ClipboardEntry>> {
+        let Some(mut entry) = self.get_entry(id)? else {
+            return Ok(None);
+        };
+        entry.pinned = pinned;
+        self.store_entry(&entry)?;
+        Ok(Some(entry))
+    }
+
     /// Get an entry by ID
     pub fn get_entry(&self, id: &str) -> Result<Option<ClipboardEntry>> {
         match self.clips_tree.get(id.as_bytes())? {
             Some(data) => {
-                let entry = bincode::deserialize(&data).context("Failed to deserialize entry")?;
+                let entry =
+                    ClipboardEntry::from_stored(&data).context("Failed to deserialize entry")?;
                 Ok(Some(entry))
             }
             None => Ok(None),
@@ -132,35 +757,247 @@ impl ClipboardDatabase {
 
         for item in self.clips_tree.iter() {
             let (_, value) = item?;
-            let entry: ClipboardEntry =
-                bincode::deserialize(&value).context("Failed to deserialize entry")?;
+            let entry =
+                ClipboardEntry::from_stored(&value).context("Failed to deserialize entry")?;
             entries.push(entry);
         }
 
-        // Sort by timestamp, newest first
-        entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        entries.sort_by(ClipboardEntry::newest_first);
 
         Ok(entries)
     }
 
-    /// Check if an entry with the given hash already exists
-    pub fn hash_exists(&self, hash: &str) -> Result<bool> {
-        for item in self.clips_tree.iter() {
-            let (_, value) = item?;
-            let entry: ClipboardEntry =
-                bincode::deserialize(&value).context("Failed to deserialize entry")?;
-            if entry.hash == hash {
-                return Ok(true);
+    /// List entries with timestamps in the given range (inclusive, newest first)
+    ///
+    /// Uses the timestamp index, so entries outside the range are never read.
+    pub fn entries_between(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<ClipboardEntry>> {
+        let start = since.map_or([0u8; 8], |t| timestamp_prefix(&t)).to_vec();
+        let range = match until {
+            // Every key for the last millisecond sorts before the next millisecond's prefix
+            Some(until) => {
+                let end = timestamp_prefix(&(until + chrono::Duration::milliseconds(1)));
+                self.timestamps_tree.range(start..end.to_vec())
+            }
+            None => self.timestamps_tree.range(start..),
+        };
+
+        let mut entries = Vec::new();
+        for item in range.rev() {
+            let (_, id) = item?;
+            if let Some(entry) = self.get_entry(&String::from_utf8_lossy(&id))? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Entries from newest to oldest, each read only when the iterator gets to it
+    ///
+    /// Walks the timestamp index, so stopping early (e.g. with `take`) never
+    /// deserializes the older entries.
+    ///
+    /// Reversed, it goes from oldest to newest.
+    pub fn iter_entries_newest_first(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = Result<ClipboardEntry>> + '_ {
+        self.timestamps_tree
+            .iter()
+            .rev()
+            .filter_map(move |item| match item {
+                Ok((_, id)) => self.get_entry(&String::from_utf8_lossy(&id)).transpose(),
+                Err(e) => Some(Err(e.into())),
+            })
+    }
+
+    /// List the newest entries, reading only as many as requested
+    pub fn newest_entries(&self, limit: usize) -> Result<Vec<ClipboardEntry>> {
+        self.iter_entries_newest_first().take(limit).collect()
+    }
+
+    /// The most recent text entry, used as the base for delta storage
+    ///
+    /// Delta entries are resolved to their base so that deltas are never chained.
+    pub fn latest_text_base(&self) -> Result<Option<ClipboardEntry>> {
+        for entry in self.iter_entries_newest_first() {
+            let entry = entry?;
+            if entry.content_type != ClipboardContentType::Text {
+                continue;
+            }
+            return match &entry.delta_base {
+                Some(base) => self.get_entry(base),
+                None => Ok(Some(entry)),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Decrypt an entry, reconstructing delta entries from their base
+    pub fn decrypt_entry(&self, key: &MasterKey, entry: &ClipboardEntry) -> Result<Vec<u8>> {
+        let base = match &entry.delta_base {
+            Some(id) => self.get_entry(id)?,
+            None => None,
+        };
+        entry.decrypt(key, base.as_ref())
+    }
+
+    /// Decrypt an entry and decode it according to its content type
+    pub fn decrypt_content(
+        &self,
+        key: &MasterKey,
+        entry: &ClipboardEntry,
+    ) -> Result<DecryptedEntry> {
+        DecryptedEntry::from_plaintext(&entry.content_type, self.decrypt_entry(key, entry)?)
+    }
+
+    /// Get an entry by ID along with its decrypted content
+    pub fn get_decrypted(
+        &self,
+        key: &MasterKey,
+        id: &str,
+    ) -> Result<(ClipboardEntry, DecryptedEntry)> {
+        let (entry, plaintext) = self.get_plaintext(key, id)?;
+        let content = DecryptedEntry::from_plaintext(&entry.content_type, plaintext)?;
+        Ok((entry, content))
+    }
+
+    /// Get an entry by ID along with its decrypted bytes, before they're decoded
+    pub fn get_plaintext(&self, key: &MasterKey, id: &str) -> Result<(ClipboardEntry, Vec<u8>)> {
+        let entry = self
+            .get_entry(id)?
+            .ok_or_else(|| anyhow::anyhow!("Entry '{}' not found", id))?;
+        let plaintext = self.decrypt_entry(key, &entry)?;
+        Ok((entry, plaintext))
+    }
+
+    /// Text entries containing the query, newest first unless asked otherwise
+    ///
+    /// Entries are decrypted one at a time, stopping once `limit` matched.
+    /// Entries that fail to decrypt are skipped.
+    pub fn search_text(
+        &self,
+        key: &MasterKey,
+        search: &TextSearch,
+    ) -> Result<Vec<(ClipboardEntry, TextEntry)>> {
+        let entries: Box<dyn Iterator<Item = Result<ClipboardEntry>>> = if search.oldest_first {
+            Box::new(self.iter_entries_newest_first().rev())
+        } else {
+            Box::new(self.iter_entries_newest_first())
+        };
+        let mut matches = Vec::new();
+        for entry in entries {
+            if search.limit.is_some_and(|limit| matches.len() >= limit) {
+                break;
+            }
+            let entry = entry?;
+            if entry.content_type != ClipboardContentType::Text {
+                continue;
+            }
+            if let Ok(DecryptedEntry::Text(text)) = self.decrypt_content(key, &entry)
+                && !match_ranges(&text.lossy(), &search.query, search.ignore_case).is_empty()
+            {
+                matches.push((entry, text));
             }
         }
-        Ok(false)
+        Ok(matches)
+    }
+
+    /// IDs of the delta entries stored against the given entry
+    ///
+    /// A lookup in the delta index; no entry is read.
+    pub fn dependents_of(&self, id: &str) -> Result<Vec<String>> {
+        let prefix = delta_prefix(id);
+        let mut dependents = Vec::new();
+        for item in self.deltas_tree.scan_prefix(&prefix) {
+            let (key, _) = item?;
+            dependents.push(String::from_utf8_lossy(&key[prefix.len()..]).into_owned());
+        }
+        Ok(dependents)
+    }
+
+    /// Rewrite a delta entry with its full content
+    fn materialize(&self, key: &MasterKey, id: &str) -> Result<()> {
+        let Some(mut entry) = self.get_entry(id)? else {
+            return Ok(());
+        };
+        let plaintext = self.decrypt_entry(key, &entry)?;
+        if entry.delta_base.take().is_none() {
+            return Ok(());
+        }
+        entry.encrypt_payload(key, &plaintext)?;
+        // Same ID and content, so nothing for the audit log
+        self.store_entry(&entry)
+    }
+
+    /// Check if an entry with the given hash (or dedupe hash) already exists
+    ///
+    /// A lookup in the hash index; no entry is read.
+    pub fn hash_exists(&self, hash: &str) -> Result<bool> {
+        Ok(self
+            .hashes_tree
+            .scan_prefix(hash_prefix(hash))
+            .next()
+            .transpose()?
+            .is_some())
     }
 
     /// Delete an entry by ID
+    ///
+    /// Fails if delta entries are stored against it, since they can't be
+    /// reconstructed afterwards; see [`Self::delete_entry_with_key`].
     pub fn delete_entry(&self, id: &str) -> Result<bool> {
-        let removed = self.clips_tree.remove(id.as_bytes())?;
-        if removed.is_some() {
-            self.clips_tree.flush()?;
+        self.delete_entry_with_key(id, None)
+    }
+
+    /// Delete an entry by ID, first rewriting any delta entries stored against it
+    pub fn delete_entry_with_key(&self, id: &str, key: Option<&MasterKey>) -> Result<bool> {
+        self.remove_entry(id, key, true)
+    }
+
+    /// Like [`Self::delete_entry_with_key`], but leaves writing the change to disk
+    /// to a later [`Self::flush`], so several deletions cost one flush
+    pub fn delete_entry_unflushed(&self, id: &str, key: Option<&MasterKey>) -> Result<bool> {
+        self.remove_entry(id, key, false)
+    }
+
+    fn remove_entry(&self, id: &str, key: Option<&MasterKey>, flush: bool) -> Result<bool> {
+        let dependents = self.dependents_of(id)?;
+        if !dependents.is_empty() {
+            let Some(key) = key else {
+                anyhow::bail!(
+                    "Entry '{}' is the base of {} delta entries and can't be deleted without the key",
+                    id,
+                    dependents.len()
+                );
+            };
+            for dependent in dependents {
+                self.materialize(key, &dependent)?;
+            }
+        }
+
+        // The hash of the removed entry, empty if it was unreadable
+        let removed = self.entry_transaction(|trees| {
+            let Some(value) = trees.clips.remove(id.as_bytes())? else {
+                return Ok(None);
+            };
+            trees.add_to_count(ENTRY_COUNT_KEY, -1)?;
+            Ok(Some(
+                trees.unindex(id.as_bytes(), &value)?.unwrap_or_default(),
+            ))
+        })?;
+        if let Some(hash) = removed {
+            if flush {
+                self.meta_tree.flush()?;
+                self.timestamps_tree.flush()?;
+                self.hashes_tree.flush()?;
+                self.pinned_tree.flush()?;
+                self.deltas_tree.flush()?;
+                self.clips_tree.flush()?;
+            }
+            self.log_audit(AuditOp::Delete, id, &hash)?;
             Ok(true)
         } else {
             Ok(false)
@@ -168,23 +1005,71 @@ impl ClipboardDatabase {
     }
 
     /// Get the total number of entries
-    pub fn count_entries(&self) -> usize {
-        self.clips_tree.len()
+    ///
+    /// Kept in the meta tree, so no entry is read.
+    pub fn count_entries(&self) -> Result<usize> {
+        self.stored_count(ENTRY_COUNT_KEY)
+    }
+
+    fn stored_count(&self, key: &[u8]) -> Result<usize> {
+        Ok(count_from(self.meta_tree.get(key)?.as_ref()) as usize)
+    }
+
+    /// Run `f` on the entry trees and the meta tree in one transaction
+    fn entry_transaction<T>(
+        &self,
+        f: impl Fn(&EntryTrees) -> ConflictableTransactionResult<T>,
+    ) -> Result<T> {
+        (
+            &self.clips_tree,
+            &self.timestamps_tree,
+            &self.hashes_tree,
+            &self.pinned_tree,
+            &self.deltas_tree,
+            &self.meta_tree,
+        )
+            .transaction(|(clips, timestamps, hashes, pinned, deltas, meta)| {
+                f(&EntryTrees {
+                    clips,
+                    timestamps,
+                    hashes,
+                    pinned,
+                    deltas,
+                    meta,
+                })
+            })
+            .map_err(|e| anyhow::anyhow!("Failed to write the entry: {:?}", e))
     }
 
     /// Delete the oldest entries to maintain a maximum count
-    pub fn prune_to_limit(&self, max_entries: usize) -> Result<usize> {
-        let entries = self.list_entries()?;
+    ///
+    /// The key is needed to keep delta entries whose base gets pruned.
+    pub fn prune_to_limit(&self, max_entries: usize, key: Option<&MasterKey>) -> Result<usize> {
+        // Pinned entries don't count toward the limit
+        let count = self
+            .count_entries()?
+            .saturating_sub(self.stored_count(PINNED_COUNT_KEY)?);
 
-        if entries.len() <= max_entries {
+        if count <= max_entries {
             return Ok(0);
         }
 
+        // The index is ordered oldest first, so only the entries to delete are read
+        let mut oldest = Vec::with_capacity(count - max_entries);
+        for item in self.timestamps_tree.iter() {
+            if oldest.len() == count - max_entries {
+                break;
+            }
+            let (_, id) = item?;
+            if !self.pinned_tree.contains_key(&id)? {
+                oldest.push(String::from_utf8_lossy(&id).to_string());
+            }
+        }
+
         let mut deleted = 0;
 
-        // Delete oldest entries (at the end of the sorted list)
-        for entry in entries.iter().skip(max_entries) {
-            if self.delete_entry(&entry.id)? {
+        for id in oldest {
+            if self.delete_entry_with_key(&id, key)? {
                 deleted += 1;
             }
         }
@@ -192,143 +1077,1124 @@ impl ClipboardDatabase {
         Ok(deleted)
     }
 
+    /// Entries captured before `cutoff` that aren't pinned, newest first
+    pub fn entries_older_than(&self, cutoff: DateTime<Utc>) -> Result<Vec<ClipboardEntry>> {
+        let mut entries =
+            self.entries_between(None, Some(cutoff - chrono::Duration::milliseconds(1)))?;
+        entries.retain(|entry| !entry.pinned);
+        Ok(entries)
+    }
+
+    /// Delete the entries captured before `cutoff`, returning how many were deleted
+    ///
+    /// Deltas among them go before their bases, so `key` is only needed when a newer
+    /// delta entry is based on one of them.
+    pub fn prune_older_than(
+        &self,
+        cutoff: DateTime<Utc>,
+        key: Option<&MasterKey>,
+    ) -> Result<usize> {
+        let mut old = self.entries_older_than(cutoff)?;
+        old.sort_by_key(|entry| entry.delta_base.is_none());
+
+        let mut deleted = 0;
+        for entry in old {
+            if self.delete_entry_with_key(&entry.id, key)? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
     /// Flush all pending writes
-    #[allow(dead_code)]
     pub fn flush(&self) -> Result<()> {
         self.meta_tree.flush()?;
         self.clips_tree.flush()?;
+        self.timestamps_tree.flush()?;
+        self.hashes_tree.flush()?;
+        self.pinned_tree.flush()?;
+        self.deltas_tree.flush()?;
         self.db.flush()?;
         Ok(())
     }
-}
-
-pub enum ClipboardType {
-    Local(LocalClipboardWatcher),
-    Network(NetworkClipboardDatabase),
-}
 
-impl ClipboardType {
-    pub fn hash_data(self, data: &[u8]) -> String {
-        match self {
-            ClipboardType::Local(_) => LocalClipboardWatcher::hash_data(data),
-            ClipboardType::Network(_) => NetworkClipboardDatabase::hash_data(data),
+    /// How much disk the database takes, and how much of that each tree holds
+    ///
+    /// Reads keys and values without decrypting anything.
+    pub fn storage_usage(&self) -> Result<StorageUsage> {
+        let mut trees = Vec::new();
+        for name in self.db.tree_names() {
+            let tree = self.db.open_tree(&name)?;
+            let mut usage = TreeUsage {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                keys: 0,
+                bytes: 0,
+            };
+            for item in tree.iter() {
+                let (key, value) = item?;
+                usage.keys += 1;
+                usage.bytes += (key.len() + value.len()) as u64;
+            }
+            if usage.keys > 0 {
+                trees.push(usage);
+            }
         }
-    }
+        trees.sort_by_key(|tree| std::cmp::Reverse(tree.bytes));
 
-    pub async fn process_text(self, text: &str) -> Result<bool> {
-        match self {
-            ClipboardType::Local(mut db) => db.process_text(text),
-            ClipboardType::Network(db) => db.process_text(text).await,
-        }
+        // Measure what sled would have written by now anyway
+        self.db.flush()?;
+        Ok(StorageUsage {
+            on_disk: dir_size(&self.path)?,
+            trees,
+        })
     }
 
-    pub async fn process_image(self, image_data: &arboard::ImageData<'_>) -> Result<bool> {
-        match self {
-            ClipboardType::Local(mut db) => db.process_image(image_data),
-            ClipboardType::Network(db) => db.process_image(image_data).await,
-        }
+    /// Rewrite the database into a fresh directory to give back the space left by
+    /// deleted entries
+    ///
+    /// sled reuses freed space but rarely shrinks its files, so a database that
+    /// churned through many entries stays large. The copy is written next to the
+    /// database and swapped in only once complete and checked. Consumes the
+    /// database, as its files must be closed for the swap; returns it reopened.
+    pub fn compact(self) -> Result<(Self, CompactStats)> {
+        self.flush()?;
+        let path = self.path.clone();
+        let before = dir_size(&path)?;
+
+        let compacted = sibling_path(&path, ".compact");
+        remove_leftover(&compacted)?;
+        copy_database(&self.db, &compacted).context("Failed to write the compacted database")?;
+        drop(self);
+
+        swap_into_place(&path, &compacted, ".compact-old")
+            .context("Failed to move the compacted database into place")?;
+
+        // Measured once reopened like `before`, as sled writes some files lazily
+        let database = Self::open(path.clone())?;
+        database.flush()?;
+        let after = dir_size(&path)?;
+        Ok((database, CompactStats { before, after }))
     }
 
-    pub async fn check_clipboard(self) -> Result<bool> {
-        match self {
-            ClipboardType::Local(mut db) => db.check_clipboard(),
-            ClipboardType::Network(mut db) => db.check_clipboard().await,
-        }
+    /// Write a copy of the database to `dest`, which must not exist yet
+    ///
+    /// sled's files can't be copied consistently while it has them open, so the
+    /// copy goes through its export like `compact()`: every tree, key and value
+    /// is rewritten into a fresh database that is flushed and closed.
+    pub fn snapshot_to(&self, dest: &Path) -> Result<()> {
+        self.flush()?;
+        copy_database(&self.db, dest)
+            .with_context(|| format!("Failed to write a copy to {}", dest.display()))
     }
 
-    pub async fn watch(self) -> Result<()> {
-        match self {
-            ClipboardType::Local(db) => db.watch(),
-            ClipboardType::Network(mut db) => db.watch().await,
+    /// Replace the database at `path` with a copy of the one at `source`,
+    /// leaving `source` as it is (e.g. to restore a backup)
+    ///
+    /// Neither may be open. The copy is made next to `path` and swapped in only
+    /// once complete, so an interrupted run leaves the database untouched.
+    pub fn replace_with_copy(path: &Path, source: &Path) -> Result<()> {
+        let incoming = sibling_path(path, ".restore");
+        remove_leftover(&incoming)?;
+        {
+            let source = sled::open(source)
+                .with_context(|| format!("Failed to open {}", source.display()))?;
+            copy_database(&source, &incoming).context("Failed to write the restored database")?;
+        }
+        if !path.exists() {
+            return std::fs::rename(&incoming, path)
+                .context("Failed to move the restored database into place");
+        }
+        swap_into_place(path, &incoming, ".restore-old")
+            .context("Failed to move the restored database into place")
+    }
+
+    /// Check the database structure without decrypting anything
+    ///
+    /// Every entry is deserialized and checked against its key and the timestamp
+    /// index. With `quarantine`, unreadable entries are moved to a separate tree
+    /// (so they can still be recovered by hand) and the index is rebuilt.
+    pub fn fsck(&self, quarantine: bool) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        if self.is_initialized()? {
+            if self.meta_tree.get(PAYLOAD_KEY)?.is_none() {
+                report
+                    .meta_problems
+                    .push("password verification payload is missing".to_string());
+            }
+            match self.meta_tree.get(VERSION_KEY)? {
+                Some(version) if version.len() == 4 => {}
+                Some(_) => report
+                    .meta_problems
+                    .push("version marker is malformed".to_string()),
+                None => report
+                    .meta_problems
+                    .push("version marker is missing".to_string()),
+            }
+        }
+
+        let mut index_keys = HashSet::new();
+        let mut hash_index_keys = HashSet::new();
+        let mut delta_index_keys = HashSet::new();
+        let mut full_ids = HashSet::new();
+        let mut deltas = Vec::new();
+        for item in self.clips_tree.iter() {
+            let (key, value) = item?;
+            report.entries += 1;
+            match ClipboardEntry::from_stored(&value) {
+                Ok(entry) if entry.id.as_bytes() == key.as_ref() => {
+                    let index_key = timestamp_key(&entry.timestamp, &entry.id);
+                    if !self.timestamps_tree.contains_key(&index_key)? {
+                        report.unindexed += 1;
+                    }
+                    index_keys.insert(index_key);
+                    hash_index_keys.extend(hash_keys(&entry));
+                    match entry.delta_base {
+                        Some(base) => {
+                            delta_index_keys.insert(delta_key(&base, &entry.id));
+                            deltas.push((entry.id, base));
+                        }
+                        None => {
+                            full_ids.insert(entry.id);
+                        }
+                    }
+                }
+                Ok(_) => report
+                    .mismatched
+                    .push(String::from_utf8_lossy(&key).to_string()),
+                Err(_) => report
+                    .unreadable
+                    .push(String::from_utf8_lossy(&key).to_string()),
+            }
+        }
+
+        for (id, base) in deltas {
+            if !full_ids.contains(&base) {
+                report.broken_deltas.push(id);
+            }
+        }
+
+        for item in self.timestamps_tree.iter() {
+            let (key, _) = item?;
+            if !index_keys.contains(key.as_ref()) {
+                report.dangling_index += 1;
+            }
+        }
+        report.hash_index = check_index(&self.hashes_tree, &hash_index_keys)?;
+        report.delta_index = check_index(&self.deltas_tree, &delta_index_keys)?;
+
+        for (what, key, stored) in [
+            ("entry", ENTRY_COUNT_KEY, report.entries),
+            ("pinned entry", PINNED_COUNT_KEY, self.pinned_tree.len()),
+        ] {
+            let count = self.stored_count(key)?;
+            if count != stored {
+                report.meta_problems.push(format!(
+                    "{} count is {}, but {} are stored",
+                    what, count, stored
+                ));
+            }
+        }
+
+        if quarantine && !report.is_clean() {
+            let quarantine_tree = self.db.open_tree(QUARANTINE_TREE)?;
+            for key in report
+                .unreadable
+                .iter()
+                .chain(&report.mismatched)
+                .chain(&report.broken_deltas)
+            {
+                self.pinned_tree.remove(key.as_bytes())?;
+                if let Some(value) = self.clips_tree.remove(key.as_bytes())? {
+                    let hash = ClipboardEntry::from_stored(&value)
+                        .map(|entry| entry.hash)
+                        .unwrap_or_default();
+                    quarantine_tree.insert(key.as_bytes(), value)?;
+                    self.log_audit(AuditOp::Quarantine, key, &hash)?;
+                    report.quarantined += 1;
+                }
+            }
+            quarantine_tree.flush()?;
+            self.clips_tree.flush()?;
+            self.pinned_tree.flush()?;
+            self.rebuild_timestamp_index()?;
+            self.rebuild_hash_index()?;
+            self.rebuild_delta_index()?;
+            self.recount()?;
+            self.meta_tree.flush()?;
+        }
+
+        Ok(report)
+    }
+
+    /// Copy entries from another database that aren't present here (by hash)
+    ///
+    /// Entries are copied as-is when `other_key` is `None`, which requires both
+    /// databases to share `key`. Otherwise each entry is decrypted with
+    /// `other_key` and encrypted again with `key`. Delta entries are stored with
+    /// their full content, as their base may have been skipped.
+    pub fn merge_from(
+        &self,
+        other: &ClipboardDatabase,
+        key: &MasterKey,
+        other_key: Option<&MasterKey>,
+    ) -> Result<MergeReport> {
+        if other_key.is_none() && self.get_salt()? != other.get_salt()? {
+            anyhow::bail!("Databases use different salts; their entries must be re-encrypted");
+        }
+
+        let mut known = self
+            .list_entries()?
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect::<HashSet<_>>();
+        let mut report = MergeReport::default();
+
+        for mut entry in other.list_entries()? {
+            if !known.insert(entry.hash.clone()) {
+                report.skipped += 1;
+                continue;
+            }
+
+            if other_key.is_some() || entry.delta_base.is_some() {
+                let plaintext = other
+                    .decrypt_entry(other_key.unwrap_or(key), &entry)
+                    .with_context(|| format!("Failed to decrypt entry '{}'", entry.id))?;
+                entry.encrypt_payload(key, &plaintext)?;
+                entry.delta_base = None;
+                if other_key.is_some() {
+                    report.reencrypted += 1;
+                }
+            }
+
+            self.insert_entry(&entry)?;
+            report.merged += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Write a backup of the database: the salt and password check, then every
+    /// entry oldest first in the network's compressed form, one per line
+    ///
+    /// Entries stay encrypted, so this needs no key. Returns the number of entries.
+    pub fn write_backup<W: Write>(&self, mut out: W) -> Result<usize> {
+        writeln!(out, "{}", BACKUP_HEADER)?;
+        writeln!(
+            out,
+            "salt {}",
+            general_purpose::STANDARD.encode(self.get_salt()?)
+        )?;
+        writeln!(
+            out,
+            "payload {}",
+            general_purpose::STANDARD.encode(self.get_payload()?)
+        )?;
+        writeln!(out, "kdf {}", self.kdf_params()?)?;
+
+        let entries = self.list_entries()?;
+        for entry in entries.iter().rev() {
+            writeln!(out, "{}", entry.to_compressed_string())?;
+        }
+        out.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Add the entries of a backup that aren't present here (by hash)
+    ///
+    /// The backup must come from a database with the same salt and password.
+    /// Delta entries are stored with their full content, like in [`Self::merge_from`].
+    pub fn restore_backup(&self, backup: &Backup, key: &MasterKey) -> Result<MergeReport> {
+        if backup.salt != self.get_salt()? {
+            anyhow::bail!("The backup was made from a database with a different salt");
+        }
+        if !backup.verify_password(key) {
+            anyhow::bail!("The backup uses a different password");
+        }
+
+        let by_id: HashMap<&str, &ClipboardEntry> = backup
+            .entries
+            .iter()
+            .map(|entry| (entry.id.as_str(), entry))
+            .collect();
+        let mut known = self
+            .list_entries()?
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect::<HashSet<_>>();
+        let mut report = MergeReport::default();
+
+        for entry in &backup.entries {
+            if !known.insert(entry.hash.clone()) {
+                report.skipped += 1;
+                continue;
+            }
+
+            let mut entry = entry.clone();
+            if let Some(base_id) = &entry.delta_base {
+                let plaintext = entry
+                    .decrypt(key, by_id.get(base_id.as_str()).copied())
+                    .with_context(|| format!("Failed to decrypt entry '{}'", entry.id))?;
+                entry.encrypt_payload(key, &plaintext)?;
+                entry.delta_base = None;
+            }
+
+            self.insert_entry(&entry)?;
+            report.merged += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Re-encrypt every entry and the password check for `new_key`, which was
+    /// derived with `new_salt`
+    ///
+    /// Each payload is decrypted with `old_key`, encrypted with `new_key` and
+    /// decrypted again to check the round trip, all in memory. Nothing is written
+    /// with `dry_run`. Otherwise the entries that passed, the salt and the password
+    /// check are replaced in one transaction, so the database is never left half
+    /// re-encrypted. Entries that failed keep their old encryption, so they won't
+    /// open with the new password either. Delta entries stay deltas: their payload
+    /// is re-encrypted as it is. Entries `fsck` quarantined are re-encrypted in the
+    /// same transaction, as far as they can still be read, so that recovering
+    /// them doesn't need the old password.
+    pub fn rekey(
+        &self,
+        old_key: &MasterKey,
+        new_key: &MasterKey,
+        new_salt: &[u8],
+        dry_run: bool,
+    ) -> Result<RekeyReport> {
+        let mut report = RekeyReport::default();
+        let reencrypt = |value: &[u8]| -> Result<Vec<u8>> {
+            let mut entry =
+                ClipboardEntry::from_stored(value).context("Failed to deserialize entry")?;
+            let plaintext = entry.decrypt_payload(old_key)?;
+            entry.encrypt_payload(new_key, &plaintext)?;
+            if entry.decrypt_payload(new_key)? != plaintext {
+                anyhow::bail!("Re-encrypted entry doesn't decrypt to the same content");
+            }
+            Ok(bincode::serialize(&entry)?)
+        };
+
+        let mut rewritten = Vec::new();
+        for item in self.clips_tree.iter() {
+            let (id, value) = item?;
+            match reencrypt(&value) {
+                Ok(serialized) => rewritten.push((id, serialized)),
+                Err(_) => report
+                    .failed
+                    .push(String::from_utf8_lossy(&id).into_owned()),
+            }
+        }
+        report.reencrypted = rewritten.len();
+
+        // Opened only if fsck ever created it
+        let quarantine = self
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == QUARANTINE_TREE.as_bytes())
+            .then(|| self.db.open_tree(QUARANTINE_TREE))
+            .transpose()?;
+        let mut requarantined = Vec::new();
+        for item in quarantine.iter().flat_map(Tree::iter) {
+            let (id, value) = item?;
+            match reencrypt(&value) {
+                Ok(serialized) => requarantined.push((id, serialized)),
+                Err(_) => report
+                    .quarantine_left
+                    .push(String::from_utf8_lossy(&id).into_owned()),
+            }
+        }
+        report.quarantine_reencrypted = requarantined.len();
+        if dry_run {
+            return Ok(report);
+        }
+
+        let payload = encrypt(new_key, VERIFY_PLAINTEXT)?;
+        let mut trees = vec![&self.clips_tree, &self.meta_tree];
+        trees.extend(&quarantine);
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                for (id, serialized) in &rewritten {
+                    trees[0].insert(id, serialized.as_slice())?;
+                }
+                trees[1].insert(SALT_KEY, new_salt)?;
+                trees[1].insert(PAYLOAD_KEY, payload.as_slice())?;
+                for (id, serialized) in &requarantined {
+                    trees[2].insert(id, serialized.as_slice())?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| anyhow::anyhow!("Failed to re-encrypt the database: {:?}", e))?;
+        self.db
+            .flush()
+            .context("Failed to write the database to disk")?;
+
+        if !self.verify_password(new_key)? {
+            anyhow::bail!("The stored password check doesn't decrypt with the new key");
+        }
+        Ok(report)
+    }
+}
+
+/// The contents of a file written by [`ClipboardDatabase::write_backup`]
+pub struct Backup {
+    pub salt: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub kdf: KdfParams,
+    pub entries: Vec<ClipboardEntry>,
+}
+
+impl Backup {
+    pub fn read<R: BufRead>(input: R) -> Result<Self> {
+        let mut lines = input.lines();
+        let mut next_line = |what: &str| -> Result<String> {
+            lines
+                .next()
+                .transpose()?
+                .ok_or_else(|| anyhow::anyhow!("Backup ends before its {}", what))
+        };
+
+        let header = next_line("header")?;
+        if header != BACKUP_HEADER && header != BACKUP_HEADER_V1 {
+            anyhow::bail!("Not a clpd backup file");
+        }
+        let mut field = |name: &str| -> Result<Vec<u8>> {
+            let line = next_line(name)?;
+            let value = line
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(' '))
+                .ok_or_else(|| anyhow::anyhow!("Backup is missing its {}", name))?;
+            general_purpose::STANDARD
+                .decode(value)
+                .with_context(|| format!("Backup has an invalid {}", name))
+        };
+        let salt = field("salt")?;
+        let payload = field("payload")?;
+        let kdf = if header == BACKUP_HEADER_V1 {
+            KdfParams::LEGACY
+        } else {
+            next_line("kdf")?
+                .strip_prefix("kdf ")
+                .ok_or_else(|| anyhow::anyhow!("Backup is missing its kdf"))?
+                .parse()
+                .context("Backup has invalid key derivation parameters")?
+        };
+
+        // Three header lines come first, four since the kdf line
+        let first_entry_line = if header == BACKUP_HEADER_V1 { 4 } else { 5 };
+        let mut entries = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry = ClipboardEntry::from_compressed_string(&line).map_err(|e| {
+                anyhow::anyhow!(
+                    "Unreadable entry on line {} of the backup: {}",
+                    index + first_entry_line,
+                    e
+                )
+            })?;
+            entries.push(entry);
+        }
+
+        Ok(Self {
+            salt,
+            payload,
+            kdf,
+            entries,
+        })
+    }
+
+    /// Whether `key` is the key of the database the backup was made from
+    pub fn verify_password(&self, key: &MasterKey) -> bool {
+        decrypt(key, &self.payload).is_ok_and(|plaintext| plaintext == VERIFY_PLAINTEXT)
+    }
+}
+
+/// Outcome of [`ClipboardDatabase::fsck`]
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub entries: usize,
+    /// Keys of entries that can't be deserialized
+    pub unreadable: Vec<String>,
+    /// Keys of entries stored under a key other than their ID
+    pub mismatched: Vec<String>,
+    /// Delta entries whose base entry is missing or unreadable
+    pub broken_deltas: Vec<String>,
+    /// Readable entries missing from the timestamp index
+    pub unindexed: usize,
+    /// Index keys that don't point at a readable entry
+    pub dangling_index: usize,
+    pub hash_index: IndexProblems,
+    pub delta_index: IndexProblems,
+    pub meta_problems: Vec<String>,
+    pub quarantined: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.unreadable.is_empty()
+            && self.mismatched.is_empty()
+            && self.broken_deltas.is_empty()
+            && self.unindexed == 0
+            && self.dangling_index == 0
+            && self.hash_index.is_clean()
+            && self.delta_index.is_clean()
+            && self.meta_problems.is_empty()
+    }
+}
+
+/// How an index differs from the keys the readable entries should have in it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IndexProblems {
+    /// Keys of readable entries missing from the index
+    pub missing: usize,
+    /// Keys in the index that no readable entry has
+    pub dangling: usize,
+}
+
+impl IndexProblems {
+    pub fn is_clean(&self) -> bool {
+        self.missing == 0 && self.dangling == 0
+    }
+}
+
+/// Compare an index tree with the keys it should hold
+fn check_index(tree: &Tree, expected: &HashSet<Vec<u8>>) -> Result<IndexProblems> {
+    let mut problems = IndexProblems::default();
+    let mut found = 0;
+    for item in tree.iter() {
+        let (key, _) = item?;
+        if expected.contains(key.as_ref()) {
+            found += 1;
+        } else {
+            problems.dangling += 1;
+        }
+    }
+    problems.missing = expected.len() - found;
+    Ok(problems)
+}
+
+/// On-disk size of the database before and after [`ClipboardDatabase::compact`]
+#[derive(Debug, Clone, Copy)]
+pub struct CompactStats {
+    pub before: u64,
+    pub after: u64,
+}
+
+impl CompactStats {
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// Disk usage of the database, for `clpd stats --storage`
+#[derive(Debug, Clone, Default)]
+pub struct StorageUsage {
+    /// Size of the files in the database directory
+    pub on_disk: u64,
+    /// Non-empty trees, largest first
+    pub trees: Vec<TreeUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeUsage {
+    pub name: String,
+    pub keys: usize,
+    /// Summed length of the tree's keys and values
+    pub bytes: u64,
+}
+
+impl StorageUsage {
+    /// What the trees hold, without sled's own structures
+    pub fn data(&self) -> u64 {
+        self.trees.iter().map(|tree| tree.bytes).sum()
+    }
+
+    /// Disk space beyond the data: sled's logs and page headers, and the space
+    /// of deleted entries not yet reused
+    pub fn overhead(&self) -> u64 {
+        self.on_disk.saturating_sub(self.data())
+    }
+}
+
+/// Write every tree of `source` into a new database at `dest`, and check that the
+/// copy holds exactly what `source` does
+///
+/// A copy that fails part way is removed again.
+fn copy_database(source: &Db, dest: &Path) -> Result<()> {
+    let copy = || -> Result<()> {
+        let copy = sled::open(dest).context("Failed to create the database")?;
+        import_checked(&copy, source.export())?;
+        copy.flush()?;
+        if copy.checksum()? != source.checksum()? {
+            anyhow::bail!("The copy doesn't match the database");
+        }
+        Ok(())
+    };
+    let result = copy();
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(dest);
+    }
+    result
+}
+
+/// [`sled::Db::import`], which panics when a write fails, returning that as an error
+fn import_checked(
+    db: &Db,
+    export: Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>>)>,
+) -> Result<()> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| db.import(export))).map_err(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown error");
+        anyhow::anyhow!("Failed to copy the database: {}", message)
+    })
+}
+
+/// `path` with `suffix` appended to its file name, e.g. `db.compact`
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Remove a directory left behind by an interrupted run, if there is one
+fn remove_leftover(path: &Path) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_dir_all(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Move the database directory `replacement` to `path`, deleting the one that
+/// was there; it's kept aside (as `path` + `old_suffix`) until the move
+/// succeeded, and put back if it fails
+fn swap_into_place(path: &Path, replacement: &Path, old_suffix: &str) -> Result<()> {
+    let old = sibling_path(path, old_suffix);
+    remove_leftover(&old)?;
+    std::fs::rename(path, &old).context("Failed to move the old database aside")?;
+    if let Err(e) = std::fs::rename(replacement, path) {
+        std::fs::rename(&old, path).context("Failed to restore the old database")?;
+        return Err(e.into());
+    }
+    std::fs::remove_dir_all(&old).context("Failed to remove the old database")
+}
+
+/// Total size of the files in a directory and its subdirectories
+pub(crate) fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)
+        .with_context(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Actions counted while usage counting is on, see `clpd stats --usage`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsageCounter {
+    Captures,
+    Copies,
+    Searches,
+}
+
+impl UsageCounter {
+    pub const ALL: [UsageCounter; 3] = [
+        UsageCounter::Captures,
+        UsageCounter::Copies,
+        UsageCounter::Searches,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            UsageCounter::Captures => "Captures",
+            UsageCounter::Copies => "Copies",
+            UsageCounter::Searches => "Searches",
+        }
+    }
+
+    fn key(self) -> &'static [u8] {
+        match self {
+            UsageCounter::Captures => b"usage:captures",
+            UsageCounter::Copies => b"usage:copies",
+            UsageCounter::Searches => b"usage:searches",
+        }
+    }
+}
+
+/// A sled tree of the database, for `clpd debug dump-tree`
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SledTree {
+    Meta,
+    Clips,
+    Timestamps,
+    Hashes,
+    Pinned,
+    Quarantine,
+    Usage,
+    Deltas,
+}
+
+impl SledTree {
+    fn name(self) -> &'static str {
+        match self {
+            SledTree::Meta => META_TREE,
+            SledTree::Clips => CLIPS_TREE,
+            SledTree::Timestamps => TIMESTAMPS_TREE,
+            SledTree::Hashes => HASHES_TREE,
+            SledTree::Pinned => PINNED_TREE,
+            SledTree::Quarantine => QUARANTINE_TREE,
+            SledTree::Usage => USAGE_TREE,
+            SledTree::Deltas => DELTAS_TREE,
+        }
+    }
+}
+
+/// A key as text when it's printable UTF-8, and as hex otherwise
+fn display_key(key: &[u8], hex_keys: bool) -> String {
+    match std::str::from_utf8(key) {
+        Ok(text) if !hex_keys && !text.is_empty() && !text.chars().any(char::is_control) => {
+            text.to_string()
+        }
+        _ => format!("0x{}", hex::encode(key)),
+    }
+}
+
+/// Print every key of a tree with the size of its value, returning the number of
+/// keys
+///
+/// Opens sled directly rather than through [`ClipboardDatabase::open`], which
+/// checks the format version and repairs the index, so that the bytes are shown
+/// exactly as they are on disk. Nothing is deserialized or decrypted.
+pub fn dump_tree<W: Write>(
+    path: &Path,
+    tree: SledTree,
+    hex_keys: bool,
+    out: &mut W,
+) -> Result<usize> {
+    if !path.exists() {
+        anyhow::bail!("No database at {}", path.display());
+    }
+    let db = sled::open(path).context("Failed to open database")?;
+    if !db
+        .tree_names()
+        .iter()
+        .any(|name| name == tree.name().as_bytes())
+    {
+        anyhow::bail!("The database has no {} tree", tree.name());
+    }
+    let tree = db.open_tree(tree.name())?;
+
+    let mut count = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        writeln!(
+            out,
+            "{}\t{} bytes",
+            display_key(&key, hex_keys),
+            value.len()
+        )?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Outcome of [`ClipboardDatabase::audit`]
+#[derive(Debug, Default)]
+pub struct AuditCheck {
+    pub log: AuditReport,
+    /// Stored entries the log has no record of
+    pub unlogged: Vec<String>,
+    /// Entries the log says are stored, but aren't
+    pub missing: Vec<String>,
+    /// Stored entries whose content hash differs from the logged one
+    pub changed: Vec<String>,
+}
+
+impl AuditCheck {
+    pub fn is_clean(&self) -> bool {
+        self.log.broken.is_none()
+            && self.unlogged.is_empty()
+            && self.missing.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// What [`ClipboardDatabase::search_text`] looks for
+#[derive(Debug, Clone, Default)]
+pub struct TextSearch {
+    pub query: String,
+    /// Compare lowercased characters
+    pub ignore_case: bool,
+    /// Go through the history from the oldest entry
+    pub oldest_first: bool,
+    /// Stop after this many matches
+    pub limit: Option<usize>,
+}
+
+/// Outcome of [`ClipboardDatabase::merge_from`]
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeReport {
+    pub merged: usize,
+    pub skipped: usize,
+    pub reencrypted: usize,
+}
+
+/// Outcome of [`ClipboardDatabase::rekey`]
+#[derive(Debug, Default, PartialEq)]
+pub struct RekeyReport {
+    /// Entries re-encrypted (or that would be, for a dry run)
+    pub reencrypted: usize,
+    /// IDs of the entries that don't decrypt with the old key
+    pub failed: Vec<String>,
+    /// Quarantined entries re-encrypted
+    pub quarantine_reencrypted: usize,
+    /// IDs of the quarantined entries left encrypted with the old key, because
+    /// they can't be read or don't decrypt with it
+    pub quarantine_left: Vec<String>,
+}
+
+pub enum ClipboardType {
+    Local(Box<LocalClipboardWatcher>),
+    Network(Box<NetworkClipboardDatabase>),
+}
+
+impl ClipboardType {
+    /// Pause a watcher in this process while clpd writes to the clipboard, until
+    /// the returned guard is dropped
+    pub fn begin_clipboard_write(&self) -> Option<WriteInProgress> {
+        match self {
+            ClipboardType::Local(db) => db.begin_clipboard_write(),
+            ClipboardType::Network(_) => None,
+        }
+    }
+
+    /// Record that clpd is putting this content on the clipboard, so watchers
+    /// don't capture it again, and count the copy
+    pub fn record_clipboard_write(&self, hash: &str) {
+        match self {
+            ClipboardType::Local(db) => {
+                db.record_clipboard_write(hash);
+                db.db.read().count_usage(UsageCounter::Copies);
+            }
+            ClipboardType::Network(_) => {}
+        }
+    }
+
+    /// Where entries come from: the database path, or the server URL
+    pub fn location(&self) -> String {
+        match self {
+            ClipboardType::Local(db) => db.db.read().path().display().to_string(),
+            ClipboardType::Network(db) => db.base_url.to_string(),
         }
     }
 
     pub async fn list_entries(&self) -> Result<Vec<ClipboardEntry>> {
         match self {
-            ClipboardType::Local(db) => db.db.list_entries(),
+            ClipboardType::Local(db) => db.db.read().list_entries(),
             ClipboardType::Network(db) => db.list_entries().await,
         }
     }
 
-    pub async fn delete_entry(&self, id: &str) -> Result<bool> {
+    pub async fn count_entries(&self) -> Result<usize> {
         match self {
-            ClipboardType::Local(db) => db.db.delete_entry(id),
-            ClipboardType::Network(db) => db.delete_entry(id).await,
+            ClipboardType::Local(db) => db.db.read().count_entries(),
+            ClipboardType::Network(db) => Ok(db.list_entries().await?.len()),
         }
     }
 
-    pub async fn is_initialized(&self) -> Result<bool> {
+    /// Delete an entry without waiting for it to reach the disk; call [`Self::flush`]
+    /// afterwards. The server persists network deletions itself.
+    pub async fn delete_entry_unflushed(&self, id: &str) -> Result<bool> {
         match self {
-            ClipboardType::Local(db) => db.db.is_initialized(),
-            ClipboardType::Network(db) => Ok(true), // Assume network DB is always initialized
+            ClipboardType::Local(db) => db.delete_entry_unflushed(id),
+            ClipboardType::Network(db) => db.delete_entry(id).await,
         }
     }
 
-    pub async fn get_salt(&self) -> Result<Vec<u8>> {
+    pub async fn touch_entry(&self, id: &str) -> Result<Option<ClipboardEntry>> {
         match self {
-            ClipboardType::Local(db) => db.db.get_salt(),
-            ClipboardType::Network(db) => db.get_salt().await,
+            ClipboardType::Local(db) => db.db.read().touch_entry(id),
+            ClipboardType::Network(_) => {
+                anyhow::bail!("Moving entries to the top isn't supported over the network")
+            }
         }
     }
 
-    pub async fn verify_password(&self, key: &MasterKey) -> Result<bool> {
+    /// Write pending changes to disk
+    pub async fn flush(&self) -> Result<()> {
         match self {
-            ClipboardType::Local(db) => db.db.verify_password(key),
-            ClipboardType::Network(_) => Ok(true), // Assume network DB password is always valid
+            ClipboardType::Local(db) => db.db.read().flush(),
+            ClipboardType::Network(_) => Ok(()),
         }
     }
 }
 
 pub struct NetworkClipboardDatabase {
     client: reqwest::Client,
-    base_url: String,
+    base_url: reqwest::Url,
     key: MasterKey,
-    clipboard: Clipboard,
+    clipboard: Option<Clipboard>,
     max_entries: Option<usize>,
     poll_interval: std::time::Duration,
+    // Largest insert request sent, which should match the server's limit
+    max_payload: usize,
+    // Hash of the last content found too large to send or refused by the
+    // server, and why, so that it isn't encrypted and sent again on every poll
+    refused: parking_lot::Mutex<Option<(String, String)>>,
+    // Keeps the entries sent by this client in capture order
+    clock: MonotonicClock,
+}
+
+/// Base URL of the clipboard API on a server: `<server>/clipboard`
+///
+/// Empty path segments (trailing or doubled slashes), the query and the fragment
+/// are dropped, so every way of writing the server URL gives the same endpoints.
+fn clipboard_url(server: &reqwest::Url) -> Result<reqwest::Url> {
+    if !matches!(server.scheme(), "http" | "https") {
+        anyhow::bail!(
+            "Unsupported server URL scheme '{}', use http:// or https://",
+            server.scheme()
+        );
+    }
+    if server.host_str().is_none_or(str::is_empty) {
+        anyhow::bail!("Server URL {} has no host", server);
+    }
+
+    let mut url = server.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|segment| !segment.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Server URL {} can't have a path", server))?
+        .clear()
+        .extend(&segments)
+        .push("clipboard");
+    Ok(url)
+}
+
+/// URL of an API endpoint below `base`, e.g. `&["delete", id]` for `<base>/delete/<id>`
+///
+/// Segments are percent-encoded, so an ID can't change which endpoint is called.
+fn endpoint(base: &reqwest::Url, segments: &[&str]) -> reqwest::Url {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .expect("checked by clipboard_url")
+        .extend(segments);
+    url
 }
 
 impl NetworkClipboardDatabase {
-    /// Create a new NetworkClipboard with the given base URL
-    pub fn new(key: &MasterKey, max_entries: Option<usize>) -> Result<Self> {
-        // let mut default_headers = reqwest::header::HeaderMap::new();
-        // default_headers.insert(
-        //     AUTHORIZATION,
-        //     HeaderValue::from_str(&format!("Bearer {}", String::from_utf8_lossy(&key.hash())))
-        //         .unwrap(),
-        // );
+    /// Fetch the key derivation salt from a server, before a key exists
+    pub async fn fetch_salt(server: &reqwest::Url) -> Result<Vec<u8>> {
+        let url = endpoint(&clipboard_url(server)?, &["salt"]);
+        let resp = reqwest::get(url)
+            .await
+            .with_context(|| format!("Failed to reach the clpd server at {}", server))?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Get salt request failed with status {}", resp.status());
+        }
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Fetch the Argon2 parameters from a server, before a key exists
+    ///
+    /// Servers that predate them don't have the endpoint and use the legacy ones.
+    pub async fn fetch_kdf_params(server: &reqwest::Url) -> Result<KdfParams> {
+        let url = endpoint(&clipboard_url(server)?, &["kdf"]);
+        let resp = reqwest::get(url)
+            .await
+            .with_context(|| format!("Failed to reach the clpd server at {}", server))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(KdfParams::LEGACY);
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("Get kdf request failed with status {}", resp.status());
+        }
+        resp.text()
+            .await?
+            .parse()
+            .context("The server sent invalid key derivation parameters")
+    }
+
+    /// Create a new NetworkClipboard for the server at the given URL
+    pub fn new(server: &reqwest::Url, key: &MasterKey, max_entries: Option<usize>) -> Result<Self> {
+        let mut default_headers = reqwest::header::HeaderMap::new();
+        let mut authorization =
+            reqwest::header::HeaderValue::from_str(&AuthToken::for_key(key).header_value())
+                .context("Invalid authorization token")?;
+        authorization.set_sensitive(true);
+        default_headers.insert(reqwest::header::AUTHORIZATION, authorization);
         let client = ClientBuilder::new()
-            // .default_headers(default_headers)
+            .default_headers(default_headers)
             .build()
             .context("Failed to build HTTP client")?;
-        let clipboard = Clipboard::new().context("Failed to initialize clipboard")?;
-        let base_url = "http://localhost:2573/clipboard".to_string();
+        let base_url = clipboard_url(server)?;
         Ok(Self {
             client,
             base_url,
             key: key.clone(),
             max_entries,
-            clipboard,
+            // Created on first use, browsing never touches the clipboard
+            clipboard: None,
             poll_interval: std::time::Duration::from_millis(500),
+            max_payload: DEFAULT_MAX_PAYLOAD_BYTES,
+            refused: parking_lot::Mutex::new(None),
+            clock: MonotonicClock::new(),
         })
     }
 
+    /// Skip entries whose insert request would be larger than `bytes`
+    pub fn with_max_payload(mut self, bytes: usize) -> Self {
+        self.max_payload = bytes;
+        self
+    }
+
     pub async fn list_entries(&self) -> Result<Vec<ClipboardEntry>> {
-        let url = format!("{}/list", self.base_url);
-        let resp = self.client.get(&url).send().await?;
+        let url = endpoint(&self.base_url, &["list"]);
+        let resp = self.client.get(url).send().await?;
         // .context("Failed to send list entries request")?;
 
         if resp.status().is_success() {
             let body = resp.text().await?;
             // .context("Failed to read list entries response body")?;
-            let entries: Vec<String> =
-                bincode::deserialize(&base64::decode(&body).context("Failed to decode entries")?)
-                    .context("Failed to deserialize entries")?;
+            let entries: Vec<String> = bincode::deserialize(
+                &general_purpose::STANDARD
+                    .decode(&body)
+                    .context("Failed to decode entries")?,
+            )
+            .context("Failed to deserialize entries")?;
             let mut entries_decoded = Vec::new();
             for entry_str in entries {
                 let entry = ClipboardEntry::from_compressed_string(&entry_str).unwrap();
                 entries_decoded.push(entry);
             }
+            // Don't rely on the server's order
+            entries_decoded.sort_by(ClipboardEntry::newest_first);
             Ok(entries_decoded)
         } else {
             Err(anyhow::anyhow!(
@@ -338,26 +2204,24 @@ impl NetworkClipboardDatabase {
         }
     }
 
-    pub async fn get_salt(&self) -> Result<Vec<u8>> {
-        let url = format!("{}/salt", self.base_url);
-        let resp = self.client.get(&url).send().await?;
-        // .context("Failed to send get salt request")?;
-
-        if resp.status().is_success() {
-            let body = resp.bytes().await?;
-            // .context("Failed to read get salt response body")?;
-            Ok(body.to_vec())
-        } else {
-            Err(anyhow::anyhow!(
-                "Get salt request failed with status {}",
-                resp.status()
-            ))
+    /// Whether the server accepts the token of this client's key, i.e. the
+    /// password was right
+    pub async fn verify_password(&self) -> Result<bool> {
+        let url = endpoint(&self.base_url, &["count"]);
+        let resp = self.client.get(url).send().await?;
+        match resp.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::UNAUTHORIZED => Ok(false),
+            status => Err(anyhow::anyhow!(
+                "Count request failed with status {}",
+                status
+            )),
         }
     }
 
     pub async fn delete_entry(&self, id: &str) -> Result<bool> {
-        let url = format!("{}/delete/{}", self.base_url, id);
-        let resp = self.client.get(&url).send().await?;
+        let url = endpoint(&self.base_url, &["delete", id]);
+        let resp = self.client.get(url).send().await?;
         // .context("Failed to send delete entry request")?;
 
         if resp.status().is_success() {
@@ -373,27 +2237,29 @@ impl NetworkClipboardDatabase {
     }
 
     /// Calculate SHA-256 hash of data
-
     pub(crate) fn hash_data(data: &[u8]) -> String {
         let mut hasher = Sha256::new();
         hasher.update(data);
         hex::encode(hasher.finalize())
     }
 
-    async fn process_text(&self, text: &str) -> Result<bool> {
+    async fn process_text(&self, text: &str) -> Result<CaptureOutcome> {
         let data = text.as_bytes();
         let hash = Self::hash_data(data);
+        if let Some(reason) = self.known_refused(&hash) {
+            return Ok(CaptureOutcome::Skipped(reason));
+        }
 
         // Check if this hash already exists in the database
-        let url = format!("{}/check_hash/{}", self.base_url, hash);
-        let resp = self.client.get(&url).send().await?;
+        let url = endpoint(&self.base_url, &["check_hash", &hash]);
+        let resp = self.client.get(url).send().await?;
         // .expect("Failed to send hash check request");
 
         if resp.status().is_success() {
             let body = resp.text().await?;
             // .expect("Failed to read hash check response body")?;
             if body.trim() == "1" {
-                return Ok(false);
+                return Ok(CaptureOutcome::Duplicate);
             }
         } else {
             return Err(anyhow::anyhow!(
@@ -405,28 +2271,21 @@ impl NetworkClipboardDatabase {
         // Encrypt and store
         let encrypted = encrypt(&self.key, data).context("Failed to encrypt clipboard data")?;
 
-        let entry = ClipboardEntry::new(ClipboardContentType::Text, encrypted, hash.clone());
-
-        let url = format!("{}/insert", self.base_url);
-        let resp = self
-            .client
-            .post(&url)
-            .body(entry.to_compressed_string())
-            .send()
-            .await?;
-        // .context("Failed to send insert request")?;
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, encrypted, hash.clone());
+        entry.stamp(&self.clock);
+        self.insert(&entry).await
+    }
 
-        if resp.status().is_success() {
-            Ok(true)
-        } else {
-            Err(anyhow::anyhow!(
-                "Insert request failed with status {}",
-                resp.status()
-            ))
+    async fn process_image(&self, image_data: &arboard::ImageData<'_>) -> Result<CaptureOutcome> {
+        if let Err(e) = check_image_size(
+            image_data.width,
+            image_data.height,
+            image_data.bytes.len(),
+            DEFAULT_MAX_IMAGE_BYTES,
+        ) {
+            return Ok(CaptureOutcome::Skipped(e));
         }
-    }
 
-    async fn process_image(&self, image_data: &arboard::ImageData<'_>) -> Result<bool> {
         // Store image metadata along with RGBA bytes
         let img_data = ImageData::new(
             image_data.width,
@@ -434,21 +2293,21 @@ impl NetworkClipboardDatabase {
             image_data.bytes.to_vec(),
         );
 
-        // Serialize the image data structure
-        let serialized = bincode::serialize(&img_data).context("Failed to serialize image data")?;
-
-        let hash = Self::hash_data(&serialized);
+        let hash = Self::hash_data(&img_data.hash_input());
+        if let Some(reason) = self.known_refused(&hash) {
+            return Ok(CaptureOutcome::Skipped(reason));
+        }
 
         // Check if this hash already exists in the database
-        let url = format!("{}/check_hash/{}", self.base_url, hash);
-        let resp = self.client.get(&url).send().await?;
+        let url = endpoint(&self.base_url, &["check_hash", &hash]);
+        let resp = self.client.get(url).send().await?;
         // .expect("Failed to send hash check request");
 
         if resp.status().is_success() {
             let body = resp.text().await?;
             // .expect("Failed to read hash check response body")?;
             if body.trim() == "1" {
-                return Ok(false);
+                return Ok(CaptureOutcome::Duplicate);
             }
         } else {
             return Err(anyhow::anyhow!(
@@ -457,23 +2316,58 @@ impl NetworkClipboardDatabase {
             ));
         }
 
-        // Encrypt and store
+        // Encrypt and store, compressed as PNG
+        let (serialized, encoding) = img_data
+            .to_plaintext()
+            .context("Failed to serialize image data")?;
         let encrypted =
             encrypt(&self.key, &serialized).context("Failed to encrypt clipboard data")?;
 
-        let entry = ClipboardEntry::new(ClipboardContentType::Image, encrypted, hash.clone());
+        let mut entry = ClipboardEntry::new_image(encrypted, hash.clone(), &img_data);
+        entry.stamp(&self.clock);
+        entry.image_encoding = Some(encoding);
+        self.insert(&entry).await
+    }
+
+    /// Why the content with this hash was last skipped, if it was too large or
+    /// the server refused its type
+    fn known_refused(&self, hash: &str) -> Option<String> {
+        match &*self.refused.lock() {
+            Some((known, reason)) if known == hash => Some(reason.clone()),
+            _ => None,
+        }
+    }
+
+    /// Send an entry to the server, unless the request would exceed the payload limit
+    async fn insert(&self, entry: &ClipboardEntry) -> Result<CaptureOutcome> {
+        let body = entry.to_compressed_string();
+        if body.len() > self.max_payload {
+            let reason = format!(
+                "{} entry is {} bytes encoded, over the limit of {} bytes (--max-payload-mb)",
+                entry.describe(),
+                body.len(),
+                self.max_payload
+            );
+            *self.refused.lock() = Some((entry.hash.clone(), reason.clone()));
+            return Ok(CaptureOutcome::Skipped(reason));
+        }
 
-        let url = format!("{}/insert", self.base_url);
-        let resp = self
-            .client
-            .post(&url)
-            .body(entry.to_compressed_string())
-            .send()
-            .await?;
+        let url = endpoint(&self.base_url, &["insert"]);
+        let resp = self.client.post(url).body(body).send().await?;
         // .context("Failed to send insert request")?;
 
         if resp.status().is_success() {
-            Ok(true)
+            self.prune().await?;
+            Ok(CaptureOutcome::Stored)
+        } else if resp.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE {
+            // The server says which types it takes
+            let reason = resp.text().await?;
+            *self.refused.lock() = Some((entry.hash.clone(), reason.clone()));
+            Ok(CaptureOutcome::Skipped(reason))
+        } else if resp.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+            Err(anyhow::anyhow!(
+                "The server refused the entry as too large; its --max-payload-mb is lower than this client's"
+            ))
         } else {
             Err(anyhow::anyhow!(
                 "Insert request failed with status {}",
@@ -482,36 +2376,68 @@ impl NetworkClipboardDatabase {
         }
     }
 
-    pub async fn check_clipboard(&mut self) -> Result<bool> {
-        // Try to get text first
-        // let clipboard = Clipboard::new().context("Failed to access clipboard")?;
-        if let Ok(text) = self.clipboard.get_text()
-            && !text.is_empty()
-        {
+    /// Ask the server to prune the oldest entries if a maximum is set
+    async fn prune(&self) -> Result<()> {
+        let Some(max) = self.max_entries else {
+            return Ok(());
+        };
+
+        let url = endpoint(&self.base_url, &["prune", &max.to_string()]);
+        let resp = self.client.get(url).send().await?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Prune request failed with status {}",
+                resp.status()
+            ))
+        }
+    }
+
+    pub async fn check_clipboard(&mut self) -> Result<CaptureOutcome> {
+        if self.clipboard.is_none() {
+            self.clipboard = Some(Clipboard::new().context("Failed to initialize clipboard")?);
+        }
+        let clipboard = self.clipboard.as_mut().unwrap();
+
+        // Try to get text first
+        if let Ok(text) = clipboard.get_text()
+            && !text.is_empty()
+        {
             return self.process_text(&text).await;
         }
 
         // Try to get image if no text
-        if let Ok(image) = self.clipboard.get_image() {
+        if let Ok(image) = clipboard.get_image() {
             return self.process_image(&image).await;
         }
 
-        Ok(false)
+        Ok(CaptureOutcome::Empty)
     }
 
     pub async fn watch(&mut self) -> Result<()> {
         println!("🔒 Network clipboard watcher started. Press Ctrl+C to stop.");
+        // Apply the limit right away, not only after the next capture
+        self.prune().await?;
         println!("📋 Monitoring clipboard for changes...");
 
         let mut stored_count = 0;
+        let mut last_skip = None;
 
         loop {
             match self.check_clipboard().await {
-                Ok(true) => {
+                Ok(CaptureOutcome::Stored) => {
                     stored_count += 1;
                     println!("✓ Stored encrypted entry #{}", stored_count);
                 }
-                Ok(false) => {
+                Ok(CaptureOutcome::Skipped(reason)) => {
+                    if last_skip.as_ref() != Some(&reason) {
+                        eprintln!("⚠ Not stored: {}", reason);
+                        last_skip = Some(reason);
+                    }
+                }
+                Ok(CaptureOutcome::Duplicate | CaptureOutcome::Empty) => {
                     // No new data
                 }
                 Err(e) => {
@@ -525,18 +2451,39 @@ impl NetworkClipboardDatabase {
     }
 }
 
-pub type WebClipboardData = web::Data<Arc<RwLock<ClipboardDatabase>>>;
+pub type WebClipboardData = web::Data<SharedDatabase>;
 
 #[post("/insert")]
 async fn create_entry(
     // req: HttpRequest,
     body: String,
     clipboard_data: WebClipboardData,
+    accepted: Option<web::Data<AcceptedTypes>>,
 ) -> impl Responder {
     // Handle the creation of a new clipboard entry
     let entry = ClipboardEntry::from_compressed_string(&body);
     match entry {
         Ok(entry) => {
+            // The payload is encrypted, but the cleartext metadata must be sane
+            if let Some(image) = entry.image
+                && let Err(e) = check_image_size(
+                    image.width,
+                    image.height,
+                    image.size,
+                    DEFAULT_MAX_IMAGE_BYTES,
+                )
+            {
+                return HttpResponse::BadRequest().body(e);
+            }
+            if let Some(accepted) = accepted
+                && !accepted.accepts(&entry.content_type)
+            {
+                return HttpResponse::UnsupportedMediaType().body(format!(
+                    "the server only accepts {} entries (--accept)",
+                    accepted.names()
+                ));
+            }
+
             let db = clipboard_data.read();
             db.insert_entry(&entry).expect("failed to insert entry");
             HttpResponse::Created().finish()
@@ -560,11 +2507,18 @@ async fn get_entry(req: HttpRequest, clipboard_data: WebClipboardData) -> impl R
 
 // #[get("/list")]
 
+/// The database's key, for rewriting delta entries whose base is deleted
+pub type WebKey = web::Data<MasterKey>;
+
 #[get("/delete/{id}")]
-async fn delete_entry(req: HttpRequest, clipboard_data: WebClipboardData) -> impl Responder {
+async fn delete_entry(
+    req: HttpRequest,
+    clipboard_data: WebClipboardData,
+    key: Option<WebKey>,
+) -> impl Responder {
     let id = req.match_info().get("id").unwrap();
     let db = clipboard_data.read();
-    match db.delete_entry(id) {
+    match db.delete_entry_with_key(id, key.as_ref().map(|key| key.get_ref())) {
         Ok(deleted) => {
             if deleted {
                 HttpResponse::Ok().body("Entry deleted")
@@ -577,14 +2531,18 @@ async fn delete_entry(req: HttpRequest, clipboard_data: WebClipboardData) -> imp
 }
 
 #[get("/prune/{max}")]
-async fn prune_entries(req: HttpRequest, clipboard_data: WebClipboardData) -> impl Responder {
+async fn prune_entries(
+    req: HttpRequest,
+    clipboard_data: WebClipboardData,
+    key: Option<WebKey>,
+) -> impl Responder {
     let max_str = req.match_info().get("max").unwrap();
     let max: usize = match max_str.parse() {
         Ok(m) => m,
         Err(_) => return HttpResponse::BadRequest().body("Invalid max value"),
     };
     let db = clipboard_data.read();
-    match db.prune_to_limit(max) {
+    match db.prune_to_limit(max, key.as_ref().map(|key| key.get_ref())) {
         Ok(deleted) => HttpResponse::Ok().body(format!("Deleted {} entries", deleted)),
         Err(_) => HttpResponse::InternalServerError().body("Failed to prune entries"),
     }
@@ -609,7 +2567,10 @@ async fn check_hash(req: HttpRequest, clipboard_data: WebClipboardData) -> impl
 #[get("/count")]
 async fn count_entries(clipboard_data: WebClipboardData) -> impl Responder {
     let db = clipboard_data.read();
-    HttpResponse::Ok().body(db.count_entries().to_string())
+    match db.count_entries() {
+        Ok(count) => HttpResponse::Ok().body(count.to_string()),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to count entries"),
+    }
 }
 
 #[get("/salt")]
@@ -621,6 +2582,15 @@ async fn get_salt(clipboard_data: WebClipboardData) -> impl Responder {
     }
 }
 
+#[get("/kdf")]
+async fn get_kdf_params(clipboard_data: WebClipboardData) -> impl Responder {
+    let db = clipboard_data.read();
+    match db.kdf_params() {
+        Ok(kdf) => HttpResponse::Ok().body(kdf.to_string()),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to get kdf parameters"),
+    }
+}
+
 #[get("/list")]
 async fn list_entries(clipboard_data: WebClipboardData) -> impl Responder {
     let db = clipboard_data.read();
@@ -631,9 +2601,21 @@ async fn list_entries(clipboard_data: WebClipboardData) -> impl Responder {
             for entry in entries {
                 compressed_entries.push(entry.to_compressed_string());
             }
-            HttpResponse::Ok().body(base64::encode(
-                bincode::serialize(&compressed_entries).unwrap(),
-            ))
+            HttpResponse::Ok().body(
+                general_purpose::STANDARD.encode(bincode::serialize(&compressed_entries).unwrap()),
+            )
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to list entries"),
+    }
+}
+
+/// Metadata of all entries as JSON (newest first), without payloads
+#[get("/meta")]
+async fn list_metadata(clipboard_data: WebClipboardData) -> impl Responder {
+    let db = clipboard_data.read();
+    match db.list_entries() {
+        Ok(entries) => {
+            HttpResponse::Ok().json(entries.iter().map(EntryMetadata::from).collect::<Vec<_>>())
         }
         Err(_) => HttpResponse::InternalServerError().body("Failed to list entries"),
     }
@@ -650,8 +2632,21 @@ async fn list_entries(clipboard_data: WebClipboardData) -> impl Responder {
 //     HttpResponse::Ok().body(payload)
 // }
 
-pub fn clipboard_scope() -> Scope {
+/// The server's endpoints, open only to clients sending the bearer `token`
+/// (except the salt and KDF parameters they need to derive it)
+pub fn clipboard_scope(
+    token: AuthToken,
+) -> Scope<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
     web::scope("/clipboard")
+        .wrap(CheckAuthorization::new(token))
         .service(create_entry)
         .service(get_entry)
         .service(delete_entry)
@@ -659,49 +2654,143 @@ pub fn clipboard_scope() -> Scope {
         .service(check_hash)
         .service(count_entries)
         .service(get_salt)
+        .service(get_kdf_params)
         .service(list_entries)
+        .service(list_metadata)
+}
+
+/// Which cross-origin (browser) requests the network server accepts
+#[derive(Debug, Clone, Default)]
+pub enum CorsPolicy {
+    /// Only same-origin requests, any website you visit is refused
+    #[default]
+    SameOrigin,
+    /// Same-origin requests plus the listed origins
+    Origins(Vec<String>),
+    /// Any origin, which lets every website you visit reach the server
+    Any,
+}
+
+impl CorsPolicy {
+    fn to_cors(&self) -> Cors {
+        // Refuse mismatched origins outright rather than only withholding the CORS
+        // headers, otherwise a website could still trigger side effects like deletes
+        match self {
+            CorsPolicy::SameOrigin => Cors::default().block_on_origin_mismatch(true),
+            CorsPolicy::Origins(origins) => origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+                .allow_any_method()
+                .allow_any_header()
+                .block_on_origin_mismatch(true),
+            CorsPolicy::Any => Cors::default()
+                .allow_any_origin()
+                .allow_any_method()
+                .allow_any_header(),
+        }
+    }
+}
+
+/// Options for the network clipboard server
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub cors: CorsPolicy,
+    /// Address and port to listen on, loopback only unless told otherwise
+    pub bind: std::net::SocketAddr,
+    /// Largest request body accepted, in bytes
+    pub max_payload: usize,
+    /// Content types stored; entries of other types are refused
+    pub accept: Vec<EntryType>,
+}
+
+/// Content types the network server stores (`net-listen --accept`); without
+/// it in the app data, every type is stored
+#[derive(Debug, Clone)]
+pub struct AcceptedTypes(pub Vec<EntryType>);
+
+impl AcceptedTypes {
+    fn accepts(&self, content_type: &ClipboardContentType) -> bool {
+        self.0.iter().any(|accepted| accepted.matches(content_type))
+    }
+
+    fn names(&self) -> String {
+        let names: Vec<_> = self.0.iter().map(|accepted| accepted.name()).collect();
+        names.join(" and ")
+    }
 }
 
-pub async fn run_clipboard_server(db: ClipboardDatabase) {
-    // let db = ClipboardDatabase::open(db_path).unwrap();
-    // let salt = db.get_salt().unwrap();
-    // let key = derive_key(&password, &salt).unwrap();
-    // if !db.verify_password(&key).unwrap() {
-    //     panic!("Invalid password for clipboard database");
-    // }
-    let payload_size = 1024 * 1024 * 50; // 50 MB
+pub async fn run_clipboard_server(
+    db: ClipboardDatabase,
+    options: ServerOptions,
+    key: MasterKey,
+) -> Result<()> {
+    // Clients prove they have the key with the token derived from it
+    let token = AuthToken::for_key(&key);
+    let key = web::Data::new(key);
+    let payload_size = options.max_payload;
+    let accepted = web::Data::new(AcceptedTypes(options.accept.clone()));
     let db = Arc::new(RwLock::new(db));
     let db = web::Data::new(db);
+    let bind = options.bind;
     let server = HttpServer::new(move || {
         App::new()
             // .wrap(middleware::Compress::default())
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header(),
-            )
+            .wrap(options.cors.to_cors())
             .app_data(web::PayloadConfig::new(payload_size))
+            .app_data(accepted.clone())
             .app_data(db.clone())
-            .service(clipboard_scope())
+            .app_data(key.clone())
+            .service(clipboard_scope(token.clone()))
     })
-    .bind(("127.0.0.1", 2573))
-    .unwrap();
-    server.run().await.unwrap();
+    .bind(bind)
+    .with_context(|| format!("Failed to listen on {}", bind))?;
+    server.run().await.context("The server stopped")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::TextDelta;
     use tempfile::TempDir;
 
+    /// Token of the `[1u8; 32]` key the network tests' clients use
+    fn test_token() -> AuthToken {
+        AuthToken::for_key(&MasterKey::from_bytes([1u8; 32]))
+    }
+
+    /// Serve the clipboard API for `data` on a free local port, with the optional
+    /// app data `run_clipboard_server` adds, and return its address
+    fn spawn_server(
+        data: web::Data<SharedDatabase>,
+        key: Option<WebKey>,
+        accepted: Option<web::Data<AcceptedTypes>>,
+    ) -> std::net::SocketAddr {
+        let server = HttpServer::new(move || {
+            let mut app = App::new().app_data(data.clone());
+            if let Some(key) = &key {
+                app = app.app_data(key.clone());
+            }
+            if let Some(accepted) = &accepted {
+                app = app.app_data(accepted.clone());
+            }
+            app.service(clipboard_scope(test_token()))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        addr
+    }
+
     #[test]
     fn test_database_creation() {
         let temp_dir = TempDir::new().unwrap();
         let db_path = temp_dir.path().join("test.db");
 
-        let db = ClipboardDatabase::open(db_path).unwrap();
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
         assert!(!db.is_initialized().unwrap());
+        assert_eq!(db.path(), db_path);
     }
 
     #[test]
@@ -717,4 +2806,1354 @@ mod tests {
         assert!(db.is_initialized().unwrap());
         assert_eq!(db.get_salt().unwrap(), salt);
     }
+
+    #[test]
+    fn test_initialize_with_key_verifies() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let key = MasterKey::from_bytes([5u8; 32]);
+
+        let kdf = KdfParams::new(32, 3);
+
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        // Databases that never stored them derive keys with the legacy parameters
+        assert_eq!(db.kdf_params().unwrap(), KdfParams::LEGACY);
+        db.initialize_with_key(&[7; 16], &key, kdf).unwrap();
+        assert!(db.verify_password(&key).unwrap());
+        assert!(
+            !db.verify_password(&MasterKey::from_bytes([6u8; 32]))
+                .unwrap()
+        );
+
+        // Still there after reopening
+        drop(db);
+        let db = ClipboardDatabase::open(db_path).unwrap();
+        assert_eq!(db.get_salt().unwrap(), vec![7; 16]);
+        assert_eq!(db.kdf_params().unwrap(), kdf);
+        assert!(db.verify_password(&key).unwrap());
+    }
+
+    #[test]
+    fn test_open_rejects_newer_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+
+        {
+            let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+            db.initialize(&[1; 16], &[1, 2, 3]).unwrap();
+            db.meta_tree
+                .insert(VERSION_KEY, &(SCHEMA_VERSION + 1).to_le_bytes())
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        let err = ClipboardDatabase::open(db_path).err().unwrap();
+        assert!(err.to_string().contains("newer version of clpd"));
+    }
+
+    #[test]
+    fn test_hash_index_with_duplicate_hashes() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        let first = entry_at(1_000);
+        let mut second = entry_at(2_000);
+        second.hash = first.hash.clone();
+        second.dedupe_hash = Some("normalized".to_string());
+        db.insert_entry(&first).unwrap();
+        db.insert_entry(&second).unwrap();
+        assert_eq!(db.hashes_tree.len(), 3);
+        assert!(db.hash_exists(&first.hash).unwrap());
+        assert!(db.hash_exists("normalized").unwrap());
+        // A hash that is a prefix of a stored one isn't a match
+        assert!(!db.hash_exists("hash-1").unwrap());
+
+        // Touching rewrites the entry under the same keys
+        db.touch_entry(&first.id).unwrap();
+        assert_eq!(db.hashes_tree.len(), 3);
+
+        db.delete_entry(&first.id).unwrap();
+        assert!(db.hash_exists(&first.hash).unwrap());
+        db.delete_entry(&second.id).unwrap();
+        assert!(!db.hash_exists(&first.hash).unwrap());
+        assert!(!db.hash_exists("normalized").unwrap());
+        assert!(db.hashes_tree.is_empty());
+    }
+
+    #[test]
+    fn test_hash_index_built_for_older_databases() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let entry = entry_at(1_000);
+        {
+            let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+            db.initialize(&[1; 16], &[1, 2, 3]).unwrap();
+            db.insert_entry(&entry).unwrap();
+            // As written by a version without the index
+            db.hashes_tree.clear().unwrap();
+            db.meta_tree
+                .insert(VERSION_KEY, &1u32.to_le_bytes())
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        // The hook sees the database before it's upgraded, and can copy it
+        let snapshot = temp_dir.path().join("before-migration");
+        let db =
+            ClipboardDatabase::open_with(db_path.clone(), |db| db.snapshot_to(&snapshot)).unwrap();
+        assert!(db.hash_exists(&entry.hash).unwrap());
+        assert_eq!(
+            db.meta_tree.get(VERSION_KEY).unwrap().unwrap().as_ref(),
+            SCHEMA_VERSION.to_le_bytes()
+        );
+        drop(db);
+        let old = sled::open(&snapshot).unwrap();
+        assert_eq!(
+            old.open_tree(META_TREE)
+                .unwrap()
+                .get(VERSION_KEY)
+                .unwrap()
+                .unwrap()
+                .as_ref(),
+            1u32.to_le_bytes()
+        );
+        assert!(old.open_tree(HASHES_TREE).unwrap().is_empty());
+        drop(old);
+
+        // Only once
+        ClipboardDatabase::open_with(db_path, |_| panic!("migrated twice")).unwrap();
+    }
+
+    #[test]
+    fn test_fsck_quarantines_unreadable_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.insert_entry(&entry_at(1_000)).unwrap();
+        db.insert_entry(&entry_at(2_000)).unwrap();
+        assert!(db.fsck(false).unwrap().is_clean());
+
+        // Simulate a torn write
+        db.clips_tree.insert(b"garbage", &[0xff, 0x01][..]).unwrap();
+
+        let report = db.fsck(false).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.unreadable, vec!["garbage".to_string()]);
+        assert_eq!(
+            report.meta_problems,
+            vec!["entry count is 2, but 3 are stored".to_string()]
+        );
+        assert_eq!(report.quarantined, 0);
+
+        let report = db.fsck(true).unwrap();
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(db.count_entries().unwrap(), 2);
+        let quarantine = db.db.open_tree(QUARANTINE_TREE).unwrap();
+        assert!(quarantine.contains_key(b"garbage").unwrap());
+
+        assert!(db.fsck(false).unwrap().is_clean());
+        assert_eq!(db.list_entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_fsck_checks_hash_and_delta_indexes() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let db = keyed_db(&temp_dir, "test.db", &[1; 16], &key);
+        let base = text_entry(&key, "one\n");
+        db.insert_entry(&base).unwrap();
+        let delta = delta_entry(&key, &base, "one\n", "one\ntwo\n");
+        db.insert_entry(&delta).unwrap();
+        assert!(db.fsck(false).unwrap().is_clean());
+
+        db.hashes_tree.clear().unwrap();
+        db.hashes_tree.insert(b"stale\0id", &b"id"[..]).unwrap();
+        db.deltas_tree.clear().unwrap();
+        db.deltas_tree.insert(b"base\0stale", &[]).unwrap();
+
+        let report = db.fsck(false).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.hash_index.dangling, 1);
+        assert_eq!(
+            report.hash_index.missing,
+            hash_keys(&base).len() + hash_keys(&delta).len()
+        );
+        assert_eq!(
+            report.delta_index,
+            IndexProblems {
+                missing: 1,
+                dangling: 1
+            }
+        );
+
+        let report = db.fsck(true).unwrap();
+        assert_eq!(report.quarantined, 0);
+        assert!(db.fsck(false).unwrap().is_clean());
+        assert_eq!(db.dependents_of(&base.id).unwrap(), vec![delta.id]);
+    }
+
+    #[test]
+    fn test_dump_tree_shows_raw_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let entry = entry_at(1_000);
+        {
+            let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+            db.insert_entry(&entry).unwrap();
+            db.meta_tree.insert(SALT_KEY, &[0u8; 16][..]).unwrap();
+            db.db.flush().unwrap();
+        }
+
+        let mut out = Vec::new();
+        assert_eq!(
+            dump_tree(&db_path, SledTree::Meta, false, &mut out).unwrap(),
+            3
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "meta:entry_count\t8 bytes\nmeta:pinned_count\t8 bytes\nmeta:salt\t16 bytes\n"
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(
+            dump_tree(&db_path, SledTree::Clips, false, &mut out).unwrap(),
+            1
+        );
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .starts_with(&format!("{}\t", entry.id))
+        );
+
+        // Timestamp keys start with binary millis
+        let mut out = Vec::new();
+        dump_tree(&db_path, SledTree::Timestamps, false, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("0x"));
+
+        let err = dump_tree(&db_path, SledTree::Quarantine, false, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("no quarantine tree"));
+    }
+
+    #[test]
+    fn test_audit_log_tracks_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.insert_entry(&entry_at(1_000)).unwrap();
+        assert!(db.audit().is_err());
+
+        assert_eq!(db.enable_audit_log().unwrap(), 1);
+        assert!(db.enable_audit_log().is_err());
+        let second = entry_at(2_000);
+        db.insert_entry(&second).unwrap();
+        db.insert_entry(&entry_at(3_000)).unwrap();
+        db.delete_entry(&second.id).unwrap();
+        db.prune_to_limit(1, None).unwrap();
+
+        let check = db.audit().unwrap();
+        assert!(check.is_clean(), "{:?}", check);
+        assert_eq!(check.log.records, 5);
+        assert_eq!(check.log.live.len(), 1);
+
+        // Changes that bypass the log
+        let entries = db.list_entries().unwrap();
+        db.clips_tree.remove(entries[0].id.as_bytes()).unwrap();
+        let sneaked = entry_at(4_000);
+        db.store_entry(&sneaked).unwrap();
+
+        let check = db.audit().unwrap();
+        assert_eq!(check.log.broken, None);
+        assert_eq!(check.missing, vec![entries[0].id.clone()]);
+        assert_eq!(check.unlogged, vec![sneaked.id]);
+        assert!(!check.is_clean());
+    }
+
+    fn entry_at(millis: i64) -> ClipboardEntry {
+        let mut entry = ClipboardEntry::new(
+            ClipboardContentType::Text,
+            vec![1, 2, 3],
+            format!("hash-{}", millis),
+        );
+        entry.timestamp = DateTime::from_timestamp_millis(millis).unwrap();
+        entry.id = format!("{}-0", millis);
+        entry
+    }
+
+    #[test]
+    fn test_clock_is_seeded_per_database() {
+        let temp_dir = TempDir::new().unwrap();
+        let now = Utc::now();
+        let future = now + chrono::TimeDelta::days(1);
+
+        // Written while the clock was a day ahead
+        let ahead = ClipboardDatabase::open(temp_dir.path().join("ahead")).unwrap();
+        ahead
+            .insert_entry(&entry_at(future.timestamp_millis()))
+            .unwrap();
+        drop(ahead);
+        let ahead = ClipboardDatabase::open(temp_dir.path().join("ahead")).unwrap();
+        let other = ClipboardDatabase::open(temp_dir.path().join("other")).unwrap();
+
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, vec![], String::new());
+        entry.timestamp = now;
+        entry.stamp(ahead.clock());
+        assert!(entry.timestamp > future);
+        // Another database opened by the same process isn't held back by it
+        assert_eq!(other.clock().stamp(now), now);
+    }
+
+    #[test]
+    fn test_compact_keeps_entries_and_shrinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        let salt = vec![7; 16];
+        db.initialize(&salt, &[1, 2, 3]).unwrap();
+
+        let entries: Vec<ClipboardEntry> = (0..30)
+            .map(|i| {
+                ClipboardEntry::new(
+                    ClipboardContentType::Text,
+                    vec![i as u8; 64 * 1024],
+                    i.to_string(),
+                )
+            })
+            .collect();
+        for entry in &entries {
+            db.insert_entry(entry).unwrap();
+        }
+        for entry in &entries[1..] {
+            db.delete_entry(&entry.id).unwrap();
+        }
+
+        let (db, stats) = db.compact().unwrap();
+        assert!(stats.after < stats.before, "{:?}", stats);
+        assert_eq!(stats.reclaimed(), stats.before - stats.after);
+        assert_eq!(db.get_salt().unwrap(), salt);
+        let remaining = db.list_entries().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, entries[0].id);
+        assert_eq!(db.newest_entries(5).unwrap().len(), 1);
+
+        // Only the database itself is left
+        let files: Vec<_> = std::fs::read_dir(temp_dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_failed_copy_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.initialize(&[7; 16], &[1, 2, 3]).unwrap();
+
+        // sled's import panics rather than overwrite data already in the copy
+        let dest = temp_dir.path().join("copy.db");
+        {
+            let existing = sled::open(&dest).unwrap();
+            existing
+                .open_tree(META_TREE)
+                .unwrap()
+                .insert(SALT_KEY, &[1])
+                .unwrap();
+            existing.flush().unwrap();
+        }
+        let err = copy_database(&db.db, &dest).unwrap_err();
+        assert!(
+            err.to_string().contains("overwriting existing data"),
+            "{}",
+            err
+        );
+        assert!(!dest.exists());
+
+        db.snapshot_to(&dest).unwrap();
+        let copy = sled::open(&dest).unwrap();
+        assert_eq!(copy.checksum().unwrap(), db.db.checksum().unwrap());
+    }
+
+    #[test]
+    fn test_storage_usage_per_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.initialize(&[7; 16], &[1, 2, 3]).unwrap();
+        for millis in [1_000, 2_000] {
+            db.insert_entry(&entry_at(millis)).unwrap();
+        }
+
+        let usage = db.storage_usage().unwrap();
+        let tree = |name: &str| usage.trees.iter().find(|tree| tree.name == name).unwrap();
+        assert_eq!(tree(CLIPS_TREE).keys, 2);
+        assert_eq!(tree(TIMESTAMPS_TREE).keys, 2);
+        let clips: u64 = db
+            .clips_tree
+            .iter()
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                (key.len() + value.len()) as u64
+            })
+            .sum();
+        assert_eq!(tree(CLIPS_TREE).bytes, clips);
+        assert!(tree(META_TREE).bytes > 0);
+        // Empty trees, like the quarantine that was never used, are left out
+        assert!(usage.trees.iter().all(|tree| tree.keys > 0));
+        assert!(usage.on_disk > usage.data());
+        assert_eq!(usage.overhead(), usage.on_disk - usage.data());
+    }
+
+    #[test]
+    fn test_prune_older_than() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+
+        let now = Utc::now();
+        let aged = |days: i64| {
+            let mut entry = ClipboardEntry::new(
+                ClipboardContentType::Text,
+                vec![1],
+                format!("hash-{}", days),
+            );
+            entry.timestamp = now - chrono::Duration::days(days);
+            entry
+        };
+        for days in [0, 5, 29, 31, 90] {
+            db.insert_entry(&aged(days)).unwrap();
+        }
+
+        let cutoff = now - chrono::Duration::days(30);
+        assert_eq!(db.entries_older_than(cutoff).unwrap().len(), 2);
+        assert_eq!(db.prune_older_than(cutoff, None).unwrap(), 2);
+        assert_eq!(db.prune_older_than(cutoff, None).unwrap(), 0);
+
+        let kept: Vec<String> = db
+            .list_entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.hash)
+            .collect();
+        assert_eq!(kept, ["hash-0", "hash-5", "hash-29"]);
+    }
+
+    #[test]
+    fn test_same_millisecond_order_is_stable() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+
+        let timestamp = Utc::now();
+        let created: Vec<ClipboardEntry> = (0..6)
+            .map(|i| {
+                let mut entry =
+                    ClipboardEntry::new(ClipboardContentType::Text, vec![i], format!("hash-{}", i));
+                entry.timestamp = timestamp;
+                entry
+            })
+            .collect();
+        for i in [3, 0, 5, 1, 4, 2] {
+            db.insert_entry(&created[i]).unwrap();
+        }
+
+        let newest_first: Vec<&str> = created.iter().rev().map(|e| e.id.as_str()).collect();
+        let ids = |entries: Vec<ClipboardEntry>| -> Vec<String> {
+            entries.into_iter().map(|e| e.id).collect()
+        };
+        for _ in 0..3 {
+            assert_eq!(ids(db.list_entries().unwrap()), newest_first);
+        }
+        assert_eq!(ids(db.newest_entries(6).unwrap()), newest_first);
+        assert_eq!(ids(db.entries_between(None, None).unwrap()), newest_first);
+    }
+
+    #[test]
+    fn test_limited_iteration_stops_early() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        let oldest = entry_at(1_000);
+        db.insert_entry(&oldest).unwrap();
+        for millis in [2_000, 3_000, 4_000] {
+            db.insert_entry(&entry_at(millis)).unwrap();
+        }
+        // An unreadable oldest entry fails a full listing...
+        db.clips_tree
+            .insert(oldest.id.as_bytes(), b"not an entry".to_vec())
+            .unwrap();
+        assert!(db.list_entries().is_err());
+        assert!(db.iter_entries_newest_first().any(|entry| entry.is_err()));
+
+        // ...but is never read when fewer entries are wanted
+        let newest: Vec<i64> = db
+            .iter_entries_newest_first()
+            .take(3)
+            .map(|entry| entry.unwrap().timestamp.timestamp_millis())
+            .collect();
+        assert_eq!(newest, [4_000, 3_000, 2_000]);
+        assert_eq!(db.newest_entries(2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_touched_entry_becomes_newest() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        let old = entry_at(1_000);
+        db.insert_entry(&old).unwrap();
+        db.insert_entry(&entry_at(2_000)).unwrap();
+        assert_ne!(db.newest_entries(1).unwrap()[0].id, old.id);
+
+        let touched = db.touch_entry(&old.id).unwrap().unwrap();
+        assert!(touched.timestamp > old.timestamp);
+        assert_eq!(db.list_entries().unwrap()[0].id, old.id);
+        assert_eq!(db.newest_entries(1).unwrap()[0].id, old.id);
+        // Still one index key per entry
+        assert_eq!(db.timestamps_tree.len(), 2);
+        assert_eq!(db.get_entry(&old.id).unwrap().unwrap().payload, old.payload);
+
+        assert!(db.touch_entry("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_entries_between_uses_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+
+        for millis in [1_000, 2_000, 3_000, 4_000, 5_000] {
+            db.insert_entry(&entry_at(millis)).unwrap();
+        }
+
+        // An unreadable entry outside the range would fail the query if it were read
+        db.clips_tree
+            .insert(b"9000-0", b"garbage".to_vec())
+            .unwrap();
+        db.timestamps_tree
+            .insert(
+                timestamp_key(&DateTime::from_timestamp_millis(9_000).unwrap(), "9000-0"),
+                b"9000-0".to_vec(),
+            )
+            .unwrap();
+
+        let since = DateTime::from_timestamp_millis(2_000);
+        let until = DateTime::from_timestamp_millis(4_000);
+        let ids: Vec<_> = db
+            .entries_between(since, until)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec!["4000-0", "3000-0", "2000-0"]);
+    }
+
+    #[test]
+    fn test_pinned_entries_survive_pruning() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+
+        db.insert_entry(&entry_at(1_000)).unwrap();
+        assert!(db.set_pinned("1000-0", true).unwrap().unwrap().pinned);
+        assert!(db.set_pinned("missing", true).unwrap().is_none());
+        for millis in [2_000, 3_000, 4_000, 5_000] {
+            db.insert_entry(&entry_at(millis)).unwrap();
+        }
+
+        // The pinned entry doesn't count toward the limit either
+        assert_eq!(db.prune_to_limit(2, None).unwrap(), 2);
+        let ids: Vec<_> = db
+            .list_entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec!["5000-0", "4000-0", "1000-0"]);
+        assert_eq!(db.prune_to_limit(2, None).unwrap(), 0);
+
+        let cutoff = DateTime::from_timestamp_millis(4_500).unwrap();
+        assert_eq!(db.prune_older_than(cutoff, None).unwrap(), 1);
+        assert!(db.get_entry("1000-0").unwrap().unwrap().pinned);
+
+        // Unpinned, it's the oldest again
+        db.set_pinned("1000-0", false).unwrap();
+        assert_eq!(db.prune_to_limit(1, None).unwrap(), 1);
+        assert!(db.get_entry("1000-0").unwrap().is_none());
+        assert!(db.pinned_tree.is_empty());
+
+        db.insert_entry(&entry_at(6_000)).unwrap();
+        db.set_pinned("6000-0", true).unwrap();
+        db.delete_entry("6000-0").unwrap();
+        assert!(db.pinned_tree.is_empty());
+    }
+
+    #[test]
+    fn test_timestamp_index_stays_in_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        db.initialize(&[1; 16], &[1, 2, 3]).unwrap();
+
+        for millis in [1_000, 2_000, 3_000, 4_000] {
+            db.insert_entry(&entry_at(millis)).unwrap();
+        }
+        assert!(db.delete_entry("2000-0").unwrap());
+        assert_eq!(db.timestamps_tree.len(), 3);
+        assert_eq!(db.count_entries().unwrap(), 3);
+        // A pinned entry doesn't count toward the limit
+        db.set_pinned("1000-0", true).unwrap();
+        db.touch_entry("3000-0").unwrap();
+        assert_eq!(db.timestamps_tree.len(), 3);
+        assert!(db.fsck(false).unwrap().is_clean());
+        assert_eq!(db.prune_to_limit(2, None).unwrap(), 0);
+        db.set_pinned("1000-0", false).unwrap();
+
+        let ids: Vec<_> = db
+            .newest_entries(2)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec!["3000-0", "4000-0"]);
+
+        assert_eq!(db.prune_to_limit(1, None).unwrap(), 2);
+        assert_eq!(db.list_entries().unwrap()[0].id, "3000-0");
+        assert_eq!(db.count_entries().unwrap(), 1);
+
+        // Opening doesn't check the index of a current database...
+        db.timestamps_tree.clear().unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let db = ClipboardDatabase::open(db_path.clone()).unwrap();
+        assert!(db.timestamps_tree.is_empty());
+        // ...but one written before entries and index were written together gets
+        // it rebuilt, and its entries counted
+        db.meta_tree
+            .insert(VERSION_KEY, &DELTA_INDEX_VERSION.to_le_bytes())
+            .unwrap();
+        db.meta_tree.remove(ENTRY_COUNT_KEY).unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let db = ClipboardDatabase::open(db_path).unwrap();
+        assert_eq!(db.timestamps_tree.len(), 1);
+        assert_eq!(db.count_entries().unwrap(), 1);
+        assert!(db.fsck(false).unwrap().is_clean());
+    }
+
+    /// An initialized database whose entries are encrypted with `key`
+    fn keyed_db(dir: &TempDir, name: &str, salt: &[u8], key: &MasterKey) -> ClipboardDatabase {
+        let db = ClipboardDatabase::open(dir.path().join(name)).unwrap();
+        db.initialize(salt, &encrypt(key, b"clpd_test").unwrap())
+            .unwrap();
+        db
+    }
+
+    fn text_entry(key: &MasterKey, text: &str) -> ClipboardEntry {
+        ClipboardEntry::new(
+            ClipboardContentType::Text,
+            encrypt(key, text.as_bytes()).unwrap(),
+            LocalClipboardWatcher::hash_data(text.as_bytes()),
+        )
+    }
+
+    #[test]
+    fn test_invalid_utf8_text_kept_exactly() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let db = keyed_db(&temp_dir, "test.db", &[1; 16], &key);
+        let bytes = b"line one\n\xc3\x28 broken".to_vec();
+        let entry = ClipboardEntry::new(
+            ClipboardContentType::Text,
+            encrypt(&key, &bytes).unwrap(),
+            LocalClipboardWatcher::hash_data(&bytes),
+        );
+        db.insert_entry(&entry).unwrap();
+
+        // `print` reads the exact bytes
+        let (_, plaintext) = db.get_plaintext(&key, &entry.id).unwrap();
+        assert_eq!(plaintext, bytes);
+        // Decrypted entries keep them too, decoding only for display...
+        let (_, content) = db.get_decrypted(&key, &entry.id).unwrap();
+        let DecryptedEntry::Text(text) = content else {
+            panic!("{:?}", content);
+        };
+        assert_eq!(text.as_bytes(), bytes);
+        assert!(text.lossy().contains("line one"));
+        // ...so search still finds the entry
+        let found = db
+            .search_text(
+                &key,
+                &TextSearch {
+                    query: "broken".to_string(),
+                    ..TextSearch::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_merge_same_salt() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let local = keyed_db(&temp_dir, "local.db", &[1; 16], &key);
+        let other = keyed_db(&temp_dir, "other.db", &[1; 16], &key);
+
+        local.insert_entry(&text_entry(&key, "shared")).unwrap();
+        other.insert_entry(&text_entry(&key, "shared")).unwrap();
+        other.insert_entry(&text_entry(&key, "only other")).unwrap();
+
+        let report = local.merge_from(&other, &key, None).unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                merged: 1,
+                skipped: 1,
+                reencrypted: 0
+            }
+        );
+        assert_eq!(local.count_entries().unwrap(), 2);
+
+        // Merging again finds nothing new
+        assert_eq!(local.merge_from(&other, &key, None).unwrap().merged, 0);
+    }
+
+    #[test]
+    fn test_merge_different_salt_reencrypts() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let other_key = MasterKey::from_bytes([2u8; 32]);
+        let local = keyed_db(&temp_dir, "local.db", &[1; 16], &key);
+        let other = keyed_db(&temp_dir, "other.db", &[2; 16], &other_key);
+
+        other
+            .insert_entry(&text_entry(&other_key, "from other"))
+            .unwrap();
+
+        assert!(local.merge_from(&other, &key, None).is_err());
+
+        let report = local.merge_from(&other, &key, Some(&other_key)).unwrap();
+        assert_eq!(report.merged, 1);
+        assert_eq!(report.reencrypted, 1);
+
+        let merged = &local.list_entries().unwrap()[0];
+        assert_eq!(decrypt(&key, &merged.payload).unwrap(), b"from other");
+    }
+
+    #[test]
+    fn test_rekey_dry_run_then_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let new_key = MasterKey::from_bytes([2u8; 32]);
+        let db = keyed_db(&temp_dir, "db", &[1; 16], &key);
+        db.insert_entry(&text_entry(&key, "first")).unwrap();
+        db.insert_entry(&text_entry(&key, "second")).unwrap();
+        let corrupt = text_entry(&MasterKey::from_bytes([3u8; 32]), "unreadable");
+        db.insert_entry(&corrupt).unwrap();
+
+        let report = db.rekey(&key, &new_key, &[2; 16], true).unwrap();
+        assert_eq!(report.reencrypted, 2);
+        assert_eq!(report.failed, vec![corrupt.id.clone()]);
+        // Nothing changed
+        assert_eq!(db.get_salt().unwrap(), [1; 16]);
+        assert!(db.verify_password(&key).unwrap());
+        let entries = db.list_entries().unwrap();
+        assert!(
+            entries
+                .iter()
+                .filter(|entry| entry.id != corrupt.id)
+                .all(|entry| db.decrypt_entry(&key, entry).is_ok())
+        );
+
+        assert_eq!(db.rekey(&key, &new_key, &[2; 16], false).unwrap(), report);
+        assert_eq!(db.get_salt().unwrap(), [2; 16]);
+        assert!(db.verify_password(&new_key).unwrap());
+        assert!(!db.verify_password(&key).unwrap());
+        let mut texts: Vec<_> = db
+            .list_entries()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.id != corrupt.id)
+            .map(|entry| db.decrypt_entry(&new_key, entry).unwrap())
+            .collect();
+        texts.sort();
+        assert_eq!(texts, [b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(db.count_entries().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_rekey_reencrypts_quarantine() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let new_key = MasterKey::from_bytes([2u8; 32]);
+        let db = keyed_db(&temp_dir, "db", &[1; 16], &key);
+        db.insert_entry(&text_entry(&key, "kept")).unwrap();
+        // Quarantined by fsck: one entry that still reads, one torn write
+        let readable = text_entry(&key, "recoverable");
+        let quarantine = db.db.open_tree(QUARANTINE_TREE).unwrap();
+        quarantine
+            .insert(&readable.id, bincode::serialize(&readable).unwrap())
+            .unwrap();
+        quarantine.insert(b"garbage", &[0xff, 0x01][..]).unwrap();
+
+        let report = db.rekey(&key, &new_key, &[2; 16], true).unwrap();
+        assert_eq!(report.reencrypted, 1);
+        assert_eq!(report.quarantine_reencrypted, 1);
+        assert_eq!(report.quarantine_left, vec!["garbage".to_string()]);
+
+        assert_eq!(db.rekey(&key, &new_key, &[2; 16], false).unwrap(), report);
+        let stored = quarantine.get(&readable.id).unwrap().unwrap();
+        let entry = ClipboardEntry::from_stored(&stored).unwrap();
+        assert_eq!(entry.decrypt_payload(&new_key).unwrap(), b"recoverable");
+        assert_eq!(
+            quarantine.get(b"garbage").unwrap().unwrap().as_ref(),
+            [0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_rotate_salt_keeps_password() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("db")).unwrap();
+        let salt = crate::crypto::generate_salt(16);
+        let key = MasterKey::from_password("correct horse", &salt, KdfParams::LEGACY).unwrap();
+        db.initialize_with_key(&salt, &key, KdfParams::LEGACY)
+            .unwrap();
+        db.insert_entry(&text_entry(&key, "kept")).unwrap();
+
+        let new_salt = crate::crypto::generate_salt(16);
+        let new_key =
+            MasterKey::from_password("correct horse", &new_salt, KdfParams::LEGACY).unwrap();
+        db.rekey(&key, &new_key, &new_salt, false).unwrap();
+
+        let stored_salt = db.get_salt().unwrap();
+        assert_ne!(stored_salt, salt);
+        // The same password opens it, through the new salt
+        let reopened =
+            MasterKey::from_password("correct horse", &stored_salt, KdfParams::LEGACY).unwrap();
+        assert!(db.verify_password(&reopened).unwrap());
+        assert!(!db.verify_password(&key).unwrap());
+        let entry = &db.list_entries().unwrap()[0];
+        assert_eq!(db.decrypt_entry(&reopened, entry).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn test_backup_restores_into_fresh_database() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let source = keyed_db(&temp_dir, "source.db", &[1; 16], &key);
+
+        let base_text = "fn main() {\n    println!(\"one\");\n}\n";
+        let text = "fn main() {\n    println!(\"two\");\n}\n";
+        let mut base = text_entry(&key, base_text);
+        base.timestamp = DateTime::from_timestamp_millis(1_000).unwrap();
+        source.insert_entry(&base).unwrap();
+        source
+            .insert_entry(&delta_entry(&key, &base, base_text, text))
+            .unwrap();
+        source.insert_entry(&text_entry(&key, "plain")).unwrap();
+
+        let mut file = Vec::new();
+        assert_eq!(source.write_backup(&mut file).unwrap(), 3);
+        let backup = Backup::read(file.as_slice()).unwrap();
+        assert_eq!(backup.kdf, KdfParams::LEGACY);
+        assert!(backup.verify_password(&key));
+        assert!(!backup.verify_password(&MasterKey::from_bytes([2u8; 32])));
+
+        // Restoring starts a fresh database with the backup's salt and password
+        let restored = ClipboardDatabase::open(temp_dir.path().join("restored.db")).unwrap();
+        restored.initialize(&backup.salt, &backup.payload).unwrap();
+        let report = restored.restore_backup(&backup, &key).unwrap();
+        assert_eq!((report.merged, report.skipped), (3, 0));
+
+        let texts = |db: &ClipboardDatabase| -> Vec<Vec<u8>> {
+            db.list_entries()
+                .unwrap()
+                .iter()
+                .map(|entry| db.decrypt_entry(&key, entry).unwrap())
+                .collect()
+        };
+        assert_eq!(texts(&restored), texts(&source));
+        assert!(restored.fsck(false).unwrap().is_clean());
+
+        // Restoring again adds nothing
+        assert_eq!(restored.restore_backup(&backup, &key).unwrap().merged, 0);
+
+        // Nor can it be restored into a database with another salt
+        let other = keyed_db(&temp_dir, "other.db", &[2; 16], &key);
+        assert!(other.restore_backup(&backup, &key).is_err());
+        assert!(Backup::read(&b"not a backup\n"[..]).is_err());
+
+        // Backups from before the kdf line was written still read
+        let text = String::from_utf8(file).unwrap();
+        let v1: String = text
+            .replacen(BACKUP_HEADER, BACKUP_HEADER_V1, 1)
+            .lines()
+            .filter(|line| !line.starts_with("kdf "))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let old = Backup::read(v1.as_bytes()).unwrap();
+        assert_eq!(old.kdf, KdfParams::LEGACY);
+        assert_eq!(old.entries.len(), 3);
+    }
+
+    fn delta_entry(
+        key: &MasterKey,
+        base: &ClipboardEntry,
+        base_text: &str,
+        text: &str,
+    ) -> ClipboardEntry {
+        let delta = TextDelta::between(base_text.as_bytes(), text.as_bytes());
+        let mut entry = text_entry(key, text);
+        entry.payload = encrypt(key, &bincode::serialize(&delta).unwrap()).unwrap();
+        entry.delta_base = Some(base.id.clone());
+        entry
+    }
+
+    #[test]
+    fn test_delta_entries_survive_base_deletion() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let db = keyed_db(&temp_dir, "test.db", &[1; 16], &key);
+
+        let base_text = "fn main() {\n    println!(\"one\");\n}\n";
+        let text = "fn main() {\n    println!(\"two\");\n}\n";
+        let mut base = text_entry(&key, base_text);
+        base.timestamp = DateTime::from_timestamp_millis(1_000).unwrap();
+        db.insert_entry(&base).unwrap();
+        let delta = delta_entry(&key, &base, base_text, text);
+        db.insert_entry(&delta).unwrap();
+
+        assert_eq!(db.latest_text_base().unwrap().unwrap().id, base.id);
+        assert_eq!(db.decrypt_entry(&key, &delta).unwrap(), text.as_bytes());
+        assert_eq!(db.dependents_of(&base.id).unwrap(), vec![delta.id.clone()]);
+        assert!(db.dependents_of(&delta.id).unwrap().is_empty());
+
+        // Without the key the delta would be orphaned
+        assert!(db.delete_entry(&base.id).is_err());
+        assert_eq!(db.prune_to_limit(1, Some(&key)).unwrap(), 1);
+
+        let stored = db.get_entry(&delta.id).unwrap().unwrap();
+        assert_eq!(stored.delta_base, None);
+        assert_eq!(decrypt(&key, &stored.payload).unwrap(), text.as_bytes());
+        assert!(db.fsck(false).unwrap().is_clean());
+        assert!(db.deltas_tree.is_empty());
+
+        // Databases written before the index get it built on open
+        let path = db.path().to_path_buf();
+        let again = delta_entry(&key, &stored, text, base_text);
+        db.insert_entry(&again).unwrap();
+        db.deltas_tree.clear().unwrap();
+        db.meta_tree
+            .insert(VERSION_KEY, &HASH_INDEX_VERSION.to_le_bytes())
+            .unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let db = ClipboardDatabase::open(path).unwrap();
+        assert_eq!(db.dependents_of(&stored.id).unwrap(), vec![again.id]);
+    }
+
+    #[test]
+    fn test_search_text() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let db = keyed_db(&temp_dir, "test.db", &[1; 16], &key);
+
+        for (millis, text) in [
+            (1_000, "Meeting notes"),
+            (2_000, "grocery list"),
+            (3_000, "NOTES v2"),
+        ] {
+            let mut entry = text_entry(&key, text);
+            entry.timestamp = DateTime::from_timestamp_millis(millis).unwrap();
+            db.insert_entry(&entry).unwrap();
+        }
+        // Entries under another key are skipped rather than failing the search
+        db.insert_entry(&text_entry(&MasterKey::from_bytes([2u8; 32]), "notes"))
+            .unwrap();
+
+        let texts = |search: TextSearch| -> Vec<String> {
+            db.search_text(&key, &search)
+                .unwrap()
+                .into_iter()
+                .map(|(_, text)| text.lossy().into_owned())
+                .collect()
+        };
+        let search = TextSearch {
+            query: "notes".to_string(),
+            ..TextSearch::default()
+        };
+        assert_eq!(texts(search.clone()), vec!["Meeting notes"]);
+        let search = TextSearch {
+            ignore_case: true,
+            ..search
+        };
+        assert_eq!(texts(search.clone()), vec!["NOTES v2", "Meeting notes"]);
+        assert_eq!(
+            texts(TextSearch {
+                oldest_first: true,
+                ..search.clone()
+            }),
+            vec!["Meeting notes", "NOTES v2"]
+        );
+        assert!(
+            texts(TextSearch {
+                query: "missing".to_string(),
+                ..search.clone()
+            })
+            .is_empty()
+        );
+
+        // The limit stops the search before the unreadable oldest entry
+        let oldest = db.iter_entries_newest_first().last().unwrap().unwrap();
+        db.clips_tree
+            .insert(oldest.id.as_bytes(), b"not an entry".to_vec())
+            .unwrap();
+        assert!(db.search_text(&key, &search).is_err());
+        assert_eq!(
+            texts(TextSearch {
+                limit: Some(1),
+                ..search
+            }),
+            vec!["NOTES v2"]
+        );
+    }
+
+    #[test]
+    fn test_merge_expands_delta_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let local = keyed_db(&temp_dir, "local.db", &[1; 16], &key);
+        let other = keyed_db(&temp_dir, "other.db", &[1; 16], &key);
+
+        // The base is already present locally, so only the delta is merged
+        local.insert_entry(&text_entry(&key, "draft one")).unwrap();
+        let base = text_entry(&key, "draft one");
+        other.insert_entry(&base).unwrap();
+        let delta = delta_entry(&key, &base, "draft one", "draft two");
+        other.insert_entry(&delta).unwrap();
+
+        assert_eq!(local.merge_from(&other, &key, None).unwrap().merged, 1);
+        let merged = local.get_entry(&delta.id).unwrap().unwrap();
+        assert_eq!(merged.delta_base, None);
+        assert_eq!(decrypt(&key, &merged.payload).unwrap(), b"draft two");
+    }
+
+    #[test]
+    fn test_server_url_normalization() {
+        let expected = "http://localhost:2573/clipboard/delete/abc";
+        for server in [
+            "http://localhost:2573",
+            "http://localhost:2573/",
+            "http://localhost:2573//",
+            "http://localhost:2573/?token=x#top",
+        ] {
+            let base = clipboard_url(&server.parse().unwrap()).unwrap();
+            assert_eq!(
+                endpoint(&base, &["delete", "abc"]).as_str(),
+                expected,
+                "{}",
+                server
+            );
+        }
+
+        // Behind a reverse proxy under a path
+        for server in ["https://example.com/clpd", "https://example.com/clpd/"] {
+            let base = clipboard_url(&server.parse().unwrap()).unwrap();
+            assert_eq!(
+                endpoint(&base, &["list"]).as_str(),
+                "https://example.com/clpd/clipboard/list"
+            );
+        }
+
+        let base = clipboard_url(&"http://localhost:2573".parse().unwrap()).unwrap();
+        assert_eq!(
+            endpoint(&base, &["delete", "../salt"]).as_str(),
+            "http://localhost:2573/clipboard/delete/..%2Fsalt"
+        );
+
+        for invalid in [
+            "ftp://localhost/",
+            "file:///tmp/db",
+            "mailto:someone@example.com",
+        ] {
+            assert!(
+                clipboard_url(&invalid.parse().unwrap()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_network_max_entries_prunes_server() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db = ClipboardDatabase::open(db_path).unwrap();
+        db.initialize(&[3; 16], &[1, 2, 3]).unwrap();
+        // Stored locally: pruning the base rewrites the delta, with the server's key
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let mut base = text_entry(&key, "base");
+        base.timestamp = DateTime::from_timestamp_millis(1_000).unwrap();
+        db.insert_entry(&base).unwrap();
+        db.insert_entry(&delta_entry(&key, &base, "base", "based"))
+            .unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let addr = spawn_server(data.clone(), Some(web::Data::new(key.clone())), None);
+
+        let server_url = format!("http://{}/", addr).parse().unwrap();
+        let client = NetworkClipboardDatabase::new(&server_url, &key, Some(2)).unwrap();
+        assert_eq!(
+            client.base_url.as_str(),
+            format!("http://{}/clipboard", addr)
+        );
+        assert_eq!(
+            NetworkClipboardDatabase::fetch_salt(&server_url)
+                .await
+                .unwrap(),
+            vec![3; 16]
+        );
+
+        for text in ["one", "two", "three", "four"] {
+            assert_eq!(
+                client.process_text(text).await.unwrap(),
+                CaptureOutcome::Stored
+            );
+        }
+
+        assert_eq!(data.read().count_entries().unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_network_oversized_entry_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.initialize(&[3; 16], &[1, 2, 3]).unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let addr = spawn_server(data.clone(), None, None);
+
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let server_url = format!("http://{}/", addr).parse().unwrap();
+        let client = NetworkClipboardDatabase::new(&server_url, &key, None)
+            .unwrap()
+            .with_max_payload(4096);
+
+        // Random text, so compression doesn't bring it under the limit
+        let large = hex::encode(crate::crypto::generate_salt(4096));
+        let CaptureOutcome::Skipped(reason) = client.process_text(&large).await.unwrap() else {
+            panic!("oversized entry was sent");
+        };
+        assert!(
+            reason.contains("over the limit of 4096 bytes"),
+            "{}",
+            reason
+        );
+        // Not encoded again while it stays on the clipboard
+        assert!(
+            client
+                .known_refused(&NetworkClipboardDatabase::hash_data(large.as_bytes()))
+                .is_some()
+        );
+        assert_eq!(
+            client.process_text(&large).await.unwrap(),
+            CaptureOutcome::Skipped(reason)
+        );
+        assert_eq!(data.read().count_entries().unwrap(), 0);
+
+        assert_eq!(
+            client.process_text("small").await.unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert_eq!(data.read().count_entries().unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_text_only_server_refuses_images() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.initialize(&[3; 16], &[1, 2, 3]).unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let accepted = web::Data::new(AcceptedTypes(vec![EntryType::Text]));
+        let addr = spawn_server(data.clone(), None, Some(accepted));
+
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let server_url = format!("http://{}/", addr).parse().unwrap();
+        let client = NetworkClipboardDatabase::new(&server_url, &key, None).unwrap();
+
+        let image = arboard::ImageData {
+            width: 2,
+            height: 2,
+            bytes: vec![255; 16].into(),
+        };
+        let CaptureOutcome::Skipped(reason) = client.process_image(&image).await.unwrap() else {
+            panic!("image was stored");
+        };
+        assert_eq!(reason, "the server only accepts text entries (--accept)");
+        assert_eq!(data.read().count_entries().unwrap(), 0);
+        // Not sent again while it stays on the clipboard
+        let hash = NetworkClipboardDatabase::hash_data(
+            &ImageData::new(2, 2, image.bytes.to_vec()).hash_input(),
+        );
+        assert_eq!(client.known_refused(&hash), Some(reason));
+
+        assert_eq!(
+            client.process_text("text").await.unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert_eq!(data.read().count_entries().unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_cors_policy() {
+        use actix_web::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, ORIGIN};
+        use actix_web::test;
+
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db = ClipboardDatabase::open(db_path).unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let policy = CorsPolicy::Origins(vec!["http://dashboard.local".to_string()]);
+        let app = test::init_service(
+            App::new()
+                .wrap(policy.to_cors())
+                .app_data(data.clone())
+                .service(clipboard_scope(test_token())),
+        )
+        .await;
+
+        // Requests without an origin (e.g. the clpd client) are unaffected
+        let req = test::TestRequest::get()
+            .uri("/clipboard/count")
+            .insert_header((AUTHORIZATION, test_token().header_value()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // The allowed origin is accepted
+        let req = test::TestRequest::get()
+            .uri("/clipboard/count")
+            .insert_header((AUTHORIZATION, test_token().header_value()))
+            .insert_header((ORIGIN, "http://dashboard.local"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "http://dashboard.local"
+        );
+
+        // Any other origin is refused
+        let req = test::TestRequest::get()
+            .uri("/clipboard/count")
+            .insert_header((AUTHORIZATION, test_token().header_value()))
+            .insert_header((ORIGIN, "http://evil.example"))
+            .to_request();
+        let resp = test::try_call_service(&app, req).await;
+        assert!(resp.is_err() || !resp.unwrap().status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_metadata_endpoint() {
+        use actix_web::test;
+
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        let entry = entry_at(1_000);
+        db.insert_entry(&entry).unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(data)
+                .service(clipboard_scope(test_token())),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/clipboard/meta")
+            .insert_header((
+                actix_web::http::header::AUTHORIZATION,
+                test_token().header_value(),
+            ))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let items = body.as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["id"], entry.id);
+        assert_eq!(items[0]["content_type"], "Text");
+        assert_eq!(items[0]["size"], entry.payload.len());
+        assert_eq!(items[0]["hash"], entry.hash);
+        assert!(items[0].get("payload").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_server_requires_auth_token() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        db.initialize(&[3; 16], &[1, 2, 3]).unwrap();
+        db.insert_entry(&entry_at(1_000)).unwrap();
+        let data = web::Data::new(Arc::new(RwLock::new(db)));
+
+        let addr = spawn_server(data, None, None);
+
+        let http = reqwest::Client::new();
+        let count_url = format!("http://{}/clipboard/count", addr);
+        let status = |request: reqwest::RequestBuilder| async move {
+            request.send().await.unwrap().status()
+        };
+        assert_eq!(
+            status(http.get(&count_url)).await,
+            reqwest::StatusCode::UNAUTHORIZED
+        );
+        let wrong = AuthToken::for_key(&MasterKey::from_bytes([2u8; 32]));
+        assert_eq!(
+            status(
+                http.get(&count_url)
+                    .header(reqwest::header::AUTHORIZATION, wrong.header_value())
+            )
+            .await,
+            reqwest::StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(
+                http.get(&count_url)
+                    .header(reqwest::header::AUTHORIZATION, test_token().header_value())
+            )
+            .await,
+            reqwest::StatusCode::OK
+        );
+        // Clients need the salt and KDF parameters before they can derive a token
+        for public in ["salt", "kdf"] {
+            let url = format!("http://{}/clipboard/{}", addr, public);
+            assert_eq!(status(http.get(url)).await, reqwest::StatusCode::OK);
+        }
+
+        // The client sends its key's token, and is refused with another key
+        let server_url = format!("http://{}/", addr).parse().unwrap();
+        let key = MasterKey::from_bytes([1u8; 32]);
+        let client = NetworkClipboardDatabase::new(&server_url, &key, None).unwrap();
+        assert!(client.verify_password().await.unwrap());
+        assert_eq!(client.list_entries().await.unwrap().len(), 1);
+        let key = MasterKey::from_bytes([2u8; 32]);
+        let client = NetworkClipboardDatabase::new(&server_url, &key, None).unwrap();
+        assert!(!client.verify_password().await.unwrap());
+        assert!(client.list_entries().await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_server_reports_bind_failure() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = ClipboardDatabase::open(temp_dir.path().join("test.db")).unwrap();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = ServerOptions {
+            cors: CorsPolicy::SameOrigin,
+            bind: taken.local_addr().unwrap(),
+            max_payload: DEFAULT_MAX_PAYLOAD_BYTES,
+            accept: vec![EntryType::Text, EntryType::Image],
+        };
+
+        let err = run_clipboard_server(db, options, MasterKey::from_bytes([1u8; 32]))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).starts_with(&format!(
+                "Failed to listen on {}",
+                taken.local_addr().unwrap()
+            )),
+            "{:#}",
+            err
+        );
+    }
 }
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use std::process::Child;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Notifications from the OS that the clipboard changed, for `clpd start --events`
+///
+/// A background thread listens and sends one message per change; the watcher
+/// only reads the clipboard after one arrives.
+pub struct ChangeEvents {
+    receiver: Receiver<()>,
+    // `wl-paste --watch` processes on Wayland, stopped along with the watcher
+    children: Vec<Child>,
+}
+
+impl ChangeEvents {
+    /// Start listening for changes of the clipboard, and of the primary selection
+    /// too with `primary`
+    ///
+    /// Windows has a clipboard listener and X11 the XFixes extension; Wayland
+    /// compositors are asked through `wl-paste --watch`, which needs their
+    /// data-control protocol. macOS doesn't notify applications at all.
+    pub fn start(primary: bool) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let children = listen(sender, primary)?;
+        Ok(Self { receiver, children })
+    }
+
+    /// Wait up to `timeout` for a change, returning whether there was one
+    ///
+    /// Changes that piled up meanwhile count as one, the clipboard is read once
+    /// for them all. Fails once notifications stop for good, e.g. when the
+    /// connection to the X server is lost.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(()) => {
+                while self.receiver.try_recv().is_ok() {}
+                Ok(true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("clipboard change notifications stopped")
+            }
+        }
+    }
+}
+
+impl Drop for ChangeEvents {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(windows)]
+fn listen(sender: Sender<()>, _primary: bool) -> Result<Vec<Child>> {
+    use anyhow::Context;
+    use windows_sys::Win32::System::DataExchange::AddClipboardFormatListener;
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, GetMessageW, HWND_MESSAGE, MSG, WM_CLIPBOARDUPDATE,
+    };
+
+    // The window must be created on the thread that receives its messages
+    let (ready, started) = mpsc::channel();
+    std::thread::spawn(move || {
+        let class: Vec<u16> = "STATIC\0".encode_utf16().collect();
+        // SAFETY: plain Win32 calls; a message-only window of a system class, and
+        // the message is only written by GetMessageW
+        unsafe {
+            let window = CreateWindowExW(
+                0,
+                class.as_ptr(),
+                std::ptr::null(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                HWND_MESSAGE,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            );
+            if window.is_null() || AddClipboardFormatListener(window) == 0 {
+                let _ = ready.send(Err(std::io::Error::last_os_error()));
+                return;
+            }
+            let _ = ready.send(Ok(()));
+            let mut message: MSG = std::mem::zeroed();
+            while GetMessageW(&mut message, window, WM_CLIPBOARDUPDATE, WM_CLIPBOARDUPDATE) > 0 {
+                if sender.send(()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    started
+        .recv()
+        .context("The clipboard listener thread failed")?
+        .context("Failed to listen for clipboard changes")?;
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "macos")]
+fn listen(_sender: Sender<()>, _primary: bool) -> Result<Vec<Child>> {
+    anyhow::bail!(
+        "macOS doesn't notify applications of clipboard changes; --on-change-only polls its cheap change counter instead"
+    )
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn listen(sender: Sender<()>, primary: bool) -> Result<Vec<Child>> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        listen_wayland(sender, primary)
+    } else {
+        listen_x11(sender, primary)?;
+        Ok(Vec::new())
+    }
+}
+
+/// One `wl-paste --watch` per selection, printing a line on each change
+#[cfg(not(any(windows, target_os = "macos")))]
+fn listen_wayland(sender: Sender<()>, primary: bool) -> Result<Vec<Child>> {
+    use anyhow::Context;
+    use std::io::BufRead;
+    use std::process::{Command, Stdio};
+
+    let selections: &[&[&str]] = if primary {
+        &[&[], &["--primary"]]
+    } else {
+        &[&[]]
+    };
+    let mut children = Vec::new();
+    for &args in selections {
+        let mut child = Command::new("wl-paste")
+            .args(args)
+            .args(["--watch", "echo"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Failed to run wl-paste (is wl-clipboard installed?)")?;
+        let stdout = child.stdout.take().context("wl-paste has no output")?;
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            for _ in std::io::BufReader::new(stdout)
+                .lines()
+                .map_while(Result::ok)
+            {
+                if sender.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        children.push(child);
+    }
+    Ok(children)
+}
+
+/// XFixes reports every new owner of the selections on the root window
+#[cfg(not(any(windows, target_os = "macos")))]
+fn listen_x11(sender: Sender<()>, primary: bool) -> Result<()> {
+    use anyhow::Context;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::Event;
+    use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+    use x11rb::protocol::xproto::ConnectionExt as _;
+
+    let (conn, screen) = x11rb::connect(None).context("Failed to connect to the X server")?;
+    conn.xfixes_query_version(5, 0)?
+        .reply()
+        .context("The X server lacks the XFixes extension")?;
+    let root = conn.setup().roots[screen].root;
+    let selections: &[&[u8]] = if primary {
+        &[b"CLIPBOARD", b"PRIMARY"]
+    } else {
+        &[b"CLIPBOARD"]
+    };
+    for name in selections {
+        let atom = conn.intern_atom(false, name)?.reply()?.atom;
+        conn.xfixes_select_selection_input(
+            root,
+            atom,
+            SelectionEventMask::SET_SELECTION_OWNER
+                | SelectionEventMask::SELECTION_WINDOW_DESTROY
+                | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+        )?;
+    }
+    conn.flush()?;
+
+    std::thread::spawn(move || {
+        while let Ok(event) = conn.wait_for_event() {
+            if matches!(event, Event::XfixesSelectionNotify(_)) && sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_coalesces_changes() {
+        let (sender, receiver) = mpsc::channel();
+        let events = ChangeEvents {
+            receiver,
+            children: Vec::new(),
+        };
+
+        assert!(!events.wait(Duration::from_millis(10)).unwrap());
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+        assert!(events.wait(Duration::from_millis(10)).unwrap());
+        // Both were handled by the one read
+        assert!(!events.wait(Duration::from_millis(10)).unwrap());
+
+        drop(sender);
+        assert!(events.wait(Duration::from_millis(10)).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+
+/// Names of the application that has the keyboard focus, for `--ignore-app`
+///
+/// Whichever the platform offers: the executable's file name on Windows, the
+/// app name and bundle identifier on macOS, and the window class and process
+/// name on X11.
+#[cfg(windows)]
+pub fn focused_app() -> Result<Vec<String>> {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+        QueryFullProcessImageNameW,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        GetForegroundWindow, GetWindowThreadProcessId,
+    };
+
+    // SAFETY: plain Win32 calls; the buffer outlives them and its length is passed along
+    let path = unsafe {
+        let window = GetForegroundWindow();
+        if window.is_null() {
+            anyhow::bail!("no window has the focus");
+        }
+        let mut pid = 0;
+        GetWindowThreadProcessId(window, &mut pid);
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            anyhow::bail!("can't open process {}", pid);
+        }
+        let mut buffer = [0u16; 1024];
+        let mut len = buffer.len() as u32;
+        let ok =
+            QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, buffer.as_mut_ptr(), &mut len);
+        CloseHandle(process);
+        if ok == 0 {
+            anyhow::bail!("can't read the executable of process {}", pid);
+        }
+        String::from_utf16_lossy(&buffer[..len as usize])
+    };
+    Ok(std::path::Path::new(&path)
+        .file_name()
+        .map(|name| vec![name.to_string_lossy().into_owned()])
+        .unwrap_or_default())
+}
+
+#[cfg(target_os = "macos")]
+pub fn focused_app() -> Result<Vec<String>> {
+    let app = objc2_app_kit::NSWorkspace::sharedWorkspace()
+        .frontmostApplication()
+        .context("no application is frontmost")?;
+    Ok([app.localizedName(), app.bundleIdentifier()]
+        .into_iter()
+        .flatten()
+        .map(|name| name.to_string())
+        .collect())
+}
+
+/// Asks the X server with `xprop`; Wayland doesn't tell other clients which
+/// window is focused
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn focused_app() -> Result<Vec<String>> {
+    use std::process::Command;
+
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        anyhow::bail!("the focused application can't be detected on Wayland");
+    }
+    let xprop = |args: &[&str]| -> Result<String> {
+        let output = Command::new("xprop")
+            .args(args)
+            .output()
+            .context("Failed to run xprop (is it installed?)")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "xprop failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let root = xprop(&["-root", "_NET_ACTIVE_WINDOW"])?;
+    let window = parse_active_window(&root).context("no window has the focus")?;
+    let properties = xprop(&["-id", &window, "WM_CLASS", "_NET_WM_PID"])?;
+    let (mut names, pid) = parse_window_properties(&properties);
+    if let Some(pid) = pid
+        && let Ok(comm) = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+    {
+        names.push(comm.trim().to_string());
+    }
+    Ok(names)
+}
+
+/// The window ID in `xprop -root _NET_ACTIVE_WINDOW` output, unless it's 0x0
+#[cfg_attr(any(windows, target_os = "macos"), allow(dead_code))]
+fn parse_active_window(output: &str) -> Option<String> {
+    let id = output.split('#').nth(1)?.split(',').next()?.trim();
+    let number = u64::from_str_radix(id.strip_prefix("0x")?, 16).ok()?;
+    (number != 0).then(|| id.to_string())
+}
+
+/// The WM_CLASS names and process ID in `xprop -id <window> WM_CLASS _NET_WM_PID`
+/// output
+#[cfg_attr(any(windows, target_os = "macos"), allow(dead_code))]
+fn parse_window_properties(output: &str) -> (Vec<String>, Option<u32>) {
+    let mut names = Vec::new();
+    let mut pid = None;
+    for line in output.lines() {
+        let Some((property, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        if property.starts_with("WM_CLASS") {
+            names.extend(
+                value
+                    .split(',')
+                    .map(|name| name.trim().trim_matches('"'))
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string),
+            );
+        } else if property.starts_with("_NET_WM_PID") {
+            pid = value.trim().parse().ok();
+        }
+    }
+    (names, pid)
+}
+
+/// The first of the `ignored` patterns found in one of the focused application's
+/// names, ignoring case
+pub fn ignored_app<'a>(names: &[String], ignored: &'a [String]) -> Option<&'a str> {
+    ignored
+        .iter()
+        .map(String::as_str)
+        .filter(|pattern| !pattern.is_empty())
+        .find(|pattern| {
+            let pattern = pattern.to_lowercase();
+            names
+                .iter()
+                .any(|name| name.to_lowercase().contains(&pattern))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ignored_app() {
+        let ignored = names(&["keepass", "1Password"]);
+        let focused = names(&["keepassxc", "KeePassXC"]);
+        assert_eq!(ignored_app(&focused, &ignored), Some("keepass"));
+        assert_eq!(
+            ignored_app(&names(&["1password.exe"]), &ignored),
+            Some("1Password")
+        );
+        assert_eq!(
+            ignored_app(&names(&["com.1password.1password"]), &ignored),
+            Some("1Password")
+        );
+        assert_eq!(ignored_app(&names(&["firefox", "Firefox"]), &ignored), None);
+        // Nothing detected, or nothing to ignore
+        assert_eq!(ignored_app(&[], &ignored), None);
+        assert_eq!(ignored_app(&focused, &[]), None);
+        assert_eq!(ignored_app(&focused, &names(&[""])), None);
+    }
+
+    #[test]
+    fn test_parse_xprop_output() {
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007\n").as_deref(),
+            Some("0x3a00007")
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0\n"),
+            None
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW:  not found.\n"),
+            None
+        );
+
+        let (names, pid) = parse_window_properties(
+            "WM_CLASS(STRING) = \"keepassxc\", \"KeePassXC\"\n_NET_WM_PID(CARDINAL) = 4242\n",
+        );
+        assert_eq!(names, ["keepassxc", "KeePassXC"]);
+        assert_eq!(pid, Some(4242));
+        let (names, pid) =
+            parse_window_properties("WM_CLASS:  not found.\n_NET_WM_PID:  not found.\n");
+        assert!(names.is_empty());
+        assert_eq!(pid, None);
+    }
+}
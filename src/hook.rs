@@ -0,0 +1,86 @@
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// A user command run after every stored entry (`clpd start --on-capture`)
+///
+/// The command only learns the entry's metadata, through environment variables:
+/// `CLPD_ENTRY_ID`, `CLPD_CONTENT_TYPE` (`text` or `image`) and `CLPD_SIZE` (bytes
+/// of plaintext). Content is never passed, so it can't show up in process lists
+/// or the hook's logs.
+#[derive(Debug, Clone)]
+pub struct CaptureHook {
+    command: String,
+}
+
+impl CaptureHook {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+
+    /// The hook's process for one capture, run through the platform's shell
+    fn command(&self, id: &str, content_type: &str, size: usize) -> Command {
+        #[cfg(windows)]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.command);
+            command
+        };
+        #[cfg(not(windows))]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.command);
+            command
+        };
+        command
+            .env("CLPD_ENTRY_ID", id)
+            .env("CLPD_CONTENT_TYPE", content_type)
+            .env("CLPD_SIZE", size.to_string())
+            .stdin(Stdio::null());
+        command
+    }
+
+    /// Start the hook for a stored entry without waiting for it
+    ///
+    /// A failing or slow hook never holds up capturing, it's only reported.
+    pub fn fire(&self, id: &str, content_type: &str, size: usize) {
+        let mut child = match self.command(id, content_type, size).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("⚠ Warning: Failed to run --on-capture command: {}", e);
+                return;
+            }
+        };
+        // Waited for on another thread so that it doesn't linger as a zombie
+        let id = id.to_string();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!(
+                "⚠ Warning: --on-capture command for entry {} failed ({})",
+                id, status
+            ),
+            Err(e) => eprintln!("⚠ Warning: Failed to wait for --on-capture command: {}", e),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_receives_metadata() {
+        let hook = CaptureHook::new(
+            r#"printf '%s %s %s' "$CLPD_ENTRY_ID" "$CLPD_CONTENT_TYPE" "$CLPD_SIZE""#.to_string(),
+        );
+        let output = hook
+            .command("1700000000000-0000012345", "image", 4096)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "1700000000000-0000012345 image 4096"
+        );
+    }
+}
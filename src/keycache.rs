@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroize;
+
+use crate::crypto::MasterKey;
+
+const SERVICE: &str = "clpd";
+
+/// Keyring entry for the database with this salt, so several databases don't share a slot
+fn entry(salt: &[u8]) -> Result<keyring::Entry> {
+    let id = hex::encode(&Sha256::digest(salt)[..8]);
+    keyring::Entry::new(SERVICE, &format!("key-{}", id)).context("Failed to access OS keyring")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Secret stored in the keyring: key || expiry (unix seconds, little endian)
+fn encode(key: &MasterKey, expires_at: u64) -> Vec<u8> {
+    let mut secret = key.as_bytes().to_vec();
+    secret.extend_from_slice(&expires_at.to_le_bytes());
+    secret
+}
+
+/// The key, if the secret is well formed and hasn't expired at `now`
+fn decode(secret: &[u8], now: u64) -> Option<MasterKey> {
+    let (key, expires_at) = secret.split_first_chunk::<32>()?;
+    let expires_at = u64::from_le_bytes(expires_at.try_into().ok()?);
+    (now < expires_at).then(|| MasterKey::from_bytes(*key))
+}
+
+/// Cache the key for `ttl`
+pub fn store(salt: &[u8], key: &MasterKey, ttl: Duration) -> Result<()> {
+    let mut secret = encode(key, now().saturating_add(ttl.as_secs()));
+    let result = entry(salt)?
+        .set_secret(&secret)
+        .context("Failed to store key in OS keyring");
+    secret.zeroize();
+    result
+}
+
+/// The cached key, if there is one that hasn't expired
+///
+/// Any keyring error counts as "no cached key", the caller just prompts instead.
+pub fn load(salt: &[u8]) -> Option<MasterKey> {
+    let entry = entry(salt).ok()?;
+    let mut secret = entry.get_secret().ok()?;
+    let key = decode(&secret, now());
+    secret.zeroize();
+
+    if key.is_none() {
+        // Expired, don't leave it lying around
+        let _ = entry.delete_credential();
+    }
+    key
+}
+
+/// Remove the cached key, returning whether there was one
+pub fn clear(salt: &[u8]) -> Result<bool> {
+    match entry(salt)?.delete_credential() {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(e).context("Failed to remove key from OS keyring"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_expiry() {
+        let key = MasterKey::from_bytes([9u8; 32]);
+        let secret = encode(&key, 1_000);
+
+        let cached = decode(&secret, 999).unwrap();
+        assert_eq!(cached.as_bytes(), key.as_bytes());
+        assert!(decode(&secret, 1_000).is_none());
+        assert!(decode(&secret[..20], 0).is_none());
+    }
+}
@@ -0,0 +1,125 @@
+use base64::{Engine as _, engine::general_purpose};
+
+/// Largest base64 payload the kitty graphics protocol accepts in one escape sequence
+const CHUNK_SIZE: usize = 4096;
+
+/// Removes every image placed with [`place_png`]
+pub const DELETE_ALL: &str = "\x1b_Ga=d,q=2\x1b\\";
+
+/// Whether the terminal we're running in shows images sent with the kitty
+/// graphics protocol
+pub fn is_supported() -> bool {
+    supported_by(|name| std::env::var(name).ok())
+}
+
+/// Terminals known to implement the protocol, recognized by the variables they set
+///
+/// Terminal multiplexers don't pass the escape sequences through, so inside tmux
+/// or screen the answer is no whatever the outer terminal is.
+fn supported_by(var: impl Fn(&str) -> Option<String>) -> bool {
+    if var("TMUX").is_some() || var("STY").is_some() {
+        return false;
+    }
+    if var("KITTY_WINDOW_ID").is_some() {
+        return true;
+    }
+    let term = var("TERM").unwrap_or_default();
+    let term_program = var("TERM_PROGRAM").unwrap_or_default();
+    matches!(term.as_str(), "xterm-kitty" | "xterm-ghostty")
+        || matches!(term_program.as_str(), "WezTerm" | "ghostty")
+}
+
+/// Escape sequences that show a PNG at the cursor, scaled to `columns` x `rows`
+/// cells, without moving the cursor
+///
+/// The image is sent inline, so it never touches the disk.
+pub fn place_png(png: &[u8], columns: u16, rows: u16) -> String {
+    let data = general_purpose::STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(CHUNK_SIZE).collect();
+
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let chunk = std::str::from_utf8(chunk).expect("base64 is ASCII");
+        if i == 0 {
+            out.push_str(&format!(
+                "\x1b_Ga=T,f=100,t=d,q=2,C=1,c={},r={},m={};{}\x1b\\",
+                columns, rows, more, chunk
+            ));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    out
+}
+
+/// Cells an image of `width` x `height` pixels takes up when shrunk to fit
+/// `columns` x `rows` cells of `cell_width` x `cell_height` pixels; images that
+/// fit are shown at their own size
+pub fn fit(
+    (width, height): (usize, usize),
+    (columns, rows): (u16, u16),
+    (cell_width, cell_height): (u16, u16),
+) -> (u16, u16) {
+    if width == 0 || height == 0 || columns == 0 || rows == 0 {
+        return (0, 0);
+    }
+    let (cell_width, cell_height) = (cell_width.max(1) as f64, cell_height.max(1) as f64);
+    let scale = (columns as f64 * cell_width / width as f64)
+        .min(rows as f64 * cell_height / height as f64)
+        .min(1.0);
+    let cells = |pixels: usize, cell: f64, max: u16| {
+        ((pixels as f64 * scale / cell).ceil() as u16).clamp(1, max)
+    };
+    (
+        cells(width, cell_width, columns),
+        cells(height, cell_height, rows),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_place_png_chunks() {
+        let short = place_png(b"png", 10, 5);
+        assert_eq!(short, "\x1b_Ga=T,f=100,t=d,q=2,C=1,c=10,r=5,m=0;cG5n\x1b\\");
+
+        // 3 bytes per 4 base64 characters: just over two chunks
+        let long = place_png(&vec![0u8; CHUNK_SIZE / 4 * 3 * 2 + 3], 1, 1);
+        assert_eq!(long.matches("\x1b_G").count(), 3);
+        assert_eq!(long.matches("m=1;").count(), 2);
+        assert!(long.contains("\x1b_Gm=0;AAAA\x1b\\"));
+    }
+
+    #[test]
+    fn test_fit() {
+        // Small images keep their size
+        assert_eq!(fit((100, 40), (80, 24), (10, 20)), (10, 2));
+        // Large ones shrink to the tighter dimension, keeping their shape
+        assert_eq!(fit((1600, 400), (80, 24), (10, 20)), (80, 10));
+        assert_eq!(fit((400, 4000), (80, 24), (10, 20)), (5, 24));
+        assert_eq!(fit((0, 10), (80, 24), (10, 20)), (0, 0));
+        assert_eq!(fit((10, 10), (0, 24), (10, 20)), (0, 0));
+    }
+
+    #[test]
+    fn test_supported_terminals() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert!(supported_by(env(&[("TERM", "xterm-kitty")])));
+        assert!(supported_by(env(&[("KITTY_WINDOW_ID", "1")])));
+        assert!(supported_by(env(&[("TERM_PROGRAM", "WezTerm")])));
+        assert!(!supported_by(env(&[("TERM", "xterm-256color")])));
+        assert!(!supported_by(env(&[
+            ("TERM", "xterm-kitty"),
+            ("TMUX", "/tmp/tmux-1000/default,1,0")
+        ])));
+    }
+}
@@ -1,198 +1,516 @@
+mod audit;
+mod backups;
 mod cli;
+mod config;
+mod confirm;
 mod crypto;
+mod cycle;
 mod database;
+mod events;
+mod focus;
+mod hook;
+mod keycache;
+mod kitty;
 mod middleware;
 mod models;
+mod notify;
+mod selftest;
+mod tempfiles;
+#[cfg(test)]
+mod testutil;
 mod tui;
 mod watcher;
 use anyhow::{Context, Result};
 use arboard::Clipboard;
+use chrono::{DateTime, Utc};
 use mimalloc::MiMalloc;
+use parking_lot::RwLock;
+use std::borrow::Cow;
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use tokio::runtime;
-
-use cli::{Commands, parse_args};
-use crypto::{decrypt, derive_key, encrypt, generate_salt};
+use std::io::{self, IsTerminal, Read, Write};
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use backups::{BackupTrigger, Backups};
+use cli::{
+    BackupsCommand, Commands, ConfigCommand, CopyTarget, CycleCommand, DebugCommand, DumpContent,
+    EntryType, ListColumn, SelectionFilter, ShowFormat, TypeFilter, parse_args,
+};
+use config::{Config, ConfigFile};
+use confirm::confirm;
+use crypto::{KdfParams, derive_key, generate_salt};
+use cycle::{CycleCursor, Step};
 use database::ClipboardDatabase;
-use models::{ClipboardContentType, ImageData};
-use watcher::start_watcher;
+use models::{
+    ClipboardContentType, ClipboardEntry, ClipboardSelection, DecryptedEntry, EntryMetadata,
+    ImageData, TextEntry,
+};
+use tempfiles::AtomicFile;
+use tui::TuiOptions;
+use watcher::{
+    AnnouncedWrites, CaptureOutcome, ClipboardSource, SelfWrites, WatcherOptions, print_changes,
+    start_watcher,
+};
 
 use crate::crypto::MasterKey;
-use crate::database::{ClipboardType, NetworkClipboardDatabase};
+use crate::database::{
+    Backup, ClipboardType, CompactStats, CorsPolicy, NetworkClipboardDatabase, ServerOptions,
+    SharedDatabase, TextSearch, UsageCounter,
+};
 use crate::watcher::LocalClipboardWatcher;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+/// `clear` compacts the database afterwards when it deleted at least this many entries
+const AUTO_COMPACT_AFTER: usize = 1000;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let args = parse_args();
+    confirm::set_assume_yes(
+        args.assume_yes
+            || confirm::assume_yes_from_env(std::env::var(confirm::ASSUME_YES_ENV).ok().as_deref()),
+    );
 
-    // Handle install command separately (doesn't need database)
-    if matches!(args.command, Commands::Install) {
-        return cmd_install();
-    }
-
-    if matches!(args.command, Commands::NetStart { max_entries }) {
-        return cmd_net_start(None).await;
-    }
+    // Decrypted files left behind by an earlier run that was killed
+    tempfiles::remove_stale(&tempfiles::dir(), tempfiles::STALE_AFTER);
 
-    if matches!(args.command, Commands::NetBrowse) {
-        // let clipboard_db = ClipboardType::Network(NetworkClipboardDatabase);
-        return cmd_net_browse(None).await;
-    }
+    // A broken config file mustn't keep `clpd config` from fixing it
+    let config = if matches!(args.command, Commands::Config { .. }) {
+        Config::default()
+    } else {
+        Config::load(&Config::default_path()?)?
+    };
 
-    // Get database path
-    let db_path = match args.database {
+    // Get database path: flag, then config file, then the default location
+    let db_path = match args.database.or(config.database_path) {
         Some(path) => path,
         None => ClipboardDatabase::default_path()?,
     };
+    let server_url = |server: Option<reqwest::Url>| -> Result<reqwest::Url> {
+        match server.or_else(|| config.server_url.clone()) {
+            Some(url) => Ok(url),
+            None => Ok(database::DEFAULT_SERVER_URL.parse()?),
+        }
+    };
+    let poll_interval_or_config = |poll_interval: Option<u64>| {
+        poll_interval
+            .or(config.poll_interval_ms)
+            .map_or(watcher::POLL_INTERVAL, Duration::from_millis)
+    };
 
-    // Open database
-    let db = ClipboardDatabase::open(db_path)?;
-
-    // Handle commands
-    match args.command {
-        Commands::Init => cmd_init(db)?,
-        Commands::NetListen => cmd_net_listen(db).await?,
-        // Commands::NetStart { max_entries } => cmd_net_start(max_entries).await?,
-        Commands::Start { max_entries } => cmd_start(db, max_entries)?,
-        Commands::List { verbose, limit } => cmd_list(db, verbose, limit)?,
-        Commands::Show { id } => cmd_show(db, &id)?,
-        Commands::Copy { id } => cmd_copy(db, &id)?,
-        Commands::Delete { id, yes } => cmd_delete(db, &id, yes)?,
-        Commands::Clear { yes } => cmd_clear(db, yes)?,
-        Commands::Stats => cmd_stats(db)?,
-        Commands::Dump { directory, yes } => cmd_dump(db, directory, yes)?,
-        Commands::Browse => {
-            if !db.is_initialized()? {
-                anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    // Operations the database is copied aside before: flags, then config file
+    let backup_before = if args.backup_before.is_empty() {
+        config.backup_before.clone()
+    } else {
+        args.backup_before
+    };
+    let backups = Backups::for_database(&db_path);
+    let backups_for = |trigger: BackupTrigger| backup_before.contains(&trigger).then_some(&backups);
+
+    // The local database is only opened by the commands that use it, so that
+    // `install` and the network client commands work without one
+    let open_db = || {
+        ClipboardDatabase::open_with(db_path.clone(), |db| {
+            match backups_for(BackupTrigger::Migrate) {
+                Some(backups) => back_up(backups, db, BackupTrigger::Migrate),
+                None => Ok(()),
             }
+        })
+    };
 
-            // Get password
-            let password = rpassword::prompt_password("Enter master password: ")?;
-
-            // Get salt and derive key
-            let salt = db.get_salt()?;
-            let key = derive_key(&password, &salt)?;
-
-            // Verify password
-            if !db.verify_password(&key)? {
-                anyhow::bail!("❌ Incorrect password!");
+    // Handle commands; `copy --json` reports its own failures and only sets this
+    let mut exit_code = ExitCode::SUCCESS;
+    match args.command {
+        Commands::Install => cmd_install()?,
+        Commands::Config { command } => cmd_config(command)?,
+        Commands::NetStart {
+            max_entries,
+            server,
+            max_payload_mb,
+        } => {
+            cmd_net_start(
+                server_url(server)?,
+                max_entries.or(config.max_entries),
+                max_payload_mb.saturating_mul(1024 * 1024),
+            )
+            .await?
+        }
+        Commands::NetBrowse {
+            server,
+            open_images,
+        } => {
+            let options = TuiOptions {
+                open_images: open_images.or(config.open_images).unwrap_or_default(),
+                ..TuiOptions::default()
+            };
+            cmd_net_browse(server_url(server)?, options).await?
+        }
+        Commands::Init {
+            salt_length,
+            per_entry_keys,
+            kdf_memory,
+            kdf_iterations,
+        } => cmd_init(
+            open_db()?,
+            salt_length,
+            per_entry_keys,
+            KdfParams::new(kdf_memory, kdf_iterations),
+        )?,
+        Commands::ChangePassword { dry_run, yes } => cmd_change_password(
+            open_db()?,
+            RekeyOptions {
+                dry_run,
+                yes,
+                backups: backups_for(BackupTrigger::Passwd),
+            },
+        )?,
+        Commands::RotateSalt { dry_run, yes } => cmd_rotate_salt(
+            open_db()?,
+            RekeyOptions {
+                dry_run,
+                yes,
+                backups: backups_for(BackupTrigger::Passwd),
+            },
+        )?,
+        Commands::NetListen {
+            allow_origin,
+            allow_any_origin,
+            bind,
+            max_payload_mb,
+            accept,
+        } => {
+            let cors = if allow_any_origin {
+                CorsPolicy::Any
+            } else if !allow_origin.is_empty() {
+                CorsPolicy::Origins(allow_origin)
+            } else {
+                CorsPolicy::SameOrigin
+            };
+            let bind = bind.unwrap_or_else(|| {
+                let port = config
+                    .listen_port
+                    .map_or(database::DEFAULT_PORT, NonZeroU16::get);
+                (std::net::Ipv4Addr::LOCALHOST, port).into()
+            });
+            let options = ServerOptions {
+                cors,
+                bind,
+                max_payload: max_payload_mb.saturating_mul(1024 * 1024),
+                accept,
+            };
+            cmd_net_listen(open_db()?, options).await?
+        }
+        Commands::Start {
+            on_change_only,
+            poll_interval,
+            print_only: true,
+            ..
+        } => print_changes(on_change_only, poll_interval_or_config(poll_interval))?,
+        Commands::Start {
+            max_entries,
+            max_age,
+            dedupe_window,
+            poll_interval,
+            events,
+            watch_interval_jitter,
+            watch_selection,
+            notify,
+            quiet_hours,
+            strip_ansi,
+            normalize,
+            max_image_mb,
+            images,
+            swap_rb,
+            canonical_image_hash,
+            delta,
+            on_change_only,
+            skip_initial,
+            ignore_app,
+            max_rate,
+            no_max_rate,
+            verbose,
+            on_capture,
+            once,
+            json,
+            print_only: false,
+        } => {
+            let options = WatcherOptions {
+                max_entries: max_entries.or(config.max_entries),
+                max_age: max_age.or(config.max_age),
+                dedupe_window,
+                poll_interval: poll_interval_or_config(poll_interval),
+                poll_jitter: watch_interval_jitter,
+                events,
+                selections: watch_selection.selections(),
+                notify,
+                quiet_hours,
+                strip_ansi,
+                normalize,
+                max_image_bytes: max_image_mb.saturating_mul(1024 * 1024),
+                images,
+                swap_rb,
+                canonical_images: canonical_image_hash,
+                delta,
+                on_change_only,
+                skip_initial,
+                ignore_apps: if ignore_app.is_empty() {
+                    config.ignore_apps
+                } else {
+                    ignore_app
+                },
+                max_rate: (!no_max_rate).then_some(max_rate),
+                on_capture,
+                verbose,
+            };
+            if once {
+                cmd_capture_once(open_db()?, options, json)?
+            } else {
+                cmd_start(open_db()?, options)?
             }
+        }
+        Commands::Copy {
+            id,
+            json: true,
+            target,
+        } => exit_code = cmd_copy_json(open_db(), &id, target),
+        command @ (Commands::List { .. }
+        | Commands::Show { .. }
+        | Commands::Copy { .. }
+        | Commands::Search { .. }
+        | Commands::Touch { .. }
+        | Commands::Pin { .. }
+        | Commands::Unpin { .. }
+        | Commands::Delete { .. }) => run_entry_command(open_db()?, command)?,
+        Commands::Shell => cmd_shell(open_db()?)?,
+        Commands::PasteFromStdin => cmd_paste_from_stdin(open_db()?)?,
+        Commands::Print { id } => cmd_print(open_db()?, &id)?,
+        Commands::Clear { yes, types } => {
+            cmd_clear(open_db()?, yes, types, backups_for(BackupTrigger::Clear))?
+        }
+        Commands::Backups { command } => cmd_backups(&backups, &db_path, command)?,
+        Commands::Stats {
+            usage: true,
+            enable,
+            disable,
+            ..
+        } => cmd_usage_stats(open_db()?, enable, disable)?,
+        Commands::Stats { storage: true, .. } => cmd_storage_stats(open_db()?)?,
+        Commands::Stats { .. } => cmd_stats(open_db()?)?,
+        Commands::Dump {
+            directory,
+            yes,
+            limit,
+            since,
+            types,
+            content,
+        } => cmd_dump(open_db()?, directory, yes, limit, since, types, content)?,
+        Commands::Fingerprint => cmd_fingerprint(open_db()?)?,
+        Commands::Audit { enable } => cmd_audit(open_db()?, enable)?,
+        Commands::Formats => cmd_formats()?,
+        Commands::Debug {
+            command: DebugCommand::DumpTree { tree, hex },
+        } => cmd_dump_tree(&db_path, tree, hex)?,
+        Commands::Selftest => cmd_selftest()?,
+        Commands::Fsck { quarantine } => cmd_fsck(open_db()?, quarantine)?,
+        Commands::Prune { older_than, yes } => cmd_prune(open_db()?, older_than, yes)?,
+        Commands::Compact => cmd_compact(open_db()?)?,
+        Commands::Unlock { ttl } => cmd_unlock(open_db()?, ttl)?,
+        Commands::Lock => cmd_lock(open_db()?)?,
+        Commands::Cycle { command } => cmd_cycle(open_db()?, command)?,
+        Commands::Merge { other, reencrypt } => cmd_merge(open_db()?, other, reencrypt)?,
+        Commands::Backup {
+            file,
+            restore: false,
+        } => cmd_backup(open_db()?, file)?,
+        Commands::Backup {
+            file,
+            restore: true,
+        } => cmd_restore(open_db()?, file)?,
+        Commands::Browse {
+            watch,
+            session_gap,
+            selection,
+            open_images,
+        } => {
+            let db = open_db()?;
+            let key = unlock(&db)?;
+            let options = TuiOptions {
+                live: watch,
+                session_gap: Duration::from_secs(session_gap.saturating_mul(60)),
+                selection,
+                open_images: open_images.or(config.open_images).unwrap_or_default(),
+            };
 
             println!("✓ Password verified");
             println!();
-            let db = LocalClipboardWatcher::new(db, key.clone(), None)?;
-            let db = ClipboardType::Local(db);
-            cmd_browse(db, key).await?
+            if watch {
+                cmd_browse_watch(db, key, options).await?
+            } else {
+                let db = LocalClipboardWatcher::new(db, key.clone(), None)?;
+                cmd_browse(ClipboardType::Local(Box::new(db)), key, options).await?
+            }
         }
-        Commands::Install => unreachable!(), // Handled above
-        Commands::NetStart { max_entries } => unreachable!(), // Handled above
-        Commands::NetBrowse => unreachable!(), // Handled above
     };
     // Clean up by deleting any temporary files if needed
-    let temp_dir = std::env::temp_dir().join("clpd_temp");
-    if temp_dir.exists() {
-        fs::remove_dir_all(&temp_dir).context("Failed to clean up temporary files")?;
-    };
-    Ok(())
+    let temp_dir = tempfiles::dir();
+    if temp_dir.exists()
+        && let Err(e) = fs::remove_dir_all(&temp_dir)
+    {
+        eprintln!("⚠ Warning: Failed to clean up temporary files: {}", e);
+    }
+    Ok(exit_code)
 }
 
-async fn cmd_net_listen(db: ClipboardDatabase) -> Result<()> {
+async fn cmd_net_listen(db: ClipboardDatabase, options: ServerOptions) -> Result<()> {
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    // Get password
-    let password = rpassword::prompt_password("Enter master password: ")?;
+    // The key yields the token clients must present; entries are only decrypted
+    // to rewrite delta entries whose base is deleted
+    let key = unlock(&db)?;
 
-    // Get salt and derive key
-    let salt = db.get_salt()?;
-    let key = derive_key(&password, &salt)?;
+    println!("✓ Password verified");
+    println!();
 
-    // Verify password
-    if !db.verify_password(&key)? {
-        anyhow::bail!("❌ Incorrect password!");
+    match &options.cors {
+        CorsPolicy::SameOrigin => {}
+        CorsPolicy::Origins(origins) => {
+            println!(
+                "🌐 Allowing cross-origin requests from: {}",
+                origins.join(", ")
+            );
+        }
+        CorsPolicy::Any => {
+            println!("⚠️  Allowing cross-origin requests from ANY origin.");
+            println!("   Any website you visit can read and modify your clipboard history!");
+        }
+    }
+    if !options.accept.contains(&EntryType::Image) {
+        println!("📝 Accepting only text entries, images are refused");
+    } else if !options.accept.contains(&EntryType::Text) {
+        println!("🖼️  Accepting only image entries, text is refused");
     }
 
-    println!("✓ Password verified");
-    println!();
+    if options.bind.ip().is_loopback() {
+        println!("📡 Listening on http://{}", options.bind);
+    } else {
+        println!(
+            "📡 Listening on http://{}, reachable from other machines",
+            options.bind
+        );
+        println!(
+            "   Entries stay encrypted, but use HTTPS (e.g. a reverse proxy) on untrusted networks"
+        );
+    }
 
     // Start server and remain running
-    database::run_clipboard_server(db).await;
+    database::run_clipboard_server(db, options, key).await
+}
+
+/// Show or change settings in the config file
+fn cmd_config(command: ConfigCommand) -> Result<()> {
+    let path = Config::default_path()?;
+    match command {
+        ConfigCommand::Path => println!("{}", path.display()),
+        ConfigCommand::Get { key } => match ConfigFile::open(&path)?.get(&key)? {
+            Some(value) => println!("{}", value),
+            None => eprintln!("⚠ {} is not set", key),
+        },
+        ConfigCommand::Set { key, value } => {
+            let mut file = match ConfigFile::open(&path) {
+                Ok(file) => file,
+                // Not TOML at all: start over, keeping the old file aside
+                Err(e) if e.downcast_ref::<toml_edit::TomlError>().is_some() => {
+                    let kept = path.with_extension("toml.bak");
+                    fs::rename(&path, &kept)
+                        .with_context(|| format!("Failed to move {} aside", path.display()))?;
+                    eprintln!("⚠ {:#}", e);
+                    eprintln!(
+                        "   Starting from an empty config, the old file is now {}",
+                        kept.display()
+                    );
+                    ConfigFile::empty(&path)
+                }
+                Err(e) => return Err(e),
+            };
+            let stored = file.set(&key, &value)?;
+            file.save()?;
+            println!("✓ {} = {}", key, stored);
+        }
+    }
     Ok(())
 }
 
-async fn cmd_net_browse(max_entries: Option<usize>) -> Result<()> {
-    // Get password
+async fn cmd_net_browse(server: reqwest::Url, options: TuiOptions) -> Result<()> {
+    // Reach the server before asking for the password
+    let salt = NetworkClipboardDatabase::fetch_salt(&server).await?;
+    let kdf = NetworkClipboardDatabase::fetch_kdf_params(&server).await?;
     let password = rpassword::prompt_password("Enter master password: ")?;
+    let key = derive_key(&password, &salt, kdf)?;
 
-    // Get salt and derive key
-    // let salt = db.get_salt()?;
-
-    let temp_client = reqwest::Client::new();
-    let salt_resp = temp_client
-        .get("http://localhost:2573/clipboard/salt")
-        .send()
-        .await?;
-    let salt = salt_resp.text().await?;
-    let salt = salt.as_bytes();
-
-    let key = derive_key(&password, &salt)?;
-
-    let network_clip = NetworkClipboardDatabase::new(&key, max_entries)?;
-    let network_clip = ClipboardType::Network(network_clip);
+    // Browsing never stores entries, so there is nothing to prune
+    let network_clip = NetworkClipboardDatabase::new(&server, &key, None)?;
+    if !network_clip.verify_password().await? {
+        anyhow::bail!("❌ Incorrect password!");
+    }
+    let network_clip = ClipboardType::Network(Box::new(network_clip));
 
     println!("✓ Password verified");
     println!();
-    cmd_browse(network_clip, key).await?;
+    cmd_browse(network_clip, key, options).await?;
     Ok(())
 }
 
-async fn cmd_net_start(max_entries: Option<usize>) -> Result<()> {
-    // Get password
+async fn cmd_net_start(
+    server: reqwest::Url,
+    max_entries: Option<usize>,
+    max_payload: usize,
+) -> Result<()> {
+    // Reach the server before asking for the password
+    let salt = NetworkClipboardDatabase::fetch_salt(&server).await?;
+    let kdf = NetworkClipboardDatabase::fetch_kdf_params(&server).await?;
     let password = rpassword::prompt_password("Enter master password: ")?;
+    let key = derive_key(&password, &salt, kdf)?;
 
-    // Get salt and derive key
-    // let salt = db.get_salt()?;
-
-    let temp_client = reqwest::Client::new();
-    let salt_resp = temp_client
-        .get("http://localhost:2573/clipboard/salt")
-        .send()
-        .await?;
-    let salt = salt_resp.text().await?;
-    let salt = salt.as_bytes();
-
-    let key = derive_key(&password, &salt)?;
-
-    let mut network_clip = NetworkClipboardDatabase::new(&key, max_entries)?;
+    let mut network_clip =
+        NetworkClipboardDatabase::new(&server, &key, max_entries)?.with_max_payload(max_payload);
+    if !network_clip.verify_password().await? {
+        anyhow::bail!("❌ Incorrect password!");
+    }
 
     println!("✓ Password verified");
     println!();
 
+    if let Some(max) = max_entries {
+        println!("📊 Maximum entries: {}", max);
+    }
+
     // Start watcher
     network_clip.watch().await
 }
 
 /// Initialize the database
-fn cmd_init(db: ClipboardDatabase) -> Result<()> {
+fn cmd_init(
+    db: ClipboardDatabase,
+    salt_length: usize,
+    per_entry_keys: bool,
+    kdf: KdfParams,
+) -> Result<()> {
     // Check if already initialized
     if db.is_initialized()? {
         println!("⚠ Database is already initialized.");
-        print!(
-            "Do you want to reinitialize? This will NOT delete existing entries but will change the password. (y/N): "
-        );
-        io::stdout().flush()?;
-
-        let mut response = String::new();
-        io::stdin().read_line(&mut response)?;
-
-        if !response.trim().eq_ignore_ascii_case("y") {
+        println!("💡 To change the password, use 'clpd change-password'.");
+        if !confirm(
+            "Do you want to reinitialize? Existing entries stay but can no longer be decrypted.",
+        )? {
             println!("Initialization cancelled.");
             return Ok(());
         }
@@ -202,7 +520,39 @@ fn cmd_init(db: ClipboardDatabase) -> Result<()> {
     println!();
 
     // Get password from user
-    let password = rpassword::prompt_password("Enter master password: ")?;
+    let password = prompt_new_password("Enter master password: ")?;
+
+    // Generate salt
+    let salt = generate_salt(salt_length);
+
+    // Derive key
+    println!("\n⏳ Deriving encryption key...");
+    let key = derive_key(&password, &salt, kdf)?;
+
+    // Store in database, checking it was written before reporting success
+    db.set_per_entry_keys(per_entry_keys)?;
+    db.initialize_with_key(&salt, &key, kdf)
+        .context("Failed to initialize the database")?;
+
+    println!("✓ Database initialized successfully!");
+    if kdf != KdfParams::default() {
+        println!(
+            "🔑 Key derived with Argon2id using {} MiB of memory and {} iterations.",
+            kdf.m_cost / 1024,
+            kdf.t_cost
+        );
+    }
+    if per_entry_keys {
+        println!("🔑 Each new entry will be encrypted under its own derived key.");
+    }
+    println!("\n💡 Use 'clpd start' to begin watching your clipboard.");
+
+    Ok(())
+}
+
+/// Prompt for a new master password twice, checking it's long enough
+fn prompt_new_password(prompt: &str) -> Result<String> {
+    let password = rpassword::prompt_password(prompt)?;
     let password_confirm = rpassword::prompt_password("Confirm master password: ")?;
 
     if password != password_confirm {
@@ -212,80 +562,458 @@ fn cmd_init(db: ClipboardDatabase) -> Result<()> {
     if password.len() < 8 {
         anyhow::bail!("Password must be at least 8 characters long");
     }
+    Ok(password)
+}
 
-    // Generate salt
-    let salt = generate_salt();
+/// Prompt for the current master password, even if a key is cached, returning
+/// it with its verified key
+fn prompt_current_password(db: &ClipboardDatabase) -> Result<(String, MasterKey)> {
+    if !db.is_initialized()? {
+        anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    }
+    let password = rpassword::prompt_password("Enter current master password: ")?;
+    let key = derive_key(&password, &db.get_salt()?, db.kdf_params()?)?;
+    if !db.verify_password(&key)? {
+        anyhow::bail!("❌ Incorrect password!");
+    }
+    Ok((password, key))
+}
 
-    // Derive key
+/// Options for `clpd change-password` and `clpd rotate-salt`
+struct RekeyOptions<'a> {
+    /// Only report how re-encrypting would go
+    dry_run: bool,
+    /// Don't ask before changing anything
+    yes: bool,
+    /// Where the database is backed up first (`--backup-before passwd`)
+    backups: Option<&'a Backups>,
+}
+
+/// Change the master password, re-encrypting every entry under the new key
+fn cmd_change_password(db: ClipboardDatabase, options: RekeyOptions) -> Result<()> {
+    let (_, old_key) = prompt_current_password(&db)?;
+
+    let password = prompt_new_password("Enter new master password: ")?;
+    let salt = generate_salt(db.get_salt()?.len());
     println!("\n⏳ Deriving encryption key...");
-    let key = derive_key(&password, &salt)?;
+    let new_key = derive_key(&password, &salt, db.kdf_params()?)?;
 
-    // Create payload
-    let test_payload = encrypt(&key, b"clpd_test")?;
+    rekey_checked(&db, &old_key, &new_key, &salt, "Password", options)
+}
 
-    // Store in database
-    db.initialize(&salt, &test_payload)?;
+/// Re-encrypt every entry under a key derived from the same password and a
+/// fresh salt of the same length
+fn cmd_rotate_salt(db: ClipboardDatabase, options: RekeyOptions) -> Result<()> {
+    let (password, old_key) = prompt_current_password(&db)?;
 
-    println!("✓ Database initialized successfully!");
-    println!("\n💡 Use 'clpd start' to begin watching your clipboard.");
+    let salt = generate_salt(db.get_salt()?.len());
+    println!("\n⏳ Deriving encryption key...");
+    let new_key = derive_key(&password, &salt, db.kdf_params()?)?;
 
+    rekey_checked(&db, &old_key, &new_key, &salt, "Salt", options)
+}
+
+/// Switch the database to `new_key`, derived with `salt`, reporting a dry run first
+///
+/// The database is only changed after confirming, unless `yes`; the question
+/// warns when some entries don't re-encrypt cleanly. `what` is the `Password`
+/// or `Salt` being changed. With `backups`, the database is backed up first.
+fn rekey_checked(
+    db: &ClipboardDatabase,
+    old_key: &MasterKey,
+    new_key: &MasterKey,
+    salt: &[u8],
+    what: &str,
+    options: RekeyOptions,
+) -> Result<()> {
+    let RekeyOptions {
+        dry_run,
+        yes,
+        backups,
+    } = options;
+    let old_salt = db.get_salt()?;
+    let report = db.rekey(old_key, new_key, salt, true)?;
+    println!(
+        "📊 Dry run: {} entries re-encrypt cleanly, {} fail",
+        report.reencrypted,
+        report.failed.len()
+    );
+    for id in &report.failed {
+        println!("  - {} doesn't decrypt with the current password", id);
+    }
+    print_quarantine_left(&report);
+    if dry_run {
+        println!("Nothing was changed.");
+        return Ok(());
+    }
+    let question = if report.failed.is_empty() {
+        format!(
+            "Change the {} and re-encrypt {} entries?",
+            what.to_lowercase(),
+            report.reencrypted
+        )
+    } else {
+        format!(
+            "⚠ Change the {} anyway? Those entries will stay unreadable.",
+            what.to_lowercase()
+        )
+    };
+    if !yes && !confirm(&question)? {
+        println!("{} not changed.", what);
+        return Ok(());
+    }
+    if let Some(backups) = backups {
+        back_up(backups, db, BackupTrigger::Passwd)?;
+    }
+
+    let report = db.rekey(old_key, new_key, salt, false)?;
+    // The cached key is the old one. The change is committed by now, so a
+    // keyring error mustn't hide that it succeeded
+    if let Err(e) = keycache::clear(&old_salt) {
+        eprintln!("⚠ Warning: Failed to remove the cached key: {:#}", e);
+    }
+
+    println!(
+        "✓ {} changed, {} entries re-encrypted",
+        what, report.reencrypted
+    );
+    if !report.failed.is_empty() {
+        println!(
+            "⚠ {} entries were left as they were and can't be decrypted",
+            report.failed.len()
+        );
+    }
+    if report.quarantine_reencrypted > 0 {
+        println!(
+            "✓ {} quarantined entries re-encrypted",
+            report.quarantine_reencrypted
+        );
+    }
+    print_quarantine_left(&report);
     Ok(())
 }
 
+/// Warn about the quarantined entries that stay encrypted with the old key
+fn print_quarantine_left(report: &database::RekeyReport) {
+    if !report.quarantine_left.is_empty() {
+        println!(
+            "⚠ {} quarantined entries can't be read and stay under the old key",
+            report.quarantine_left.len()
+        );
+    }
+}
+
+/// Run one of the commands that are also available in `clpd shell`
+fn run_entry_command(db: ClipboardDatabase, command: Commands) -> Result<()> {
+    match command {
+        Commands::List {
+            verbose,
+            limit,
+            since,
+            until,
+            json,
+            columns,
+            preview,
+            preview_lines,
+            preview_width,
+            copy_first,
+            types,
+            selection,
+        } => cmd_list(
+            db,
+            ListOptions {
+                verbose,
+                limit,
+                since,
+                until,
+                json,
+                columns,
+                preview: preview.then_some((preview_lines, preview_width)),
+                copy_first,
+                types,
+                selection,
+            },
+        ),
+        Commands::Show { id, format } => cmd_show(
+            db,
+            &id,
+            format,
+            &mut io::BufWriter::new(io::stdout().lock()),
+        ),
+        // Reports failures only through the exit status, which the shell doesn't have
+        Commands::Copy { json: true, .. } => {
+            anyhow::bail!("'copy --json' isn't available in the shell")
+        }
+        Commands::Copy {
+            id,
+            json: false,
+            target,
+        } => cmd_copy(db, &id, target, &mut ClipboardWriter::system()?),
+        Commands::Search {
+            query,
+            ignore_case,
+            limit,
+            copy,
+            copy_ids,
+            oldest_first,
+        } => {
+            let search = TextSearch {
+                query,
+                ignore_case,
+                oldest_first,
+                limit,
+            };
+            let mut clipboard = copy.then(ClipboardWriter::system).transpose()?;
+            cmd_search(db, &search, clipboard.as_mut(), copy_ids)
+        }
+        Commands::Touch { id } => cmd_touch(db, &id),
+        Commands::Pin { id } => cmd_pin(db, &id, true),
+        Commands::Unpin { id } => cmd_pin(db, &id, false),
+        Commands::Delete { id, yes } => cmd_delete(db, &id, yes),
+        _ => {
+            anyhow::bail!(
+                "Only list, show, copy, touch, pin, unpin, delete and search can be run in the shell"
+            )
+        }
+    }
+}
+
+/// Key of the running `clpd shell` session, used by its commands instead of prompting
+static SESSION_KEY: parking_lot::Mutex<Option<MasterKey>> = parking_lot::const_mutex(None);
+
+/// Read commands from a prompt and run them, unlocking the database only once
+fn cmd_shell(db: ClipboardDatabase) -> Result<()> {
+    let key = unlock(&db)?;
+    println!("✓ Password verified");
+    println!(
+        "💡 Run list, show, copy, touch, pin, unpin, delete or search as on the command line,"
+    );
+    println!("   e.g. 'search todo' or 'show <id>'. Type 'help' for more, 'exit' to quit.");
+    println!();
+
+    *SESSION_KEY.lock() = Some(key);
+    let result = shell_loop(&db);
+    // Dropping the key zeroizes it
+    SESSION_KEY.lock().take();
+    result
+}
+
+fn shell_loop(db: &ClipboardDatabase) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        print!("clpd> ");
+        io::stdout().flush()?;
+
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            // End of input (Ctrl+D)
+            println!();
+            return Ok(());
+        }
+
+        match line.trim() {
+            "" => continue,
+            "exit" | "quit" => return Ok(()),
+            "help" => {
+                println!(
+                    "Commands: list, show <id>, copy <id>, touch <id>, pin <id>, unpin <id>, delete <id>, search <query>"
+                );
+                println!("Add --help to a command to see its options. 'exit' quits.");
+                continue;
+            }
+            _ => {}
+        }
+
+        let result = match cli::parse_shell_line(&line) {
+            Ok(command) => run_entry_command(db.clone(), command),
+            Err(e) => {
+                // Also how --help is shown
+                let _ = e.print();
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("❌ {:#}", e);
+        }
+        println!();
+    }
+}
+
 /// Start the clipboard watcher
-fn cmd_start(db: ClipboardDatabase, max_entries: Option<usize>) -> Result<()> {
+fn cmd_start(db: ClipboardDatabase, options: WatcherOptions) -> Result<()> {
+    if options.notify && !notify::CaptureNotifier::is_supported() {
+        anyhow::bail!(
+            "This build of clpd has no notification support. Rebuild with '--features notify'."
+        );
+    }
+
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    // Get password
-    let password = rpassword::prompt_password("Enter master password: ")?;
-
-    // Get salt and derive key
-    let salt = db.get_salt()?;
-    let key = derive_key(&password, &salt)?;
-
-    // Verify password
-    if !db.verify_password(&key)? {
-        anyhow::bail!("❌ Incorrect password!");
-    }
+    let key = unlock(&db)?;
 
     println!("✓ Password verified");
     println!();
 
-    if let Some(max) = max_entries {
+    if let Some(max) = options.max_entries {
         println!("📊 Maximum entries: {}", max);
     }
+    if let Some(max_age) = options.max_age {
+        println!("⏳ Maximum age: {}", cli::format_age(max_age));
+    }
 
     // Start watcher
-    start_watcher(db, key, max_entries)
+    start_watcher(db, key, options)
+}
+
+/// Capture what is on the clipboard now, for scripts: stdout gets the new entry's
+/// ID or metadata and nothing else
+fn cmd_capture_once(db: ClipboardDatabase, options: WatcherOptions, json: bool) -> Result<()> {
+    if options.notify && !notify::CaptureNotifier::is_supported() {
+        anyhow::bail!(
+            "This build of clpd has no notification support. Rebuild with '--features notify'."
+        );
+    }
+
+    let key = unlock(&db)?;
+    let (outcome, entry) = watcher::capture_once(db, key, options)?;
+    match (outcome, entry) {
+        (CaptureOutcome::Stored, Some(entry)) => {
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&select_columns(&entry, None)?)?
+                );
+            } else {
+                println!("{}", entry.id);
+            }
+        }
+        (CaptureOutcome::Stored, None) => eprintln!("⚠ The captured entry was pruned right away"),
+        (CaptureOutcome::Duplicate, _) => eprintln!("⚠ Nothing captured: already stored"),
+        (CaptureOutcome::Empty, _) => eprintln!("⚠ Nothing captured: the clipboard is empty"),
+        (CaptureOutcome::Skipped(reason), _) => eprintln!("⚠ Nothing captured: {}", reason),
+    }
+    Ok(())
+}
+
+/// Options for `clpd list`
+struct ListOptions {
+    verbose: bool,
+    limit: Option<usize>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    json: bool,
+    columns: Option<Vec<ListColumn>>,
+    preview: Option<(usize, usize)>, // max lines, max width
+    copy_first: bool,
+    types: TypeFilter,
+    selection: SelectionFilter,
 }
 
 /// List all entries
-fn cmd_list(db: ClipboardDatabase, verbose: bool, limit: Option<usize>) -> Result<()> {
+fn cmd_list(db: ClipboardDatabase, options: ListOptions) -> Result<()> {
+    let ListOptions {
+        verbose,
+        limit,
+        since,
+        until,
+        json,
+        columns,
+        preview,
+        copy_first,
+        types,
+        selection,
+    } = options;
+
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    let entries = db.list_entries()?;
+    // Use the timestamp index where possible rather than reading every entry.
+    // The total is None when only some of the matching entries were read; one
+    // past the limit is read then to tell whether there are more.
+    let (entries, total) = if since.is_some() || until.is_some() {
+        let mut entries = db.entries_between(since, until)?;
+        entries.retain(|entry| types.matches(&entry.content_type) && selection.matches(entry));
+        let total = entries.len();
+        (entries, Some(total))
+    } else if let Some(limit) = limit
+        && !types.is_set()
+        && !selection.is_set()
+    {
+        (db.newest_entries(limit)?, Some(db.count_entries()?))
+    } else if let Some(limit) = limit {
+        let mut entries = Vec::new();
+        for entry in db.iter_entries_newest_first() {
+            let entry = entry?;
+            if types.matches(&entry.content_type) && selection.matches(&entry) {
+                entries.push(entry);
+                if entries.len() > limit {
+                    break;
+                }
+            }
+        }
+        (entries, None)
+    } else {
+        let mut entries = db.list_entries()?;
+        entries.retain(|entry| types.matches(&entry.content_type) && selection.matches(entry));
+        let total = entries.len();
+        (entries, Some(total))
+    };
+
+    let matched = entries.len();
+    let display_count = limit.unwrap_or(matched).min(matched);
+    let entries = &entries[..display_count];
+
+    // Machine-readable output, without headers or hints
+    if json {
+        let items = entries
+            .iter()
+            .map(|entry| select_columns(entry, columns.as_deref()))
+            .collect::<Result<Vec<_>>>()?;
+        println!("{}", serde_json::to_string_pretty(&items)?);
+        return Ok(());
+    }
+    if let Some(columns) = &columns {
+        for entry in entries {
+            let values: Vec<String> = columns.iter().map(|c| column_text(entry, *c)).collect();
+            println!("{}", values.join("\t"));
+        }
+        return Ok(());
+    }
 
     if entries.is_empty() {
         println!("No entries found. Start the watcher with 'clpd start'.");
         return Ok(());
     }
 
-    let display_count = limit.unwrap_or(entries.len()).min(entries.len());
+    // Only needed to show or copy content
+    let key = if preview.is_some() || copy_first {
+        Some(unlock(&db)?)
+    } else {
+        None
+    };
+    let content_preview = |entry: &models::ClipboardEntry| -> Option<String> {
+        let (key, (lines, width)) = (key.as_ref()?, preview?);
+        Some(match db.decrypt_content(key, entry) {
+            Ok(content) => content.preview_line(lines, width),
+            Err(e) => format!("⚠ {}", e),
+        })
+    };
 
-    println!(
-        "📋 Clipboard History ({} entries, showing {})",
-        entries.len(),
-        display_count
-    );
+    match total {
+        Some(total) => println!(
+            "📋 Clipboard History ({} entries, showing {})",
+            total, display_count
+        ),
+        None => println!("📋 Clipboard History (showing {})", display_count),
+    }
     println!();
 
-    for entry in entries.iter().take(display_count) {
+    for entry in entries {
         if verbose {
             println!("ID: {}", entry.id);
             println!(
@@ -293,160 +1021,509 @@ fn cmd_list(db: ClipboardDatabase, verbose: bool, limit: Option<usize>) -> Resul
                 entry.timestamp.format("%Y-%m-%d %H:%M:%S%.3f %Z")
             );
             println!("  Type: {:?}", entry.content_type);
-            println!("  Size: {} bytes (encrypted)", entry.payload.len());
+            if let Some(image) = entry.image {
+                println!("  Dimensions: {} x {} pixels", image.width, image.height);
+            }
+            match entry.content_size() {
+                (size, true) => println!(
+                    "  Size: {} bytes stored (encrypted), {} bytes uncompressed ({})",
+                    entry.payload.len(),
+                    size,
+                    storage_ratio(entry.payload.len(), size)
+                ),
+                (_, false) => println!("  Size: {} bytes (encrypted)", entry.payload.len()),
+            }
             println!("  Hash: {}", entry.hash);
+            if let Some(content) = content_preview(entry) {
+                println!("  Content: {}", content);
+            }
             println!();
+        } else if let Some(content) = content_preview(entry) {
+            println!("{}  {}", entry.preview(), content);
         } else {
             println!("{}", entry.preview());
         }
     }
 
-    if display_count < entries.len() {
-        println!(
+    match total {
+        Some(total) if display_count < total => println!(
             "\n... and {} more entries. Use --limit to show more or --verbose for details.",
-            entries.len() - display_count
-        );
+            total - display_count
+        ),
+        None if display_count < matched => {
+            println!("\n... and more entries. Use --limit to show more or --verbose for details.")
+        }
+        _ => {}
+    }
+
+    if copy_first && let (Some(key), Some(entry)) = (&key, entries.first()) {
+        println!();
+        copy_to_selection(
+            db.decrypt_content(key, entry)?,
+            ClipboardSelection::Clipboard,
+            entry,
+        )?;
+        db.count_usage(UsageCounter::Copies);
     }
 
     Ok(())
 }
 
+/// An entry's metadata as a JSON object, optionally restricted to some fields
+fn select_columns(
+    entry: &models::ClipboardEntry,
+    columns: Option<&[ListColumn]>,
+) -> Result<serde_json::Value> {
+    let value = serde_json::to_value(EntryMetadata::from(entry))?;
+    let Some(columns) = columns else {
+        return Ok(value);
+    };
+
+    let serde_json::Value::Object(mut fields) = value else {
+        unreachable!("entry metadata serializes to an object");
+    };
+    let selected = columns
+        .iter()
+        .filter_map(|c| fields.remove(c.key()).map(|v| (c.key().to_string(), v)))
+        .collect();
+    Ok(serde_json::Value::Object(selected))
+}
+
+/// A single field of an entry's metadata as plain text
+fn column_text(entry: &models::ClipboardEntry, column: ListColumn) -> String {
+    match column {
+        ListColumn::Id => entry.id.clone(),
+        ListColumn::Timestamp => entry.timestamp.to_rfc3339(),
+        ListColumn::ContentType => format!("{:?}", entry.content_type),
+        ListColumn::Size => entry.payload.len().to_string(),
+        ListColumn::Hash => entry.hash.clone(),
+        ListColumn::Image => entry
+            .image
+            .map_or_else(|| "-".to_string(), |i| format!("{}x{}", i.width, i.height)),
+    }
+}
+
 /// Show a specific entry
-fn cmd_show(db: ClipboardDatabase, id: &str) -> Result<()> {
+fn cmd_show(
+    db: ClipboardDatabase,
+    id: &str,
+    format: ShowFormat,
+    out: &mut impl Write,
+) -> Result<()> {
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    // Get password
-    let password = rpassword::prompt_password("Enter master password: ")?;
-
-    // Get salt and derive key
-    let salt = db.get_salt()?;
-    let key = derive_key(&password, &salt)?;
+    let key = unlock(&db)?;
+    let (entry, content) = db.get_decrypted(&key, id)?;
 
-    // Verify password
-    if !db.verify_password(&key)? {
-        anyhow::bail!("❌ Incorrect password!");
+    if format == ShowFormat::Base64 {
+        content.write_base64(&mut *out)?;
+        writeln!(out)?;
+        out.flush()?;
+        return Ok(());
     }
 
-    // Get entry
-    let entry = db
-        .get_entry(id)?
-        .ok_or_else(|| anyhow::anyhow!("Entry '{}' not found", id))?;
-
-    // Decrypt
-    let plaintext = decrypt(&key, &entry.payload).context("Failed to decrypt entry")?;
-
-    println!("📋 Entry: {}", entry.id);
-    println!(
+    writeln!(out, "📋 Entry: {}", entry.id)?;
+    writeln!(
+        out,
         "⏰ Timestamp: {}",
         entry.timestamp.format("%Y-%m-%d %H:%M:%S %Z")
-    );
-    println!("📝 Type: {:?}", entry.content_type);
-    println!();
-
-    match entry.content_type {
-        ClipboardContentType::Text => {
-            let text = String::from_utf8_lossy(&plaintext);
-            println!("Content:");
-            println!("─────────────────────────────────────");
-            println!("{}", text);
-            println!("─────────────────────────────────────");
+    )?;
+    writeln!(out, "📝 Type: {:?}", entry.content_type)?;
+    writeln!(out)?;
+
+    match content {
+        DecryptedEntry::Text(text) => {
+            // Text is shown even when it isn't valid UTF-8, with a warning
+            if !text.is_valid_utf8() {
+                eprintln!("⚠ Entry contains invalid UTF-8 bytes, displaying them as �");
+            }
+            writeln!(out, "Content:")?;
+            writeln!(out, "─────────────────────────────────────")?;
+            writeln!(out, "{}", text.lossy())?;
+            writeln!(out, "─────────────────────────────────────")?;
         }
-        ClipboardContentType::Image => {
-            // Deserialize to show image dimensions
-            match bincode::deserialize::<ImageData>(&plaintext) {
-                Ok(img_data) => {
-                    println!("Content: Image");
-                    println!(
-                        "  Dimensions: {} x {} pixels",
-                        img_data.width, img_data.height
-                    );
-                    println!("  Size: {} bytes (raw RGBA)", img_data.bytes.len());
-                    println!(
-                        "💡 Use 'clpd copy {}' to copy this image to clipboard",
-                        entry.id
-                    );
-                }
-                Err(_) => {
-                    println!("Content: Image data ({} bytes)", plaintext.len());
-                    println!(
-                        "💡 Use 'clpd copy {}' to copy this image to clipboard",
-                        entry.id
-                    );
-                }
+        DecryptedEntry::Image(img_data) => {
+            writeln!(out, "Content: Image")?;
+            writeln!(
+                out,
+                "  Dimensions: {} x {} pixels",
+                img_data.width, img_data.height
+            )?;
+            writeln!(out, "  Size: {} bytes (raw RGBA)", img_data.bytes.len())?;
+            if let Some(encoding) = entry.image_encoding {
+                writeln!(out, "  Stored as: {}", encoding.name())?;
+            }
+            if let Some(warning) = entry.thumbnail_warning() {
+                writeln!(out, "⚠ {}", warning)?;
             }
+            writeln!(
+                out,
+                "💡 Use 'clpd copy {}' to copy this image to clipboard",
+                entry.id
+            )?;
         }
     }
 
+    out.flush()?;
     Ok(())
 }
 
-/// Copy an entry back to clipboard
-fn cmd_copy(db: ClipboardDatabase, id: &str) -> Result<()> {
+/// Copy an entry back to the clipboard (on Linux, the selection it came from)
+fn cmd_copy(
+    db: ClipboardDatabase,
+    id: &str,
+    target: CopyTarget,
+    clipboard: &mut ClipboardWriter<impl ClipboardSource>,
+) -> Result<()> {
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    // Get password
-    let password = rpassword::prompt_password("Enter master password: ")?;
+    let key = unlock(&db)?;
+    let (entry, content) = db.get_decrypted(&key, id)?;
+    clipboard.copy(content, target.selection_for(&entry), Some(&entry))?;
+    db.count_usage(UsageCounter::Copies);
+    Ok(())
+}
 
-    // Get salt and derive key
-    let salt = db.get_salt()?;
-    let key = derive_key(&password, &salt)?;
+/// Copy the entry before or after the one `cycle` copied last, or start over
+fn cmd_cycle(db: ClipboardDatabase, command: CycleCommand) -> Result<()> {
+    let cursor = CycleCursor::for_database(db.path());
+    let step = match command {
+        CycleCommand::Prev => Step::Older,
+        CycleCommand::Next => Step::Newer,
+        CycleCommand::Reset => {
+            cursor.reset()?;
+            println!("✓ Cycling starts over from the newest entry");
+            return Ok(());
+        }
+    };
 
-    // Verify password
-    if !db.verify_password(&key)? {
-        anyhow::bail!("❌ Incorrect password!");
+    let key = unlock(&db)?;
+    let entries = db.list_entries()?;
+    let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+    let Some(index) = cycle::step(&ids, cursor.load().as_deref(), step) else {
+        println!("No entries found.");
+        return Ok(());
+    };
+    let entry = &entries[index];
+    // Saved first, so an entry that can't be copied is stepped over next time
+    cursor.save(&entry.id)?;
+
+    let content = db.decrypt_content(&key, entry)?;
+    copy_to_selection(content, ClipboardSelection::Clipboard, entry)?;
+    db.count_usage(UsageCounter::Copies);
+    println!("  Entry {} of {} ({})", index + 1, entries.len(), entry.id);
+    Ok(())
+}
+
+/// `copy --json`: report the outcome as one JSON object on stdout, failures included
+///
+/// Returns a failure status on failure, without the usual error message on stderr.
+fn cmd_copy_json(db: Result<ClipboardDatabase>, id: &str, target: CopyTarget) -> ExitCode {
+    let copy = || -> Result<(models::ClipboardEntry, DecryptedEntry)> {
+        let db = db?;
+        if !db.is_initialized()? {
+            anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+        }
+        let key = unlock(&db)?;
+        let (entry, content) = db.get_decrypted(&key, id)?;
+        ClipboardWriter::system()?.set(&content, target.selection_for(&entry), Some(&entry))?;
+        db.count_usage(UsageCounter::Copies);
+        Ok((entry, content))
+    };
+
+    match copy() {
+        Ok((entry, content)) => {
+            let bytes = match &content {
+                DecryptedEntry::Text(text) => text.len(),
+                DecryptedEntry::Image(img_data) => img_data.bytes.len(),
+            };
+            let report = serde_json::json!({
+                "id": entry.id,
+                "content_type": entry.content_type,
+                "bytes": bytes,
+                "thumbnail": entry.original_image.is_some(),
+                "status": "copied",
+            });
+            println!("{}", report);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            let report = serde_json::json!({
+                "id": id,
+                "status": "error",
+                "error": format!("{:#}", e),
+            });
+            println!("{}", report);
+            ExitCode::FAILURE
+        }
     }
+}
 
-    // Get entry
-    let entry = db
-        .get_entry(id)?
-        .ok_or_else(|| anyhow::anyhow!("Entry '{}' not found", id))?;
+/// The clipboard copies go to, and where other clpd processes learn that clpd
+/// wrote them
+struct ClipboardWriter<C: ClipboardSource = Clipboard> {
+    clipboard: C,
+    /// So a `clpd start` running in another process doesn't capture a copy as new
+    writes: Option<AnnouncedWrites>,
+}
 
-    // Decrypt
-    let plaintext = decrypt(&key, &entry.payload).context("Failed to decrypt entry")?;
+impl ClipboardWriter {
+    fn system() -> Result<Self> {
+        Ok(Self {
+            clipboard: Clipboard::new().context("Failed to access clipboard")?,
+            writes: AnnouncedWrites::default_location(),
+        })
+    }
+}
 
-    // Copy to clipboard
-    let mut clipboard = Clipboard::new().context("Failed to access clipboard")?;
+impl<C: ClipboardSource> ClipboardWriter<C> {
+    /// Put decrypted content on the clipboard, or another selection on Linux
+    ///
+    /// Text goes back exactly as stored, and in the flavor `entry` was captured
+    /// in as well, if it had one.
+    fn set(
+        &mut self,
+        content: &DecryptedEntry,
+        selection: ClipboardSelection,
+        entry: Option<&models::ClipboardEntry>,
+    ) -> Result<()> {
+        let hash = match content {
+            DecryptedEntry::Text(text) => LocalClipboardWatcher::hash_data(text.as_bytes()),
+            DecryptedEntry::Image(img_data) => {
+                LocalClipboardWatcher::hash_data(&img_data.hash_input())
+            }
+        };
+        if let Some(writes) = &self.writes
+            && let Err(e) = writes.announce(&hash)
+        {
+            eprintln!("⚠ Warning: {:#}", e);
+        }
 
-    match entry.content_type {
-        ClipboardContentType::Text => {
-            let text = String::from_utf8(plaintext).context("Entry contains invalid UTF-8")?;
-            clipboard
-                .set_text(text)
-                .context("Failed to set clipboard text")?;
-            println!("✓ Text copied to clipboard");
+        match content {
+            DecryptedEntry::Text(text) => {
+                watcher::set_text_entry(&mut self.clipboard, text, selection)?;
+                if let Some(flavor) = entry.and_then(|entry| entry.text_flavor.as_deref())
+                    && selection == ClipboardSelection::Clipboard
+                    && let Ok(text) = text.as_str()
+                    && let Err(e) = watcher::add_text_flavor(flavor, text)
+                {
+                    // The text is on the clipboard, just not in its original flavor
+                    eprintln!("⚠ Warning: {:#}", e);
+                }
+            }
+            DecryptedEntry::Image(img_data) => {
+                img_data.check_well_formed()?;
+
+                // Create arboard ImageData from our stored data
+                let arboard_img = arboard::ImageData {
+                    width: img_data.width,
+                    height: img_data.height,
+                    bytes: img_data.bytes.as_slice().into(),
+                };
+
+                watcher::set_image(&mut self.clipboard, arboard_img, selection)
+                    .context("Failed to set clipboard image")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put decrypted content on a selection and say so
+    fn copy(
+        &mut self,
+        content: DecryptedEntry,
+        selection: ClipboardSelection,
+        entry: Option<&models::ClipboardEntry>,
+    ) -> Result<()> {
+        self.set(&content, selection, entry)?;
+
+        let target = selection.target();
+        match content {
+            DecryptedEntry::Text(_) => println!("✓ Text copied to {}", target),
+            DecryptedEntry::Image(img_data) => println!(
+                "✓ Image copied to {} ({} x {} pixels)",
+                target, img_data.width, img_data.height
+            ),
+        }
+        if let Some(warning) = entry.and_then(models::ClipboardEntry::thumbnail_warning) {
+            println!("⚠ {}", warning);
+        }
+
+        Ok(())
+    }
+}
+
+/// Put an entry's decrypted content on a selection and say so
+fn copy_to_selection(
+    content: DecryptedEntry,
+    selection: ClipboardSelection,
+    entry: &models::ClipboardEntry,
+) -> Result<()> {
+    ClipboardWriter::system()?.copy(content, selection, Some(entry))
+}
+
+/// Store text from stdin as a new entry
+fn cmd_paste_from_stdin(db: ClipboardDatabase) -> Result<()> {
+    let key = unlock(&db)?;
+
+    let mut data = Vec::new();
+    io::stdin()
+        .read_to_end(&mut data)
+        .context("Failed to read stdin")?;
+    let text = String::from_utf8(data).context("Input is not valid UTF-8 text")?;
+    if text.is_empty() {
+        anyhow::bail!("Nothing to store, stdin was empty.");
+    }
+
+    let mut watcher = LocalClipboardWatcher::without_clipboard(db, key);
+    match watcher.process_text(&text)? {
+        CaptureOutcome::Stored => eprintln!("✓ Stored encrypted entry ({} bytes)", text.len()),
+        CaptureOutcome::Duplicate => eprintln!("⚠ Identical content is already stored"),
+        CaptureOutcome::Empty => eprintln!("⚠ Nothing to store"),
+        CaptureOutcome::Skipped(reason) => eprintln!("⚠ Not stored: {}", reason),
+    }
+
+    Ok(())
+}
+
+/// Search text entries, copying the matches with `clipboard`
+fn cmd_search(
+    db: ClipboardDatabase,
+    search: &TextSearch,
+    clipboard: Option<&mut ClipboardWriter<impl ClipboardSource>>,
+    copy_ids: bool,
+) -> Result<()> {
+    let key = unlock(&db)?;
+
+    let matches = db.search_text(&key, search)?;
+    db.count_usage(UsageCounter::Searches);
+
+    // For scripting: nothing but the IDs
+    if copy_ids {
+        for (entry, _) in &matches {
+            println!("{}", entry.id);
+        }
+        return Ok(());
+    }
+
+    if matches.is_empty() {
+        println!("No text entries contain '{}'.", search.query);
+        return Ok(());
+    }
+
+    match search.limit {
+        Some(limit) if matches.len() == limit => {
+            println!("🔍 First {} matching entries", limit)
+        }
+        _ => println!("🔍 {} matching entries", matches.len()),
+    }
+    println!();
+    // Matches stand out in a terminal, but escape codes would garble a pipe
+    let (highlight, reset) = if io::stdout().is_terminal() {
+        ("\x1b[1;33m", "\x1b[0m")
+    } else {
+        ("", "")
+    };
+    for (entry, text) in &matches {
+        let text = text.lossy();
+        let found = models::match_ranges(&text, &search.query, search.ignore_case)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let [before, matched, after] = models::match_snippet(&text, found, 80);
+        println!(
+            "{}  {}{}{}{}{}",
+            entry.preview(),
+            before,
+            highlight,
+            matched,
+            reset,
+            after
+        );
+    }
+
+    if let Some(clipboard) = clipboard {
+        println!();
+        if let [(entry, text)] = matches.as_slice() {
+            // Like `copy`: the exact bytes, in the flavor and selection captured
+            clipboard.copy(
+                DecryptedEntry::Text(text.clone()),
+                CopyTarget::default().selection_for(entry),
+                Some(entry),
+            )?;
+        } else {
+            let count = matches.len();
+            let joined = matches
+                .iter()
+                .map(|(_, text)| text.as_bytes())
+                .collect::<Vec<_>>()
+                .join(&b'\n');
+            clipboard.copy(
+                DecryptedEntry::Text(TextEntry::from_bytes(joined)),
+                ClipboardSelection::Clipboard,
+                None,
+            )?;
+            println!("  ({} entries, joined with newlines)", count);
         }
+        db.count_usage(UsageCounter::Copies);
+    }
+
+    Ok(())
+}
+
+/// Write an entry's raw content to stdout
+fn cmd_print(db: ClipboardDatabase, id: &str) -> Result<()> {
+    let key = unlock(&db)?;
+    let (entry, plaintext) = db.get_plaintext(&key, id)?;
+
+    let mut stdout = io::stdout().lock();
+    match entry.content_type {
+        // The exact bytes, even if they aren't valid UTF-8
+        ClipboardContentType::Text => stdout.write_all(&plaintext)?,
         ClipboardContentType::Image => {
-            // Deserialize the ImageData structure
-            let img_data: ImageData =
-                bincode::deserialize(&plaintext).context("Failed to deserialize image data")?;
-
-            // Create arboard ImageData from our stored data
-            let arboard_img = arboard::ImageData {
-                width: img_data.width,
-                height: img_data.height,
-                bytes: img_data.bytes.into(),
-            };
+            let img_data = ImageData::from_plaintext(&plaintext)?;
+            if stdout.is_terminal() {
+                anyhow::bail!("Refusing to write image data to a terminal, redirect it to a file.");
+            }
 
-            clipboard
-                .set_image(arboard_img)
-                .context("Failed to set clipboard image")?;
+            let img = img_data.into_rgba_image()?;
 
-            println!(
-                "✓ Image copied to clipboard ({} x {} pixels)",
-                img_data.width, img_data.height
-            );
+            let mut png = io::Cursor::new(Vec::new());
+            img.write_to(&mut png, image::ImageFormat::Png)
+                .context("Failed to encode image")?;
+            stdout.write_all(png.get_ref())?;
         }
     }
+    stdout.flush()?;
 
     Ok(())
 }
 
+/// Move an entry to the top of the history
+fn cmd_touch(db: ClipboardDatabase, id: &str) -> Result<()> {
+    match db.touch_entry(id)? {
+        Some(_) => println!("✓ Entry '{}' moved to the top", id),
+        None => println!("⚠ Entry '{}' not found", id),
+    }
+    Ok(())
+}
+
+fn cmd_pin(db: ClipboardDatabase, id: &str, pinned: bool) -> Result<()> {
+    match db.set_pinned(id, pinned)? {
+        Some(_) if pinned => println!("📌 Entry '{}' pinned, pruning keeps it", id),
+        Some(_) => println!("✓ Entry '{}' unpinned", id),
+        None => println!("⚠ Entry '{}' not found", id),
+    }
+    Ok(())
+}
+
 /// Delete an entry
 fn cmd_delete(db: ClipboardDatabase, id: &str, yes: bool) -> Result<()> {
     // Check if initialized
@@ -455,21 +1532,20 @@ fn cmd_delete(db: ClipboardDatabase, id: &str, yes: bool) -> Result<()> {
     }
 
     // Confirm deletion
-    if !yes {
-        print!("⚠ Delete entry '{}'? (y/N): ", id);
-        io::stdout().flush()?;
-
-        let mut response = String::new();
-        io::stdin().read_line(&mut response)?;
-
-        if !response.trim().eq_ignore_ascii_case("y") {
-            println!("Deletion cancelled.");
-            return Ok(());
-        }
+    if !yes && !confirm(&format!("⚠ Delete entry '{}'?", id))? {
+        println!("Deletion cancelled.");
+        return Ok(());
     }
 
+    // Delta entries stored against this one are rewritten in full, which needs the key
+    let key = if db.dependents_of(id)?.is_empty() {
+        None
+    } else {
+        Some(unlock(&db)?)
+    };
+
     // Delete
-    if db.delete_entry(id)? {
+    if db.delete_entry_with_key(id, key.as_ref())? {
         println!("✓ Entry '{}' deleted", id);
     } else {
         println!("⚠ Entry '{}' not found", id);
@@ -479,38 +1555,48 @@ fn cmd_delete(db: ClipboardDatabase, id: &str, yes: bool) -> Result<()> {
 }
 
 /// Clear all entries
-fn cmd_clear(db: ClipboardDatabase, yes: bool) -> Result<()> {
+fn cmd_clear(
+    db: ClipboardDatabase,
+    yes: bool,
+    types: TypeFilter,
+    backups: Option<&Backups>,
+) -> Result<()> {
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    let count = db.count_entries();
+    // Deltas before the entries they're based on
+    let mut entries = db.list_entries()?;
+    entries.retain(|entry| types.matches(&entry.content_type));
+    entries.sort_by_key(|entry| entry.delta_base.is_none());
+    let count = entries.len();
+    let kind = types.label().map(|label| format!("{} ", label));
+    let kind = kind.as_deref().unwrap_or("");
 
     if count == 0 {
-        println!("Database is already empty.");
+        if types.is_set() {
+            println!("No {}entries to delete.", kind);
+        } else {
+            println!("Database is already empty.");
+        }
         return Ok(());
     }
 
     // Confirm clearing
-    if !yes {
-        print!(
-            "⚠ Delete all {} entries? This cannot be undone! (y/N): ",
-            count
-        );
-        io::stdout().flush()?;
-
-        let mut response = String::new();
-        io::stdin().read_line(&mut response)?;
-
-        if !response.trim().eq_ignore_ascii_case("y") {
-            println!("Clear cancelled.");
-            return Ok(());
-        }
+    if !yes
+        && !confirm(&format!(
+            "⚠ Delete all {} {}entries? This cannot be undone!",
+            count, kind
+        ))?
+    {
+        println!("Clear cancelled.");
+        return Ok(());
+    }
+    if let Some(backups) = backups {
+        back_up(backups, &db, BackupTrigger::Clear)?;
     }
 
-    // Get all entries and delete them
-    let entries = db.list_entries()?;
     let mut deleted = 0;
 
     for entry in entries {
@@ -520,10 +1606,147 @@ fn cmd_clear(db: ClipboardDatabase, yes: bool) -> Result<()> {
     }
 
     println!("✓ Deleted {} entries", deleted);
+    compact_after_bulk_delete(db, deleted);
+
+    Ok(())
+}
+
+/// Delete entries older than `older_than`
+fn cmd_prune(db: ClipboardDatabase, older_than: chrono::TimeDelta, yes: bool) -> Result<()> {
+    // Check if initialized
+    if !db.is_initialized()? {
+        anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    }
+
+    let cutoff = Utc::now() - older_than;
+    let old = db.entries_older_than(cutoff)?;
+    let age = cli::format_age(older_than);
+    if old.is_empty() {
+        println!("No entries older than {}.", age);
+        return Ok(());
+    }
+
+    if !yes
+        && !confirm(&format!(
+            "⚠ Delete {} entries older than {} (captured before {})? This cannot be undone!",
+            old.len(),
+            age,
+            cutoff
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+        ))?
+    {
+        println!("Prune cancelled.");
+        return Ok(());
+    }
+
+    // Newer delta entries stored against old ones are rewritten in full, which needs the key
+    let mut needs_key = false;
+    for entry in &old {
+        let dependents = db.dependents_of(&entry.id)?;
+        if dependents.iter().any(|id| !old.iter().any(|e| e.id == *id)) {
+            needs_key = true;
+            break;
+        }
+    }
+    let key = if needs_key { Some(unlock(&db)?) } else { None };
+
+    let deleted = db.prune_older_than(cutoff, key.as_ref())?;
+    println!("✓ Deleted {} entries older than {}", deleted, age);
+    compact_after_bulk_delete(db, deleted);
+
+    Ok(())
+}
+
+/// Compact the database after deleting many entries
+///
+/// Deleting doesn't shrink the files, which is what clearing a large history is
+/// often for. The entries are gone either way, so failing here isn't an error.
+fn compact_after_bulk_delete(db: ClipboardDatabase, deleted: usize) {
+    if deleted < AUTO_COMPACT_AFTER {
+        return;
+    }
+    println!("🧹 Compacting database...");
+    match db.compact() {
+        Ok((_, stats)) => print_compact_stats(&stats),
+        Err(e) => eprintln!(
+            "⚠ Warning: Failed to compact database: {:#}. Run 'clpd compact' to retry.",
+            e
+        ),
+    }
+}
+
+/// Copy the whole database aside before `trigger`'s operation, which doesn't
+/// run if that fails
+fn back_up(backups: &Backups, db: &ClipboardDatabase, trigger: BackupTrigger) -> Result<()> {
+    let name = backups.create(db, trigger.label())?;
+    // On stderr, a migration may precede output meant for scripts (`list --json`)
+    eprintln!(
+        "💾 Backed up the database as {} (undo with 'clpd backups restore {}')",
+        name, name
+    );
+    Ok(())
+}
+
+/// List or restore the copies of the database taken by `--backup-before`
+fn cmd_backups(backups: &Backups, db_path: &Path, command: BackupsCommand) -> Result<()> {
+    match command {
+        BackupsCommand::List => {
+            let list = backups.list()?;
+            if list.is_empty() {
+                println!("No backups in {}.", backups.dir().display());
+                println!(
+                    "💡 Add --backup-before (or backup_before in the config file) to take them."
+                );
+                return Ok(());
+            }
+            println!("💾 {} backups in {}", list.len(), backups.dir().display());
+            for backup in &list {
+                println!(
+                    "  {:<32} {:>8.2} MB",
+                    backup.name,
+                    backup.size as f64 / (1024.0 * 1024.0)
+                );
+            }
+        }
+        BackupsCommand::Restore { name, yes } => {
+            let backup = backups.find(&name)?;
+            if !yes
+                && !confirm(&format!(
+                    "Replace the database with backup {}? The current database is backed up first.",
+                    backup.name
+                ))?
+            {
+                println!("Restore cancelled.");
+                return Ok(());
+            }
+            let saved = backups.restore(&backup, db_path)?;
+            println!("✓ Restored backup {}", backup.name);
+            if let Some(saved) = saved {
+                println!("💡 The database as it was before is backup {}", saved);
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Rewrite the database to reclaim the space of deleted entries
+fn cmd_compact(db: ClipboardDatabase) -> Result<()> {
+    println!("🧹 Compacting database...");
+    let (_, stats) = db.compact()?;
+    print_compact_stats(&stats);
     Ok(())
 }
 
+fn print_compact_stats(stats: &CompactStats) {
+    println!(
+        "✓ Database compacted: {:.2} MB -> {:.2} MB ({} bytes reclaimed)",
+        stats.before as f64 / (1024.0 * 1024.0),
+        stats.after as f64 / (1024.0 * 1024.0),
+        stats.reclaimed()
+    );
+}
+
 /// Show database statistics
 fn cmd_stats(db: ClipboardDatabase) -> Result<()> {
     // Check if initialized
@@ -531,30 +1754,39 @@ fn cmd_stats(db: ClipboardDatabase) -> Result<()> {
         anyhow::bail!("Database not initialized. Run 'clpd init' first.");
     }
 
-    let entries = db.list_entries()?;
-    let total_count = entries.len();
+    // Totted up one entry at a time rather than holding the whole history
+    let mut total_count = 0;
+    let mut text_count = 0;
+    let mut image_count = 0;
+    let mut total_size = 0;
+    let mut content_size = 0;
+    let mut estimated = 0;
+    let mut newest = None;
+    let mut oldest = None;
+    for entry in db.iter_entries_newest_first() {
+        let entry = entry?;
+        total_count += 1;
+        match entry.content_type {
+            ClipboardContentType::Text => text_count += 1,
+            ClipboardContentType::Image => image_count += 1,
+        }
+        total_size += entry.payload.len();
+        let (size, exact) = entry.content_size();
+        content_size += size;
+        if !exact {
+            estimated += 1;
+        }
+        newest.get_or_insert(entry.timestamp);
+        oldest = Some(entry.timestamp);
+    }
 
-    if total_count == 0 {
+    let (Some(newest), Some(oldest)) = (newest, oldest) else {
         println!("📊 Database Statistics");
         println!();
         println!("Total entries: 0");
         println!("💡 Start the watcher with 'clpd start' to begin collecting clipboard history.");
         return Ok(());
-    }
-
-    let text_count = entries
-        .iter()
-        .filter(|e| e.content_type == ClipboardContentType::Text)
-        .count();
-    let image_count = entries
-        .iter()
-        .filter(|e| e.content_type == ClipboardContentType::Image)
-        .count();
-
-    let total_size: usize = entries.iter().map(|e| e.payload.len()).sum();
-
-    let oldest = entries.last().unwrap();
-    let newest = entries.first().unwrap();
+    };
 
     println!("📊 Database Statistics");
     println!();
@@ -567,31 +1799,166 @@ fn cmd_stats(db: ClipboardDatabase) -> Result<()> {
         total_size,
         total_size as f64 / 1024.0
     );
+    println!(
+        "Uncompressed content size: {} bytes ({:.2} KB), stored in {}",
+        content_size,
+        content_size as f64 / 1024.0,
+        storage_ratio(total_size, content_size)
+    );
+    if estimated > 0 {
+        println!(
+            "  (estimated for {} text entries captured before sizes were recorded)",
+            estimated
+        );
+    }
     println!(
         "Average size per entry: {:.2} bytes",
         total_size as f64 / total_count as f64
     );
     println!();
+    println!("Oldest entry: {}", oldest.format("%Y-%m-%d %H:%M:%S"));
+    println!("Newest entry: {}", newest.format("%Y-%m-%d %H:%M:%S"));
+
+    Ok(())
+}
+
+/// Show the local usage counters, or switch counting on or off
+fn cmd_usage_stats(db: ClipboardDatabase, enable: bool, disable: bool) -> Result<()> {
+    if enable {
+        db.set_usage_counting(true)?;
+        println!("✓ Counting captures, copies and searches in this database");
+        println!("  The counts never leave your machine; 'clpd stats --usage' shows them.");
+        return Ok(());
+    }
+    if disable {
+        db.set_usage_counting(false)?;
+        println!("✓ Usage counting stopped and the counts deleted");
+        return Ok(());
+    }
+
+    let Some(counts) = db.usage_counts()? else {
+        println!("Usage counting is off.");
+        println!("💡 Run 'clpd stats --usage --enable' to count captures, copies and searches.");
+        return Ok(());
+    };
+    println!("📊 Usage");
+    println!();
+    for (counter, count) in counts {
+        println!("{}: {}", counter.label(), count);
+    }
+    Ok(())
+}
+
+/// Share of the disk taken by sled's overhead above which compacting is suggested
+const COMPACT_HINT_OVERHEAD: f64 = 0.5;
+/// Databases smaller than this are mostly sled's fixed overhead; not worth compacting
+const COMPACT_HINT_MIN_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Show how much disk the database takes and what for
+fn cmd_storage_stats(db: ClipboardDatabase) -> Result<()> {
+    let usage = db.storage_usage()?;
+
+    println!("💾 Storage of {}", db.path().display());
+    println!();
+    println!("On disk: {}", format_size(usage.on_disk));
+    for tree in &usage.trees {
+        println!(
+            "  - {:<12} {:>10}  ({} keys)",
+            tree.name,
+            format_size(tree.bytes),
+            tree.keys
+        );
+    }
+    println!("Data in trees: {}", format_size(usage.data()));
+
+    let overhead = usage.overhead();
+    let share = if usage.on_disk == 0 {
+        0.0
+    } else {
+        overhead as f64 / usage.on_disk as f64
+    };
     println!(
-        "Oldest entry: {}",
-        oldest.timestamp.format("%Y-%m-%d %H:%M:%S")
-    );
-    println!(
-        "Newest entry: {}",
-        newest.timestamp.format("%Y-%m-%d %H:%M:%S")
+        "sled overhead and free space: {} ({:.0}% of the disk usage)",
+        format_size(overhead),
+        share * 100.0
     );
+    if share > COMPACT_HINT_OVERHEAD && usage.on_disk >= COMPACT_HINT_MIN_SIZE {
+        println!();
+        println!("💡 Most of the space isn't used by entries. Run 'clpd compact' to reclaim it.");
+    }
+    Ok(())
+}
+
+/// A byte count in B, KB or MB
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.2} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.2} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
 
-    Ok(())
+/// Stored size as a share of the uncompressed size, e.g. "12% of the size"
+fn storage_ratio(stored: usize, uncompressed: usize) -> String {
+    if uncompressed == 0 {
+        return "no content".to_string();
+    }
+    format!(
+        "{:.0}% of the size",
+        stored as f64 * 100.0 / uncompressed as f64
+    )
+}
+
+impl DumpContent {
+    /// Header of the CSV column holding the text
+    fn header(&self) -> &'static str {
+        if self.hash_content {
+            "Content SHA-256"
+        } else {
+            "Content"
+        }
+    }
+
+    /// What to write to the CSV for `text`, when the content isn't hashed
+    fn csv_value<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.redact {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, "***") {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
 }
 
 /// Dump all entries to a directory
-fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()> {
+fn cmd_dump(
+    db: ClipboardDatabase,
+    directory: PathBuf,
+    yes: bool,
+    limit: Option<usize>,
+    since: Option<DateTime<Utc>>,
+    types: TypeFilter,
+    content: DumpContent,
+) -> Result<()> {
     // Check if initialized
     if !db.is_initialized()? {
         anyhow::bail!("Database not initialized. Run 'clipd init' first.");
     }
 
-    let entries = db.list_entries()?;
+    // Newest first, so the limit keeps the most recent matching entries
+    let mut entries = match since {
+        Some(since) => db.entries_between(Some(since), None)?,
+        None => db.list_entries()?,
+    };
+    entries.retain(|entry| types.matches(&entry.content_type));
+    let matching = entries.len();
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+    let skipped = matching - entries.len();
 
     if entries.is_empty() {
         println!("No entries to dump.");
@@ -600,39 +1967,33 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
 
     // Create directory if it doesn't exist
     if directory.exists() {
-        if !yes {
-            print!(
-                "⚠ Directory '{}' already exists. Files may be overwritten. Continue? (y/N): ",
+        if !yes
+            && !confirm(&format!(
+                "⚠ Directory '{}' already exists. Files may be overwritten. Continue?",
                 directory.display()
-            );
-            io::stdout().flush()?;
-
-            let mut response = String::new();
-            io::stdin().read_line(&mut response)?;
-
-            if !response.trim().eq_ignore_ascii_case("y") {
-                println!("Dump cancelled.");
-                return Ok(());
-            }
+            ))?
+        {
+            println!("Dump cancelled.");
+            return Ok(());
         }
     } else {
         fs::create_dir_all(&directory).context("Failed to create output directory")?;
     }
 
-    // Get password
-    let password = rpassword::prompt_password("Enter master password: ")?;
-
-    // Get salt and derive key
-    let salt = db.get_salt()?;
-    let key = derive_key(&password, &salt)?;
-
-    // Verify password
-    if !db.verify_password(&key)? {
-        anyhow::bail!("❌ Incorrect password!");
-    }
+    // The hash of a text entry is stored with it, so only images and written
+    // text need the key
+    let hashed = |entry: &ClipboardEntry| {
+        content.hash_content && entry.content_type == ClipboardContentType::Text
+    };
+    let key = if entries.iter().all(hashed) {
+        None
+    } else {
+        let key = unlock(&db)?;
+        println!("✓ Password verified");
+        println!();
+        Some(key)
+    };
 
-    println!("✓ Password verified");
-    println!();
     println!(
         "📁 Dumping {} entries to '{}'",
         entries.len(),
@@ -640,12 +2001,15 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
     );
     println!();
 
-    // Create CSV file for text entries
+    // Create CSV file for text entries; like the images, it only gets its name
+    // once it's complete, so an interrupted dump leaves no partial files
     let csv_path = directory.join("clipboard_text_entries.csv");
-    let mut csv_writer = csv::Writer::from_path(&csv_path).context("Failed to create CSV file")?;
+    let mut csv_writer = csv::Writer::from_writer(
+        AtomicFile::create(&csv_path).context("Failed to create CSV file")?,
+    );
 
     // Write CSV header
-    csv_writer.write_record(["ID", "Timestamp", "Content"])?;
+    csv_writer.write_record(["ID", "Timestamp", content.header()])?;
 
     let mut text_count = 0;
     let mut image_count = 0;
@@ -653,9 +2017,23 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
 
     // Process each entry
     for entry in entries.iter() {
+        let timestamp = entry.timestamp.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+        let key = match &key {
+            Some(key) if !hashed(entry) => key,
+            _ => {
+                // The stored hash is of the exact bytes, even when they aren't
+                // valid UTF-8
+                csv_writer.write_record([entry.id.as_str(), &timestamp, &entry.hash])?;
+                text_count += 1;
+                print!(".");
+                io::stdout().flush()?;
+                continue;
+            }
+        };
+
         // Decrypt entry
-        let plaintext = match decrypt(&key, &entry.payload) {
-            Ok(data) => data,
+        let decrypted = match db.decrypt_content(key, entry) {
+            Ok(decrypted) => decrypted,
             Err(e) => {
                 eprintln!("⚠ Failed to decrypt entry {}: {}", entry.id, e);
                 errors += 1;
@@ -663,69 +2041,53 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
             }
         };
 
-        match entry.content_type {
-            ClipboardContentType::Text => {
+        match decrypted {
+            DecryptedEntry::Text(text) => {
                 // Write to CSV
-                let text = String::from_utf8_lossy(&plaintext).to_string();
                 csv_writer.write_record([
-                    &entry.id,
-                    &entry.timestamp.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
-                    &text,
+                    entry.id.as_str(),
+                    &timestamp,
+                    &content.csv_value(&text.lossy()),
                 ])?;
                 text_count += 1;
                 print!(".");
                 io::stdout().flush()?;
             }
-            ClipboardContentType::Image => {
-                // Deserialize image data
-                match bincode::deserialize::<ImageData>(&plaintext) {
-                    Ok(img_data) => {
-                        // Save as PNG
-                        let image_filename = format!(
-                            "image_{}_{}.png",
-                            entry.timestamp.format("%Y%m%d_%H%M%S"),
-                            &entry.id[entry.id.len().saturating_sub(8)..]
-                        );
-                        let image_path = directory.join(&image_filename);
-
-                        // Convert RGBA to PNG using image crate
-                        match image::RgbaImage::from_raw(
-                            img_data.width as u32,
-                            img_data.height as u32,
-                            img_data.bytes,
-                        ) {
-                            Some(img) => {
-                                if let Err(e) = img.save(&image_path) {
-                                    eprintln!("\n⚠ Failed to save image {}: {}", image_filename, e);
-                                    errors += 1;
-                                } else {
-                                    image_count += 1;
-                                    print!(".");
-                                    io::stdout().flush()?;
-                                }
-                            }
-                            None => {
-                                eprintln!(
-                                    "\n⚠ Failed to create image from data for entry {}",
-                                    entry.id
-                                );
-                                errors += 1;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!(
-                            "\n⚠ Failed to deserialize image data for entry {}: {}",
-                            entry.id, e
-                        );
-                        errors += 1;
-                    }
+            DecryptedEntry::Image(img_data) => {
+                // Save as PNG
+                let image_filename = format!(
+                    "image_{}_{}.png",
+                    entry.timestamp.format("%Y%m%d_%H%M%S"),
+                    &entry.id[entry.id.len().saturating_sub(8)..]
+                );
+                let image_path = directory.join(&image_filename);
+
+                if let Err(e) = img_data.check_well_formed() {
+                    eprintln!("\n⚠ Failed to save image for entry {}: {}", entry.id, e);
+                    errors += 1;
+                    continue;
+                }
+                let save = || -> Result<()> {
+                    let mut file = AtomicFile::create(&image_path)?;
+                    img_data.write_png(&mut file)?;
+                    file.commit()
+                };
+                if let Err(e) = save() {
+                    eprintln!("\n⚠ Failed to save image {}: {:#}", image_filename, e);
+                    errors += 1;
+                } else {
+                    image_count += 1;
+                    print!(".");
+                    io::stdout().flush()?;
                 }
             }
         }
     }
 
-    csv_writer.flush()?;
+    csv_writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .commit()?;
     println!();
     println!();
     println!("✓ Dump complete!");
@@ -737,6 +2099,9 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
         csv_path.display()
     );
     println!("  - Images: {} (saved as PNG files)", image_count);
+    if skipped > 0 {
+        println!("  - Skipped due to --limit: {}", skipped);
+    }
 
     if errors > 0 {
         println!("  ⚠ Errors: {}", errors);
@@ -745,8 +2110,371 @@ fn cmd_dump(db: ClipboardDatabase, directory: PathBuf, yes: bool) -> Result<()>
     Ok(())
 }
 
+/// Merge entries from another database
+fn cmd_merge(db: ClipboardDatabase, other_path: PathBuf, reencrypt: bool) -> Result<()> {
+    let key = unlock(&db)?;
+
+    let other = ClipboardDatabase::open(other_path.clone())
+        .with_context(|| format!("Failed to open database at {}", other_path.display()))?;
+    if !other.is_initialized()? {
+        anyhow::bail!("Database at {} is not initialized.", other_path.display());
+    }
+
+    let report = if other.get_salt()? == db.get_salt()? {
+        if !other.verify_password(&key)? {
+            anyhow::bail!("❌ The other database uses a different password.");
+        }
+        db.merge_from(&other, &key, None)?
+    } else {
+        if !reencrypt {
+            anyhow::bail!(
+                "The other database uses a different salt (different password or init).\n\
+                 Re-run with --reencrypt to re-encrypt its entries with this database's key."
+            );
+        }
+
+        let password = rpassword::prompt_password("Enter master password for other database: ")?;
+        let other_key = derive_key(&password, &other.get_salt()?, other.kdf_params()?)?;
+        if !other.verify_password(&other_key)? {
+            anyhow::bail!("❌ Incorrect password for other database!");
+        }
+        db.merge_from(&other, &key, Some(&other_key))?
+    };
+
+    println!("✓ Merged {} entries", report.merged);
+    println!("  - Skipped (already present): {}", report.skipped);
+    if report.reencrypted > 0 {
+        println!("  - Re-encrypted: {}", report.reencrypted);
+    }
+
+    Ok(())
+}
+
+/// Write all entries to a backup file
+fn cmd_backup(db: ClipboardDatabase, path: PathBuf) -> Result<()> {
+    if !db.is_initialized()? {
+        anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    }
+
+    let file =
+        fs::File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let count = db.write_backup(io::BufWriter::new(file))?;
+
+    println!("✓ Backed up {} entries to {}", count, path.display());
+    println!("💡 Entries stay encrypted; restoring them needs the same password.");
+    Ok(())
+}
+
+/// Add the entries of a backup file, initializing an empty database from it
+fn cmd_restore(db: ClipboardDatabase, path: PathBuf) -> Result<()> {
+    let file =
+        fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let backup = Backup::read(io::BufReader::new(file))
+        .with_context(|| format!("Failed to read backup {}", path.display()))?;
+
+    let key = if db.is_initialized()? {
+        if backup.salt != db.get_salt()? {
+            anyhow::bail!(
+                "The backup was made from a database with a different salt (different password or init).\n\
+                 Restore it into a new database with --database, then use 'clpd merge --reencrypt' to combine them."
+            );
+        }
+        unlock(&db)?
+    } else {
+        let password = rpassword::prompt_password("Enter master password of the backup: ")?;
+        let key = MasterKey::from_password(&password, &backup.salt, backup.kdf)?;
+        if !backup.verify_password(&key) {
+            anyhow::bail!("❌ Incorrect password!");
+        }
+        db.set_kdf_params(backup.kdf)?;
+        db.initialize(&backup.salt, &backup.payload)?;
+        println!("✓ Database initialized from the backup");
+        key
+    };
+
+    let report = db.restore_backup(&backup, &key)?;
+    println!("✓ Restored {} entries", report.merged);
+    println!("  - Skipped (already present): {}", report.skipped);
+    Ok(())
+}
+
+/// Print the raw keys of a sled tree (`clpd debug dump-tree`)
+fn cmd_dump_tree(db_path: &std::path::Path, tree: database::SledTree, hex: bool) -> Result<()> {
+    let count = database::dump_tree(db_path, tree, hex, &mut io::stdout().lock())?;
+    eprintln!("{} keys", count);
+    Ok(())
+}
+
+fn cmd_selftest() -> Result<()> {
+    println!("⏳ Running round-trips with a throwaway key...");
+    println!();
+    let checks = selftest::run();
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("✓ {}", check.name),
+            Err(e) => println!("❌ {}: {:#}", check.name, e),
+        }
+    }
+    println!();
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} self-tests failed; don't trust this build with real data",
+            failed,
+            checks.len()
+        );
+    }
+    println!("✓ All {} self-tests passed", checks.len());
+    Ok(())
+}
+
+/// Check the database structure
+fn cmd_fsck(db: ClipboardDatabase, quarantine: bool) -> Result<()> {
+    let report = db.fsck(quarantine)?;
+
+    println!(
+        "🔍 Checked {} entries in {}",
+        report.entries,
+        db.path().display()
+    );
+    println!();
+
+    if report.is_clean() {
+        println!("✓ No problems found");
+        return Ok(());
+    }
+
+    for problem in &report.meta_problems {
+        println!("⚠ Metadata: {}", problem);
+    }
+    for key in &report.unreadable {
+        println!("⚠ Unreadable entry: {}", key);
+    }
+    for key in &report.mismatched {
+        println!("⚠ Entry stored under the wrong key: {}", key);
+    }
+    for id in &report.broken_deltas {
+        println!("⚠ Delta entry without its base entry: {}", id);
+    }
+    if report.unindexed > 0 {
+        println!(
+            "⚠ Entries missing from the timestamp index: {}",
+            report.unindexed
+        );
+    }
+    if report.dangling_index > 0 {
+        println!("⚠ Stale timestamp index keys: {}", report.dangling_index);
+    }
+    for (name, index) in [("hash", report.hash_index), ("delta", report.delta_index)] {
+        if index.missing > 0 {
+            println!(
+                "⚠ Entries missing from the {} index: {}",
+                name, index.missing
+            );
+        }
+        if index.dangling > 0 {
+            println!("⚠ Stale {} index keys: {}", name, index.dangling);
+        }
+    }
+    println!();
+
+    if quarantine {
+        println!(
+            "✓ Quarantined {} entries and rebuilt the indexes",
+            report.quarantined
+        );
+    } else {
+        println!(
+            "💡 Run 'clpd fsck --quarantine' to set bad entries aside and rebuild the indexes."
+        );
+    }
+
+    Ok(())
+}
+
+/// Start or verify the audit log
+fn cmd_audit(db: ClipboardDatabase, enable: bool) -> Result<()> {
+    if !db.is_initialized()? {
+        anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    }
+
+    if enable {
+        let baseline = db.enable_audit_log()?;
+        println!(
+            "✓ Audit log started at {} with {} existing entries",
+            db.audit_log_path().display(),
+            baseline
+        );
+        return Ok(());
+    }
+
+    let check = db.audit()?;
+    println!(
+        "🔍 Checked {} audit records in {}",
+        check.log.records,
+        db.audit_log_path().display()
+    );
+    println!();
+
+    if check.is_clean() {
+        println!(
+            "✓ The audit log is intact and matches all {} entries",
+            check.log.live.len()
+        );
+        return Ok(());
+    }
+
+    if let Some((line, reason)) = &check.log.broken {
+        println!("⚠ Chain broken at line {}: {}", line, reason);
+    }
+    for id in &check.missing {
+        println!("⚠ Entry removed without being logged: {}", id);
+    }
+    for id in &check.unlogged {
+        println!("⚠ Entry added without being logged: {}", id);
+    }
+    for id in &check.changed {
+        println!("⚠ Entry content changed since it was logged: {}", id);
+    }
+    println!();
+
+    anyhow::bail!("The audit found signs of tampering")
+}
+
+/// List the clipboard's current formats and what clpd would capture from them
+fn cmd_formats() -> Result<()> {
+    // Still worth showing what arboard reads when the formats can't be listed
+    match watcher::clipboard_formats() {
+        Ok(formats) if formats.is_empty() => println!("📋 The clipboard is empty."),
+        Ok(formats) => {
+            println!("📋 Clipboard formats ({}):", formats.len());
+            for format in &formats {
+                println!("  {}", format);
+            }
+        }
+        Err(e) => println!("⚠ Couldn't list clipboard formats: {:#}", e),
+    }
+    println!();
+
+    // The same reads the watcher does, in the same order
+    let mut clipboard = Clipboard::new().context("Failed to access clipboard")?;
+    match (clipboard.get_text(), clipboard.get_image()) {
+        (Ok(text), _) if !text.is_empty() => {
+            println!("✓ clpd would capture text ({} chars)", text.chars().count())
+        }
+        (_, Ok(image)) => println!(
+            "✓ clpd would capture an image ({}x{})",
+            image.width, image.height
+        ),
+        (_, Err(e)) => println!("✗ clpd would capture nothing: {}", e),
+    }
+
+    Ok(())
+}
+
+/// Print the fingerprint of the derived key
+fn cmd_fingerprint(db: ClipboardDatabase) -> Result<()> {
+    let key = unlock(&db)?;
+
+    println!("🔑 Key fingerprint: {}", key.fingerprint());
+    println!();
+    println!("💡 Machines showing the same fingerprint can decrypt each other's entries.");
+
+    Ok(())
+}
+
+/// Prompt for the master password and derive the verified key
+///
+/// A key cached by `clpd unlock` is used instead, if there is one.
+fn unlock(db: &ClipboardDatabase) -> Result<MasterKey> {
+    // Check if initialized
+    if !db.is_initialized()? {
+        anyhow::bail!("Database not initialized. Run 'clpd init' first.");
+    }
+
+    if let Some(key) = SESSION_KEY.lock().clone()
+        && db.verify_password(&key)?
+    {
+        return Ok(key);
+    }
+
+    let salt = db.get_salt()?;
+    if let Some(key) = keycache::load(&salt)
+        && db.verify_password(&key)?
+    {
+        return Ok(key);
+    }
+
+    // Get password
+    let password = rpassword::prompt_password("Enter master password: ")?;
+
+    // Derive key
+    let key = MasterKey::from_password(&password, &salt, db.kdf_params()?)?;
+
+    // Verify password
+    if !db.verify_password(&key)? {
+        anyhow::bail!("❌ Incorrect password!");
+    }
+
+    Ok(key)
+}
+
+/// Cache the key in the OS keyring for a while
+fn cmd_unlock(db: ClipboardDatabase, ttl_minutes: u64) -> Result<()> {
+    let key = unlock(&db)?;
+    let ttl = Duration::from_secs(ttl_minutes.saturating_mul(60));
+    keycache::store(&db.get_salt()?, &key, ttl)?;
+
+    println!("🔓 Key cached for {} minutes.", ttl_minutes);
+    println!("💡 Run 'clpd lock' to forget it sooner.");
+
+    Ok(())
+}
+
+/// Remove the cached key from the OS keyring
+fn cmd_lock(db: ClipboardDatabase) -> Result<()> {
+    if keycache::clear(&db.get_salt()?)? {
+        println!("🔒 Cached key removed.");
+    } else {
+        println!("No cached key.");
+    }
+
+    Ok(())
+}
+
+/// Browse clipboard history while a background thread keeps capturing
+async fn cmd_browse_watch(
+    db: ClipboardDatabase,
+    key: MasterKey,
+    options: TuiOptions,
+) -> Result<()> {
+    let db: SharedDatabase = Arc::new(RwLock::new(db));
+    // Lets the watcher recognize entries copied from the TUI
+    let self_writes = SelfWrites::default();
+
+    let watcher = LocalClipboardWatcher::shared(db.clone(), key.clone(), None)?
+        .with_self_writes(self_writes.clone());
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let stop = stop.clone();
+        std::thread::spawn(move || watcher.watch_until(&stop))
+    };
+
+    let browser =
+        LocalClipboardWatcher::shared(db, key.clone(), None)?.with_self_writes(self_writes);
+    let result = cmd_browse(ClipboardType::Local(Box::new(browser)), key, options).await;
+
+    // Stop the watcher before returning, so the database is closed cleanly
+    stop.store(true, Ordering::Relaxed);
+    if handle.join().is_err() {
+        eprintln!("⚠ Warning: Clipboard watcher thread panicked");
+    }
+
+    result
+}
+
 /// Browse clipboard history with interactive TUI
-async fn cmd_browse(db: ClipboardType, key: MasterKey) -> Result<()> {
+async fn cmd_browse(db: ClipboardType, key: MasterKey, options: TuiOptions) -> Result<()> {
     // Check if initialized
     // if !db.is_initialized().await? {
     //     anyhow::bail!("Database not initialized. Run 'clpd init' first.");
@@ -765,7 +2493,7 @@ async fn cmd_browse(db: ClipboardType, key: MasterKey) -> Result<()> {
     // }
 
     // Run TUI
-    tui::run(db, key).await?;
+    tui::run(db, key, options).await?;
 
     Ok(())
 }
@@ -796,20 +2524,14 @@ fn cmd_install() -> Result<()> {
     println!();
 
     // Copy the binary
-    if target_path.exists() {
-        print!(
-            "⚠️  clpd is already installed at {}. Overwrite? (y/N): ",
+    if target_path.exists()
+        && !confirm(&format!(
+            "⚠️  clpd is already installed at {}. Overwrite?",
             target_path.display()
-        );
-        io::stdout().flush()?;
-
-        let mut response = String::new();
-        io::stdin().read_line(&mut response)?;
-
-        if !response.trim().eq_ignore_ascii_case("y") {
-            println!("Installation cancelled.");
-            return Ok(());
-        }
+        ))?
+    {
+        println!("Installation cancelled.");
+        return Ok(());
     }
 
     fs::copy(&current_exe, &target_path)
@@ -937,3 +2659,201 @@ fn cmd_install() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ClipboardEntry;
+    use crate::testutil::test_key;
+    use crate::watcher::FakeClipboard;
+    use base64::Engine;
+
+    const INVALID_UTF8: &[u8] = b"caf\xe9 \xff!";
+
+    /// A database holding one text entry with `bytes`, unlocked as in `clpd shell`
+    fn db_with_text(dir: &tempfile::TempDir, bytes: &[u8]) -> (ClipboardDatabase, String) {
+        let key = test_key();
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        db.initialize(&[7; 16], &crypto::encrypt(&key, b"clpd_test").unwrap())
+            .unwrap();
+        let entry = ClipboardEntry::new(
+            ClipboardContentType::Text,
+            crypto::encrypt(&key, bytes).unwrap(),
+            LocalClipboardWatcher::hash_data(bytes),
+        );
+        db.insert_entry(&entry).unwrap();
+        *SESSION_KEY.lock() = Some(key);
+        (db, entry.id)
+    }
+
+    fn writer(clipboard: FakeClipboard) -> ClipboardWriter<FakeClipboard> {
+        ClipboardWriter {
+            clipboard,
+            writes: None,
+        }
+    }
+
+    #[test]
+    fn test_show_invalid_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, id) = db_with_text(&dir, INVALID_UTF8);
+        let mut out = Vec::new();
+        cmd_show(db, &id, ShowFormat::Pretty, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\ncaf\u{fffd} \u{fffd}!\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_show_base64_keeps_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, id) = db_with_text(&dir, INVALID_UTF8);
+        let mut out = Vec::new();
+        cmd_show(db, &id, ShowFormat::Base64, &mut out).unwrap();
+        let encoded = String::from_utf8(out).unwrap();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim_end())
+            .unwrap();
+        assert_eq!(decoded, INVALID_UTF8);
+    }
+
+    #[test]
+    fn test_copy_invalid_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, id) = db_with_text(&dir, INVALID_UTF8);
+
+        // Copied byte for byte where the clipboard takes bytes...
+        let mut clipboard = writer(FakeClipboard::taking_bytes());
+        cmd_copy(db, &id, CopyTarget::default(), &mut clipboard).unwrap();
+        assert_eq!(clipboard.clipboard.raw_text(), Some(INVALID_UTF8));
+
+        // ...and refused where it only takes strings, rather than altered
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        let mut clipboard = writer(FakeClipboard::default());
+        let err = cmd_copy(db, &id, CopyTarget::default(), &mut clipboard).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid UTF-8 (at byte 3)"),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            clipboard.clipboard.get_text(ClipboardSelection::Clipboard),
+            Err(arboard::Error::ContentNotAvailable)
+        ));
+    }
+
+    #[test]
+    fn test_search_copies_exact_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, _) = db_with_text(&dir, INVALID_UTF8);
+        let search = TextSearch {
+            query: "caf".to_string(),
+            ..TextSearch::default()
+        };
+
+        // One match is copied like `copy` would
+        let mut clipboard = writer(FakeClipboard::taking_bytes());
+        cmd_search(db, &search, Some(&mut clipboard), false).unwrap();
+        assert_eq!(clipboard.clipboard.raw_text(), Some(INVALID_UTF8));
+
+        // Several are joined byte for byte
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        let key = test_key();
+        db.insert_entry(&ClipboardEntry::new(
+            ClipboardContentType::Text,
+            crypto::encrypt(&key, b"cafe").unwrap(),
+            LocalClipboardWatcher::hash_data(b"cafe"),
+        ))
+        .unwrap();
+        let mut clipboard = writer(FakeClipboard::taking_bytes());
+        cmd_search(db, &search, Some(&mut clipboard), false).unwrap();
+        assert_eq!(
+            clipboard.clipboard.raw_text(),
+            Some([b"cafe\n".as_slice(), INVALID_UTF8].concat().as_slice())
+        );
+    }
+
+    /// The content column of the CSV `dump` writes for `texts`, header first
+    fn dumped_content(texts: &[&[u8]], content: DumpContent) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, _) = db_with_text(&dir, texts[0]);
+        let key = test_key();
+        for text in &texts[1..] {
+            db.insert_entry(&ClipboardEntry::new(
+                ClipboardContentType::Text,
+                crypto::encrypt(&key, text).unwrap(),
+                LocalClipboardWatcher::hash_data(text),
+            ))
+            .unwrap();
+        }
+        let out = dir.path().join("out");
+        cmd_dump(
+            db,
+            out.clone(),
+            true,
+            None,
+            None,
+            TypeFilter::default(),
+            content,
+        )
+        .unwrap();
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(out.join("clipboard_text_entries.csv"))
+            .unwrap();
+        let mut column: Vec<String> = reader
+            .records()
+            .map(|record| record.unwrap()[2].to_string())
+            .collect();
+        // Newest first in the dump, so in the order of `texts` after the header
+        column[1..].reverse();
+        column
+    }
+
+    #[test]
+    fn test_dump_redacts_content() {
+        let texts: [&[u8]; 3] = [
+            b"password: hunter2",
+            b"mail me at a@b.example, c@d.example",
+            b"nothing here",
+        ];
+
+        assert_eq!(
+            dumped_content(&texts, DumpContent::default()),
+            [
+                "Content",
+                "password: hunter2",
+                "mail me at a@b.example, c@d.example",
+                "nothing here"
+            ]
+        );
+
+        let redacted = DumpContent {
+            redact: vec![
+                regex::Regex::new(r"(?i)password: \S+").unwrap(),
+                regex::Regex::new(r"\S+@\S+\.example").unwrap(),
+            ],
+            hash_content: false,
+        };
+        assert_eq!(
+            dumped_content(&texts, redacted),
+            ["Content", "***", "mail me at ***, ***", "nothing here"]
+        );
+    }
+
+    #[test]
+    fn test_dump_hashes_content() {
+        let hashed = DumpContent {
+            redact: Vec::new(),
+            hash_content: true,
+        };
+        assert_eq!(
+            dumped_content(&[b"secret", b"caf\xe9"], hashed),
+            [
+                "Content SHA-256",
+                "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+                &LocalClipboardWatcher::hash_data(b"caf\xe9"),
+            ]
+        );
+    }
+}
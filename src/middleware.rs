@@ -3,6 +3,7 @@ use actix_web::HttpResponse;
 use actix_web::dev::ServiceRequest;
 use actix_web::dev::ServiceResponse;
 use actix_web::dev::Transform;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
 use actix_web::{
     Error,
     dev::{Service, forward_ready},
@@ -11,10 +12,55 @@ use futures::future::LocalBoxFuture;
 use futures::future::Ready;
 use futures::future::ready;
 
+use crate::crypto::MasterKey;
+
+/// Endpoints clients need before they have a key, to derive it from the password
+const PUBLIC_PATHS: &[&str] = &["/clipboard/salt", "/clipboard/kdf"];
+
+/// The bearer token clients of the network server authenticate with
+///
+/// Derived from the master key ([`MasterKey::auth_token`]): `net-listen` unlocks
+/// the database at startup to get it, and clients derive the same token from the
+/// key they get from the password.
+#[derive(Clone)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    pub fn for_key(key: &MasterKey) -> Self {
+        Self(key.auth_token())
+    }
+
+    /// The `Authorization` header value clients send
+    pub fn header_value(&self) -> String {
+        format!("Bearer {}", self.0)
+    }
+
+    /// Whether an `Authorization` header value carries this token, compared in
+    /// constant time
+    fn matches(&self, header: &[u8]) -> bool {
+        let expected = self.header_value();
+        let expected = expected.as_bytes();
+        header.len() == expected.len()
+            && header
+                .iter()
+                .zip(expected)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
 /// Middleware to check if the user is authorized to access the resource
-/// by checking the JWT token in the Authorization header.
+/// by checking the bearer token in the Authorization header.
 #[derive(Clone)]
-pub struct CheckAuthorization;
+pub struct CheckAuthorization {
+    token: AuthToken,
+}
+
+impl CheckAuthorization {
+    pub fn new(token: AuthToken) -> Self {
+        Self { token }
+    }
+}
 
 impl<S> Transform<S, ServiceRequest> for CheckAuthorization
 where
@@ -28,12 +74,16 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(CheckAuthorizationMiddleware { service }))
+        ready(Ok(CheckAuthorizationMiddleware {
+            service,
+            token: self.token.clone(),
+        }))
     }
 }
 
 pub struct CheckAuthorizationMiddleware<S> {
     service: S,
+    token: AuthToken,
 }
 
 impl<S> Service<ServiceRequest> for CheckAuthorizationMiddleware<S>
@@ -48,23 +98,21 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let (http_req, payload) = req.into_parts();
-        if let Some(auth_header) = http_req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                // right now we are just checking if the token is valid,
-                // there are currently no claims that we are checking for
-                // if let Ok(_jwt_claims) = SERVER_SIGNING_KEY.verify_jwt(auth_str) {
-                let fut = self
-                    .service
-                    .call(ServiceRequest::from_parts(http_req, payload));
-                return Box::pin(async move {
-                    let res = fut.await?;
-                    Ok(res)
-                });
-                // }
-            }
+        let authorized = PUBLIC_PATHS.contains(&req.path())
+            || req
+                .headers()
+                .get(AUTHORIZATION)
+                .is_some_and(|header| self.token.matches(header.as_bytes()));
+        if authorized {
+            let fut = self.service.call(req);
+            return Box::pin(async move {
+                let res = fut.await?;
+                Ok(res)
+            });
         }
+        let (http_req, _) = req.into_parts();
         let res = HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
             .body("The user attempting to access this resource is not authorized");
         Box::pin(async move {
             actix_web::Result::<ServiceResponse<BoxBody>>::Ok(ServiceResponse::new(http_req, res))
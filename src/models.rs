@@ -1,8 +1,17 @@
-use crate::crypto::compress;
+use crate::crypto::{ENCRYPTION_OVERHEAD, MasterKey, compress, decrypt, encrypt};
+use anyhow::Context;
 use base64::{Engine as _, engine::general_purpose};
+use bincode::Options;
 use chrono::{DateTime, Utc};
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ExtendedColorType, ImageEncoder};
+use serde::de::{self, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
 
 /// Type of clipboard content
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -11,12 +20,190 @@ pub enum ClipboardContentType {
     Image,
 }
 
+/// The X11/Wayland selection an entry was captured from
+///
+/// Only Linux has more than one, entries captured elsewhere don't record it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ClipboardSelection {
+    /// The clipboard of explicit copy and paste (CLIPBOARD)
+    Clipboard,
+    /// Selected text, pasted with the middle mouse button (PRIMARY)
+    Primary,
+}
+
+impl ClipboardSelection {
+    /// What content is copied to, for messages like "Text copied to clipboard"
+    pub fn target(self) -> &'static str {
+        match self {
+            Self::Clipboard => "clipboard",
+            Self::Primary => "primary selection",
+        }
+    }
+}
+
+/// Default cap on the raw RGBA size of an accepted image (256 MiB)
+pub const DEFAULT_MAX_IMAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Check that an image of the given dimensions fits in `max_bytes` of RGBA data
+/// and that its byte count matches the dimensions
+///
+/// Dimensions are reported by the clipboard (or a remote client) and may be
+/// bogus, so this is done before copying or indexing into the pixel data.
+pub fn check_image_size(
+    width: usize,
+    height: usize,
+    len: usize,
+    max_bytes: usize,
+) -> Result<(), String> {
+    if width == 0 || height == 0 {
+        return Err(format!("image {}x{} has no pixels", width, height));
+    }
+    let expected = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .ok_or_else(|| format!("image dimensions {}x{} are too large", width, height))?;
+    if expected > max_bytes {
+        return Err(format!(
+            "image {}x{} needs {} bytes, over the limit of {} bytes",
+            width, height, expected, max_bytes
+        ));
+    }
+    if len != expected {
+        return Err(format!(
+            "image {}x{} has {} bytes of pixel data, expected {}",
+            width, height, len, expected
+        ));
+    }
+    Ok(())
+}
+
+/// Pixel data with the padding at the end of each row removed, for images whose
+/// `height` rows are longer than `width` RGBA pixels (some applications pad rows
+/// to an alignment); `None` if the bytes aren't laid out that way
+pub fn strip_row_padding(width: usize, height: usize, bytes: &[u8]) -> Option<Vec<u8>> {
+    let row = width.checked_mul(4)?;
+    if row == 0 || height == 0 || !bytes.len().is_multiple_of(height) {
+        return None;
+    }
+    let stride = bytes.len() / height;
+    if stride <= row {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(stride)
+            .flat_map(|line| &line[..row])
+            .copied()
+            .collect(),
+    )
+}
+
+/// How the bytes of an `ImageData` are laid out
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum ImageEncoding {
+    /// Raw RGBA pixels, as handed out by the clipboard
+    #[default]
+    Rgba,
+    /// A PNG file, usually many times smaller than the raw pixels
+    Png,
+}
+
+impl ImageEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rgba => "raw RGBA",
+            Self::Png => "PNG",
+        }
+    }
+}
+
 /// Image metadata and data for clipboard storage
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Like `ClipboardEntry`, this is stored with `bincode`; `encoding` was appended later
+/// and images stored before it existed are raw RGBA.
+#[derive(Debug, Clone, Serialize)]
 pub struct ImageData {
     pub width: usize,
     pub height: usize,
-    pub bytes: Vec<u8>, // RGBA bytes
+    pub bytes: Vec<u8>, // RGBA bytes, or encoded as given by `encoding`
+    pub encoding: ImageEncoding,
+}
+
+/// Reads structs stored with `bincode::serialize` that have had fields appended
+///
+/// bincode doesn't record how many fields a struct has, so a value written before a
+/// field was appended can only be told apart by where its bytes end. This sequence
+/// ends there: [`appended`] fields that follow get their defaults, while bytes that
+/// don't decode are still an error.
+struct StoredFields<'a, 'b>(&'a mut &'b [u8]);
+
+impl<'de> SeqAccess<'de> for StoredFields<'_, '_> {
+    type Error = bincode::Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> bincode::Result<Option<T::Value>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        // The options `bincode::serialize` and `bincode::deserialize` use; the limit
+        // stops a corrupt length from allocating more than there are bytes left
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.0.len() as u64);
+        let mut deserializer = bincode::Deserializer::with_reader(&mut *self.0, options);
+        seed.deserialize(&mut deserializer).map(Some)
+    }
+}
+
+/// Read `bytes` written by `bincode::serialize` with `visitor`, whose appended
+/// fields are read with [`appended`]
+fn from_stored<'de, V: Visitor<'de>>(bytes: &[u8], visitor: V) -> bincode::Result<V::Value> {
+    let mut bytes = bytes;
+    visitor.visit_seq(StoredFields(&mut bytes))
+}
+
+/// The next field, or its default if the value ends before it
+fn appended<'de, T, A>(seq: &mut A) -> Result<T, A::Error>
+where
+    T: Deserialize<'de> + Default,
+    A: SeqAccess<'de>,
+{
+    Ok(seq.next_element()?.unwrap_or_default())
+}
+
+struct ImageVisitor;
+
+impl<'de> Visitor<'de> for ImageVisitor {
+    type Value = ImageData;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("image data")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let width = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let height = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let bytes = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+
+        // Appended fields: older images end here
+        let encoding = appended(&mut seq)?;
+
+        Ok(ImageData {
+            width,
+            height,
+            bytes,
+            encoding,
+        })
+    }
 }
 
 impl ImageData {
@@ -25,25 +212,356 @@ impl ImageData {
             width,
             height,
             bytes,
+            encoding: ImageEncoding::Rgba,
+        }
+    }
+
+    /// Bytes that identify the image for deduplication
+    ///
+    /// This is the serialized form images had before they could be encoded, so that hashes
+    /// of newly captured images still match those of images stored by older versions.
+    pub fn hash_input(&self) -> Vec<u8> {
+        bincode::serialize(&(self.width, self.height, &self.bytes))
+            .expect("serializing to memory can't fail")
+    }
+
+    /// Like [`Self::hash_input`], but with an alpha channel that holds the same
+    /// value for every pixel made fully opaque
+    ///
+    /// Screenshots fill unused alpha with 0 or 255 depending on the tool, and look
+    /// the same either way. Alpha that varies is real transparency and is kept.
+    pub fn canonical_hash_input(&self) -> Vec<u8> {
+        let mut alpha = self.bytes.chunks_exact(4).map(|pixel| pixel[3]);
+        let uniform = alpha.next().is_some_and(|first| alpha.all(|a| a == first));
+        if self.encoding != ImageEncoding::Rgba || !uniform {
+            return self.hash_input();
+        }
+        let mut opaque = self.clone();
+        for pixel in opaque.bytes.chunks_exact_mut(4) {
+            pixel[3] = 255;
+        }
+        opaque.hash_input()
+    }
+
+    /// Serialize for storage, PNG-encoding raw pixels when possible, and the encoding
+    /// that was stored
+    ///
+    /// The clipboard only hands out raw RGBA pixels, whatever format the application
+    /// copying the image offered, so there's no original encoding to keep. PNG is
+    /// lossless: the pixels are decoded back exactly.
+    pub fn to_plaintext(&self) -> anyhow::Result<(Vec<u8>, ImageEncoding)> {
+        let png = self.to_png();
+        let image = png.as_ref().unwrap_or(self);
+        Ok((bincode::serialize(image)?, image.encoding))
+    }
+
+    /// Deserialize an image from a decrypted payload, decoding it to raw RGBA
+    pub fn from_plaintext(plaintext: &[u8]) -> anyhow::Result<Self> {
+        let image = from_stored(plaintext, ImageVisitor)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize image data: {}", e))?;
+        image.into_rgba()
+    }
+
+    /// Encode raw RGBA pixels as PNG for compact storage
+    pub fn to_png(&self) -> anyhow::Result<Self> {
+        if self.encoding == ImageEncoding::Png {
+            return Ok(self.clone());
+        }
+
+        let mut png = Vec::new();
+        self.write_png(&mut png)?;
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            bytes: png,
+            encoding: ImageEncoding::Png,
+        })
+    }
+
+    /// Write the image as a PNG file, encoding raw pixels as it goes
+    pub fn write_png<W: Write>(&self, mut out: W) -> anyhow::Result<()> {
+        if self.encoding == ImageEncoding::Png {
+            out.write_all(&self.bytes)?;
+            return Ok(());
+        }
+
+        let width = u32::try_from(self.width)?;
+        let height = u32::try_from(self.height)?;
+        PngEncoder::new_with_quality(out, CompressionType::Fast, FilterType::Adaptive)
+            .write_image(&self.bytes, width, height, ExtendedColorType::Rgba8)?;
+        Ok(())
+    }
+
+    /// Decode to raw RGBA pixels, which is what the clipboard and previews need
+    pub fn into_rgba(self) -> anyhow::Result<Self> {
+        match self.encoding {
+            ImageEncoding::Rgba => Ok(self),
+            ImageEncoding::Png => {
+                let decoded =
+                    image::load_from_memory_with_format(&self.bytes, image::ImageFormat::Png)?
+                        .into_rgba8();
+                if decoded.width() as usize != self.width
+                    || decoded.height() as usize != self.height
+                {
+                    anyhow::bail!("Decoded image doesn't match its recorded dimensions");
+                }
+                Ok(Self::new(self.width, self.height, decoded.into_raw()))
+            }
+        }
+    }
+
+    /// Exchange the red and blue channels of raw pixels, for images that were handed
+    /// out as BGRA although labelled RGBA
+    ///
+    /// Encoded images are left alone, their channel order is part of the encoding.
+    pub fn swap_red_blue(&mut self) {
+        if self.encoding != ImageEncoding::Rgba {
+            return;
+        }
+        for pixel in self.bytes.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    /// Whether the pixel data matches the dimensions, so it can be indexed safely
+    pub fn is_well_formed(&self) -> bool {
+        self.encoding == ImageEncoding::Rgba
+            && check_image_size(self.width, self.height, self.bytes.len(), usize::MAX).is_ok()
+    }
+
+    /// Fail with a description of the problem unless the image [is well formed](Self::is_well_formed)
+    ///
+    /// Checked before pixels are handed to the clipboard or an encoder, which would
+    /// otherwise reject them with an unhelpful error or produce a garbled image.
+    pub fn check_well_formed(&self) -> anyhow::Result<()> {
+        if !self.is_well_formed() {
+            anyhow::bail!(
+                "Image data doesn't match its dimensions ({}x{}, {} bytes)",
+                self.width,
+                self.height,
+                self.bytes.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// A copy scaled down to fit within `max_side` pixels, keeping the aspect ratio
+    ///
+    /// Images that already fit are returned as they are, decoded to raw RGBA.
+    pub fn thumbnail(&self, max_side: usize) -> anyhow::Result<Self> {
+        let image = self.clone().into_rgba()?;
+        if image.width <= max_side && image.height <= max_side {
+            return Ok(image);
+        }
+        let scale = max_side as f64 / image.width.max(image.height) as f64;
+        let scaled = |side: usize| ((side as f64 * scale).round() as u32).max(1);
+        let (width, height) = (scaled(image.width), scaled(image.height));
+        let resized = image::imageops::resize(
+            &image.into_rgba_image()?,
+            width,
+            height,
+            image::imageops::FilterType::Triangle,
+        );
+        Ok(Self::new(
+            width as usize,
+            height as usize,
+            resized.into_raw(),
+        ))
+    }
+
+    /// Convert to an `image` buffer for saving to a file
+    pub fn into_rgba_image(self) -> anyhow::Result<image::RgbaImage> {
+        self.check_well_formed()?;
+        let width = u32::try_from(self.width)?;
+        let height = u32::try_from(self.height)?;
+        image::RgbaImage::from_raw(width, height, self.bytes)
+            .ok_or_else(|| anyhow::anyhow!("Failed to create image from data"))
+    }
+
+    /// Cleartext metadata describing this image
+    pub fn metadata(&self) -> ImageMetadata {
+        ImageMetadata {
+            width: self.width,
+            height: self.height,
+            size: match self.encoding {
+                ImageEncoding::Rgba => self.bytes.len(),
+                ImageEncoding::Png => self.width.saturating_mul(self.height).saturating_mul(4),
+            },
         }
     }
 }
 
+/// Cleartext image metadata, so image entries can be described without decrypting them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct ImageMetadata {
+    pub width: usize,
+    pub height: usize,
+    pub size: usize, // raw RGBA byte count
+}
+
 /// A clipboard entry stored in the database
 /// The payload field contains: nonce || encrypted data
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Entries are stored with `bincode`, which isn't self-describing. Fields added after the
+/// original five must be appended at the end and read with `appended`; entries written
+/// by older versions simply lack them and they fall back to their defaults.
+#[derive(Debug, Clone, Serialize)]
 pub struct ClipboardEntry {
     pub id: String,
     pub timestamp: DateTime<Utc>,
     pub content_type: ClipboardContentType,
-    pub payload: Vec<u8>, // encrypted: nonce || ciphertext
-    pub hash: String,     // SHA-256 hash of plaintext for deduplication
+    pub payload: Vec<u8>,                      // encrypted: nonce || ciphertext
+    pub hash: String,                          // SHA-256 hash of plaintext for deduplication
+    pub image: Option<ImageMetadata>,          // only set for image entries
+    pub delta_base: Option<String>, // set when the payload is a TextDelta against this entry
+    pub per_entry_key: bool,        // payload is encrypted under a subkey derived from the ID
+    pub dedupe_hash: Option<String>, // hash of the normalized text, when it differs from `hash`
+    pub selection: Option<ClipboardSelection>, // where it was captured from (Linux)
+    pub content_size: Option<u64>,  // plaintext bytes before delta encoding, for text
+    pub text_flavor: Option<String>, // application-specific plain-text format it was offered in
+    pub pinned: bool,               // kept by pruning (`clpd pin`)
+    pub original_image: Option<ImageMetadata>, // set when only a thumbnail of this image is stored
+    pub image_encoding: Option<ImageEncoding>, // how the image payload is stored, unknown for older entries
+}
+
+/// Hands out entry timestamps that never go backward
+///
+/// When the system clock is set back (by NTP, a manual change or a dead RTC
+/// battery), new entries would otherwise sort before the ones just captured.
+/// Instead they're stamped one millisecond after the latest timestamp seen, until
+/// the clock catches up again.
+pub struct MonotonicClock {
+    last_millis: AtomicI64,
+}
+
+impl MonotonicClock {
+    pub const fn new() -> Self {
+        Self {
+            last_millis: AtomicI64::new(i64::MIN),
+        }
+    }
+
+    /// Timestamp for an entry captured at `now`
+    pub fn stamp(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let now_millis = now.timestamp_millis();
+        let previous = self
+            .last_millis
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(if now_millis < last {
+                    last + 1
+                } else {
+                    now_millis
+                })
+            })
+            .unwrap_or(i64::MIN);
+        if now_millis < previous {
+            DateTime::from_timestamp_millis(previous + 1).unwrap_or(now)
+        } else {
+            now
+        }
+    }
+
+    /// Never hand out timestamps before `timestamp`, e.g. the newest stored entry's
+    pub fn observe(&self, timestamp: &DateTime<Utc>) {
+        self.last_millis
+            .fetch_max(timestamp.timestamp_millis(), Ordering::SeqCst);
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts IDs issued by this process, so that entries created within the same
+/// millisecond still sort in creation order
+static ID_SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// A new entry ID: the timestamp in milliseconds, then a five digit sequence
+/// number and five random digits (against collisions between processes)
+///
+/// IDs issued by one process sort in creation order, as do their timestamps, even
+/// if the system clock goes back once stamped by a [`MonotonicClock`] (see
+/// [`ClipboardEntry::stamp`]).
+fn new_id(timestamp: &DateTime<Utc>) -> String {
+    let sequence = ID_SEQUENCE.fetch_add(1, Ordering::Relaxed) % 100_000;
+    format!(
+        "{}-{:05}{:05}",
+        timestamp.timestamp_millis(),
+        sequence,
+        rand::random::<u32>() % 100_000
+    )
+}
+
+struct EntryVisitor;
+
+impl<'de> Visitor<'de> for EntryVisitor {
+    type Value = ClipboardEntry;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a clipboard entry")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let id = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let timestamp = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let content_type = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let payload = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        let hash = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(4, &self))?;
+
+        // Appended fields: older entries end here
+        let image = appended(&mut seq)?;
+        let delta_base = appended(&mut seq)?;
+        let per_entry_key = appended(&mut seq)?;
+        let dedupe_hash = appended(&mut seq)?;
+        let selection = appended(&mut seq)?;
+        let content_size = appended(&mut seq)?;
+        let text_flavor = appended(&mut seq)?;
+        let pinned = appended(&mut seq)?;
+        let original_image = appended(&mut seq)?;
+        let image_encoding = appended(&mut seq)?;
+
+        Ok(ClipboardEntry {
+            id,
+            timestamp,
+            content_type,
+            payload,
+            hash,
+            image,
+            delta_base,
+            per_entry_key,
+            dedupe_hash,
+            selection,
+            content_size,
+            text_flavor,
+            pinned,
+            original_image,
+            image_encoding,
+        })
+    }
 }
 
 impl ClipboardEntry {
+    /// Read an entry as stored in the database, written by `bincode::serialize`
+    pub fn from_stored(bytes: &[u8]) -> bincode::Result<Self> {
+        from_stored(bytes, EntryVisitor)
+    }
+
     pub fn new(content_type: ClipboardContentType, payload: Vec<u8>, hash: String) -> Self {
         let timestamp = Utc::now();
-        let id = format!("{}-{}", timestamp.timestamp_millis(), rand::random::<u32>());
+        let id = new_id(&timestamp);
 
         Self {
             id,
@@ -51,19 +569,146 @@ impl ClipboardEntry {
             content_type,
             payload,
             hash,
+            image: None,
+            delta_base: None,
+            per_entry_key: false,
+            dedupe_hash: None,
+            selection: None,
+            content_size: None,
+            text_flavor: None,
+            pinned: false,
+            original_image: None,
+            image_encoding: None,
+        }
+    }
+
+    /// Ordering for listings: newest first, entries with the same timestamp by ID
+    ///
+    /// The same order as the timestamp index, so it never changes between runs.
+    pub fn newest_first(a: &ClipboardEntry, b: &ClipboardEntry) -> std::cmp::Ordering {
+        let millis = |entry: &ClipboardEntry| entry.timestamp.timestamp_millis();
+        millis(b).cmp(&millis(a)).then_with(|| b.id.cmp(&a.id))
+    }
+
+    /// Size of the content before it was compressed and encrypted (raw RGBA for
+    /// images), and whether that's known exactly
+    ///
+    /// Text entries stored before sizes were recorded only have an estimate, from
+    /// the encrypted payload, which for delta entries is just the changed part.
+    pub fn content_size(&self) -> (usize, bool) {
+        if let Some(size) = self.content_size {
+            return (size as usize, true);
+        }
+        if let Some(image) = &self.image {
+            return (image.size, true);
+        }
+        let stored = self.payload.len().saturating_sub(ENCRYPTION_OVERHEAD);
+        (stored, false)
+    }
+
+    /// Create an image entry, recording the image's cleartext metadata
+    pub fn new_image(payload: Vec<u8>, hash: String, image: &ImageData) -> Self {
+        let mut entry = Self::new(ClipboardContentType::Image, payload, hash);
+        entry.image = Some(image.metadata());
+        entry
+    }
+
+    /// Re-stamp a new entry from `clock`, e.g. the database's it's captured into,
+    /// so it never sorts before the entries already there
+    pub fn stamp(&mut self, clock: &MonotonicClock) {
+        self.timestamp = clock.stamp(self.timestamp);
+        self.id = new_id(&self.timestamp);
+    }
+
+    /// Short description of the content type, including dimensions for images when known
+    pub fn describe(&self) -> String {
+        match (&self.content_type, self.image) {
+            (ClipboardContentType::Image, Some(image)) if self.original_image.is_some() => {
+                format!("Image {}x{} (thumbnail)", image.width, image.height)
+            }
+            (ClipboardContentType::Image, Some(image)) => {
+                format!("Image {}x{}", image.width, image.height)
+            }
+            (content_type, _) => format!("{:?}", content_type),
         }
     }
 
+    /// Warning that the entry holds only a thumbnail (`start --images thumbnail`),
+    /// for commands handing out its content
+    pub fn thumbnail_warning(&self) -> Option<String> {
+        let original = self.original_image?;
+        Some(format!(
+            "This is a reduced-quality thumbnail, the original {}x{} image wasn't kept",
+            original.width, original.height
+        ))
+    }
+
     /// Get a preview of the entry for display (just metadata, no decryption)
     pub fn preview(&self) -> String {
         format!(
-            "[{}] {} - {:?}",
+            "[{}] {} - {}",
             self.timestamp.format("%Y-%m-%d %H:%M:%S"),
             self.id,
-            self.content_type
+            self.describe()
         )
     }
 
+    /// The key this entry's payload is encrypted with
+    fn payload_key(&self, key: &MasterKey) -> MasterKey {
+        if self.per_entry_key {
+            key.subkey(format!("clpd-entry:{}", self.id).as_bytes())
+        } else {
+            key.clone()
+        }
+    }
+
+    /// Encrypt `plaintext` as this entry's payload
+    ///
+    /// Set `per_entry_key` first to encrypt under the entry's own subkey.
+    pub fn encrypt_payload(&mut self, key: &MasterKey, plaintext: &[u8]) -> anyhow::Result<()> {
+        self.payload = encrypt(&self.payload_key(key), plaintext)
+            .context("Failed to encrypt clipboard data")?;
+        Ok(())
+    }
+
+    /// Decrypt this entry's payload (for delta entries, the delta itself)
+    pub fn decrypt_payload(&self, key: &MasterKey) -> anyhow::Result<Vec<u8>> {
+        decrypt(&self.payload_key(key), &self.payload).context("Failed to decrypt entry")
+    }
+
+    /// Decrypt the entry's content
+    ///
+    /// Delta entries need their base entry to be reconstructed.
+    pub fn decrypt(
+        &self,
+        key: &MasterKey,
+        base: Option<&ClipboardEntry>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let plaintext = self.decrypt_payload(key)?;
+        let Some(base_id) = &self.delta_base else {
+            return Ok(plaintext);
+        };
+
+        let base = base
+            .filter(|base| base.id == *base_id)
+            .with_context(|| format!("Base entry '{}' of delta entry is missing", base_id))?;
+        let base_plaintext = base
+            .decrypt_payload(key)
+            .context("Failed to decrypt base entry")?;
+        let delta: TextDelta =
+            bincode::deserialize(&plaintext).context("Failed to deserialize text delta")?;
+        delta.apply(&base_plaintext)
+    }
+
+    /// Decrypt the entry and decode it according to its content type
+    pub fn decrypt_content(
+        &self,
+        key: &MasterKey,
+        base: Option<&ClipboardEntry>,
+    ) -> anyhow::Result<DecryptedEntry> {
+        DecryptedEntry::from_plaintext(&self.content_type, self.decrypt(key, base)?)
+    }
+
     pub fn to_compressed_string(&self) -> String {
         let serialized = bincode::serialize(self).expect("Failed to serialize entry");
         let serialized = compress(&serialized);
@@ -73,15 +718,393 @@ impl ClipboardEntry {
     pub fn from_compressed_string(s: &str) -> Result<Self, Box<dyn Error>> {
         let decoded = general_purpose::STANDARD.decode(s)?;
         let decompressed = crate::crypto::decompress(&decoded)?;
-        let entry: ClipboardEntry = bincode::deserialize(&decompressed)?;
+        let entry = ClipboardEntry::from_stored(&decompressed)?;
         Ok(entry)
     }
 }
 
+/// The decrypted content of an entry
+#[derive(Debug, Clone)]
+pub enum DecryptedEntry {
+    /// The bytes as stored, which are only decoded (lossily) for display
+    Text(TextEntry),
+    /// Always raw RGBA, whatever encoding it was stored in
+    Image(ImageData),
+}
+
+/// The decrypted bytes of a text entry, exactly as stored, and whether they're
+/// valid UTF-8
+///
+/// Captured text always is, but an entry damaged on disk or written by another
+/// tool may not be. Displaying such text replaces the invalid bytes; anything
+/// that writes it back (the clipboard, `show --format base64`) uses the bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEntry {
+    bytes: Vec<u8>,
+    valid_utf8: bool,
+}
+
+impl TextEntry {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let valid_utf8 = std::str::from_utf8(&bytes).is_ok();
+        Self { bytes, valid_utf8 }
+    }
+
+    pub fn is_valid_utf8(&self) -> bool {
+        self.valid_utf8
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The text for display, with invalid bytes shown as `�`
+    pub fn lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes)
+    }
+
+    /// The text, failing rather than changing invalid bytes
+    ///
+    /// For clipboards that only take strings (arboard's, and Linux's always): a
+    /// lossy copy would silently differ from what was stored. Windows and macOS
+    /// take the bytes instead, see [`crate::watcher::set_text_entry`].
+    pub fn as_str(&self) -> anyhow::Result<&str> {
+        std::str::from_utf8(&self.bytes).map_err(|e| {
+            anyhow::anyhow!(
+                "Entry contains invalid UTF-8 (at byte {}), which this clipboard can't take \
+                 without changing it. 'clpd print' writes the exact bytes.",
+                e.valid_up_to()
+            )
+        })
+    }
+}
+
+impl From<String> for TextEntry {
+    fn from(text: String) -> Self {
+        Self {
+            bytes: text.into_bytes(),
+            valid_utf8: true,
+        }
+    }
+}
+
+impl From<&str> for TextEntry {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+impl PartialEq<str> for TextEntry {
+    fn eq(&self, other: &str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for TextEntry {
+    fn eq(&self, other: &&str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl DecryptedEntry {
+    pub fn from_plaintext(
+        content_type: &ClipboardContentType,
+        plaintext: Vec<u8>,
+    ) -> anyhow::Result<Self> {
+        match content_type {
+            ClipboardContentType::Text => Ok(Self::Text(TextEntry::from_bytes(plaintext))),
+            ClipboardContentType::Image => Ok(Self::Image(ImageData::from_plaintext(&plaintext)?)),
+        }
+    }
+
+    /// Write the content base64-encoded: text as the bytes stored, images as PNG
+    ///
+    /// The encoding is streamed to `out`, so the encoded form is never held in
+    /// memory as a whole.
+    pub fn write_base64<W: Write>(&self, out: W) -> anyhow::Result<()> {
+        let mut encoder = base64::write::EncoderWriter::new(out, &general_purpose::STANDARD);
+        match self {
+            Self::Text(text) => encoder.write_all(text.as_bytes())?,
+            Self::Image(image) => image.write_png(&mut encoder)?,
+        }
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Single-line summary of the content for listings
+    ///
+    /// Line breaks are shown as `⏎`. At most `max_lines` lines and `max_width`
+    /// characters are kept, with `…` marking anything cut off.
+    pub fn preview_line(&self, max_lines: usize, max_width: usize) -> String {
+        match self {
+            Self::Text(text) => preview_text(&text.lossy(), max_lines, max_width),
+            Self::Image(image) => format!("Image {}x{}", image.width, image.height),
+        }
+    }
+}
+
+/// [`DecryptedEntry::preview_line`] for text that isn't in an entry
+pub fn preview_text(text: &str, max_lines: usize, max_width: usize) -> String {
+    let mut lines = text.lines();
+    let preview = lines
+        .by_ref()
+        .take(max_lines.max(1))
+        .collect::<Vec<_>>()
+        .join("⏎");
+    let truncated = lines.next().is_some();
+
+    if !truncated && preview.chars().count() <= max_width {
+        return preview;
+    }
+    let mut preview: String = preview.chars().take(max_width.saturating_sub(1)).collect();
+    preview.push('…');
+    preview
+}
+
+/// Byte ranges of the occurrences of `query` in `line`, compared character by
+/// character (each lowercased with `ignore_case`), so the ranges are valid in
+/// `line` itself
+pub fn match_ranges(line: &str, query: &str, ignore_case: bool) -> Vec<Range<usize>> {
+    let fold = |c: char| -> Vec<char> {
+        if ignore_case {
+            c.to_lowercase().collect()
+        } else {
+            vec![c]
+        }
+    };
+    let query: Vec<char> = query.chars().flat_map(fold).collect();
+    let mut ranges = Vec::new();
+    if query.is_empty() {
+        return ranges;
+    }
+    let mut from = 0;
+    for (start, _) in line.char_indices() {
+        if start < from {
+            continue;
+        }
+        let mut query_chars = query.iter();
+        let mut end = start;
+        for c in line[start..].chars() {
+            if query_chars.as_slice().is_empty() {
+                break;
+            }
+            if !fold(c)
+                .into_iter()
+                .all(|folded| query_chars.next() == Some(&folded))
+            {
+                break;
+            }
+            end += c.len_utf8();
+        }
+        if query_chars.as_slice().is_empty() {
+            ranges.push(start..end);
+            from = end;
+        }
+    }
+    ranges
+}
+
+/// The text around `found` on a single line (line breaks shown as `⏎`), split
+/// into what comes before, the match itself and what comes after
+///
+/// About a third of `max_width` goes to the text before the match; `…` marks
+/// anything cut off at either end.
+pub fn match_snippet(text: &str, found: Range<usize>, max_width: usize) -> [String; 3] {
+    let flatten = |part: &str| part.replace("\r\n", "\n").replace('\n', "⏎");
+    let matched = flatten(&text[found.clone()]);
+    let before: Vec<char> = flatten(&text[..found.start]).chars().collect();
+    let after: Vec<char> = flatten(&text[found.end..]).chars().collect();
+
+    let room = max_width.saturating_sub(matched.chars().count());
+    let lead = (room / 3).min(before.len());
+    let mut shown_before: String = before[before.len() - lead..].iter().collect();
+    if lead > 0 && lead < before.len() {
+        shown_before.replace_range(..shown_before.chars().next().unwrap().len_utf8(), "…");
+    }
+    let tail = room - lead;
+    let mut shown_after: String = after.iter().take(tail).collect();
+    if tail > 0 && after.len() > tail {
+        shown_after.pop();
+        shown_after.push('…');
+    }
+    [shown_before, matched, shown_after]
+}
+
+/// A text entry stored as a change to an earlier entry
+///
+/// Only the changed middle part is kept: the content is the base's first `prefix`
+/// bytes, then `insert`, then the base's last `suffix` bytes. This covers the common
+/// case of copying the same text again after editing one part of it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TextDelta {
+    pub prefix: usize,
+    pub suffix: usize,
+    pub insert: Vec<u8>,
+}
+
+impl TextDelta {
+    pub fn between(base: &[u8], new: &[u8]) -> Self {
+        let prefix = base.iter().zip(new).take_while(|(a, b)| a == b).count();
+        // The suffix may not overlap the prefix in either text
+        let max_suffix = base.len().min(new.len()) - prefix;
+        let suffix = base
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        Self {
+            prefix,
+            suffix,
+            insert: new[prefix..new.len() - suffix].to_vec(),
+        }
+    }
+
+    pub fn apply(&self, base: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if self.prefix + self.suffix > base.len() {
+            anyhow::bail!("Text delta doesn't fit its base entry");
+        }
+
+        let mut content = Vec::with_capacity(self.prefix + self.insert.len() + self.suffix);
+        content.extend_from_slice(&base[..self.prefix]);
+        content.extend_from_slice(&self.insert);
+        content.extend_from_slice(&base[base.len() - self.suffix..]);
+        Ok(content)
+    }
+}
+
+/// An entry's cleartext metadata, without the encrypted payload
+///
+/// Served as JSON by the network server for browser frontends.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EntryMetadata {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub content_type: ClipboardContentType,
+    pub size: usize, // encrypted payload size
+    pub hash: String,
+    pub image: Option<ImageMetadata>,
+}
+
+impl From<&ClipboardEntry> for EntryMetadata {
+    fn from(entry: &ClipboardEntry) -> Self {
+        Self {
+            id: entry.id.clone(),
+            timestamp: entry.timestamp,
+            content_type: entry.content_type.clone(),
+            size: entry.payload.len(),
+            hash: entry.hash.clone(),
+            image: entry.image,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_text_entry_invalid_utf8() {
+        let valid = TextEntry::from_bytes("héllo".as_bytes().to_vec());
+        assert!(valid.is_valid_utf8());
+        assert_eq!(valid.as_str().unwrap(), "héllo");
+
+        let bytes = b"caf\xe9 \xff!".to_vec();
+        let text = TextEntry::from_bytes(bytes.clone());
+        assert!(!text.is_valid_utf8());
+        // Shown with replacement characters...
+        assert_eq!(text.lossy(), "caf\u{fffd} \u{fffd}!");
+        // ...but never turned into different text to copy
+        let err = text.as_str().unwrap_err().to_string();
+        assert!(err.contains("at byte 3"), "{}", err);
+
+        // Decrypted entries keep the bytes, so listings and previews still show them
+        let entry = DecryptedEntry::from_plaintext(&ClipboardContentType::Text, bytes.clone());
+        let Ok(DecryptedEntry::Text(stored)) = &entry else {
+            panic!("{:?}", entry);
+        };
+        assert_eq!(stored.as_bytes(), bytes);
+        assert_eq!(entry.unwrap().preview_line(1, 80), "caf\u{fffd} \u{fffd}!");
+    }
+
+    #[test]
+    fn test_match_ranges_and_snippet() {
+        assert_eq!(
+            match_ranges("Foo bar FOO foo", "foo", true),
+            [0..3, 8..11, 12..15]
+        );
+        assert_eq!(
+            match_ranges("Foo bar FOO foo", "foo", false).first(),
+            Some(&(12..15))
+        );
+        assert_eq!(match_ranges("aaaa", "aa", true), [0..2, 2..4]);
+        assert_eq!(match_ranges("Über über", "ÜBER", true), [0..5, 6..11]);
+        assert!(match_ranges("Über über", "ÜBER", false).is_empty());
+        assert!(match_ranges("foo", "", true).is_empty());
+        assert!(match_ranges("fo", "foo", true).is_empty());
+
+        let text = "first line\nthe invoice number is 12345 and more text follows here";
+        let found = match_ranges(text, "invoice", false)[0].clone();
+        assert_eq!(
+            match_snippet(text, found.clone(), 100),
+            [
+                "first line⏎the ",
+                "invoice",
+                " number is 12345 and more text follows here"
+            ]
+        );
+        let [before, matched, after] = match_snippet(text, found, 25);
+        assert_eq!(before, "…⏎the ");
+        assert_eq!(matched, "invoice");
+        assert_eq!(after, " number is …");
+        assert_eq!(
+            match_snippet("invoice", 0..7, 3),
+            ["".to_string(), "invoice".to_string(), "".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_clock_going_back_keeps_order() {
+        let clock = MonotonicClock::new();
+        let now = Utc::now();
+
+        let first = clock.stamp(now);
+        assert_eq!(first, now);
+        // The clock is set back an hour, then slowly catches up
+        let second = clock.stamp(now - chrono::TimeDelta::hours(1));
+        let third = clock.stamp(now - chrono::TimeDelta::minutes(30));
+        assert!(first < second && second < third);
+        let caught_up = now + chrono::TimeDelta::seconds(1);
+        assert_eq!(clock.stamp(caught_up), caught_up);
+
+        // Entries stamped this way list in capture order
+        let mut entries: Vec<ClipboardEntry> = [first, second, third]
+            .iter()
+            .map(|timestamp| {
+                let mut entry =
+                    ClipboardEntry::new(ClipboardContentType::Text, vec![], String::new());
+                entry.timestamp = *timestamp;
+                entry.id = new_id(timestamp);
+                entry
+            })
+            .collect();
+        let created: Vec<String> = entries.iter().rev().map(|e| e.id.clone()).collect();
+        entries.sort_by(ClipboardEntry::newest_first);
+        let listed: Vec<String> = entries.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(listed, created);
+
+        // A stored entry from before the clock went back holds it back too
+        let seeded = MonotonicClock::new();
+        seeded.observe(&now);
+        assert!(seeded.stamp(now - chrono::TimeDelta::days(1)) > now);
+    }
+
     #[test]
     fn test_entry_creation() {
         let entry = ClipboardEntry::new(
@@ -95,4 +1118,341 @@ mod tests {
         assert_eq!(entry.payload, vec![1, 2, 3, 4]);
         assert_eq!(entry.hash, "abc123");
     }
+
+    #[test]
+    fn test_decrypt_content() {
+        let key = MasterKey::from_bytes([3u8; 32]);
+        let text = ClipboardEntry::new(
+            ClipboardContentType::Text,
+            crate::crypto::encrypt(&key, "héllo".as_bytes()).unwrap(),
+            "abc123".to_string(),
+        );
+        assert!(matches!(
+            text.decrypt_content(&key, None).unwrap(),
+            DecryptedEntry::Text(t) if t == "héllo"
+        ));
+
+        let image = ImageData::new(1, 1, vec![1, 2, 3, 4]);
+        let entry = ClipboardEntry::new_image(
+            crate::crypto::encrypt(&key, &image.to_plaintext().unwrap().0).unwrap(),
+            "def456".to_string(),
+            &image,
+        );
+        match entry.decrypt_content(&key, None).unwrap() {
+            DecryptedEntry::Image(decoded) => assert_eq!(decoded.bytes, image.bytes),
+            other => panic!("expected an image, got {:?}", other),
+        }
+
+        let invalid = ClipboardEntry::new(
+            ClipboardContentType::Text,
+            crate::crypto::encrypt(&key, &[0xff, 0xfe]).unwrap(),
+            "0".to_string(),
+        );
+        assert!(matches!(
+            invalid.decrypt_content(&key, None).unwrap(),
+            DecryptedEntry::Text(text) if text.as_bytes() == [0xff, 0xfe]
+        ));
+        assert!(
+            text.decrypt_content(&MasterKey::from_bytes([4u8; 32]), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_per_entry_key_round_trip() {
+        let key = MasterKey::from_bytes([3u8; 32]);
+        let mut entry =
+            ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), "abc123".to_string());
+        entry.per_entry_key = true;
+        entry.encrypt_payload(&key, b"isolated").unwrap();
+
+        assert_eq!(entry.decrypt_payload(&key).unwrap(), b"isolated");
+        // Not readable with the master key itself, nor under another entry's ID
+        assert!(decrypt(&key, &entry.payload).is_err());
+        let mut moved = entry.clone();
+        moved.id = "0-0".to_string();
+        assert!(moved.decrypt_payload(&key).is_err());
+
+        // The flag survives storage; entries written before it existed use the master key
+        let stored = ClipboardEntry::from_stored(&bincode::serialize(&entry).unwrap()).unwrap();
+        assert!(stored.per_entry_key);
+
+        let mut old = entry.clone();
+        old.per_entry_key = false;
+        old.encrypt_payload(&key, b"shared").unwrap();
+        // Those end right before `per_entry_key`, after `delta_base`
+        let serialized = bincode::serialize(&(
+            &old.id,
+            &old.timestamp,
+            &old.content_type,
+            &old.payload,
+            &old.hash,
+            &old.image,
+            &old.delta_base,
+        ))
+        .unwrap();
+        assert!(bincode::serialize(&old).unwrap().starts_with(&serialized));
+        let old = ClipboardEntry::from_stored(&serialized).unwrap();
+        assert!(!old.per_entry_key);
+        assert_eq!(old.decrypt_payload(&key).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn test_preview_line() {
+        let text = DecryptedEntry::Text("first\nsecond\r\nthird".into());
+        assert_eq!(text.preview_line(3, 80), "first⏎second⏎third");
+        assert_eq!(text.preview_line(2, 80), "first⏎second…");
+        assert_eq!(text.preview_line(3, 8), "first⏎s…");
+        assert_eq!(text.preview_line(0, 80), "first…");
+
+        let short = DecryptedEntry::Text("héllo".into());
+        assert_eq!(short.preview_line(1, 5), "héllo");
+        assert_eq!(short.preview_line(1, 4), "hél…");
+
+        let image = DecryptedEntry::Image(ImageData::new(2, 3, vec![0; 24]));
+        assert_eq!(image.preview_line(1, 80), "Image 2x3");
+    }
+
+    #[test]
+    fn test_text_delta_reconstruction() {
+        let cases: &[(&str, &str)] = &[
+            ("let x = 1;\nlet y = 2;\n", "let x = 1;\nlet y = 3;\n"),
+            ("hello world", "hello brave world"),
+            ("hello brave world", "hello world"),
+            ("aaaa", "aa"),
+            ("aa", "aaaa"),
+            ("", "new"),
+            ("old", ""),
+            ("same", "same"),
+            ("héllo wörld", "héllo wörld!"),
+        ];
+
+        for (base, new) in cases {
+            let delta = TextDelta::between(base.as_bytes(), new.as_bytes());
+            assert_eq!(
+                delta.apply(base.as_bytes()).unwrap(),
+                new.as_bytes(),
+                "{:?} -> {:?}",
+                base,
+                new
+            );
+        }
+
+        let delta = TextDelta::between(b"hello world", b"hello brave world");
+        assert_eq!(delta.insert, b"brave ");
+        assert!(delta.apply(b"short").is_err());
+    }
+
+    #[test]
+    fn test_check_image_size() {
+        assert!(check_image_size(2, 3, 24, DEFAULT_MAX_IMAGE_BYTES).is_ok());
+        // Byte count doesn't match the dimensions
+        assert!(check_image_size(2, 3, 23, DEFAULT_MAX_IMAGE_BYTES).is_err());
+        // Over the cap, and overflowing usize
+        assert!(check_image_size(100, 100, 40_000, 1024).is_err());
+        assert!(check_image_size(usize::MAX, 2, 0, usize::MAX).is_err());
+        // No pixels at all
+        assert!(check_image_size(0, 0, 0, DEFAULT_MAX_IMAGE_BYTES).is_err());
+        assert!(check_image_size(0, 3, 0, DEFAULT_MAX_IMAGE_BYTES).is_err());
+
+        assert!(ImageData::new(2, 3, vec![0; 24]).is_well_formed());
+        assert!(!ImageData::new(2, 3, vec![0; 4]).is_well_formed());
+        assert!(!ImageData::new(0, 0, vec![]).is_well_formed());
+    }
+
+    #[test]
+    fn test_canonical_image_hash_input() {
+        let transparent = ImageData::new(2, 1, vec![1, 2, 3, 0, 4, 5, 6, 0]);
+        let opaque = ImageData::new(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_ne!(transparent.hash_input(), opaque.hash_input());
+        assert_eq!(
+            transparent.canonical_hash_input(),
+            opaque.canonical_hash_input()
+        );
+        // Real transparency still counts
+        let faded = ImageData::new(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 128]);
+        assert_eq!(faded.canonical_hash_input(), faded.hash_input());
+
+        // Two rows of one pixel, each padded to 8 bytes
+        let padded = [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0];
+        assert_eq!(
+            strip_row_padding(1, 2, &padded),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        assert_eq!(strip_row_padding(2, 2, &padded), None);
+        assert_eq!(strip_row_padding(1, 3, &padded), None);
+        assert_eq!(strip_row_padding(1, 0, &padded), None);
+    }
+
+    #[test]
+    fn test_swap_red_blue() {
+        // A red and a blue pixel, as BGRA
+        let mut image = ImageData::new(2, 1, vec![0, 0, 255, 255, 255, 0, 0, 128]);
+        image.swap_red_blue();
+        assert_eq!(image.bytes, vec![255, 0, 0, 255, 0, 0, 255, 128]);
+
+        let mut png = image.to_png().unwrap();
+        let encoded = png.bytes.clone();
+        png.swap_red_blue();
+        assert_eq!(png.bytes, encoded);
+    }
+
+    #[test]
+    fn test_into_rgba_image_rejects_degenerate_images() {
+        let image = ImageData::new(2, 1, vec![255; 8])
+            .into_rgba_image()
+            .unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+
+        for degenerate in [
+            ImageData::new(0, 0, vec![]),
+            ImageData::new(0, 1, vec![]),
+            ImageData::new(2, 2, vec![0; 15]),
+            // Would wrap around to 1x1 if cast to u32 unchecked
+            ImageData::new((1 << 32) + 1, 1, vec![0; 4]),
+        ] {
+            assert!(degenerate.check_well_formed().is_err());
+            assert!(degenerate.into_rgba_image().is_err());
+        }
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let pixels: Vec<u8> = (0..64 * 64 * 4).map(|i| (i / 256) as u8).collect();
+        let raw = ImageData::new(64, 64, pixels.clone());
+
+        let png = raw.to_png().unwrap();
+        assert_eq!(png.encoding, ImageEncoding::Png);
+        assert!(png.bytes.len() < pixels.len());
+        assert_eq!(png.metadata(), raw.metadata());
+
+        let plaintext = bincode::serialize(&png).unwrap();
+        let decoded = ImageData::from_plaintext(&plaintext).unwrap();
+        assert_eq!(decoded.encoding, ImageEncoding::Rgba);
+        assert_eq!(decoded.bytes, pixels);
+        assert_eq!(raw.to_plaintext().unwrap(), (plaintext, ImageEncoding::Png));
+    }
+
+    #[test]
+    fn test_write_base64() {
+        let mut out = Vec::new();
+        DecryptedEntry::Text("héllo\n".into())
+            .write_base64(&mut out)
+            .unwrap();
+        assert_eq!(out, b"aMOpbGxvCg==");
+
+        let pixels: Vec<u8> = (0..16 * 16 * 4).map(|i| i as u8).collect();
+        let mut out = Vec::new();
+        DecryptedEntry::Image(ImageData::new(16, 16, pixels.clone()))
+            .write_base64(&mut out)
+            .unwrap();
+        let png = general_purpose::STANDARD.decode(&out).unwrap();
+        let decoded = image::load_from_memory_with_format(&png, image::ImageFormat::Png)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(decoded.dimensions(), (16, 16));
+        assert_eq!(decoded.into_raw(), pixels);
+    }
+
+    #[test]
+    fn test_image_without_encoding_field() {
+        // Images stored before `encoding` existed are raw RGBA
+        #[derive(Serialize)]
+        struct OldImageData {
+            width: usize,
+            height: usize,
+            bytes: Vec<u8>,
+        }
+        let old = OldImageData {
+            width: 1,
+            height: 1,
+            bytes: vec![1, 2, 3, 4],
+        };
+
+        let serialized = bincode::serialize(&old).unwrap();
+        let image = ImageData::from_plaintext(&serialized).unwrap();
+        assert_eq!(image.encoding, ImageEncoding::Rgba);
+        assert_eq!(image.bytes, vec![1, 2, 3, 4]);
+
+        // Hashes of new captures match those of old ones
+        assert_eq!(image.hash_input(), serialized);
+    }
+
+    #[test]
+    fn test_image_entry_metadata() {
+        let image = ImageData::new(2, 3, vec![0; 24]);
+        let entry = ClipboardEntry::new_image(vec![1, 2, 3], "abc123".to_string(), &image);
+
+        assert_eq!(entry.content_type, ClipboardContentType::Image);
+        assert_eq!(entry.describe(), "Image 2x3");
+
+        let decoded = ClipboardEntry::from_stored(&bincode::serialize(&entry).unwrap()).unwrap();
+        assert_eq!(
+            decoded.image,
+            Some(ImageMetadata {
+                width: 2,
+                height: 3,
+                size: 24
+            })
+        );
+    }
+
+    #[test]
+    fn test_entry_without_appended_fields() {
+        // Layout of entries written before any fields were appended
+        #[derive(Serialize)]
+        struct OriginalEntry {
+            id: String,
+            timestamp: DateTime<Utc>,
+            content_type: ClipboardContentType,
+            payload: Vec<u8>,
+            hash: String,
+        }
+
+        let original = OriginalEntry {
+            id: "1-1".to_string(),
+            timestamp: Utc::now(),
+            content_type: ClipboardContentType::Image,
+            payload: vec![1, 2, 3],
+            hash: "abc123".to_string(),
+        };
+
+        let decoded = ClipboardEntry::from_stored(&bincode::serialize(&original).unwrap()).unwrap();
+        assert_eq!(decoded.id, "1-1");
+        assert_eq!(decoded.payload, vec![1, 2, 3]);
+        assert_eq!(decoded.image, None);
+        assert!(!decoded.pinned);
+        assert_eq!(decoded.original_image, None);
+        assert_eq!(decoded.image_encoding, None);
+        assert_eq!(decoded.describe(), "Image");
+
+        // An appended field that is there but doesn't decode is an error, not its default
+        let mut corrupt = bincode::serialize(&original).unwrap();
+        corrupt.push(7); // not an `Option` tag
+        assert!(ClipboardEntry::from_stored(&corrupt).is_err());
+        let mut newer = decoded.clone();
+        newer.content_size = Some(1000);
+        let mut truncated = bincode::serialize(&newer).unwrap();
+        // Ends within `content_size`, before the last three one-byte fields
+        truncated.truncate(truncated.len() - 3 - 4);
+        assert!(ClipboardEntry::from_stored(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_content_size() {
+        let key = MasterKey::from_bytes([7u8; 32]);
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), String::new());
+        entry.encrypt_payload(&key, b"twelve bytes").unwrap();
+
+        // Older entries: estimated from the payload
+        assert_eq!(entry.content_size(), (12, false));
+
+        entry.content_size = Some(1000);
+        let decoded = ClipboardEntry::from_stored(&bincode::serialize(&entry).unwrap()).unwrap();
+        assert_eq!(decoded.content_size(), (1000, true));
+
+        let image = ImageData::new(2, 3, vec![0; 24]);
+        let entry = ClipboardEntry::new_image(vec![1, 2, 3], String::new(), &image);
+        assert_eq!(entry.content_size(), (24, true));
+    }
 }
@@ -0,0 +1,160 @@
+use chrono::{Local, NaiveTime};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two capture notifications
+const MIN_NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum length of the text preview shown in a notification
+const PREVIEW_CHARS: usize = 80;
+
+/// A daily time range during which no notifications are shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Parse a range like `22:00-07:00` (ranges may wrap past midnight)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid quiet hours '{}', expected HH:MM-HH:MM", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?;
+        Ok(Self { start, end })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Sends rate-limited desktop notifications when entries are captured
+pub struct CaptureNotifier {
+    quiet_hours: Option<QuietHours>,
+    last_sent: Option<Instant>,
+    suppressed: usize,
+}
+
+impl CaptureNotifier {
+    pub fn new(quiet_hours: Option<QuietHours>) -> Self {
+        Self {
+            quiet_hours,
+            last_sent: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether notifications can be shown at all in this build
+    pub fn is_supported() -> bool {
+        cfg!(feature = "notify")
+    }
+
+    /// Notify about a captured entry, unless rate limited or in quiet hours
+    pub fn notify(&mut self, content_type: &str, preview: Option<&str>) {
+        if !self.should_notify(Instant::now(), Local::now().time()) {
+            return;
+        }
+
+        let mut body = match preview {
+            Some(preview) => format!("{}: {}", content_type, preview_line(preview)),
+            None => content_type.to_string(),
+        };
+        if self.suppressed > 0 {
+            body.push_str(&format!(" (+{} more)", self.suppressed));
+            self.suppressed = 0;
+        }
+
+        send("clpd captured an entry", &body);
+    }
+
+    /// Decide whether a notification may be sent now, recording it if so
+    fn should_notify(&mut self, now: Instant, time_of_day: NaiveTime) -> bool {
+        if self
+            .quiet_hours
+            .is_some_and(|quiet| quiet.contains(time_of_day))
+        {
+            return false;
+        }
+
+        if let Some(last) = self.last_sent
+            && now.duration_since(last) < MIN_NOTIFY_INTERVAL
+        {
+            self.suppressed += 1;
+            return false;
+        }
+
+        self.last_sent = Some(now);
+        true
+    }
+}
+
+/// Collapse text to a single, short line suitable for a notification
+fn preview_line(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() > PREVIEW_CHARS {
+        let truncated: String = line.chars().take(PREVIEW_CHARS).collect();
+        format!("{}…", truncated)
+    } else {
+        line
+    }
+}
+
+#[cfg(feature = "notify")]
+fn send(summary: &str, body: &str) {
+    if let Err(e) = notify_rust::Notification::new()
+        .summary(summary)
+        .body(body)
+        .appname("clpd")
+        .show()
+    {
+        eprintln!("⚠ Warning: Failed to show notification: {}", e);
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+fn send(_summary: &str, _body: &str) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut notifier = CaptureNotifier::new(None);
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let start = Instant::now();
+
+        assert!(notifier.should_notify(start, noon));
+        assert!(!notifier.should_notify(start + Duration::from_secs(1), noon));
+        assert!(!notifier.should_notify(start + Duration::from_secs(2), noon));
+        assert_eq!(notifier.suppressed, 2);
+        assert!(notifier.should_notify(start + MIN_NOTIFY_INTERVAL, noon));
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet = QuietHours::parse("22:00-07:00").unwrap();
+        assert!(quiet.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(quiet.contains(NaiveTime::from_hms_opt(6, 59, 0).unwrap()));
+        assert!(!quiet.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert!(QuietHours::parse("22:00").is_err());
+
+        let mut notifier = CaptureNotifier::new(Some(quiet));
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        assert!(!notifier.should_notify(Instant::now(), night));
+    }
+
+    #[test]
+    fn test_preview_line() {
+        assert_eq!(preview_line("hello\n  world"), "hello world");
+        assert_eq!(
+            preview_line(&"a".repeat(100)).chars().count(),
+            PREVIEW_CHARS + 1
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result, ensure};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use crate::crypto::{MasterKey, compress, compress_with_level, decompress, decrypt, encrypt};
+use crate::models::{ClipboardContentType, ClipboardEntry, DecryptedEntry, ImageData};
+
+/// Plaintext sizes the round-trips are run with, up to a large screenshot
+const SIZES: &[usize] = &[0, 1, 1000, 64 * 1024, 8 * 1024 * 1024];
+
+/// The outcome of one round-trip of `clpd selftest`
+pub struct Check {
+    pub name: String,
+    pub result: Result<()>,
+}
+
+/// Run every round-trip with a throwaway random key, never touching a database
+pub fn run() -> Vec<Check> {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let key = MasterKey::from_bytes(key);
+
+    let mut checks = Vec::new();
+    let mut check = |name: String, result: Result<()>| checks.push(Check { name, result });
+    for &size in SIZES {
+        // Random bytes don't compress; repeated text compresses well
+        let mut random = vec![0u8; size];
+        OsRng.fill_bytes(&mut random);
+        let text: Vec<u8> = b"clpd selftest "
+            .iter()
+            .copied()
+            .cycle()
+            .take(size)
+            .collect();
+
+        check(
+            format!("encrypt/decrypt {} bytes", size),
+            encryption_round_trip(&key, &random),
+        );
+        check(
+            format!("compress/decompress {} bytes", size),
+            compression_round_trip(&random).and_then(|()| compression_round_trip(&text)),
+        );
+    }
+    check(
+        "text entry to/from compressed string".to_string(),
+        text_entry_round_trip(&key),
+    );
+    check(
+        "image serialize/deserialize".to_string(),
+        image_round_trip(&key),
+    );
+    checks
+}
+
+fn encryption_round_trip(key: &MasterKey, plaintext: &[u8]) -> Result<()> {
+    let encrypted = encrypt(key, plaintext)?;
+    ensure!(
+        decrypt(key, &encrypted)? == plaintext,
+        "decrypted data differs"
+    );
+    // The authentication must catch a wrong key and a flipped bit
+    let mut other = [0u8; 32];
+    OsRng.fill_bytes(&mut other);
+    ensure!(
+        decrypt(&MasterKey::from_bytes(other), &encrypted).is_err(),
+        "decrypted with the wrong key"
+    );
+    let mut tampered = encrypted;
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    ensure!(decrypt(key, &tampered).is_err(), "decrypted tampered data");
+    Ok(())
+}
+
+fn compression_round_trip(data: &[u8]) -> Result<()> {
+    for compressed in [compress(data), compress_with_level(data, 9)] {
+        ensure!(
+            decompress(&compressed)? == data,
+            "decompressed data differs"
+        );
+    }
+    Ok(())
+}
+
+fn text_entry_round_trip(key: &MasterKey) -> Result<()> {
+    let text = "clpd selftest: ünïcödé ✓\r\n\ttabs and line breaks\n";
+    let mut entry = ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), "hash".into());
+    entry.encrypt_payload(key, text.as_bytes())?;
+
+    let restored = ClipboardEntry::from_compressed_string(&entry.to_compressed_string())
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    ensure!(
+        bincode::serialize(&restored)? == bincode::serialize(&entry)?,
+        "restored entry differs"
+    );
+    match restored.decrypt_content(key, None)? {
+        DecryptedEntry::Text(restored) if restored == text => Ok(()),
+        _ => anyhow::bail!("decrypted text differs"),
+    }
+}
+
+fn image_round_trip(key: &MasterKey) -> Result<()> {
+    let (width, height) = (67, 45);
+    let mut pixels = vec![0u8; width * height * 4];
+    OsRng.fill_bytes(&mut pixels);
+    let image = ImageData::new(width, height, pixels);
+
+    // Stored PNG-encoded, in an entry that goes through a compressed string
+    let (plaintext, encoding) = image.to_plaintext()?;
+    let mut entry = ClipboardEntry::new_image(Vec::new(), "hash".into(), &image);
+    entry.image_encoding = Some(encoding);
+    entry.encrypt_payload(key, &plaintext)?;
+    let entry = ClipboardEntry::from_compressed_string(&entry.to_compressed_string())
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let DecryptedEntry::Image(restored) = entry
+        .decrypt_content(key, None)
+        .context("Failed to decode image")?
+    else {
+        anyhow::bail!("entry isn't an image");
+    };
+    ensure!(
+        (restored.width, restored.height) == (width, height),
+        "image is {} x {} instead of {} x {}",
+        restored.width,
+        restored.height,
+        width,
+        height
+    );
+    ensure!(restored.bytes == image.bytes, "pixels differ");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        for check in run() {
+            assert!(check.result.is_ok(), "{}: {:?}", check.name, check.result);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Temporary files older than this are removed when any command starts
+pub const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Directory for decrypted entries opened in other applications
+pub fn dir() -> PathBuf {
+    std::env::temp_dir().join("clpd_temp")
+}
+
+/// Remove files in `dir` last modified more than `max_age` ago
+///
+/// These are decrypted entries left behind by a process that didn't exit cleanly.
+/// Failures are only reported, they shouldn't stop the command from running.
+/// Returns the number of files removed.
+pub fn remove_stale(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| now.duration_since(modified).unwrap_or_default() > max_age)
+            // Can't tell how old it is, so it may well be stale
+            .unwrap_or(true);
+        if !stale {
+            continue;
+        }
+
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => eprintln!(
+                "⚠ Warning: Failed to remove temporary file {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    removed
+}
+
+/// Temporary files that are deleted when this is dropped
+///
+/// Keeps decrypted content from outliving the command that wrote it, including when
+/// it returns early with an error.
+#[derive(Default)]
+pub struct TempFiles {
+    paths: Vec<PathBuf>,
+}
+
+impl TempFiles {
+    /// Delete `path` when this is dropped
+    pub fn register(&mut self, path: PathBuf) {
+        self.paths.push(path);
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in self.paths.drain(..) {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                // Already cleaned up some other way
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!(
+                    "⚠ Warning: Failed to remove temporary file {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// A file written under a temporary name next to its destination, and renamed
+/// into place by [`AtomicFile::commit`]
+///
+/// Dropped without being committed, e.g. when writing fails partway, the partial
+/// file is removed, so the destination is either complete or untouched. A process
+/// that is killed leaves only the hidden `.<name>.partial`, which the next run
+/// overwrites.
+pub struct AtomicFile {
+    file: Option<BufWriter<File>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file name", path.display()))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(".partial");
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = File::create(&temp_path)
+            .with_context(|| format!("Failed to create {}", temp_path.display()))?;
+        Ok(Self {
+            file: Some(BufWriter::new(file)),
+            temp_path,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Write everything to disk and move the file to its destination
+    pub fn commit(mut self) -> Result<()> {
+        let file = self.file.take().expect("only taken here");
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp_path, &self.path)
+            .with_context(|| format!("Failed to move file into place at {}", self.path.display()))
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        self.file.as_mut().expect("present until committed")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Either committed (and renamed away) or incomplete
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old.txt");
+        let new = dir.path().join("new.txt");
+        fs::write(&old, "secret").unwrap();
+        fs::write(&new, "secret").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_AFTER)
+            .unwrap();
+
+        assert_eq!(remove_stale(dir.path(), STALE_AFTER), 1);
+        assert!(!old.exists());
+        assert!(new.exists());
+        // A missing directory is nothing to clean up
+        assert_eq!(remove_stale(&dir.path().join("missing"), STALE_AFTER), 0);
+    }
+
+    #[test]
+    fn test_temp_files_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept.txt");
+        let opened = dir.path().join("opened.txt");
+        fs::write(&kept, "a").unwrap();
+        fs::write(&opened, "b").unwrap();
+
+        let mut files = TempFiles::default();
+        files.register(opened.clone());
+        files.register(dir.path().join("never-written.txt"));
+        drop(files);
+
+        assert!(kept.exists());
+        assert!(!opened.exists());
+    }
+
+    #[test]
+    fn test_failed_atomic_write_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, "complete old dump").unwrap();
+
+        // Fails halfway, like an encoder running into bad data
+        let write = |fail: bool| -> Result<()> {
+            let mut file = AtomicFile::create(&path)?;
+            file.write_all(b"first half")?;
+            if fail {
+                anyhow::bail!("encoding failed");
+            }
+            file.write_all(b", second half")?;
+            file.commit()
+        };
+
+        assert!(write(true).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "complete old dump");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        write(false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "first half, second half"
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
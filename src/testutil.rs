@@ -0,0 +1,24 @@
+//! Fixtures shared by the tests of several modules
+
+use tempfile::TempDir;
+
+use crate::crypto::MasterKey;
+use crate::database::ClipboardDatabase;
+use crate::watcher::LocalClipboardWatcher;
+
+/// The key [`capture_fixture`] encrypts with
+pub fn test_key() -> MasterKey {
+    MasterKey::from_bytes([7u8; 32])
+}
+
+/// A new database in a temporary directory, and a watcher without a clipboard
+/// storing into it with [`test_key`]
+///
+/// The database is a clone sharing the watcher's. Keep the directory until
+/// the test is done with both.
+pub fn capture_fixture() -> (TempDir, ClipboardDatabase, LocalClipboardWatcher) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+    let watcher = LocalClipboardWatcher::without_clipboard(db.clone(), test_key());
+    (dir, db, watcher)
+}
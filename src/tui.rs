@@ -1,7 +1,8 @@
 use anyhow::{Context, Result};
 use crossterm::{
+    cursor::MoveTo,
     event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
-    execute,
+    execute, queue,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use ratatui::{
@@ -12,15 +13,151 @@ use ratatui::{
     text::{Line, Span, Text},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
 };
-use std::io;
-use std::time::Instant;
-
-use crate::database::ClipboardDatabase;
-use crate::models::{ClipboardContentType, ClipboardEntry, ImageData};
-use crate::{
-    crypto::{MasterKey, decrypt},
-    database::ClipboardType,
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::cli::SelectionFilter;
+use crate::kitty;
+use crate::models::{
+    ClipboardContentType, ClipboardEntry, ClipboardSelection, DecryptedEntry, ImageData,
+    match_ranges,
 };
+use crate::tempfiles::{self, TempFiles};
+use crate::{crypto::MasterKey, database::ClipboardType};
+
+/// Default longest pause between copies that still counts as one session
+pub const DEFAULT_SESSION_GAP: Duration = Duration::from_secs(5 * 60);
+
+/// Settings for a TUI session
+#[derive(Debug, Clone, Copy)]
+pub struct TuiOptions {
+    /// Reload entries as they're captured by a background watcher
+    pub live: bool,
+    /// Longest pause between copies that still counts as one session in the grouped view
+    pub session_gap: Duration,
+    /// Only show entries captured from these selections (Linux)
+    pub selection: SelectionFilter,
+    /// What `o` does with image entries
+    pub open_images: ImageOpen,
+}
+
+impl Default for TuiOptions {
+    fn default() -> Self {
+        Self {
+            live: false,
+            session_gap: DEFAULT_SESSION_GAP,
+            selection: SelectionFilter::default(),
+            open_images: ImageOpen::default(),
+        }
+    }
+}
+
+/// Where `o` shows an image entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageOpen {
+    /// In the terminal if it can display images (kitty graphics protocol),
+    /// otherwise in the default image viewer
+    #[default]
+    Auto,
+    /// Always in the terminal, drawn with block characters where the terminal
+    /// can't display images
+    Inline,
+    /// In the default image viewer, through a decrypted temporary file
+    External,
+}
+
+/// An image shown over the whole terminal by `o`, until any key is pressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImageView {
+    /// Drawn with half-block characters, like the preview
+    Blocks,
+    /// Displayed by the terminal itself, in `area`; `placed` once the image was sent
+    Kitty { area: Rect, placed: bool },
+}
+
+/// Below this width or height there's no room for the list and preview side by
+/// side, so one is shown at a time and the bottom bars are left out
+const COMPACT_WIDTH: u16 = 60;
+const COMPACT_HEIGHT: u16 = 12;
+
+/// How long deletions may wait before they're flushed to disk
+const FLUSH_DELAY: Duration = Duration::from_secs(2);
+
+/// Deletions made in the database but not yet flushed to disk
+///
+/// Flushing after every deletion makes pressing `d` repeatedly stutter, so the
+/// flush happens once, a little after the first unflushed deletion (or on exit).
+#[derive(Debug, Default)]
+struct PendingFlush {
+    deletes: usize,
+    since: Option<Instant>,
+}
+
+impl PendingFlush {
+    fn record(&mut self, now: Instant) {
+        self.deletes += 1;
+        self.since.get_or_insert(now);
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.since
+            .is_some_and(|since| now.saturating_duration_since(since) >= FLUSH_DELAY)
+    }
+
+    /// Forget the pending deletions once they're flushed, returning how many there were
+    fn take(&mut self) -> usize {
+        self.since = None;
+        std::mem::take(&mut self.deletes)
+    }
+}
+
+/// Entries yanked into the registers `"a`–`"z`, for assembling a snippet from
+/// several entries
+///
+/// Registers only last for the session and only hold entry IDs; content is
+/// decrypted when a register is copied.
+#[derive(Debug, Default)]
+struct Registers {
+    entries: BTreeMap<char, Vec<String>>,
+    // Register that `y`, `p` and `Y` use, chosen with `"` and a letter
+    selected: Option<char>,
+    // `"` was pressed, the next key names the register
+    awaiting_name: bool,
+}
+
+impl Registers {
+    fn is_name(c: char) -> bool {
+        c.is_ascii_lowercase()
+    }
+
+    /// Append an entry to a register, returning how many entries it holds
+    fn yank(&mut self, name: char, id: &str) -> usize {
+        let ids = self.entries.entry(name).or_default();
+        ids.push(id.to_string());
+        ids.len()
+    }
+
+    fn get(&self, name: char) -> &[String] {
+        self.entries.get(&name).map_or(&[], Vec::as_slice)
+    }
+
+    fn clear(&mut self, name: char) {
+        self.entries.remove(&name);
+    }
+
+    /// Non-empty registers with their entry counts, e.g. `"a:2 "c:1`
+    fn summary(&self) -> String {
+        self.entries
+            .iter()
+            .map(|(name, ids)| format!("\"{}:{}", name, ids.len()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
 
 /// TUI Application State
 pub struct App {
@@ -32,11 +169,57 @@ pub struct App {
     key: MasterKey,
     message: Option<String>,
     message_time: Option<Instant>,
+    // Decoded image of the selected entry, so it isn't decrypted on every frame
+    image_cache: Option<(String, ImageData)>,
+    // Prepared preview of the selected text entry, for the same reason
+    text_cache: Option<(String, Text<'static>)>,
+    // Reload entries as they're captured by a background watcher
+    live: bool,
+    last_reload: Instant,
+    // Show entries under session headers; `list_state` still indexes entries,
+    // `display_state` indexes the rows on screen
+    grouped: bool,
+    session_gap: Duration,
+    display_state: ListState,
+    // In a terminal too small for both panes, show the preview instead of the list
+    compact_preview: bool,
+    // Decrypted files written for `open`, deleted when the TUI exits
+    temp_files: TempFiles,
+    pending_flush: PendingFlush,
+    // Whether each loaded entry can be decrypted, checked once an entry is on screen
+    readable: HashMap<String, bool>,
+    // `D` asked whether to delete the unreadable entries, the next key answers
+    confirm_delete_unreadable: bool,
+    registers: Registers,
+    selection: SelectionFilter,
+    // Entries in the database including those hidden by `selection`, to notice new ones
+    stored_count: usize,
+    open_images: ImageOpen,
+    // The terminal displays images sent with the kitty graphics protocol
+    kitty_graphics: bool,
+    image_view: Option<ImageView>,
+    // A kitty image was closed and must be removed from the screen
+    remove_inline_image: bool,
+    // Text searched for with `/`, highlighted in the preview
+    search: Option<String>,
+    // The search query is being typed
+    search_input: bool,
+}
+
+/// All entries, with those hidden by `selection` left out, and how many there were before
+async fn load_entries(
+    db: &ClipboardType,
+    selection: SelectionFilter,
+) -> Result<(Vec<ClipboardEntry>, usize)> {
+    let mut entries = db.list_entries().await?;
+    let stored_count = entries.len();
+    entries.retain(|entry| selection.matches(entry));
+    Ok((entries, stored_count))
 }
 
 impl App {
-    pub async fn new(db: ClipboardType, key: MasterKey) -> Result<Self> {
-        let entries = db.list_entries().await?;
+    pub async fn new(db: ClipboardType, key: MasterKey, options: TuiOptions) -> Result<Self> {
+        let (entries, stored_count) = load_entries(&db, options.selection).await?;
         let mut list_state = ListState::default();
         if !entries.is_empty() {
             list_state.select(Some(0));
@@ -50,19 +233,107 @@ impl App {
             key,
             message: None,
             message_time: None,
+            image_cache: None,
+            text_cache: None,
+            live: options.live,
+            last_reload: Instant::now(),
+            grouped: false,
+            session_gap: options.session_gap,
+            display_state: ListState::default(),
+            compact_preview: false,
+            temp_files: TempFiles::default(),
+            pending_flush: PendingFlush::default(),
+            readable: HashMap::new(),
+            confirm_delete_unreadable: false,
+            registers: Registers::default(),
+            selection: options.selection,
+            stored_count,
+            open_images: options.open_images,
+            kitty_graphics: kitty::is_supported(),
+            image_view: None,
+            remove_inline_image: false,
+            search: None,
+            search_input: false,
         })
     }
 
+    /// Check the entries at `indices` that haven't been checked yet, so unreadable
+    /// ones can be marked in the list
+    ///
+    /// Only the payload's authentication tag is checked: nothing is decompressed,
+    /// rebuilt from a delta or decoded.
+    fn check_readability(&mut self, indices: impl IntoIterator<Item = usize>) {
+        for i in indices {
+            let entry = &self.entries[i];
+            if !self.readable.contains_key(&entry.id) {
+                let ok = entry.decrypt_payload(&self.key).is_ok();
+                self.readable.insert(entry.id.clone(), ok);
+            }
+        }
+    }
+
+    fn is_unreadable(&self, entry: &ClipboardEntry) -> bool {
+        self.readable.get(&entry.id) == Some(&false)
+    }
+
     /// Handle key events
     pub async fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
         if key.kind != KeyEventKind::Press {
             return Ok(());
         }
 
+        // Any key closes the image view
+        if let Some(view) = self.image_view.take() {
+            self.remove_inline_image = matches!(view, ImageView::Kitty { placed: true, .. });
+            return Ok(());
+        }
+
+        if std::mem::take(&mut self.confirm_delete_unreadable) {
+            if key.code == KeyCode::Char('y') {
+                self.delete_unreadable().await?;
+            } else {
+                self.set_message("Deletion cancelled".to_string());
+            }
+            return Ok(());
+        }
+
+        if std::mem::take(&mut self.registers.awaiting_name) {
+            match key.code {
+                KeyCode::Char(name) if Registers::is_name(name) => {
+                    self.registers.selected = Some(name);
+                    self.set_message(format!(
+                        "Register \"{} ({} entries)",
+                        name,
+                        self.registers.get(name).len()
+                    ));
+                }
+                _ => self.set_message("Registers are \"a to \"z".to_string()),
+            }
+            return Ok(());
+        }
+
+        if self.search_input {
+            self.handle_search_key(key);
+            return Ok(());
+        }
+
         match key.code {
+            KeyCode::Esc if self.search.is_some() => {
+                self.set_search(None);
+            }
             KeyCode::Char('q') | KeyCode::Esc => {
                 self.should_quit = true;
             }
+            KeyCode::Char('/') => {
+                self.search_input = true;
+                self.set_search(Some(String::new()));
+            }
+            KeyCode::Char('n') => {
+                self.select_match(true, false);
+            }
+            KeyCode::Char('N') => {
+                self.select_match(false, false);
+            }
             KeyCode::Down | KeyCode::Char('j') => {
                 self.next();
             }
@@ -78,9 +349,44 @@ impl App {
             KeyCode::Char('o') => {
                 self.open_selected()?;
             }
+            KeyCode::Char('t') => {
+                self.touch_selected().await?;
+            }
             KeyCode::Char('r') => {
                 self.refresh().await?;
             }
+            KeyCode::Char('x') => {
+                self.export_selected_raw()?;
+            }
+            KeyCode::Char('D') => {
+                self.ask_delete_unreadable().await?;
+            }
+            KeyCode::Char('"') => {
+                self.registers.awaiting_name = true;
+            }
+            KeyCode::Char('y') => {
+                self.yank_selected();
+            }
+            KeyCode::Char('p') => {
+                self.copy_register()?;
+            }
+            KeyCode::Char('Y') => {
+                if let Some(name) = self.registers.selected {
+                    self.registers.clear(name);
+                    self.set_message(format!("Emptied register \"{}", name));
+                }
+            }
+            KeyCode::Char('g') => {
+                self.grouped = !self.grouped;
+                self.set_message(if self.grouped {
+                    "Grouped by session".to_string()
+                } else {
+                    "Flat list".to_string()
+                });
+            }
+            KeyCode::Tab => {
+                self.compact_preview = !self.compact_preview;
+            }
             KeyCode::Home => {
                 self.select_first();
             }
@@ -99,6 +405,70 @@ impl App {
         Ok(())
     }
 
+    /// Typing the query after `/`: Enter jumps to the first matching entry, Esc
+    /// cancels the search
+    fn handle_search_key(&mut self, key: KeyEvent) {
+        let mut query = self.search.take().unwrap_or_default();
+        match key.code {
+            KeyCode::Char(c) => query.push(c),
+            KeyCode::Backspace => {
+                query.pop();
+            }
+            KeyCode::Enter => {
+                self.search_input = false;
+                if !query.is_empty() {
+                    self.set_search(Some(query));
+                    self.select_match(true, true);
+                }
+                return;
+            }
+            KeyCode::Esc => {
+                self.search_input = false;
+                self.set_search(None);
+                return;
+            }
+            _ => {}
+        }
+        self.set_search(Some(query));
+    }
+
+    fn set_search(&mut self, query: Option<String>) {
+        self.search = query;
+        // The preview is highlighted for the query
+        self.text_cache = None;
+    }
+
+    /// Select the next (or previous) text entry containing the search query,
+    /// case-insensitively, wrapping around; `from_selected` also considers the
+    /// selected entry itself
+    fn select_match(&mut self, forward: bool, from_selected: bool) {
+        let Some(query) = self.search.as_ref().map(|query| query.to_lowercase()) else {
+            self.set_message("Search with / first".to_string());
+            return;
+        };
+        let len = self.entries.len();
+        let start = self.list_state.selected().unwrap_or(0);
+        let offsets = if from_selected { 0..len } else { 1..len + 1 };
+        let found = offsets
+            .map(|offset| {
+                if forward {
+                    (start + offset) % len
+                } else {
+                    (start + len - offset % len) % len
+                }
+            })
+            .find(|&i| {
+                let entry = &self.entries[i];
+                entry.content_type == ClipboardContentType::Text
+                    && matches!(self.decrypt_entry(entry),
+                        Ok(DecryptedEntry::Text(text)) if text.lossy().to_lowercase().contains(&query))
+            });
+        match found {
+            Some(i) => self.list_state.select(Some(i)),
+            None => self.set_message(format!("No text entries contain '{}'", query)),
+        }
+    }
+
     fn next(&mut self) {
         if self.entries.is_empty() {
             return;
@@ -168,167 +538,370 @@ impl App {
     }
 
     async fn delete_selected(&mut self) -> Result<()> {
-        if let Some(index) = self.list_state.selected() {
-            if index < self.entries.len() {
-                let entry = &self.entries[index];
-                self.db.delete_entry(&entry.id).await?;
-                self.entries.remove(index);
-
-                // Adjust selection
-                if self.entries.is_empty() {
-                    self.list_state.select(None);
-                } else if index >= self.entries.len() {
-                    self.list_state.select(Some(self.entries.len() - 1));
+        if let Some(index) = self.list_state.selected()
+            && index < self.entries.len()
+        {
+            let entry = &self.entries[index];
+            self.db.delete_entry_unflushed(&entry.id).await?;
+            self.pending_flush.record(Instant::now());
+            self.entries.remove(index);
+            self.stored_count = self.stored_count.saturating_sub(1);
+
+            // Adjust selection
+            if self.entries.is_empty() {
+                self.list_state.select(None);
+            } else if index >= self.entries.len() {
+                self.list_state.select(Some(self.entries.len() - 1));
+            }
+
+            self.set_message("Entry deleted".to_string());
+        }
+        Ok(())
+    }
+
+    /// Move the selected entry to the top, as `clpd touch` does
+    async fn touch_selected(&mut self) -> Result<()> {
+        if let Some(index) = self.list_state.selected()
+            && index < self.entries.len()
+        {
+            match self.db.touch_entry(&self.entries[index].id).await {
+                Ok(Some(entry)) => {
+                    self.entries.remove(index);
+                    self.entries.insert(0, entry);
+                    self.list_state.select(Some(0));
+                    self.set_message("Moved to the top".to_string());
                 }
+                Ok(None) => self.set_message("Entry no longer exists".to_string()),
+                // Not possible in `net-browse`, which shouldn't end the TUI
+                Err(e) => self.set_message(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check every entry and ask whether to delete those that can't be decrypted
+    async fn ask_delete_unreadable(&mut self) -> Result<()> {
+        self.check_readability(0..self.entries.len());
+        let count = self
+            .entries
+            .iter()
+            .filter(|entry| self.is_unreadable(entry))
+            .count();
+        if count == 0 {
+            self.set_message("No unreadable entries".to_string());
+        } else if crate::confirm::assume_yes() {
+            self.delete_unreadable().await?;
+        } else {
+            self.confirm_delete_unreadable = true;
+            self.set_message(format!("⚠ Delete {} unreadable entries? (y/N)", count));
+        }
+        Ok(())
+    }
+
+    /// Delete every entry found unreadable
+    async fn delete_unreadable(&mut self) -> Result<()> {
+        let unreadable: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| self.is_unreadable(entry))
+            .map(|entry| entry.id.clone())
+            .collect();
+        if unreadable.is_empty() {
+            self.set_message("No unreadable entries".to_string());
+            return Ok(());
+        }
+
+        let selected_id = self.get_selected_entry().map(|entry| entry.id.clone());
+        for id in &unreadable {
+            self.db.delete_entry_unflushed(id).await?;
+            self.pending_flush.record(Instant::now());
+        }
+        self.entries.retain(|entry| !unreadable.contains(&entry.id));
+        self.stored_count = self.stored_count.saturating_sub(unreadable.len());
+        for id in &unreadable {
+            self.readable.remove(id);
+        }
+
+        let index = selected_id
+            .and_then(|id| self.entries.iter().position(|entry| entry.id == id))
+            .or(if self.entries.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        self.list_state.select(index);
+        self.set_message(format!("Deleted {} unreadable entries", unreadable.len()));
+        Ok(())
+    }
+
+    /// Save the selected entry as stored, still encrypted, for recovering it by hand
+    fn export_selected_raw(&mut self) -> Result<()> {
+        let Some(entry) = self.get_selected_entry() else {
+            return Ok(());
+        };
+        let path = std::env::current_dir()
+            .context("Failed to get current directory")?
+            .join(format!("clpd-entry-{}.bin", entry.id));
+        let serialized = bincode::serialize(entry).context("Failed to serialize entry")?;
+        std::fs::write(&path, serialized).context("Failed to write entry")?;
+
+        self.set_message(format!("Exported encrypted entry to {}", path.display()));
+        Ok(())
+    }
+
+    /// Append the selected entry to the selected register
+    fn yank_selected(&mut self) {
+        let Some(name) = self.registers.selected else {
+            self.set_message("Choose a register first: \" then a letter".to_string());
+            return;
+        };
+        let Some(entry) = self.get_selected_entry() else {
+            return;
+        };
+        if entry.content_type != ClipboardContentType::Text {
+            self.set_message("Only text entries can be yanked".to_string());
+            return;
+        }
+        if self.is_unreadable(entry) {
+            self.set_message("This entry can't be decrypted".to_string());
+            return;
+        }
+
+        let id = entry.id.clone();
+        let count = self.registers.yank(name, &id);
+        self.set_message(format!("Yanked into \"{} ({} entries)", name, count));
+    }
 
-                self.set_message("Entry deleted".to_string());
+    /// The texts of a register's entries, one after another on separate lines
+    ///
+    /// Entries deleted since they were yanked are left out; returns the text and
+    /// how many entries it's made of.
+    fn register_text(&self, name: char) -> Result<(String, usize)> {
+        let mut texts = Vec::new();
+        for id in self.registers.get(name) {
+            let Some(entry) = self.entries.iter().find(|entry| entry.id == *id) else {
+                continue;
+            };
+            if let DecryptedEntry::Text(text) = self.decrypt_entry(entry)? {
+                texts.push(text.as_str()?.to_string());
             }
         }
+        let count = texts.len();
+        Ok((texts.join("\n"), count))
+    }
+
+    /// Copy the selected register's combined text to the clipboard
+    fn copy_register(&mut self) -> Result<()> {
+        let Some(name) = self.registers.selected else {
+            self.set_message("Choose a register first: \" then a letter".to_string());
+            return Ok(());
+        };
+        let (text, count) = self.register_text(name)?;
+        if count == 0 {
+            self.set_message(format!("Register \"{} is empty", name));
+            return Ok(());
+        }
+
+        let hash = crate::watcher::LocalClipboardWatcher::hash_data(text.as_bytes());
+        let _writing = self.db.begin_clipboard_write();
+        self.db.record_clipboard_write(&hash);
+        arboard::Clipboard::new()
+            .context("Failed to access clipboard")?
+            .set_text(text)
+            .context("Failed to set clipboard text")?;
+        self.set_message(format!(
+            "Copied register \"{} ({} entries) to clipboard",
+            name, count
+        ));
         Ok(())
     }
 
     fn copy_selected(&mut self) -> Result<()> {
-        if let Some(index) = self.list_state.selected() {
-            if index < self.entries.len() {
-                let entry = &self.entries[index];
-
-                // Decrypt entry
-                let plaintext =
-                    decrypt(&self.key, &entry.payload).context("Failed to decrypt entry")?;
-
-                // Copy to clipboard
-                let mut clipboard =
-                    arboard::Clipboard::new().context("Failed to access clipboard")?;
-
-                match entry.content_type {
-                    ClipboardContentType::Text => {
-                        let text =
-                            String::from_utf8(plaintext).context("Entry contains invalid UTF-8")?;
-                        clipboard
-                            .set_text(text)
-                            .context("Failed to set clipboard text")?;
-                        self.set_message("Text copied to clipboard".to_string());
-                    }
-                    ClipboardContentType::Image => {
-                        let img_data: ImageData = bincode::deserialize(&plaintext)
-                            .context("Failed to deserialize image data")?;
-
-                        let arboard_img = arboard::ImageData {
-                            width: img_data.width,
-                            height: img_data.height,
-                            bytes: img_data.bytes.into(),
-                        };
-
-                        clipboard
-                            .set_image(arboard_img)
-                            .context("Failed to set clipboard image")?;
-
-                        self.set_message(format!(
-                            "Image copied to clipboard ({}x{})",
-                            img_data.width, img_data.height
-                        ));
+        if let Some(index) = self.list_state.selected()
+            && index < self.entries.len()
+        {
+            let entry = &self.entries[index];
+
+            let content = self.decrypt_entry(entry)?;
+            let thumbnail_warning = entry.thumbnail_warning();
+            // A thumbnail's own hash, which isn't the stored one of the original
+            let hash = match &content {
+                DecryptedEntry::Image(img_data) if thumbnail_warning.is_some() => {
+                    crate::watcher::LocalClipboardWatcher::hash_data(&img_data.hash_input())
+                }
+                _ => entry.hash.clone(),
+            };
+            let flavor = entry.text_flavor.clone();
+            // Back to the selection it was captured from
+            let selection = entry.selection.unwrap_or(ClipboardSelection::Clipboard);
+            let target = selection.target();
+
+            // Copy to clipboard, with a watcher in this process paused meanwhile
+            let mut clipboard = arboard::Clipboard::new().context("Failed to access clipboard")?;
+            let _writing = self.db.begin_clipboard_write();
+            self.db.record_clipboard_write(&hash);
+
+            match content {
+                DecryptedEntry::Text(text) => {
+                    crate::watcher::set_text_entry(&mut clipboard, &text, selection)?;
+                    let flavor = flavor.filter(|_| selection == ClipboardSelection::Clipboard);
+                    match flavor.zip(text.as_str().ok()) {
+                        Some((flavor, text)) => {
+                            match crate::watcher::add_text_flavor(&flavor, text) {
+                                Ok(()) => self.set_message(format!(
+                                    "Text copied to {} (also as {})",
+                                    target, flavor
+                                )),
+                                Err(e) => self.set_message(format!(
+                                    "Text copied to {}, but not as {}: {:#}",
+                                    target, flavor, e
+                                )),
+                            }
+                        }
+                        None => self.set_message(format!("Text copied to {}", target)),
                     }
                 }
+                DecryptedEntry::Image(img_data) => {
+                    img_data.check_well_formed()?;
+                    let arboard_img = arboard::ImageData {
+                        width: img_data.width,
+                        height: img_data.height,
+                        bytes: img_data.bytes.into(),
+                    };
+
+                    crate::watcher::set_image(&mut clipboard, arboard_img, selection)
+                        .context("Failed to set clipboard image")?;
+
+                    self.set_message(match thumbnail_warning {
+                        Some(warning) => format!(
+                            "Thumbnail copied to {} ({}x{}). {}",
+                            target, img_data.width, img_data.height, warning
+                        ),
+                        None => format!(
+                            "Image copied to {} ({}x{})",
+                            target, img_data.width, img_data.height
+                        ),
+                    });
+                }
             }
         }
         Ok(())
     }
 
+    /// Whether `o` shows images in the terminal rather than in another application
+    fn opens_images_inline(&self) -> bool {
+        match self.open_images {
+            ImageOpen::Auto => self.kitty_graphics,
+            ImageOpen::Inline => true,
+            ImageOpen::External => false,
+        }
+    }
+
     fn open_selected(&mut self) -> Result<()> {
-        if let Some(index) = self.list_state.selected() {
-            if index < self.entries.len() {
-                let entry = &self.entries[index];
-
-                // Decrypt entry
-                let plaintext =
-                    decrypt(&self.key, &entry.payload).context("Failed to decrypt entry")?;
-
-                match entry.content_type {
-                    ClipboardContentType::Text => {
-                        let text =
-                            String::from_utf8(plaintext).context("Entry contains invalid UTF-8")?;
-
-                        // Create temporary file with .txt extension
-                        let temp_dir = std::env::temp_dir().join("clpd_temp");
-                        std::fs::create_dir_all(&temp_dir)
-                            .context("Failed to create temporary directory")?;
-                        let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
-                        let file_name = format!("clpd_text_{}.txt", timestamp);
-                        let temp_path = temp_dir.join(file_name);
-
-                        // Write text to file
-                        std::fs::write(&temp_path, text)
-                            .context("Failed to write temporary file")?;
-
-                        // Open with default application
-                        #[cfg(target_os = "windows")]
-                        std::process::Command::new("cmd")
-                            .args(["/C", "start", "", temp_path.to_str().unwrap()])
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        #[cfg(target_os = "macos")]
-                        std::process::Command::new("open")
-                            .arg(&temp_path)
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        #[cfg(target_os = "linux")]
-                        std::process::Command::new("xdg-open")
-                            .arg(&temp_path)
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        self.set_message(format!("Opened: {}", temp_path.display()));
+        // Images viewed in the terminal are never written to disk
+        if self
+            .get_selected_entry()
+            .is_some_and(|entry| entry.content_type == ClipboardContentType::Image)
+            && self.opens_images_inline()
+        {
+            if self.get_image_data()?.is_some() {
+                self.image_view = Some(if self.kitty_graphics {
+                    ImageView::Kitty {
+                        area: Rect::default(),
+                        placed: false,
                     }
-                    ClipboardContentType::Image => {
-                        let img_data: ImageData = bincode::deserialize(&plaintext)
-                            .context("Failed to deserialize image data")?;
-
-                        // Create temporary file with .png extension
-                        let temp_dir = std::env::temp_dir().join("clpd_temp");
-                        std::fs::create_dir_all(&temp_dir)
-                            .context("Failed to create temporary directory")?;
-                        let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
-                        let file_name = format!("clpd_image_{}.png", timestamp);
-                        let temp_path = temp_dir.join(file_name);
-
-                        // Convert to PNG and save
-                        let img = image::RgbaImage::from_raw(
-                            img_data.width as u32,
-                            img_data.height as u32,
-                            img_data.bytes,
-                        )
-                        .ok_or_else(|| anyhow::anyhow!("Failed to create image from data"))?;
-
-                        img.save(&temp_path).context("Failed to save image file")?;
-
-                        // Open with default application
-                        #[cfg(target_os = "windows")]
-                        std::process::Command::new("cmd")
-                            .args(["/C", "start", "", temp_path.to_str().unwrap()])
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        #[cfg(target_os = "macos")]
-                        std::process::Command::new("open")
-                            .arg(&temp_path)
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        #[cfg(target_os = "linux")]
-                        std::process::Command::new("xdg-open")
-                            .arg(&temp_path)
-                            .spawn()
-                            .context("Failed to open file")?;
-
-                        self.set_message(format!(
-                            "Opened: {} ({}x{})",
-                            temp_path.display(),
-                            img_data.width,
-                            img_data.height
-                        ));
+                } else {
+                    ImageView::Blocks
+                });
+            }
+            return Ok(());
+        }
+
+        if let Some(index) = self.list_state.selected()
+            && index < self.entries.len()
+        {
+            let entry = &self.entries[index];
+
+            let content = self.decrypt_entry(entry)?;
+            let thumbnail_warning = entry.thumbnail_warning();
+
+            match content {
+                DecryptedEntry::Text(text) => {
+                    // Create temporary file with .txt extension
+                    let temp_dir = tempfiles::dir();
+                    std::fs::create_dir_all(&temp_dir)
+                        .context("Failed to create temporary directory")?;
+                    let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
+                    let file_name = format!("clpd_text_{}.txt", timestamp);
+                    let temp_path = temp_dir.join(file_name);
+
+                    // Write text to file
+                    std::fs::write(&temp_path, text.as_bytes())
+                        .context("Failed to write temporary file")?;
+                    self.temp_files.register(temp_path.clone());
+
+                    // Open with default application
+                    #[cfg(target_os = "windows")]
+                    std::process::Command::new("cmd")
+                        .args(["/C", "start", "", temp_path.to_str().unwrap()])
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    #[cfg(target_os = "macos")]
+                    std::process::Command::new("open")
+                        .arg(&temp_path)
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    #[cfg(target_os = "linux")]
+                    std::process::Command::new("xdg-open")
+                        .arg(&temp_path)
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    self.set_message(format!("Opened: {}", temp_path.display()));
+                }
+                DecryptedEntry::Image(img_data) => {
+                    // Create temporary file with .png extension
+                    let temp_dir = tempfiles::dir();
+                    std::fs::create_dir_all(&temp_dir)
+                        .context("Failed to create temporary directory")?;
+                    let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
+                    let file_name = format!("clpd_image_{}.png", timestamp);
+                    let temp_path = temp_dir.join(file_name);
+
+                    // Convert to PNG and save
+                    let (width, height) = (img_data.width, img_data.height);
+                    let img = img_data.into_rgba_image()?;
+
+                    img.save(&temp_path).context("Failed to save image file")?;
+                    self.temp_files.register(temp_path.clone());
+
+                    // Open with default application
+                    #[cfg(target_os = "windows")]
+                    std::process::Command::new("cmd")
+                        .args(["/C", "start", "", temp_path.to_str().unwrap()])
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    #[cfg(target_os = "macos")]
+                    std::process::Command::new("open")
+                        .arg(&temp_path)
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    #[cfg(target_os = "linux")]
+                    std::process::Command::new("xdg-open")
+                        .arg(&temp_path)
+                        .spawn()
+                        .context("Failed to open file")?;
+
+                    let mut message =
+                        format!("Opened: {} ({}x{})", temp_path.display(), width, height);
+                    if let Some(warning) = thumbnail_warning {
+                        message = format!("{}. {}", message, warning);
                     }
+                    self.set_message(message);
                 }
             }
         }
@@ -336,20 +909,60 @@ impl App {
     }
 
     async fn refresh(&mut self) -> Result<()> {
-        self.entries = self.db.list_entries().await?;
+        self.reload_entries().await?;
+        self.set_message("Entries refreshed".to_string());
+        Ok(())
+    }
 
-        // Adjust selection if needed
-        if self.entries.is_empty() {
-            self.list_state.select(None);
-        } else if let Some(index) = self.list_state.selected() {
-            if index >= self.entries.len() {
-                self.list_state.select(Some(self.entries.len() - 1));
-            }
-        } else {
-            self.list_state.select(Some(0));
+    /// Load the entries again, keeping the selected entry selected wherever it
+    /// moved; if it's gone, the selection stays at the same position
+    async fn reload_entries(&mut self) -> Result<()> {
+        let selected_id = self.get_selected_entry().map(|entry| entry.id.clone());
+        (self.entries, self.stored_count) = load_entries(&self.db, self.selection).await?;
+        let ids: HashSet<&str> = self.entries.iter().map(|entry| entry.id.as_str()).collect();
+        self.readable.retain(|id, _| ids.contains(id.as_str()));
+
+        let index =
+            match selected_id.and_then(|id| self.entries.iter().position(|entry| entry.id == id)) {
+                Some(index) => Some(index),
+                None if self.entries.is_empty() => None,
+                None => Some(
+                    self.list_state
+                        .selected()
+                        .unwrap_or(0)
+                        .min(self.entries.len() - 1),
+                ),
+            };
+        self.list_state.select(index);
+        Ok(())
+    }
+
+    /// Pick up entries stored by the background watcher, keeping the selection
+    async fn reload_if_changed(&mut self) -> Result<()> {
+        if !self.live || self.last_reload.elapsed() < Duration::from_secs(1) {
+            return Ok(());
         }
+        self.last_reload = Instant::now();
 
-        self.set_message("Entries refreshed".to_string());
+        if self.db.count_entries().await? == self.stored_count {
+            return Ok(());
+        }
+        self.reload_entries().await
+    }
+
+    /// Flush deletions to disk once they've waited long enough
+    async fn flush_if_due(&mut self) -> Result<()> {
+        if self.pending_flush.is_due(Instant::now()) {
+            self.flush_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_pending(&mut self) -> Result<()> {
+        if self.pending_flush.deletes > 0 {
+            self.db.flush().await?;
+            self.pending_flush.take();
+        }
         Ok(())
     }
 
@@ -364,38 +977,46 @@ impl App {
 
     fn clear_old_message(&mut self) {
         // Clear message after 10 seconds
-        if let Some(time) = self.message_time {
-            if time.elapsed() > std::time::Duration::from_secs(10) {
-                self.message = None;
-                self.message_time = None;
-            }
+        if let Some(time) = self.message_time
+            && time.elapsed() > std::time::Duration::from_secs(10)
+        {
+            self.message = None;
+            self.message_time = None;
         }
     }
 
-    fn render_preview_text(&self) -> Result<Text<'static>> {
+    fn render_preview_text(&mut self) -> Result<Text<'static>> {
+        if let Some((id, text)) = &self.text_cache
+            && self
+                .get_selected_entry()
+                .is_some_and(|entry| entry.id == *id)
+        {
+            return Ok(text.clone());
+        }
+
         if let Some(entry) = self.get_selected_entry() {
-            // Decrypt entry
-            let plaintext =
-                decrypt(&self.key, &entry.payload).context("Failed to decrypt entry")?;
-
-            match entry.content_type {
-                ClipboardContentType::Text => {
-                    let text = String::from_utf8_lossy(&plaintext);
-                    Ok(Text::from(text.to_string()))
+            // Image metadata is stored in cleartext, no need to decrypt
+            if let Some(image) = entry.image {
+                return Ok(Text::from(format!(
+                    "Image Preview\n\nDimensions: {} x {} pixels\nSize: {} bytes",
+                    image.width, image.height, image.size
+                )));
+            }
+
+            match self.decrypt_entry(entry)? {
+                DecryptedEntry::Text(text) => {
+                    let preview = text_preview(&text.lossy(), self.search.as_deref());
+                    self.text_cache = Some((entry.id.clone(), preview.clone()));
+                    Ok(preview)
                 }
-                ClipboardContentType::Image => {
-                    match bincode::deserialize::<ImageData>(&plaintext) {
-                        Ok(img_data) => {
-                            let preview_text = format!(
-                                "Image Preview\n\nDimensions: {} x {} pixels\nSize: {} bytes",
-                                img_data.width,
-                                img_data.height,
-                                img_data.bytes.len()
-                            );
-                            Ok(Text::from(preview_text))
-                        }
-                        Err(_) => Ok(Text::from("Failed to deserialize image data")),
-                    }
+                DecryptedEntry::Image(img_data) => {
+                    let preview_text = format!(
+                        "Image Preview\n\nDimensions: {} x {} pixels\nSize: {} bytes",
+                        img_data.width,
+                        img_data.height,
+                        img_data.bytes.len()
+                    );
+                    Ok(Text::from(preview_text))
                 }
             }
         } else {
@@ -403,22 +1024,189 @@ impl App {
         }
     }
 
-    fn get_image_data(&self) -> Result<Option<ImageData>> {
-        if let Some(entry) = self.get_selected_entry() {
-            if entry.content_type == ClipboardContentType::Image {
-                let plaintext =
-                    decrypt(&self.key, &entry.payload).context("Failed to decrypt entry")?;
-                let img_data: ImageData =
-                    bincode::deserialize(&plaintext).context("Failed to deserialize image data")?;
-                return Ok(Some(img_data));
+    /// Decrypt an entry, taking the base of delta entries from the loaded list
+    fn decrypt_entry(&self, entry: &ClipboardEntry) -> Result<DecryptedEntry> {
+        entry.decrypt_content(&self.key, self.delta_base_of(entry))
+    }
+
+    fn delta_base_of(&self, entry: &ClipboardEntry) -> Option<&ClipboardEntry> {
+        entry
+            .delta_base
+            .as_ref()
+            .and_then(|id| self.entries.iter().find(|base| base.id == *id))
+    }
+
+    /// Send the image of the image view to the terminal once its area is known,
+    /// or take a closed one off the screen
+    fn update_inline_image(&mut self, out: &mut impl Write) -> Result<()> {
+        if std::mem::take(&mut self.remove_inline_image) {
+            write!(out, "{}", kitty::DELETE_ALL)?;
+            out.flush()?;
+        }
+
+        let Some(ImageView::Kitty {
+            area,
+            placed: false,
+        }) = self.image_view
+        else {
+            return Ok(());
+        };
+        let Some(img_data) = self.get_image_data()? else {
+            return Ok(());
+        };
+        let (columns, rows) = kitty::fit(
+            (img_data.width, img_data.height),
+            (area.width, area.height),
+            cell_size(),
+        );
+        let mut png = Vec::new();
+        img_data.write_png(&mut png)?;
+
+        // An earlier placement is left over after a resize
+        write!(out, "{}", kitty::DELETE_ALL)?;
+        queue!(out, MoveTo(area.x, area.y))?;
+        write!(out, "{}", kitty::place_png(&png, columns, rows))?;
+        out.flush()?;
+        self.image_view = Some(ImageView::Kitty { area, placed: true });
+        Ok(())
+    }
+
+    fn get_image_data(&mut self) -> Result<Option<&ImageData>> {
+        let Some(entry) = self.get_selected_entry() else {
+            return Ok(None);
+        };
+        if entry.content_type != ClipboardContentType::Image {
+            return Ok(None);
+        }
+
+        let cached = matches!(&self.image_cache, Some((id, _)) if *id == entry.id);
+        if !cached {
+            let DecryptedEntry::Image(img_data) = self.decrypt_entry(entry)? else {
+                return Ok(None);
+            };
+            img_data.check_well_formed()?;
+            self.image_cache = Some((entry.id.clone(), img_data));
+        }
+
+        Ok(self.image_cache.as_ref().map(|(_, img_data)| img_data))
+    }
+}
+
+/// Longest line shown in the text preview; wrapping is slow for lines of
+/// minified code or base64 that are megabytes long, and they're unreadable anyway
+const MAX_PREVIEW_LINE_CHARS: usize = 4000;
+
+/// How occurrences of the search query are shown in the preview
+const MATCH_STYLE: Style = Style::new()
+    .fg(Color::Black)
+    .bg(Color::Yellow)
+    .add_modifier(Modifier::BOLD);
+
+/// Spans for a line of text, with the occurrences of `query` highlighted
+fn highlighted_spans(line: &str, query: Option<&str>) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut shown = 0;
+    for range in query.map_or_else(Vec::new, |query| match_ranges(line, query, true)) {
+        if range.start > shown {
+            spans.push(Span::raw(line[shown..range.start].to_string()));
+        }
+        spans.push(Span::styled(line[range.clone()].to_string(), MATCH_STYLE));
+        shown = range.end;
+    }
+    if shown < line.len() || spans.is_empty() {
+        spans.push(Span::raw(line[shown..].to_string()));
+    }
+    spans
+}
+
+/// Preview of text content, with overlong lines cut off and marked as such, and
+/// the occurrences of `query` highlighted
+fn text_preview(text: &str, query: Option<&str>) -> Text<'static> {
+    let lines = text.lines().map(
+        |line| match line.char_indices().nth(MAX_PREVIEW_LINE_CHARS) {
+            None => Line::from(highlighted_spans(line, query)),
+            Some((cut, _)) => {
+                let hidden = line[cut..].chars().count();
+                let mut spans = highlighted_spans(&line[..cut], query);
+                spans.push(Span::styled(
+                    format!(
+                        " … [{} more characters on this line, press o to open the full text]",
+                        hidden
+                    ),
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::ITALIC),
+                ));
+                Line::from(spans)
             }
+        },
+    );
+    Text::from(lines.collect::<Vec<_>>())
+}
+
+/// Rows to scroll a wrapped preview `width` columns wide by so the first
+/// highlighted match shows, a couple of lines below the top
+fn scroll_to_first_match(text: &Text, width: u16) -> u16 {
+    let Some(line) = text
+        .lines
+        .iter()
+        .position(|line| line.spans.iter().any(|span| span.style == MATCH_STYLE))
+    else {
+        return 0;
+    };
+    let width = usize::from(width.max(1));
+    let rows: usize = text.lines[..line]
+        .iter()
+        .map(|line| line.width().div_ceil(width).max(1))
+        .sum();
+    rows.saturating_sub(2).try_into().unwrap_or(u16::MAX)
+}
+
+/// Split entries (newest first) into sessions of copies made close together
+///
+/// A new session starts wherever more than `gap` passed between two consecutive
+/// entries. Returns index ranges into `entries`, newest session first.
+fn session_ranges(entries: &[ClipboardEntry], gap: Duration) -> Vec<Range<usize>> {
+    let gap = chrono::Duration::from_std(gap).unwrap_or(chrono::Duration::MAX);
+    let mut sessions = Vec::new();
+    let mut start = 0;
+    for i in 1..entries.len() {
+        if entries[i - 1].timestamp - entries[i].timestamp > gap {
+            sessions.push(start..i);
+            start = i;
         }
-        Ok(None)
     }
+    if start < entries.len() {
+        sessions.push(start..entries.len());
+    }
+    sessions
+}
+
+/// Header row for a session, showing when it started and ended
+fn session_header(session: &[ClipboardEntry]) -> ListItem<'static> {
+    let (Some(newest), Some(oldest)) = (session.first(), session.last()) else {
+        return ListItem::new("");
+    };
+    let end_format = if newest.timestamp.date_naive() == oldest.timestamp.date_naive() {
+        "%H:%M"
+    } else {
+        "%m-%d %H:%M"
+    };
+    let header = format!(
+        "── {}–{} ({}) ──",
+        oldest.timestamp.format("%m-%d %H:%M"),
+        newest.timestamp.format(end_format),
+        session.len()
+    );
+    ListItem::new(header).style(
+        Style::default()
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::BOLD),
+    )
 }
 
 /// Run the TUI
-pub async fn run(db: ClipboardType, key: MasterKey) -> Result<()> {
+pub async fn run(db: ClipboardType, key: MasterKey, options: TuiOptions) -> Result<()> {
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -427,7 +1215,7 @@ pub async fn run(db: ClipboardType, key: MasterKey) -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // Create app
-    let mut app = App::new(db, key).await?;
+    let mut app = App::new(db, key, options).await?;
 
     // Main loop
     let res = run_app(&mut terminal, &mut app).await;
@@ -441,6 +1229,11 @@ pub async fn run(db: ClipboardType, key: MasterKey) -> Result<()> {
         eprintln!("Error: {:?}", err);
     }
 
+    // Whatever happened, deletions made in the TUI must reach the disk
+    app.flush_pending()
+        .await
+        .context("Failed to save deleted entries")?;
+
     Ok(())
 }
 
@@ -451,13 +1244,16 @@ async fn run_app<B: ratatui::backend::Backend>(
     loop {
         // Clear old messages
         app.clear_old_message();
+        app.reload_if_changed().await?;
+        app.flush_if_due().await?;
 
         terminal.draw(|f| ui(f, app))?;
+        app.update_inline_image(&mut io::stdout())?;
 
-        if event::poll(std::time::Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                app.handle_key(key).await?;
-            }
+        if event::poll(std::time::Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+        {
+            app.handle_key(key).await?;
         }
 
         if app.should_quit {
@@ -469,13 +1265,27 @@ async fn run_app<B: ratatui::backend::Backend>(
 }
 
 fn ui(f: &mut Frame, app: &mut App) {
+    let area = f.area();
+    if app.image_view.is_some() {
+        render_image_view(f, app, area);
+        return;
+    }
+    if area.width < COMPACT_WIDTH || area.height < COMPACT_HEIGHT {
+        if app.compact_preview {
+            render_preview(f, app, area);
+        } else {
+            render_entry_list(f, app, area);
+        }
+        return;
+    }
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Min(3),    // Main content area
             Constraint::Length(3), // Bottom bar (status + controls)
         ])
-        .split(f.area());
+        .split(area);
 
     // Main area split into left (list) and right (preview)
     let main_chunks = Layout::default()
@@ -503,43 +1313,92 @@ fn ui(f: &mut Frame, app: &mut App) {
 }
 
 fn render_entry_list(f: &mut Frame, app: &mut App, area: Rect) {
-    let items: Vec<ListItem> = app
-        .entries
-        .iter()
-        .enumerate()
-        .map(|(i, entry)| {
-            let type_icon = match entry.content_type {
-                ClipboardContentType::Text => "📝",
-                ClipboardContentType::Image => "🖼️",
-            };
+    // Headers are only displayed, so navigation (by entry index) never lands on them
+    let sessions = if app.grouped {
+        session_ranges(&app.entries, app.session_gap)
+    } else {
+        std::iter::once(0..app.entries.len()).collect()
+    };
+    let mut rows = Vec::new();
+    for session in &sessions {
+        if app.grouped {
+            rows.push(None);
+        }
+        rows.extend(session.clone().map(Some));
+    }
+    let selected_row = app
+        .list_state
+        .selected()
+        .and_then(|selected| rows.iter().position(|&row| row == Some(selected)));
+
+    // Only the entries on screen are checked, scrolled the way the list will be
+    let height = area.height.saturating_sub(2) as usize;
+    let mut first = app.display_state.offset();
+    if let Some(row) = selected_row {
+        first = first.min(row).max((row + 1).saturating_sub(height));
+    }
+    let visible = rows.iter().skip(first).take(height).flatten().copied();
+    app.check_readability(visible.collect::<Vec<_>>());
+
+    let entry_item = |i: usize, entry: &ClipboardEntry| {
+        let unreadable = app.is_unreadable(entry);
+        let type_icon = match entry.content_type {
+            _ if unreadable => "⚠️",
+            ClipboardContentType::Text => "📝",
+            ClipboardContentType::Image => "🖼️",
+        };
 
-            let time_str = entry.timestamp.format("%H:%M:%S").to_string();
-            let content = format!(
-                "{} {} | {}",
-                type_icon,
-                time_str,
-                &entry.id[..entry.id.len()]
-            );
-
-            let style = if Some(i) == app.list_state.selected() {
-                Style::default()
-                    .fg(Color::Black)
-                    .bg(Color::Cyan)
-                    .add_modifier(Modifier::BOLD)
-            } else {
-                Style::default()
-            };
+        let time_str = entry.timestamp.format("%H:%M:%S").to_string();
+        let pin = if entry.pinned { " 📌" } else { "" };
+        let content = format!(
+            "{} {} | {}{}",
+            type_icon,
+            time_str,
+            &entry.id[..entry.id.len()],
+            pin
+        );
 
-            ListItem::new(content).style(style)
-        })
-        .collect();
+        let style = if Some(i) == app.list_state.selected() {
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Cyan)
+                .add_modifier(Modifier::BOLD)
+        } else if unreadable {
+            Style::default().fg(Color::Red)
+        } else {
+            Style::default()
+        };
+
+        ListItem::new(content).style(style)
+    };
 
-    let title = format!(" Clipboard History ({}) ", app.entries.len());
+    let mut items = Vec::new();
+    for session in &sessions {
+        if app.grouped {
+            items.push(session_header(&app.entries[session.clone()]));
+        }
+        for i in session.clone() {
+            items.push(entry_item(i, &app.entries[i]));
+        }
+    }
+
+    let title = if app.grouped {
+        format!(
+            " Clipboard History ({}, {} sessions) ",
+            app.entries.len(),
+            sessions.len()
+        )
+    } else {
+        format!(" Clipboard History ({}) ", app.entries.len())
+    };
+    // Which history this is, so entries aren't deleted from the wrong one
+    let location = format!(" {} ", app.db.location());
     let list = List::new(items)
         .block(
             Block::default()
                 .borders(Borders::ALL)
                 .title(title)
+                .title_bottom(Line::from(location).right_aligned())
                 .border_style(Style::default().fg(Color::Cyan)),
         )
         .highlight_style(
@@ -549,7 +1408,8 @@ fn render_entry_list(f: &mut Frame, app: &mut App, area: Rect) {
                 .add_modifier(Modifier::BOLD),
         );
 
-    f.render_stateful_widget(list, area, &mut app.list_state);
+    app.display_state.select(selected_row);
+    f.render_stateful_widget(list, area, &mut app.display_state);
 }
 
 fn render_preview(f: &mut Frame, app: &mut App, area: Rect) {
@@ -557,7 +1417,7 @@ fn render_preview(f: &mut Frame, app: &mut App, area: Rect) {
     if let Ok(Some(img_data)) = app.get_image_data() {
         // For images, create a visual representation using ASCII/block characters
         let preview_text = create_image_preview(
-            &img_data,
+            img_data,
             area.width.saturating_sub(2),
             area.height.saturating_sub(2),
         );
@@ -579,23 +1439,96 @@ fn render_preview(f: &mut Frame, app: &mut App, area: Rect) {
     }
 
     // Fallback to text preview
-    let preview_text = app
-        .render_preview_text()
-        .unwrap_or_else(|e| Text::from(format!("Error decrypting entry: {}", e)));
+    let preview_text = app.render_preview_text().unwrap_or_else(|e| {
+        Text::from(format!(
+            "Error decrypting entry: {}\n\nPress x to export it as stored (still encrypted), \
+                 or D to delete all unreadable entries.",
+            e
+        ))
+    });
+
+    // Scrolled to the first occurrence of the search query
+    let matches = preview_text
+        .lines
+        .iter()
+        .flat_map(|line| &line.spans)
+        .filter(|span| span.style == MATCH_STYLE)
+        .count();
+    let (title, scroll) = match &app.search {
+        Some(query) if matches > 0 => (
+            format!(" Preview ({} matches for '{}') ", matches, query),
+            scroll_to_first_match(&preview_text, area.width.saturating_sub(2)),
+        ),
+        _ => (" Preview ".to_string(), 0),
+    };
 
     let paragraph = Paragraph::new(preview_text)
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .title(" Preview ")
+                .title(title)
                 .border_style(Style::default().fg(Color::Cyan)),
         )
-        .wrap(Wrap { trim: false });
+        .wrap(Wrap { trim: false })
+        .scroll((scroll, 0));
 
     f.render_widget(paragraph, area);
 }
 
+/// An image over the whole terminal, opened with `o`
+fn render_image_view(f: &mut Frame, app: &mut App, area: Rect) {
+    let view = app.image_view;
+    let Ok(Some(img_data)) = app.get_image_data() else {
+        app.image_view = None;
+        return;
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!(" Image ({}x{}) ", img_data.width, img_data.height))
+        .title_bottom(Line::from(" Press any key to close ").right_aligned())
+        .border_style(Style::default().fg(Color::Cyan));
+    let inner = block.inner(area);
+
+    match view {
+        Some(ImageView::Kitty {
+            area: placed_in, ..
+        }) => {
+            f.render_widget(block, area);
+            // The terminal draws the image itself, over the empty block
+            if placed_in != inner {
+                app.image_view = Some(ImageView::Kitty {
+                    area: inner,
+                    placed: false,
+                });
+            }
+        }
+        _ => {
+            let image = create_image_preview(img_data, inner.width, inner.height);
+            f.render_widget(Paragraph::new(image).block(block), area);
+        }
+    }
+}
+
+/// Size of a terminal cell in pixels, guessed when the terminal doesn't say
+fn cell_size() -> (u16, u16) {
+    match crossterm::terminal::window_size() {
+        Ok(size) if size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0 => {
+            (size.width / size.columns, size.height / size.rows)
+        }
+        _ => (10, 20),
+    }
+}
+
 fn create_image_preview(img_data: &ImageData, max_width: u16, max_height: u16) -> Text<'static> {
+    // Indexing below relies on the pixel data matching the dimensions
+    if let Err(e) = img_data.check_well_formed() {
+        return Text::from(e.to_string());
+    }
+    // Nothing fits, e.g. the borders took up a tiny terminal
+    if max_width == 0 || max_height == 0 {
+        return Text::default();
+    }
+
     // Calculate downsampling ratio
     // With half-block chars, each line represents 2 vertical pixels
     let width_ratio = img_data.width as f32 / max_width.max(1) as f32;
@@ -676,8 +1609,13 @@ fn create_image_preview(img_data: &ImageData, max_width: u16, max_height: u16) -
 }
 
 fn render_status_bar(f: &mut Frame, app: &mut App, area: Rect) {
-    // Display message if present, otherwise show empty space
-    let status_text = if let Some(msg) = &app.message {
+    // The search being typed, else the message if present, otherwise empty space
+    let status_text = if app.search_input {
+        vec![Line::from(format!(
+            "/{}",
+            app.search.as_deref().unwrap_or_default()
+        ))]
+    } else if let Some(msg) = &app.message {
         vec![Line::from(vec![Span::styled(
             msg.as_str(),
             Style::default().fg(Color::Green),
@@ -686,9 +1624,16 @@ fn render_status_bar(f: &mut Frame, app: &mut App, area: Rect) {
         vec![Line::from(vec![Span::raw("")])]
     };
 
+    let registers = app.registers.summary();
+    let title = match app.registers.selected {
+        Some(name) if registers.is_empty() => format!("Status [\"{}]", name),
+        Some(name) => format!("Status [\"{}] {}", name, registers),
+        None if registers.is_empty() => "Status".to_string(),
+        None => format!("Status {}", registers),
+    };
     let status = Paragraph::new(status_text).block(
         Block::default()
-            .title("Status")
+            .title(title)
             .borders(Borders::ALL)
             .border_style(Style::default().fg(Color::Cyan)),
     );
@@ -701,9 +1646,15 @@ fn render_controls_bar(f: &mut Frame, area: Rect) {
         // Span::styled("Controls: ", Style::default().add_modifier(Modifier::BOLD)),
         Span::raw("Navigate: ↑↓/j/k || "),
         Span::raw("Copy: Enter/c || "),
-        Span::raw("Open: o || "),
+        Span::raw("Open/view image: o || "),
         Span::raw("Delete: d || "),
+        Span::raw("To top: t || "),
+        Span::raw("Unreadable: x export/D delete all || "),
+        Span::raw("Search: / then n/N next/previous, Esc clear || "),
         Span::raw("Refresh: r || "),
+        Span::raw("Group: g || "),
+        Span::raw("Small terminal: Tab list/preview || "),
+        Span::raw("Registers: \"a-z then y yank/p copy/Y empty || "),
         Span::raw("Quit: q/Esc"),
     ])];
 
@@ -716,3 +1667,444 @@ fn render_controls_bar(f: &mut Frame, area: Rect) {
 
     f.render_widget(controls, area);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{capture_fixture, test_key};
+    use chrono::{TimeZone, Utc};
+
+    fn entry_at(minute: u32, second: u32) -> ClipboardEntry {
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), String::new());
+        entry.timestamp = Utc
+            .with_ymd_and_hms(2025, 1, 1, 12, minute, second)
+            .unwrap();
+        entry
+    }
+
+    #[test]
+    fn test_image_preview_of_degenerate_images() {
+        for image in [
+            ImageData::new(0, 0, vec![]),
+            ImageData::new(0, 3, vec![]),
+            ImageData::new(3, 3, vec![0; 8]),
+        ] {
+            let preview = create_image_preview(&image, 40, 20);
+            assert_eq!(preview.lines.len(), 1);
+        }
+
+        // A single row far wider than the preview
+        let preview = create_image_preview(&ImageData::new(1000, 1, vec![0; 4000]), 40, 20);
+        assert!(preview.lines.len() > 1);
+    }
+
+    #[test]
+    fn test_long_line_preview_is_cut() {
+        let short = text_preview("first line\nsecond line", None);
+        assert_eq!(short.lines.len(), 2);
+        assert_eq!(short.lines[1].to_string(), "second line");
+
+        let blob = "é".repeat(1024 * 1024);
+        let preview = text_preview(&format!("header\n{}", blob), None);
+        assert_eq!(preview.lines.len(), 2);
+        let line = preview.lines[1].to_string();
+        assert!(line.starts_with(&"é".repeat(MAX_PREVIEW_LINE_CHARS)));
+        assert!(line.contains(&format!(
+            "{} more characters",
+            1024 * 1024 - MAX_PREVIEW_LINE_CHARS
+        )));
+        assert!(line.chars().count() < MAX_PREVIEW_LINE_CHARS + 100);
+    }
+
+    #[test]
+    fn test_search_highlighting() {
+        let preview = text_preview("no match\nthe Key and the key\n\nkey", Some("KEY"));
+        let highlighted: Vec<Vec<String>> = preview
+            .lines
+            .iter()
+            .map(|line| {
+                line.spans
+                    .iter()
+                    .filter(|span| span.style == MATCH_STYLE)
+                    .map(|span| span.content.to_string())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            highlighted,
+            [
+                vec![],
+                vec!["Key".to_string(), "key".to_string()],
+                vec![],
+                vec!["key".to_string()]
+            ]
+        );
+        // The text itself is unchanged
+        assert_eq!(preview.lines[1].to_string(), "the Key and the key");
+        assert_eq!(scroll_to_first_match(&preview, 20), 0);
+
+        // Wrapped lines before the match count as several rows
+        let long = format!("{}\n\n\nneedle", "x".repeat(50));
+        assert_eq!(
+            scroll_to_first_match(&text_preview(&long, Some("needle")), 10),
+            5
+        );
+        assert_eq!(scroll_to_first_match(&text_preview(&long, None), 10), 0);
+    }
+
+    #[tokio::test]
+    async fn test_search_selects_matching_entries() {
+        let (_dir, _, mut watcher) = capture_fixture();
+        let key = test_key();
+        for text in ["Needle one", "hay", "line\nwith a needle", "more hay"] {
+            watcher.process_text(text).unwrap();
+        }
+        let mut app = App::new(
+            ClipboardType::Local(Box::new(watcher)),
+            key,
+            TuiOptions::default(),
+        )
+        .await
+        .unwrap();
+        let press = |c| KeyEvent::from(KeyCode::Char(c));
+
+        app.handle_key(press('/')).await.unwrap();
+        for c in "NEEDLX".chars() {
+            app.handle_key(press(c)).await.unwrap();
+        }
+        app.handle_key(KeyEvent::from(KeyCode::Backspace))
+            .await
+            .unwrap();
+        app.handle_key(press('e')).await.unwrap();
+        // Typed into the query rather than acting as commands
+        assert!(!app.should_quit);
+        app.handle_key(KeyEvent::from(KeyCode::Enter))
+            .await
+            .unwrap();
+        assert_eq!(app.search.as_deref(), Some("NEEDLe"));
+        // Newest first: the multi-line entry is the first match
+        assert_eq!(app.list_state.selected(), Some(1));
+
+        let backend = ratatui::backend::TestBackend::new(100, 20);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|c| c.symbol())
+            .collect();
+        assert!(screen.contains("1 matches for 'NEEDLe'"), "{}", screen);
+
+        app.handle_key(press('n')).await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(3));
+        app.handle_key(press('n')).await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(1));
+        app.handle_key(press('N')).await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(3));
+
+        // Esc clears the search before it quits
+        app.handle_key(KeyEvent::from(KeyCode::Esc)).await.unwrap();
+        assert_eq!(app.search, None);
+        assert!(!app.should_quit);
+        app.handle_key(press('n')).await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_tiny_terminal() {
+        let (_dir, _, mut watcher) = capture_fixture();
+        let key = test_key();
+        watcher.process_text("some text").unwrap();
+        let image = arboard::ImageData {
+            width: 4,
+            height: 4,
+            bytes: vec![255; 64].into(),
+        };
+        watcher.process_image_from(&image, None).unwrap();
+
+        let mut app = App::new(
+            ClipboardType::Local(Box::new(watcher)),
+            key,
+            TuiOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        for (width, height) in [(2, 2), (1, 1), (0, 0), (20, 5), (80, 3), (3, 40)] {
+            let backend = ratatui::backend::TestBackend::new(width, height);
+            let mut terminal = Terminal::new(backend).unwrap();
+            for index in 0..2 {
+                app.list_state.select(Some(index));
+                for _ in 0..2 {
+                    terminal.draw(|f| ui(f, &mut app)).unwrap();
+                    app.handle_key(KeyEvent::from(KeyCode::Tab)).await.unwrap();
+                }
+            }
+        }
+        assert!(
+            create_image_preview(&ImageData::new(1, 1, vec![0; 4]), 0, 5)
+                .lines
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inline_image_view() {
+        let (_dir, _, mut watcher) = capture_fixture();
+        let key = test_key();
+        let image = arboard::ImageData {
+            width: 2,
+            height: 2,
+            bytes: vec![255; 16].into(),
+        };
+        watcher.process_image_from(&image, None).unwrap();
+
+        let options = TuiOptions {
+            open_images: ImageOpen::Inline,
+            ..TuiOptions::default()
+        };
+        let mut app = App::new(ClipboardType::Local(Box::new(watcher)), key, options)
+            .await
+            .unwrap();
+        app.kitty_graphics = false;
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 24)).unwrap();
+
+        app.handle_key(KeyEvent::from(KeyCode::Char('o')))
+            .await
+            .unwrap();
+        assert_eq!(app.image_view, Some(ImageView::Blocks));
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("Image (2x2)"));
+        assert!(!screen.contains("Clipboard History"));
+
+        // Any key closes the view without acting on it
+        app.handle_key(KeyEvent::from(KeyCode::Char('q')))
+            .await
+            .unwrap();
+        assert_eq!(app.image_view, None);
+        assert!(!app.should_quit);
+
+        // Where the terminal displays images, the image is sent to it inline
+        app.kitty_graphics = true;
+        app.handle_key(KeyEvent::from(KeyCode::Char('o')))
+            .await
+            .unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let mut out = Vec::new();
+        app.update_inline_image(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\x1b_Ga=T,f=100"));
+        assert_eq!(
+            app.image_view,
+            Some(ImageView::Kitty {
+                area: Rect::new(1, 1, 78, 22),
+                placed: true
+            })
+        );
+        app.handle_key(KeyEvent::from(KeyCode::Esc)).await.unwrap();
+        let mut out = Vec::new();
+        app.update_inline_image(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), kitty::DELETE_ALL);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_keeps_selected_entry() {
+        let (_dir, db, mut capture) = capture_fixture();
+        let key = test_key();
+        for text in ["first", "second", "third"] {
+            capture.process_text(text).unwrap();
+        }
+
+        let browser =
+            crate::watcher::LocalClipboardWatcher::without_clipboard(db.clone(), key.clone());
+        let mut app = App::new(
+            ClipboardType::Local(Box::new(browser)),
+            key,
+            TuiOptions::default(),
+        )
+        .await
+        .unwrap();
+        app.list_state.select(Some(1));
+        let selected = app.get_selected_entry().unwrap().id.clone();
+
+        // A new entry at the top moves the selected one down
+        capture.process_text("fourth").unwrap();
+        app.handle_key(KeyEvent::from(KeyCode::Char('r')))
+            .await
+            .unwrap();
+        assert_eq!(app.entries.len(), 4);
+        assert_eq!(app.list_state.selected(), Some(2));
+        assert_eq!(app.get_selected_entry().unwrap().id, selected);
+
+        // Once it's gone, the position is kept
+        db.delete_entry(&selected).unwrap();
+        app.refresh().await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(2));
+        let last = app.entries.last().unwrap().id.clone();
+        assert_eq!(app.get_selected_entry().unwrap().id, last);
+        db.delete_entry(&last).unwrap();
+        app.refresh().await.unwrap();
+        assert_eq!(app.list_state.selected(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_registers() {
+        let (_dir, _, mut watcher) = capture_fixture();
+        let key = test_key();
+        for text in ["first", "second", "third"] {
+            watcher.process_text(text).unwrap();
+        }
+        let image = arboard::ImageData {
+            width: 1,
+            height: 1,
+            bytes: vec![0; 4].into(),
+        };
+        watcher.process_image_from(&image, None).unwrap();
+
+        let mut app = App::new(
+            ClipboardType::Local(Box::new(watcher)),
+            key,
+            TuiOptions::default(),
+        )
+        .await
+        .unwrap();
+        let press = |c| KeyEvent::from(KeyCode::Char(c));
+        let text_index = |app: &App, text: &str| {
+            app.entries
+                .iter()
+                .position(|entry| matches!(app.decrypt_entry(entry), Ok(DecryptedEntry::Text(t)) if t == text))
+                .unwrap()
+        };
+
+        // Nothing to yank into before a register is chosen
+        app.handle_key(press('y')).await.unwrap();
+        assert!(app.registers.summary().is_empty());
+
+        app.handle_key(press('"')).await.unwrap();
+        app.handle_key(press('b')).await.unwrap();
+        for text in ["third", "first"] {
+            app.list_state.select(Some(text_index(&app, text)));
+            app.handle_key(press('y')).await.unwrap();
+        }
+        // Images can't be combined with text
+        let image_index = app
+            .entries
+            .iter()
+            .position(|entry| entry.content_type == ClipboardContentType::Image);
+        app.list_state.select(image_index);
+        app.handle_key(press('y')).await.unwrap();
+
+        app.handle_key(press('"')).await.unwrap();
+        app.handle_key(press('a')).await.unwrap();
+        app.list_state.select(Some(text_index(&app, "second")));
+        app.handle_key(press('y')).await.unwrap();
+
+        assert_eq!(app.registers.summary(), "\"a:1 \"b:2");
+        assert_eq!(
+            app.register_text('b').unwrap(),
+            ("third\nfirst".to_string(), 2)
+        );
+
+        // Deleted entries drop out of registers
+        app.delete_selected().await.unwrap();
+        assert_eq!(app.register_text('a').unwrap(), (String::new(), 0));
+
+        // Not a register name, the selection stays
+        app.handle_key(press('"')).await.unwrap();
+        app.handle_key(press('1')).await.unwrap();
+        assert_eq!(app.registers.selected, Some('a'));
+        app.handle_key(press('Y')).await.unwrap();
+        assert_eq!(app.registers.summary(), "\"b:2");
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_entries() {
+        let (_dir, _, mut watcher) = capture_fixture();
+        let key = test_key();
+        watcher.process_text("fine").unwrap();
+        let mut corrupt = entry_at(0, 0);
+        corrupt.payload = vec![0; 40];
+        watcher.db.read().insert_entry(&corrupt).unwrap();
+        let shared = watcher.db.clone();
+
+        let mut app = App::new(
+            ClipboardType::Local(Box::new(watcher)),
+            key,
+            TuiOptions::default(),
+        )
+        .await
+        .unwrap();
+        let unreadable = |app: &App| -> Vec<String> {
+            app.entries
+                .iter()
+                .filter(|entry| app.is_unreadable(entry))
+                .map(|entry| entry.id.clone())
+                .collect()
+        };
+        // Checked once on screen
+        assert!(unreadable(&app).is_empty());
+        let backend = ratatui::backend::TestBackend::new(100, 20);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert_eq!(unreadable(&app), vec![corrupt.id.clone()]);
+
+        // Deleted only once confirmed
+        app.handle_key(KeyEvent::from(KeyCode::Char('D')))
+            .await
+            .unwrap();
+        assert_eq!(
+            app.message.as_deref(),
+            Some("⚠ Delete 1 unreadable entries? (y/N)")
+        );
+        app.handle_key(KeyEvent::from(KeyCode::Char('n')))
+            .await
+            .unwrap();
+        assert_eq!(app.entries.len(), 2);
+        app.handle_key(KeyEvent::from(KeyCode::Char('D')))
+            .await
+            .unwrap();
+        app.handle_key(KeyEvent::from(KeyCode::Char('y')))
+            .await
+            .unwrap();
+        assert_eq!(app.entries.len(), 1);
+        assert!(!app.is_unreadable(&app.entries[0]));
+        assert_eq!(app.list_state.selected(), Some(0));
+        app.flush_pending().await.unwrap();
+        assert_eq!(shared.read().count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_pending_flush() {
+        let start = Instant::now();
+        let mut pending = PendingFlush::default();
+        assert!(!pending.is_due(start + FLUSH_DELAY));
+
+        pending.record(start);
+        pending.record(start + Duration::from_millis(1500));
+        // Timed from the first deletion, so a steady stream of deletions still gets flushed
+        assert!(!pending.is_due(start + Duration::from_secs(1)));
+        assert!(pending.is_due(start + FLUSH_DELAY));
+
+        assert_eq!(pending.take(), 2);
+        assert!(!pending.is_due(start + FLUSH_DELAY * 2));
+        assert_eq!(pending.take(), 0);
+    }
+
+    #[test]
+    fn test_session_ranges() {
+        let gap = Duration::from_secs(5 * 60);
+        // Newest first: 12:40, 12:38, 12:33:00 | 12:27:59, 12:27 | 12:00
+        let entries: Vec<_> = [(40, 0), (38, 0), (33, 0), (27, 59), (27, 0), (0, 0)]
+            .into_iter()
+            .map(|(m, s)| entry_at(m, s))
+            .collect();
+
+        assert_eq!(session_ranges(&entries, gap), vec![0..3, 3..5, 5..6]);
+        assert_eq!(session_ranges(&entries, Duration::MAX).len(), 1);
+        assert_eq!(session_ranges(&entries[..1], gap).len(), 1);
+        assert!(session_ranges(&[], gap).is_empty());
+    }
+}
@@ -1,193 +1,3208 @@
 use anyhow::{Context, Result};
 use arboard::Clipboard;
+use parking_lot::RwLock;
+use rand::Rng;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::crypto::{MasterKey, encrypt};
-use crate::database::ClipboardDatabase;
-use crate::models::{ClipboardContentType, ClipboardEntry, ImageData};
+use crate::crypto::MasterKey;
+use crate::database::{ClipboardDatabase, SharedDatabase, UsageCounter};
+use crate::events::ChangeEvents;
+use crate::focus::{focused_app, ignored_app};
+use crate::hook::CaptureHook;
+use crate::models::{
+    ClipboardContentType, ClipboardEntry, ClipboardSelection, DEFAULT_MAX_IMAGE_BYTES, ImageData,
+    TextDelta, TextEntry, check_image_size, preview_text, strip_row_padding,
+};
+use crate::notify::{CaptureNotifier, QuietHours};
+use caseless::Caseless;
+use unicode_normalization::UnicodeNormalization;
 
-pub struct LocalClipboardWatcher {
-    clipboard: Clipboard,
-    pub db: ClipboardDatabase,
-    key: MasterKey,
-    last_hash: Option<String>,
-    max_entries: Option<usize>,
-    poll_interval: Duration,
+/// Default number of recently seen hashes remembered by the watcher
+pub const DEFAULT_DEDUPE_WINDOW: usize = 8;
+
+/// How often the clipboard is read, unless `--poll-interval` or the config file
+/// says otherwise
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often entries past `--max-age` are pruned when nothing is being captured
+const AGE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Options for the clipboard watcher started by `clpd start`
+#[derive(Debug, Clone)]
+pub struct WatcherOptions {
+    pub max_entries: Option<usize>,
+    pub max_age: Option<chrono::TimeDelta>,
+    pub dedupe_window: usize,
+    pub poll_interval: Duration,
+    pub poll_jitter: u8, // percent
+    pub events: bool,
+    pub notify: bool,
+    pub quiet_hours: Option<QuietHours>,
+    pub strip_ansi: bool,
+    pub max_image_bytes: usize,
+    pub images: ImageMode,
+    pub delta: bool,
+    pub on_change_only: bool,
+    pub skip_initial: bool,
+    pub on_capture: Option<String>,
+    pub swap_rb: bool,
+    pub canonical_images: bool,
+    pub max_rate: Option<CaptureRate>,
+    pub normalize: Option<TextNormalization>,
+    pub selections: Selections,
+    pub ignore_apps: Vec<String>,
+    pub verbose: bool,
 }
 
-impl LocalClipboardWatcher {
-    pub fn new(db: ClipboardDatabase, key: MasterKey, max_entries: Option<usize>) -> Result<Self> {
-        let clipboard = Clipboard::new().context("Failed to initialize clipboard")?;
+impl Default for WatcherOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: None,
+            max_age: None,
+            dedupe_window: DEFAULT_DEDUPE_WINDOW,
+            poll_interval: POLL_INTERVAL,
+            poll_jitter: 0,
+            events: false,
+            notify: false,
+            quiet_hours: None,
+            strip_ansi: false,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            images: ImageMode::Full,
+            delta: false,
+            on_change_only: false,
+            skip_initial: false,
+            on_capture: None,
+            swap_rb: false,
+            canonical_images: false,
+            max_rate: Some(CaptureRate::default()),
+            normalize: None,
+            selections: Selections::Clipboard,
+            ignore_apps: Vec::new(),
+            verbose: false,
+        }
+    }
+}
 
-        Ok(Self {
-            clipboard,
-            db,
-            key,
-            last_hash: None,
-            max_entries,
-            poll_interval: Duration::from_millis(500),
+/// The platform's clipboard change counter, if it has one
+///
+/// Reading it is much cheaper than reading the clipboard and doesn't open it,
+/// so it can't interfere with other applications.
+#[cfg(windows)]
+fn clipboard_change_count() -> Option<u64> {
+    clipboard_win::raw::seq_num().map(|n| u64::from(n.get()))
+}
+
+#[cfg(target_os = "macos")]
+fn clipboard_change_count() -> Option<u64> {
+    let pasteboard = objc2_app_kit::NSPasteboard::generalPasteboard();
+    Some(pasteboard.changeCount() as u64)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn clipboard_change_count() -> Option<u64> {
+    None
+}
+
+/// Names of the formats the clipboard currently offers, for diagnostics
+///
+/// arboard only reads text and images, so this goes to the platform's clipboard
+/// API directly.
+#[cfg(windows)]
+pub fn clipboard_formats() -> Result<Vec<String>> {
+    use clipboard_win::raw;
+
+    // Closes the clipboard again when dropped
+    let _open = clipboard_win::Clipboard::new_attempts(10)
+        .map_err(|e| anyhow::anyhow!("Failed to open clipboard: {}", e))?;
+    Ok(raw::EnumFormats::new()
+        .map(|format| {
+            raw::format_name_big(format).map_or_else(
+                || format!("#{}", format),
+                |name| format!("{} ({})", name, format),
+            )
         })
+        .collect())
+}
+
+#[cfg(target_os = "macos")]
+pub fn clipboard_formats() -> Result<Vec<String>> {
+    let pasteboard = objc2_app_kit::NSPasteboard::generalPasteboard();
+    Ok(pasteboard
+        .types()
+        .map(|types| types.iter().map(|t| t.to_string()).collect())
+        .unwrap_or_default())
+}
+
+/// On Linux and the BSDs the formats are MIME types (or X11 targets), listed with
+/// `wl-paste` on Wayland or `xclip` on X11
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn clipboard_formats() -> Result<Vec<String>> {
+    use std::process::Command;
+
+    let mut command = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let mut command = Command::new("wl-paste");
+        command.arg("--list-types");
+        command
+    } else {
+        let mut command = Command::new("xclip");
+        command.args(["-selection", "clipboard", "-target", "TARGETS", "-out"]);
+        command
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {} (is it installed?)", program))?;
+    // Both exit with an error when the clipboard is empty
+    if !output.status.success() {
+        return Ok(Vec::new());
     }
+    Ok(parse_format_list(&String::from_utf8_lossy(&output.stdout)))
+}
 
-    /// Calculate SHA-256 hash of data
-    pub(crate) fn hash_data(data: &[u8]) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        hex::encode(hasher.finalize())
+/// Plain-text formats that text set through arboard is always offered in, or that
+/// the platform adds by itself; only other text flavors are worth recording
+#[cfg_attr(not(any(windows, target_os = "macos")), allow(dead_code))]
+const GENERIC_TEXT_FLAVORS: &[&str] = &[
+    // Windows
+    "CF_UNICODETEXT",
+    "CF_TEXT",
+    "CF_OEMTEXT",
+    // macOS
+    "public.utf8-plain-text",
+    "public.utf16-plain-text",
+    "public.utf16-external-plain-text",
+    "public.plain-text",
+    "NSStringPboardType",
+    // Linux
+    "text/plain",
+    "text/plain;charset=utf-8",
+];
+
+/// The application-specific plain-text format among the clipboard's formats, if
+/// it offers one besides the generic ones
+///
+/// Only formats named as plain text count, so rich text or HTML flavors are never
+/// attached to the plain text that clpd stores.
+#[cfg_attr(not(any(windows, target_os = "macos")), allow(dead_code))]
+pub fn text_flavor(formats: &[String]) -> Option<String> {
+    formats
+        .iter()
+        .find(|format| {
+            let name = format.to_ascii_lowercase();
+            let plain_text = name.starts_with("text/plain")
+                || name.contains("plain-text")
+                || name.contains("plaintext");
+            plain_text
+                && !GENERIC_TEXT_FLAVORS
+                    .iter()
+                    .any(|generic| generic.eq_ignore_ascii_case(format))
+        })
+        .cloned()
+}
+
+/// The text flavor of what's on the clipboard now
+///
+/// Only Windows and macOS let clpd offer a flavor again when copying, so it's only
+/// looked up there. On Linux text is always offered under the usual `text/plain`
+/// types anyway.
+#[cfg(windows)]
+fn read_text_flavor() -> Option<String> {
+    use clipboard_win::raw;
+
+    let names: Vec<String> = {
+        let _open = clipboard_win::Clipboard::new_attempts(10).ok()?;
+        raw::EnumFormats::new()
+            .filter_map(raw::format_name_big)
+            .collect()
+    };
+    text_flavor(&names)
+}
+
+#[cfg(target_os = "macos")]
+fn read_text_flavor() -> Option<String> {
+    text_flavor(&clipboard_formats().ok()?)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn read_text_flavor() -> Option<String> {
+    None
+}
+
+/// `text` encoded for a raw clipboard format named `flavor`, NUL-terminated like
+/// Windows' own text formats
+///
+/// Formats named after UTF-16 or Unicode (like `CF_UNICODETEXT`) hold UTF-16,
+/// little-endian unless the name says otherwise; any other is taken to be UTF-8.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn encode_text_flavor(flavor: &str, text: &str) -> Vec<u8> {
+    let name = flavor.to_ascii_lowercase().replace(['-', '_'], "");
+    if name.contains("utf16") || name.contains("unicode") {
+        let big_endian = name.contains("utf16be");
+        text.encode_utf16()
+            .chain([0])
+            .flat_map(|unit| {
+                if big_endian {
+                    unit.to_be_bytes()
+                } else {
+                    unit.to_le_bytes()
+                }
+            })
+            .collect()
+    } else {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
     }
+}
 
-    /// Process text clipboard content
-    pub(crate) fn process_text(&mut self, text: &str) -> Result<bool> {
-        let data = text.as_bytes();
-        let hash = Self::hash_data(data);
+/// Offer text that [`set_text`] just put on the clipboard under an
+/// application-specific flavor as well, as it was when captured
+#[cfg(windows)]
+pub fn add_text_flavor(flavor: &str, text: &str) -> Result<()> {
+    use clipboard_win::raw;
 
-        // Check if this is a duplicate
-        if self.last_hash.as_ref() == Some(&hash) {
-            return Ok(false);
-        }
+    let format = raw::register_format(flavor)
+        .ok_or_else(|| anyhow::anyhow!("Failed to register clipboard format {}", flavor))?;
+    let _open = clipboard_win::Clipboard::new_attempts(10)
+        .map_err(|e| anyhow::anyhow!("Failed to open clipboard: {}", e))?;
+    raw::set_without_clear(format.get(), &encode_text_flavor(flavor, text))
+        .map_err(|e| anyhow::anyhow!("Failed to offer text as {}: {}", flavor, e))
+}
 
-        // Check if this hash already exists in the database
-        if self.db.hash_exists(&hash)? {
-            self.last_hash = Some(hash);
-            return Ok(false);
-        }
+#[cfg(target_os = "macos")]
+pub fn add_text_flavor(flavor: &str, text: &str) -> Result<()> {
+    use objc2_foundation::NSString;
 
-        // Encrypt and store
-        let encrypted = encrypt(&self.key, data).context("Failed to encrypt clipboard data")?;
+    let pasteboard = objc2_app_kit::NSPasteboard::generalPasteboard();
+    if !pasteboard.setString_forType(&NSString::from_str(text), &NSString::from_str(flavor)) {
+        anyhow::bail!("Failed to offer text as {}", flavor);
+    }
+    Ok(())
+}
 
-        let entry = ClipboardEntry::new(ClipboardContentType::Text, encrypted, hash.clone());
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn add_text_flavor(_flavor: &str, _text: &str) -> Result<()> {
+    Ok(())
+}
 
-        self.db
-            .insert_entry(&entry)
-            .context("Failed to insert entry")?;
+/// Put text that isn't valid UTF-8 on the clipboard byte for byte
+///
+/// arboard only takes strings, so this goes to the platform's clipboard API, in
+/// the plain-text format that holds bytes without promising an encoding:
+/// `CF_TEXT` on Windows, `public.plain-text` on macOS.
+#[cfg(windows)]
+fn set_raw_text(bytes: &[u8]) -> Result<()> {
+    use clipboard_win::{formats, raw};
 
-        self.last_hash = Some(hash);
+    let _open = clipboard_win::Clipboard::new_attempts(10)
+        .map_err(|e| anyhow::anyhow!("Failed to open clipboard: {}", e))?;
+    // CF_TEXT is NUL-terminated
+    let mut data = bytes.to_vec();
+    data.push(0);
+    raw::set(formats::CF_TEXT, &data).map_err(|e| anyhow::anyhow!("{}", e))
+}
 
-        // Prune if necessary
-        if let Some(max) = self.max_entries {
-            self.db.prune_to_limit(max)?;
+#[cfg(target_os = "macos")]
+fn set_raw_text(bytes: &[u8]) -> Result<()> {
+    use objc2_foundation::{NSData, NSString};
+
+    let pasteboard = objc2_app_kit::NSPasteboard::generalPasteboard();
+    pasteboard.clearContents();
+    let data = NSData::with_bytes(bytes);
+    if !pasteboard.setData_forType(Some(&data), &NSString::from_str("public.plain-text")) {
+        anyhow::bail!("the pasteboard refused the text");
+    }
+    Ok(())
+}
+
+/// Put stored text back on `selection`, exactly as it was stored
+///
+/// Text that isn't valid UTF-8 goes through
+/// [`ClipboardSource::set_text_bytes`], so where the clipboard only takes
+/// strings this fails rather than copy altered text.
+pub fn set_text_entry(
+    clipboard: &mut impl ClipboardSource,
+    text: &TextEntry,
+    selection: ClipboardSelection,
+) -> Result<()> {
+    match text.as_str() {
+        Ok(text) => {
+            set_text(clipboard, text.to_string(), selection).context("Failed to set clipboard text")
+        }
+        Err(invalid) => match clipboard.set_text_bytes(text.as_bytes(), selection) {
+            Ok(()) => Ok(()),
+            Err(arboard::Error::ConversionFailure) => Err(invalid),
+            Err(e) => Err(e).context("Failed to set clipboard text"),
+        },
+    }
+}
+
+/// One format per line, without blank lines or repeats
+#[cfg_attr(any(windows, target_os = "macos"), allow(dead_code))]
+fn parse_format_list(output: &str) -> Vec<String> {
+    let mut formats: Vec<String> = Vec::new();
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if !formats.iter().any(|format| format == line) {
+            formats.push(line.to_string());
         }
+    }
+    formats
+}
 
-        Ok(true)
+/// Record the current change count, returning whether it differs from the last one
+///
+/// Without a counter every poll counts as a change.
+fn counter_changed(last: &mut Option<u64>, current: Option<u64>) -> bool {
+    match current {
+        Some(count) => last.replace(count) != Some(count),
+        None => true,
     }
+}
 
-    /// Process image clipboard content
-    pub(crate) fn process_image(&mut self, image_data: &arboard::ImageData) -> Result<bool> {
-        // Store image metadata along with RGBA bytes
-        let img_data = ImageData::new(
-            image_data.width,
-            image_data.height,
-            image_data.bytes.to_vec(),
-        );
+/// Randomly perturb an interval by up to ±`percent`%
+///
+/// Keeps several tools polling the clipboard on the same interval from
+/// contending for it in lockstep.
+pub fn jittered(interval: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return interval;
+    }
+    let spread = interval.as_secs_f64() * f64::from(percent.min(100)) / 100.0;
+    let offset = rand::thread_rng().gen_range(-spread..=spread);
+    Duration::from_secs_f64((interval.as_secs_f64() + offset).max(0.0))
+}
 
-        // Serialize the image data structure
-        let serialized = bincode::serialize(&img_data).context("Failed to serialize image data")?;
+/// Whether clipboard "text" is really binary data
+///
+/// Some applications offer arbitrary bytes as text. Those show up as NULs and
+/// other control characters, or as U+FFFD where the bytes weren't valid UTF-8.
+/// Tabs and line breaks are ordinary text; anything over 30% of the other
+/// characters being unprintable is taken as binary.
+pub fn looks_binary(text: &str) -> bool {
+    let mut total = 0usize;
+    let mut unprintable = 0usize;
+    for c in text.chars() {
+        total += 1;
+        let ordinary_whitespace = matches!(c, '\t' | '\n' | '\r' | '\x0c');
+        if (c.is_control() && !ordinary_whitespace) || c == char::REPLACEMENT_CHARACTER {
+            unprintable += 1;
+        }
+    }
+    unprintable * 10 > total * 3
+}
 
-        let hash = Self::hash_data(&serialized);
+/// Remove ANSI escape sequences (colors, cursor movement, titles) from text
+pub fn strip_ansi(text: &str) -> Cow<'_, str> {
+    if !text.contains('\x1b') {
+        return Cow::Borrowed(text);
+    }
 
-        // Check if this is a duplicate
-        if self.last_hash.as_ref() == Some(&hash) {
-            return Ok(false);
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters and intermediates up to a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: terminated by BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' {
+                        if chars.peek() == Some(&'\\') {
+                            chars.next();
+                        }
+                        break;
+                    }
+                }
+            }
+            // Any other escape is a single character
+            _ => {}
         }
+    }
+    Cow::Owned(out)
+}
 
-        // Check if this hash already exists in the database
-        if self.db.hash_exists(&hash)? {
-            self.last_hash = Some(hash);
-            return Ok(false);
+/// A small ring buffer of the most recently seen content hashes
+///
+/// Remembering more than just the last hash means that alternating between a
+/// handful of values doesn't cause each alternation to be treated as new content.
+pub struct RecentHashes {
+    capacity: usize,
+    hashes: VecDeque<String>,
+}
+
+impl RecentHashes {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            hashes: VecDeque::with_capacity(capacity),
         }
+    }
 
-        // Encrypt and store
-        let encrypted =
-            encrypt(&self.key, &serialized).context("Failed to encrypt clipboard image")?;
+    /// Check if the hash was seen recently
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.iter().any(|h| h == hash)
+    }
 
-        let entry = ClipboardEntry::new(ClipboardContentType::Image, encrypted, hash.clone());
+    /// Remember a hash, evicting the oldest one if the buffer is full
+    pub fn push(&mut self, hash: String) {
+        if let Some(pos) = self.hashes.iter().position(|h| *h == hash) {
+            self.hashes.remove(pos);
+        } else if self.hashes.len() >= self.capacity {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back(hash);
+    }
+}
 
-        self.db
-            .insert_entry(&entry)
-            .context("Failed to insert entry")?;
+/// Largest width or height of images stored with `--images thumbnail`
+pub const THUMBNAIL_MAX_SIDE: usize = 256;
 
-        self.last_hash = Some(hash);
+/// How captured images are stored (`--images`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum ImageMode {
+    /// The image as copied
+    #[default]
+    Full,
+    /// Only a thumbnail of at most 256 pixels a side, for recognizing images
+    /// in the history; copying it gives back the thumbnail, not the original
+    Thumbnail,
+}
 
-        // Prune if necessary
-        if let Some(max) = self.max_entries {
-            self.db.prune_to_limit(max)?;
+/// How text is normalized before hashing it for deduplication (`--normalize`)
+///
+/// Only the hash is affected, the text is stored as copied.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TextNormalization {
+    /// Unicode NFC: canonically equivalent text (e.g. precomposed "é" and "e"
+    /// with a combining accent) is a duplicate
+    Nfc,
+    /// NFC and case folding: text differing only in case is a duplicate too
+    Casefold,
+}
+
+impl TextNormalization {
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::Nfc if unicode_normalization::is_nfc(text) => Cow::Borrowed(text),
+            Self::Nfc => Cow::Owned(text.nfc().collect()),
+            Self::Casefold => Cow::Owned(text.chars().nfd().default_case_fold().nfc().collect()),
+        }
+    }
+}
+
+/// How long capture stays paused after storing more than `--max-rate` allows
+pub const RATE_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// At most `count` entries stored within `window` (`clpd start --max-rate`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureRate {
+    pub count: usize,
+    pub window: Duration,
+}
+
+impl Default for CaptureRate {
+    fn default() -> Self {
+        Self {
+            count: 50,
+            window: Duration::from_secs(10),
         }
+    }
+}
+
+/// Pauses capture when entries are stored faster than a [`CaptureRate`]
+///
+/// Protects the database and disk from an application rewriting the clipboard
+/// in a loop. Counts stores in a sliding window; going over the limit pauses
+/// capture for [`RATE_COOLDOWN`].
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: CaptureRate,
+    stored: VecDeque<Instant>,
+    paused_until: Option<Instant>,
+}
 
-        Ok(true)
+impl RateLimiter {
+    pub fn new(rate: CaptureRate) -> Self {
+        Self {
+            rate,
+            stored: VecDeque::new(),
+            paused_until: None,
+        }
     }
 
-    /// Check clipboard once
-    pub fn check_clipboard(&mut self) -> Result<bool> {
-        // Try to get text first
-        if let Ok(text) = self.clipboard.get_text()
-            && !text.is_empty()
+    /// Record an entry stored at `now`, returning whether that paused capture
+    fn record_at(&mut self, now: Instant) -> bool {
+        while self
+            .stored
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= self.rate.window)
         {
-            return self.process_text(&text);
+            self.stored.pop_front();
+        }
+        self.stored.push_back(now);
+        if self.stored.len() <= self.rate.count {
+            return false;
         }
+        self.stored.clear();
+        self.paused_until = Some(now + RATE_COOLDOWN);
+        true
+    }
 
-        // Try to get image if no text
-        if let Ok(image) = self.clipboard.get_image() {
-            return self.process_image(&image);
+    fn is_paused_at(&mut self, now: Instant) -> bool {
+        match self.paused_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                self.paused_until = None;
+                false
+            }
+            None => false,
         }
+    }
+
+    /// Why nothing is captured while paused
+    fn pause_reason(&self) -> String {
+        format!(
+            "more than {} entries were stored within {}s, capture is paused for {}s",
+            self.rate.count,
+            self.rate.window.as_secs(),
+            RATE_COOLDOWN.as_secs()
+        )
+    }
+}
+
+/// How long after clpd writes to the clipboard a watcher ignores that content
+pub const SELF_WRITE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Hashes of content that clpd itself recently put on the clipboard
+///
+/// Shared between whatever writes to the clipboard (e.g. the TUI) and a watcher
+/// running in the same process, so the watcher doesn't treat clpd's own writes as
+/// new copies. A separate `clpd copy` process can't reach this state, see
+/// [`AnnouncedWrites`] for that.
+#[derive(Clone, Default)]
+pub struct SelfWrites {
+    writes: Arc<parking_lot::Mutex<Vec<(String, Instant)>>>,
+    // Clipboard writes under way, during which the watcher doesn't read the clipboard
+    writing: Arc<AtomicUsize>,
+}
+
+/// A clipboard write under way, see [`SelfWrites::begin_write`]
+pub struct WriteInProgress(Arc<AtomicUsize>);
 
-        Ok(false)
+impl Drop for WriteInProgress {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
+}
 
-    /// Start watching the clipboard in a loop
-    pub fn watch(mut self) -> Result<()> {
-        println!("🔒 Clipboard watcher started. Press Ctrl+C to stop.");
-        println!("📋 Monitoring clipboard for changes...");
+impl SelfWrites {
+    /// Pause the watcher's clipboard reads until the returned guard is dropped,
+    /// so it neither contends with the write nor reads the content half set
+    pub fn begin_write(&self) -> WriteInProgress {
+        self.writing.fetch_add(1, Ordering::SeqCst);
+        WriteInProgress(self.writing.clone())
+    }
 
-        let mut stored_count = 0;
+    /// Whether a clipboard write is under way
+    pub fn is_writing(&self) -> bool {
+        self.writing.load(Ordering::SeqCst) > 0
+    }
 
-        loop {
-            match self.check_clipboard() {
-                Ok(true) => {
-                    stored_count += 1;
-                    println!("✓ Stored encrypted entry #{}", stored_count);
-                }
-                Ok(false) => {
-                    // No change or duplicate, continue silently
-                }
-                Err(e) => {
-                    eprintln!("⚠ Warning: Failed to process clipboard: {}", e);
-                }
+    /// Remember that content with this hash was just written to the clipboard
+    pub fn record(&self, hash: &str) {
+        self.record_at(hash, Instant::now());
+    }
+
+    fn record_at(&self, hash: &str, at: Instant) {
+        self.writes.lock().push((hash.to_string(), at));
+    }
+
+    /// Check whether content with this hash was written within the window,
+    /// forgetting the write if so
+    pub fn take(&self, hash: &str) -> bool {
+        self.take_at(hash, Instant::now())
+    }
+
+    fn take_at(&self, hash: &str, now: Instant) -> bool {
+        let mut writes = self.writes.lock();
+        writes.retain(|(_, at)| now.saturating_duration_since(*at) <= SELF_WRITE_WINDOW);
+        match writes.iter().position(|(h, _)| h == hash) {
+            Some(pos) => {
+                writes.remove(pos);
+                true
             }
+            None => false,
+        }
+    }
+}
+
+/// The last clipboard write of any clpd process, announced through a small file
+///
+/// Watchers in other processes can't see [`SelfWrites`], so clpd writes the hash
+/// of what it's about to put on the clipboard and the time to this file, and a
+/// watcher ignores that content if it shows up within [`SELF_WRITE_WINDOW`].
+#[derive(Debug, Clone)]
+pub struct AnnouncedWrites {
+    path: PathBuf,
+}
+
+impl AnnouncedWrites {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// The file in the user's cache directory, shared by every clpd process
+    pub fn default_location() -> Option<Self> {
+        dirs::cache_dir().map(|dir| Self::new(dir.join("clpd").join("last-write")))
+    }
+
+    /// Announce that content with this hash is being written to the clipboard
+    pub fn announce(&self, hash: &str) -> Result<()> {
+        self.announce_at(hash, SystemTime::now())
+    }
+
+    fn announce_at(&self, hash: &str, at: SystemTime) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let millis = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        fs::write(&self.path, format!("{} {}\n", hash, millis))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
 
-            thread::sleep(self.poll_interval);
+    /// Check whether content with this hash was announced within the window,
+    /// forgetting the announcement if so
+    pub fn take(&self, hash: &str) -> bool {
+        self.take_at(hash, SystemTime::now())
+    }
+
+    fn take_at(&self, hash: &str, now: SystemTime) -> bool {
+        let Ok(text) = fs::read_to_string(&self.path) else {
+            return false;
+        };
+        let Some((announced, millis)) = text.trim().split_once(' ') else {
+            return false;
+        };
+        let Ok(millis) = millis.parse() else {
+            return false;
+        };
+        let at = UNIX_EPOCH + Duration::from_millis(millis);
+        let recent = now
+            .duration_since(at)
+            .is_ok_and(|age| age <= SELF_WRITE_WINDOW);
+        if announced != hash || !recent {
+            return false;
         }
+        // Another watcher may have taken it first, either way it's matched
+        let _ = fs::remove_file(&self.path);
+        true
     }
 }
 
-pub fn start_watcher(
-    db: ClipboardDatabase,
-    key: MasterKey,
-    max_entries: Option<usize>,
-) -> Result<()> {
-    let watcher = LocalClipboardWatcher::new(db, key, max_entries)?;
-    watcher.watch()
+/// Which selections are captured by the watcher, or shown by `list` and `browse`
+///
+/// Only Linux has a primary selection; elsewhere there is just the clipboard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Selections {
+    Clipboard,
+    Primary,
+    #[default]
+    Both,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Selections {
+    pub fn includes(self, selection: ClipboardSelection) -> bool {
+        self.each().contains(&selection)
+    }
 
-    #[test]
-    fn test_hash_data() {
-        let data = b"test data";
-        let hash = LocalClipboardWatcher::hash_data(data);
+    fn each(self) -> &'static [ClipboardSelection] {
+        match self {
+            Self::Clipboard => &[ClipboardSelection::Clipboard],
+            Self::Primary => &[ClipboardSelection::Primary],
+            Self::Both => &[ClipboardSelection::Clipboard, ClipboardSelection::Primary],
+        }
+    }
+}
 
-        // SHA-256 produces 64 hex characters
-        assert_eq!(hash.len(), 64);
+/// What an entry captured from `selection` records as its source
+fn recorded_selection(selection: ClipboardSelection) -> Option<ClipboardSelection> {
+    cfg!(target_os = "linux").then_some(selection)
+}
 
-        // Same data produces same hash
-        let hash2 = LocalClipboardWatcher::hash_data(data);
-        assert_eq!(hash, hash2);
+#[cfg(target_os = "linux")]
+fn linux_kind(selection: ClipboardSelection) -> arboard::LinuxClipboardKind {
+    match selection {
+        ClipboardSelection::Clipboard => arboard::LinuxClipboardKind::Clipboard,
+        ClipboardSelection::Primary => arboard::LinuxClipboardKind::Primary,
+    }
+}
 
-        // Different data produces different hash
-        let hash3 = LocalClipboardWatcher::hash_data(b"different data");
-        assert_ne!(hash, hash3);
+/// Put text on `selection` (always the clipboard outside Linux)
+pub fn set_text(
+    clipboard: &mut impl ClipboardSource,
+    text: String,
+    selection: ClipboardSelection,
+) -> Result<(), arboard::Error> {
+    clipboard.set_text(text, selection)
+}
+
+/// Put an image on `selection` (always the clipboard outside Linux)
+pub fn set_image(
+    clipboard: &mut impl ClipboardSource,
+    image: arboard::ImageData,
+    selection: ClipboardSelection,
+) -> Result<(), arboard::Error> {
+    clipboard.set_image(image, selection)
+}
+
+/// The clipboard as the watcher reads and writes it: arboard's system clipboard,
+/// or a [`FakeClipboard`] in tests
+///
+/// Selections other than the clipboard only exist on Linux; elsewhere they read
+/// and write the clipboard.
+pub trait ClipboardSource {
+    fn get_text(&mut self, selection: ClipboardSelection) -> Result<String, arboard::Error>;
+
+    fn get_image(
+        &mut self,
+        selection: ClipboardSelection,
+    ) -> Result<arboard::ImageData<'static>, arboard::Error>;
+
+    fn set_text(
+        &mut self,
+        text: String,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error>;
+
+    fn set_image(
+        &mut self,
+        image: arboard::ImageData,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error>;
+
+    /// Put text that isn't valid UTF-8 on `selection` byte for byte
+    ///
+    /// Only Windows and macOS have a plain-text format that takes bytes in no
+    /// particular encoding; elsewhere this fails with `ConversionFailure`.
+    fn set_text_bytes(
+        &mut self,
+        _bytes: &[u8],
+        _selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        Err(arboard::Error::ConversionFailure)
+    }
+
+    /// The change counter for `--on-change-only`, if there is one
+    fn change_count(&self) -> Option<u64> {
+        None
+    }
+
+    /// The application-specific flavor the text on the clipboard is offered under
+    fn text_flavor(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Wait up to `timeout` for the clipboard to change, returning whether it may
+    /// have; polling just sleeps and reads it again
+    fn wait_for_change(&mut self, timeout: Duration) -> bool {
+        thread::sleep(timeout);
+        true
+    }
+}
+
+impl ClipboardSource for Clipboard {
+    #[cfg(target_os = "linux")]
+    fn get_text(&mut self, selection: ClipboardSelection) -> Result<String, arboard::Error> {
+        use arboard::GetExtLinux;
+        self.get().clipboard(linux_kind(selection)).text()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn get_text(&mut self, _selection: ClipboardSelection) -> Result<String, arboard::Error> {
+        Clipboard::get_text(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_image(
+        &mut self,
+        selection: ClipboardSelection,
+    ) -> Result<arboard::ImageData<'static>, arboard::Error> {
+        use arboard::GetExtLinux;
+        self.get().clipboard(linux_kind(selection)).image()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn get_image(
+        &mut self,
+        _selection: ClipboardSelection,
+    ) -> Result<arboard::ImageData<'static>, arboard::Error> {
+        Clipboard::get_image(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_text(
+        &mut self,
+        text: String,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        use arboard::SetExtLinux;
+        self.set().clipboard(linux_kind(selection)).text(text)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_text(
+        &mut self,
+        text: String,
+        _selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        Clipboard::set_text(self, text)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_image(
+        &mut self,
+        image: arboard::ImageData,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        use arboard::SetExtLinux;
+        self.set().clipboard(linux_kind(selection)).image(image)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_image(
+        &mut self,
+        image: arboard::ImageData,
+        _selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        Clipboard::set_image(self, image)
+    }
+
+    #[cfg(any(windows, target_os = "macos"))]
+    fn set_text_bytes(
+        &mut self,
+        bytes: &[u8],
+        _selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        set_raw_text(bytes).map_err(|e| arboard::Error::Unknown {
+            description: format!("{:#}", e),
+        })
+    }
+
+    fn change_count(&self) -> Option<u64> {
+        clipboard_change_count()
+    }
+
+    fn text_flavor(&mut self) -> Option<String> {
+        read_text_flavor()
+    }
+}
+
+/// The system clipboard the watcher uses, polled unless OS change notifications
+/// are on (`--events`)
+pub struct SystemClipboard {
+    clipboard: Clipboard,
+    events: Option<ChangeEvents>,
+}
+
+impl SystemClipboard {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            clipboard: Clipboard::new().context("Failed to initialize clipboard")?,
+            events: None,
+        })
+    }
+}
+
+impl ClipboardSource for SystemClipboard {
+    fn get_text(&mut self, selection: ClipboardSelection) -> Result<String, arboard::Error> {
+        ClipboardSource::get_text(&mut self.clipboard, selection)
+    }
+
+    fn get_image(
+        &mut self,
+        selection: ClipboardSelection,
+    ) -> Result<arboard::ImageData<'static>, arboard::Error> {
+        ClipboardSource::get_image(&mut self.clipboard, selection)
+    }
+
+    fn set_text(
+        &mut self,
+        text: String,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        ClipboardSource::set_text(&mut self.clipboard, text, selection)
+    }
+
+    fn set_image(
+        &mut self,
+        image: arboard::ImageData,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        ClipboardSource::set_image(&mut self.clipboard, image, selection)
+    }
+
+    fn set_text_bytes(
+        &mut self,
+        bytes: &[u8],
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        self.clipboard.set_text_bytes(bytes, selection)
+    }
+
+    fn change_count(&self) -> Option<u64> {
+        self.clipboard.change_count()
+    }
+
+    fn text_flavor(&mut self) -> Option<String> {
+        self.clipboard.text_flavor()
+    }
+
+    /// Without notifications this polls; if they stop, it goes back to polling
+    fn wait_for_change(&mut self, timeout: Duration) -> bool {
+        let Some(events) = &self.events else {
+            thread::sleep(timeout);
+            return true;
+        };
+        match events.wait(timeout) {
+            Ok(changed) => changed,
+            Err(e) => {
+                eprintln!("⚠ Warning: {:#}, polling the clipboard instead", e);
+                self.events = None;
+                true
+            }
+        }
+    }
+}
+
+/// What a poll found on the clipboard
+#[derive(Clone)]
+pub enum ClipboardContent {
+    Text(String),
+    Image(arboard::ImageData<'static>),
+}
+
+impl ClipboardContent {
+    /// Read the clipboard, preferring text when it offers both
+    fn read(clipboard: &mut impl ClipboardSource) -> Result<Option<Self>, arboard::Error> {
+        Self::read_from(clipboard, ClipboardSelection::Clipboard)
+    }
+
+    /// Read a selection, preferring text when it offers both
+    ///
+    /// `None` when the selection is empty or holds neither text nor an image;
+    /// errors are failures to access it (e.g. another application holding it
+    /// open), so that they aren't mistaken for an empty clipboard.
+    fn read_from(
+        clipboard: &mut impl ClipboardSource,
+        selection: ClipboardSelection,
+    ) -> Result<Option<Self>, arboard::Error> {
+        let text_error = match clipboard.get_text(selection) {
+            Ok(text) if !text.is_empty() => return Ok(Some(Self::Text(text))),
+            Ok(_) | Err(arboard::Error::ContentNotAvailable) => None,
+            Err(e) => Some(e),
+        };
+        match clipboard.get_image(selection) {
+            Ok(image) => Ok(Some(Self::Image(image))),
+            Err(arboard::Error::ContentNotAvailable) => text_error.map_or(Ok(None), Err),
+            Err(e) => Err(text_error.unwrap_or(e)),
+        }
+    }
+
+    fn hash(&self) -> String {
+        match self {
+            Self::Text(text) => LocalClipboardWatcher::hash_data(text.as_bytes()),
+            Self::Image(image) => LocalClipboardWatcher::hash_data(&image.bytes),
+        }
+    }
+
+    /// Content type, size and a preview of text, on one line
+    fn describe(&self) -> String {
+        match self {
+            Self::Text(text) => {
+                format!(
+                    "Text, {} bytes: {}",
+                    text.len(),
+                    preview_text(text, usize::MAX, 60)
+                )
+            }
+            Self::Image(image) => format!(
+                "Image {}x{}, {} bytes",
+                image.width,
+                image.height,
+                image.bytes.len()
+            ),
+        }
+    }
+}
+
+/// What happened to the clipboard content seen by one check
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureOutcome {
+    /// A new entry was stored
+    Stored,
+    /// The content matches a recently seen or already stored entry
+    Duplicate,
+    /// There was nothing on the clipboard to store
+    Empty,
+    /// The content was deliberately not stored, for the given reason
+    Skipped(String),
+}
+
+/// Counts of clipboard changes seen during a watcher session
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CaptureStats {
+    pub captured: usize,
+    /// Skipped because the value is in the recent hashes window
+    pub skipped_recent: usize,
+    /// Skipped because an entry with the same hash is already stored
+    pub skipped_existing: usize,
+    /// Skipped because clpd put the content on the clipboard itself
+    pub skipped_own: usize,
+    /// Polls where the clipboard couldn't be read, as opposed to being empty
+    pub read_errors: usize,
+}
+
+impl CaptureStats {
+    /// Share of clipboard changes that were skipped as duplicates, in percent
+    pub fn dedupe_rate(&self) -> f64 {
+        let skipped = self.skipped_recent + self.skipped_existing;
+        let total = self.captured + skipped;
+        if total == 0 {
+            0.0
+        } else {
+            skipped as f64 * 100.0 / total as f64
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!("📊 Session summary");
+        println!("  Captured: {}", self.captured);
+        println!("  Skipped (recently seen): {}", self.skipped_recent);
+        println!("  Skipped (already stored): {}", self.skipped_existing);
+        if self.skipped_own > 0 {
+            println!("  Skipped (copied by clpd): {}", self.skipped_own);
+        }
+        if self.read_errors > 0 {
+            println!(
+                "  Failed clipboard reads: {} (--verbose shows why)",
+                self.read_errors
+            );
+        }
+        println!("  Dedupe rate: {:.1}%", self.dedupe_rate());
+    }
+}
+
+pub struct LocalClipboardWatcher<C: ClipboardSource = SystemClipboard> {
+    clipboard: Option<C>,
+    pub db: SharedDatabase,
+    key: MasterKey,
+    recent_hashes: RecentHashes,
+    max_entries: Option<usize>,
+    max_age: Option<chrono::TimeDelta>,
+    poll_interval: Duration,
+    poll_jitter: u8,
+    notifier: Option<CaptureNotifier>,
+    on_capture: Option<CaptureHook>,
+    rate_limiter: Option<RateLimiter>,
+    selections: Selections,
+    strip_ansi: bool,
+    normalize: Option<TextNormalization>,
+    max_image_bytes: usize,
+    image_mode: ImageMode,
+    swap_rb: bool,
+    canonical_images: bool,
+    delta: bool,
+    on_change_only: bool,
+    last_change_count: Option<u64>,
+    skip_initial: bool,
+    ignore_apps: Vec<String>,
+    // How the focused application is found, replaced in tests
+    detect_focus: fn() -> Result<Vec<String>>,
+    self_writes: Option<SelfWrites>,
+    announced_writes: Option<AnnouncedWrites>,
+    // Hash of clpd's own write that is still on the clipboard
+    own_content: Option<String>,
+    // ID of the entry stored most recently
+    last_stored: Option<String>,
+    verbose: bool,
+    // The last failure to read the clipboard, reported once until reads work again
+    last_read_error: Option<String>,
+    stats: CaptureStats,
+}
+
+impl LocalClipboardWatcher {
+    pub fn new(db: ClipboardDatabase, key: MasterKey, max_entries: Option<usize>) -> Result<Self> {
+        Self::shared(Arc::new(RwLock::new(db)), key, max_entries)
+    }
+
+    /// Create a watcher on a database that is also used elsewhere
+    pub fn shared(db: SharedDatabase, key: MasterKey, max_entries: Option<usize>) -> Result<Self> {
+        let clipboard = SystemClipboard::new()?;
+        Ok(Self::with_clipboard(Some(clipboard), db, key, max_entries)
+            .with_announced_writes(AnnouncedWrites::default_location()))
+    }
+
+    /// Create a watcher that doesn't access the clipboard, for storing content
+    /// obtained some other way (e.g. from stdin)
+    pub fn without_clipboard(db: ClipboardDatabase, key: MasterKey) -> Self {
+        Self::with_clipboard(None, Arc::new(RwLock::new(db)), key, None)
+    }
+
+    /// Read the clipboard when the OS reports a change instead of on every poll
+    pub fn with_change_events(mut self, events: Option<ChangeEvents>) -> Self {
+        if let Some(clipboard) = &mut self.clipboard {
+            clipboard.events = events;
+        }
+        self
+    }
+
+    /// Calculate SHA-256 hash of data
+    pub(crate) fn hash_data(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hex::encode(hasher.finalize())
+    }
+}
+
+impl<C: ClipboardSource> LocalClipboardWatcher<C> {
+    fn with_clipboard(
+        clipboard: Option<C>,
+        db: SharedDatabase,
+        key: MasterKey,
+        max_entries: Option<usize>,
+    ) -> Self {
+        Self {
+            clipboard,
+            db,
+            key,
+            recent_hashes: RecentHashes::new(DEFAULT_DEDUPE_WINDOW),
+            max_entries,
+            max_age: None,
+            poll_interval: POLL_INTERVAL,
+            poll_jitter: 0,
+            notifier: None,
+            on_capture: None,
+            rate_limiter: None,
+            selections: Selections::Clipboard,
+            strip_ansi: false,
+            normalize: None,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            image_mode: ImageMode::Full,
+            swap_rb: false,
+            canonical_images: false,
+            delta: false,
+            on_change_only: false,
+            last_change_count: None,
+            skip_initial: false,
+            ignore_apps: Vec::new(),
+            detect_focus: focused_app,
+            self_writes: None,
+            announced_writes: None,
+            own_content: None,
+            last_stored: None,
+            verbose: false,
+            last_read_error: None,
+            stats: CaptureStats::default(),
+        }
+    }
+
+    /// Set how many recently seen hashes are remembered for deduplication
+    pub fn with_dedupe_window(mut self, size: usize) -> Self {
+        self.recent_hashes = RecentHashes::new(size);
+        self
+    }
+
+    /// Delete entries once they're older than `max_age`
+    pub fn with_max_age(mut self, max_age: Option<chrono::TimeDelta>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Set how often the clipboard is read; with change notifications, how often
+    /// the watcher wakes up without one
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Set the random jitter applied to each poll interval, in percent
+    pub fn with_poll_jitter(mut self, percent: u8) -> Self {
+        self.poll_jitter = percent;
+        self
+    }
+
+    /// Show desktop notifications for captured entries
+    pub fn with_notifier(mut self, notifier: CaptureNotifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Run a command after each stored entry
+    pub fn with_on_capture(mut self, hook: CaptureHook) -> Self {
+        self.on_capture = Some(hook);
+        self
+    }
+
+    /// Pause capture for a while when entries are stored faster than `rate`
+    pub fn with_max_rate(mut self, rate: Option<CaptureRate>) -> Self {
+        self.rate_limiter = rate.map(RateLimiter::new);
+        self
+    }
+
+    /// Remove ANSI escape sequences from captured text before storing it
+    pub fn with_strip_ansi(mut self, strip: bool) -> Self {
+        self.strip_ansi = strip;
+        self
+    }
+
+    /// Capture these selections (more than the clipboard only on Linux)
+    pub fn with_selections(mut self, selections: Selections) -> Self {
+        self.selections = selections;
+        self
+    }
+
+    /// Normalize text before hashing it, so equivalent text is deduplicated
+    pub fn with_normalize(mut self, normalize: Option<TextNormalization>) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Swap the red and blue channels of captured images
+    pub fn with_swap_rb(mut self, swap: bool) -> Self {
+        self.swap_rb = swap;
+        self
+    }
+
+    /// Deduplicate images by their pixels, ignoring row padding and unused alpha
+    pub fn with_canonical_images(mut self, canonical: bool) -> Self {
+        self.canonical_images = canonical;
+        self
+    }
+
+    /// Set the largest raw RGBA image size that is captured
+    pub fn with_max_image_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_image_bytes = max_bytes;
+        self
+    }
+
+    /// Store captured images in full or only as thumbnails
+    pub fn with_image_mode(mut self, mode: ImageMode) -> Self {
+        self.image_mode = mode;
+        self
+    }
+
+    /// Store text that is very similar to the previous text entry as a diff against it
+    pub fn with_delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// Only read the clipboard when the platform's change counter says it changed
+    pub fn with_on_change_only(mut self, on_change_only: bool) -> Self {
+        self.on_change_only = on_change_only;
+        self
+    }
+
+    /// Report failures to read the clipboard, which otherwise only show up as
+    /// a count in the session summary
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Don't store what is already on the clipboard when watching starts
+    pub fn with_skip_initial(mut self, skip: bool) -> Self {
+        self.skip_initial = skip;
+        self
+    }
+
+    /// Don't store anything copied while an application whose name contains one of
+    /// these (ignoring case) has the focus
+    pub fn with_ignore_apps(mut self, apps: Vec<String>) -> Self {
+        self.ignore_apps = apps;
+        self
+    }
+
+    /// Ignore content that clpd itself wrote to the clipboard, as recorded in `writes`
+    pub fn with_self_writes(mut self, writes: SelfWrites) -> Self {
+        self.self_writes = Some(writes);
+        self
+    }
+
+    /// Ignore content that other clpd processes announce writing to the clipboard
+    pub fn with_announced_writes(mut self, writes: Option<AnnouncedWrites>) -> Self {
+        self.announced_writes = writes;
+        self
+    }
+
+    /// Pause a watcher sharing this one's [`SelfWrites`] while clpd writes to the
+    /// clipboard, until the returned guard is dropped
+    pub fn begin_clipboard_write(&self) -> Option<WriteInProgress> {
+        self.self_writes.as_ref().map(SelfWrites::begin_write)
+    }
+
+    /// Record that clpd is putting content with this hash on the clipboard, for
+    /// watchers in this process and in others
+    pub fn record_clipboard_write(&self, hash: &str) {
+        if let Some(writes) = &self.self_writes {
+            writes.record(hash);
+        }
+        if let Some(writes) = &self.announced_writes
+            && let Err(e) = writes.announce(hash)
+        {
+            eprintln!("⚠ Warning: {:#}", e);
+        }
+    }
+
+    /// Whether the content is clpd's own clipboard write rather than a new copy
+    ///
+    /// Once matched, the content is ignored until something else is copied, so
+    /// it never reaches the recent hashes.
+    fn is_own_write(&mut self, hash: &str) -> bool {
+        if self.own_content.as_deref() == Some(hash) {
+            return true;
+        }
+        self.own_content = None;
+        if self
+            .self_writes
+            .as_ref()
+            .is_some_and(|writes| writes.take(hash))
+            || self
+                .announced_writes
+                .as_ref()
+                .is_some_and(|writes| writes.take(hash))
+        {
+            self.own_content = Some(hash.to_string());
+            self.stats.skipped_own += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Why new content isn't stored, if an `--ignore-app` application has the focus
+    ///
+    /// When the focused application can't be found out the content is stored;
+    /// [`build_watcher`] warns about that once.
+    fn ignored_app_focused(&self) -> Option<String> {
+        if self.ignore_apps.is_empty() {
+            return None;
+        }
+        let names = (self.detect_focus)().ok()?;
+        ignored_app(&names, &self.ignore_apps)
+            .map(|app| format!("copied while '{}' has the focus (--ignore-app)", app))
+    }
+
+    /// Delete an entry, leaving the flush to the caller
+    pub fn delete_entry_unflushed(&self, id: &str) -> Result<bool> {
+        self.db.read().delete_entry_unflushed(id, Some(&self.key))
+    }
+
+    /// ID of the entry this watcher stored most recently
+    pub fn last_stored(&self) -> Option<&str> {
+        self.last_stored.as_deref()
+    }
+
+    /// Counts of captured and skipped clipboard changes so far
+    pub fn stats(&self) -> &CaptureStats {
+        &self.stats
+    }
+
+    /// Treat the content as already seen, so it's only stored if copied again later
+    ///
+    /// The hash is the one it would be captured under, after `strip_ansi`.
+    fn mark_seen(&mut self, content: &ClipboardContent) {
+        let hash = match content {
+            ClipboardContent::Text(text) if self.strip_ansi => {
+                self.text_dedupe_hash(&strip_ansi(text))
+            }
+            ClipboardContent::Text(text) => self.text_dedupe_hash(text),
+            ClipboardContent::Image(image) => {
+                let image = ImageData::new(image.width, image.height, self.image_pixels(image));
+                self.image_dedupe_hash(&image)
+            }
+        };
+        self.recent_hashes.push(hash);
+    }
+
+    /// The hash text is deduplicated by: that of its normalized form with `--normalize`
+    fn text_dedupe_hash(&self, text: &str) -> String {
+        match self.normalize {
+            Some(normalize) => LocalClipboardWatcher::hash_data(normalize.apply(text).as_bytes()),
+            None => LocalClipboardWatcher::hash_data(text.as_bytes()),
+        }
+    }
+
+    /// The pixel data of a clipboard image, without row padding with `--canonical-image-hash`
+    fn image_pixels(&self, image: &arboard::ImageData) -> Vec<u8> {
+        if self.canonical_images
+            && image.bytes.len() != image.width.saturating_mul(image.height).saturating_mul(4)
+            && let Some(pixels) = strip_row_padding(image.width, image.height, &image.bytes)
+        {
+            return pixels;
+        }
+        image.bytes.to_vec()
+    }
+
+    /// The hash images are deduplicated by: that of their canonical form with
+    /// `--canonical-image-hash`
+    fn image_dedupe_hash(&self, image: &ImageData) -> String {
+        if self.canonical_images {
+            LocalClipboardWatcher::hash_data(&image.canonical_hash_input())
+        } else {
+            LocalClipboardWatcher::hash_data(&image.hash_input())
+        }
+    }
+
+    /// Mark whatever is on the watched selections now as seen, returning whether
+    /// there was anything
+    fn skip_current(&mut self) -> bool {
+        // Also primes the change counter, so the next poll doesn't read it again
+        self.clipboard_changed();
+        let mut found = false;
+        for &selection in self.selections.each() {
+            let Some(clipboard) = self.clipboard.as_mut() else {
+                return false;
+            };
+            if let Ok(Some(content)) = ClipboardContent::read_from(clipboard, selection) {
+                self.mark_seen(&content);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Process text clipboard content
+    pub(crate) fn process_text(&mut self, text: &str) -> Result<CaptureOutcome> {
+        self.process_text_from(text, None)
+    }
+
+    /// Process text, recording the selection it was captured from
+    fn process_text_from(
+        &mut self,
+        text: &str,
+        selection: Option<ClipboardSelection>,
+    ) -> Result<CaptureOutcome> {
+        let text = if self.strip_ansi {
+            strip_ansi(text)
+        } else {
+            Cow::Borrowed(text)
+        };
+        if text.is_empty() {
+            return Ok(CaptureOutcome::Empty);
+        }
+        if looks_binary(&text) {
+            return Ok(CaptureOutcome::Skipped(
+                "text looks like binary data (mostly control characters)".to_string(),
+            ));
+        }
+
+        let data = text.as_bytes();
+        let hash = LocalClipboardWatcher::hash_data(data);
+        let dedupe_hash = self.text_dedupe_hash(&text);
+
+        if self.is_own_write(&hash) {
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Check if this was seen recently
+        if self.recent_hashes.contains(&dedupe_hash) {
+            self.stats.skipped_recent += 1;
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Check if this hash already exists in the database
+        if self.db.read().hash_exists(&dedupe_hash)? {
+            self.stats.skipped_existing += 1;
+            self.recent_hashes.push(dedupe_hash);
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Remembered as seen, so it isn't stored once the focus moves on either
+        if let Some(reason) = self.ignored_app_focused() {
+            self.recent_hashes.push(dedupe_hash);
+            return Ok(CaptureOutcome::Skipped(reason));
+        }
+
+        let delta = if self.delta {
+            self.text_delta(data)?
+        } else {
+            None
+        };
+
+        // Encrypt and store
+        let (plaintext, delta_base) = match &delta {
+            Some((base, delta)) => (delta.as_slice(), Some(base.clone())),
+            None => (data, None),
+        };
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), hash.clone());
+        entry.stamp(self.db.read().clock());
+        entry.delta_base = delta_base;
+        entry.dedupe_hash = (dedupe_hash != hash).then(|| dedupe_hash.clone());
+        entry.selection = selection;
+        entry.content_size = Some(data.len() as u64);
+        // Only for text read from the clipboard, not from stdin
+        if selection != Some(ClipboardSelection::Primary)
+            && let Some(clipboard) = self.clipboard.as_mut()
+        {
+            entry.text_flavor = clipboard.text_flavor();
+        }
+        entry.per_entry_key = self.db.read().per_entry_keys();
+        entry.encrypt_payload(&self.key, plaintext)?;
+
+        self.db
+            .write()
+            .insert_entry(&entry)
+            .context("Failed to insert entry")?;
+
+        self.recent_hashes.push(dedupe_hash);
+        self.last_stored = Some(entry.id.clone());
+        self.stats.captured += 1;
+        self.db.read().count_usage(UsageCounter::Captures);
+
+        if let Some(notifier) = &mut self.notifier {
+            notifier.notify("Text", Some(&text));
+        }
+        if let Some(hook) = &self.on_capture {
+            hook.fire(&entry.id, "text", data.len());
+        }
+        self.record_rate();
+
+        // Prune if necessary
+        self.prune()?;
+
+        Ok(CaptureOutcome::Stored)
+    }
+
+    /// Encode text as a diff against the latest text entry, if that's much smaller
+    ///
+    /// Returns the base entry's ID and the serialized delta.
+    fn text_delta(&self, data: &[u8]) -> Result<Option<(String, Vec<u8>)>> {
+        let Some(base) = self.db.read().latest_text_base()? else {
+            return Ok(None);
+        };
+        let base_plaintext = base
+            .decrypt_payload(&self.key)
+            .context("Failed to decrypt delta base")?;
+        let delta = bincode::serialize(&TextDelta::between(&base_plaintext, data))
+            .context("Failed to serialize text delta")?;
+
+        // Only worth it when most of the text is shared with the base
+        if delta.len() * 2 > data.len() {
+            return Ok(None);
+        }
+        Ok(Some((base.id, delta)))
+    }
+
+    /// Process image clipboard content, recording the selection it was captured from
+    pub(crate) fn process_image_from(
+        &mut self,
+        image_data: &arboard::ImageData,
+        selection: Option<ClipboardSelection>,
+    ) -> Result<CaptureOutcome> {
+        let pixels = self.image_pixels(image_data);
+        if let Err(e) = check_image_size(
+            image_data.width,
+            image_data.height,
+            pixels.len(),
+            self.max_image_bytes,
+        ) {
+            return Ok(CaptureOutcome::Skipped(e));
+        }
+
+        // Store image metadata along with RGBA bytes
+        let mut img_data = ImageData::new(image_data.width, image_data.height, pixels);
+        if self.swap_rb {
+            img_data.swap_red_blue();
+        }
+
+        let hash = LocalClipboardWatcher::hash_data(&img_data.hash_input());
+        let dedupe_hash = self.image_dedupe_hash(&img_data);
+
+        if self.is_own_write(&hash) {
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Check if this was seen recently
+        if self.recent_hashes.contains(&dedupe_hash) {
+            self.stats.skipped_recent += 1;
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Check if this hash already exists in the database
+        if self.db.read().hash_exists(&dedupe_hash)? {
+            self.stats.skipped_existing += 1;
+            self.recent_hashes.push(dedupe_hash);
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Remembered as seen, so it isn't stored once the focus moves on either
+        if let Some(reason) = self.ignored_app_focused() {
+            self.recent_hashes.push(dedupe_hash);
+            return Ok(CaptureOutcome::Skipped(reason));
+        }
+
+        // Hashes stay those of the full image, so copying it again is a duplicate
+        let original = img_data.metadata();
+        let img_data = match self.image_mode {
+            ImageMode::Full => img_data,
+            ImageMode::Thumbnail => img_data
+                .thumbnail(THUMBNAIL_MAX_SIDE)
+                .context("Failed to scale down image")?,
+        };
+
+        // Encrypt and store, compressed as PNG
+        let (serialized, encoding) = img_data
+            .to_plaintext()
+            .context("Failed to serialize image data")?;
+        let mut entry = ClipboardEntry::new_image(Vec::new(), hash.clone(), &img_data);
+        entry.stamp(self.db.read().clock());
+        entry.image_encoding = Some(encoding);
+        entry.original_image = (img_data.metadata() != original).then_some(original);
+        entry.dedupe_hash = (dedupe_hash != hash).then(|| dedupe_hash.clone());
+        entry.selection = selection;
+        entry.per_entry_key = self.db.read().per_entry_keys();
+        entry.encrypt_payload(&self.key, &serialized)?;
+
+        self.db
+            .write()
+            .insert_entry(&entry)
+            .context("Failed to insert entry")?;
+
+        self.recent_hashes.push(dedupe_hash);
+        self.last_stored = Some(entry.id.clone());
+        self.stats.captured += 1;
+        self.db.read().count_usage(UsageCounter::Captures);
+
+        if let Some(notifier) = &mut self.notifier {
+            notifier.notify(&entry.describe(), None);
+        }
+        if let Some(hook) = &self.on_capture {
+            hook.fire(&entry.id, "image", img_data.bytes.len());
+        }
+        self.record_rate();
+
+        // Prune if necessary
+        self.prune()?;
+
+        Ok(CaptureOutcome::Stored)
+    }
+
+    /// Count a stored entry against `--max-rate`
+    fn record_rate(&mut self) {
+        if let Some(limiter) = &mut self.rate_limiter {
+            limiter.record_at(Instant::now());
+        }
+    }
+
+    /// Check clipboard once
+    pub fn check_clipboard(&mut self) -> Result<CaptureOutcome> {
+        if self.clipboard.is_none() {
+            return Ok(CaptureOutcome::Empty);
+        }
+        if let Some(limiter) = &mut self.rate_limiter
+            && limiter.is_paused_at(Instant::now())
+        {
+            return Ok(CaptureOutcome::Skipped(limiter.pause_reason()));
+        }
+        if self
+            .self_writes
+            .as_ref()
+            .is_some_and(SelfWrites::is_writing)
+        {
+            // clpd is putting its own content there; it's read once that's done
+            return Ok(CaptureOutcome::Duplicate);
+        }
+        if !self.clipboard_changed() {
+            // Whatever is on the clipboard was already handled on an earlier poll
+            return Ok(CaptureOutcome::Duplicate);
+        }
+
+        // Report the most notable outcome of the watched selections
+        let rank = |outcome: &CaptureOutcome| match outcome {
+            CaptureOutcome::Empty => 0,
+            CaptureOutcome::Duplicate => 1,
+            CaptureOutcome::Skipped(_) => 2,
+            CaptureOutcome::Stored => 3,
+        };
+        let mut outcome = CaptureOutcome::Empty;
+        for &selection in self.selections.each() {
+            let Some(clipboard) = self.clipboard.as_mut() else {
+                return Ok(CaptureOutcome::Empty);
+            };
+            let source = recorded_selection(selection);
+            let content = ClipboardContent::read_from(clipboard, selection);
+            let content = self.note_read_result(content, selection);
+            let result = match content {
+                Some(ClipboardContent::Text(text)) => self.process_text_from(&text, source)?,
+                Some(ClipboardContent::Image(image)) => self.process_image_from(&image, source)?,
+                None => CaptureOutcome::Empty,
+            };
+            if rank(&result) > rank(&outcome) {
+                outcome = result;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Count a failed read, reporting it with `--verbose` unless the previous
+    /// read failed the same way; the poll then counts as empty
+    fn note_read_result(
+        &mut self,
+        result: Result<Option<ClipboardContent>, arboard::Error>,
+        selection: ClipboardSelection,
+    ) -> Option<ClipboardContent> {
+        match result {
+            Ok(content) => {
+                if self.last_read_error.take().is_some() && self.verbose {
+                    eprintln!("🔍 The clipboard can be read again");
+                }
+                content
+            }
+            Err(e) => {
+                self.stats.read_errors += 1;
+                let message = match selection {
+                    ClipboardSelection::Clipboard => format!("{}", e),
+                    ClipboardSelection::Primary => format!("{} (primary selection)", e),
+                };
+                if self.last_read_error.as_ref() != Some(&message) {
+                    if self.verbose {
+                        eprintln!("🔍 Failed to read the clipboard: {}", message);
+                    }
+                    self.last_read_error = Some(message);
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether the clipboard may have changed since the last poll
+    ///
+    /// Always true unless `on_change_only` is set and the platform has a change
+    /// counter (Windows, macOS).
+    fn clipboard_changed(&mut self) -> bool {
+        if !self.on_change_only {
+            return true;
+        }
+        let count = self.clipboard.as_ref().and_then(C::change_count);
+        counter_changed(&mut self.last_change_count, count)
+    }
+
+    /// Delete entries older than `max_age` and the oldest entries beyond
+    /// `max_entries`, returning how many were deleted
+    ///
+    /// Runs after every capture, and once when watching starts so that limits
+    /// below the current history apply right away. Entries also age out while
+    /// nothing is copied, so watching prunes every `AGE_PRUNE_INTERVAL` too.
+    pub fn prune(&self) -> Result<usize> {
+        let db = self.db.write();
+        let mut pruned = 0;
+        if let Some(max_age) = self.max_age {
+            pruned += db.prune_older_than(chrono::Utc::now() - max_age, Some(&self.key))?;
+        }
+        if let Some(max) = self.max_entries {
+            pruned += db.prune_to_limit(max, Some(&self.key))?;
+        }
+        Ok(pruned)
+    }
+
+    /// Start watching the clipboard until interrupted with Ctrl+C
+    pub fn watch(mut self) -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = stop.clone();
+            ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+                .context("Failed to install Ctrl+C handler")?;
+        }
+
+        println!("🔒 Clipboard watcher started. Press Ctrl+C to stop.");
+        let pruned = self.prune()?;
+        if pruned > 0 {
+            println!("🧹 Pruned {} old entries to stay within the limits", pruned);
+        }
+        if self.skip_initial && self.skip_current() {
+            println!("⏭ Ignoring what is already on the clipboard, only new copies are stored");
+        }
+        println!("📋 Monitoring clipboard for changes...");
+
+        // The same content is seen on every poll until it changes, report it once
+        let mut last_skip = None;
+        let mut last_prune = Instant::now();
+        let mut changed = true;
+
+        while !stop.load(Ordering::Relaxed) {
+            if self.max_age.is_some() && last_prune.elapsed() >= AGE_PRUNE_INTERVAL {
+                last_prune = Instant::now();
+                match self.prune() {
+                    Ok(0) => {}
+                    Ok(pruned) => println!("🧹 Pruned {} expired entries", pruned),
+                    Err(e) => eprintln!("⚠ Warning: Failed to prune entries: {}", e),
+                }
+            }
+
+            // With --events the clipboard is only read after a change
+            if changed {
+                match self.check_clipboard() {
+                    Ok(CaptureOutcome::Stored) => {
+                        println!("✓ Stored encrypted entry #{}", self.stats().captured);
+                        last_skip = None;
+                    }
+                    Ok(CaptureOutcome::Skipped(reason)) => {
+                        if last_skip.as_ref() != Some(&reason) {
+                            eprintln!("⚠ Not stored: {}", reason);
+                            last_skip = Some(reason);
+                        }
+                    }
+                    Ok(CaptureOutcome::Duplicate | CaptureOutcome::Empty) => {
+                        // No change or duplicate, continue silently
+                    }
+                    Err(e) => {
+                        eprintln!("⚠ Warning: Failed to process clipboard: {}", e);
+                    }
+                }
+            }
+
+            changed = self.wait_for_change();
+        }
+
+        println!();
+        println!("👋 Clipboard watcher stopped.");
+        self.stats().print_summary();
+        Ok(())
+    }
+
+    /// Watch the clipboard without printing anything until `stop` is set
+    ///
+    /// Used when another part of the program (the TUI) owns the terminal.
+    pub fn watch_until(mut self, stop: &AtomicBool) {
+        let _ = self.prune();
+        let mut last_prune = Instant::now();
+        let mut changed = true;
+        while !stop.load(Ordering::Relaxed) {
+            if self.max_age.is_some() && last_prune.elapsed() >= AGE_PRUNE_INTERVAL {
+                last_prune = Instant::now();
+                let _ = self.prune();
+            }
+            // Errors are retried on the next poll; there's nowhere to report them
+            if changed {
+                let _ = self.check_clipboard();
+            }
+            changed = self.wait_for_change();
+        }
+    }
+
+    /// Wait one poll interval, or until the OS reports a change with `--events`,
+    /// returning whether the clipboard should be read
+    fn wait_for_change(&mut self) -> bool {
+        let interval = jittered(self.poll_interval, self.poll_jitter);
+        match &mut self.clipboard {
+            Some(clipboard) => clipboard.wait_for_change(interval),
+            None => {
+                thread::sleep(interval);
+                true
+            }
+        }
+    }
+}
+
+/// Print each clipboard change until interrupted with Ctrl+C, without storing anything
+///
+/// For checking that capture works on a system before setting up a database; no
+/// password is needed. The content on the clipboard when this starts is printed too.
+pub fn print_changes(on_change_only: bool, poll_interval: Duration) -> Result<()> {
+    let mut clipboard = Clipboard::new().context("Failed to initialize clipboard")?;
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+            .context("Failed to install Ctrl+C handler")?;
+    }
+
+    println!("👀 Printing clipboard changes, nothing is stored. Press Ctrl+C to stop.");
+
+    let mut last_hash = None;
+    let mut last_error = None;
+    let mut last_change_count = None;
+    let mut changes = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let changed =
+            !on_change_only || counter_changed(&mut last_change_count, clipboard_change_count());
+        if changed {
+            match ClipboardContent::read(&mut clipboard) {
+                Ok(content) => {
+                    last_error = None;
+                    if let Some(content) = content {
+                        let hash = content.hash();
+                        if last_hash.as_ref() != Some(&hash) {
+                            println!(
+                                "📋 [{}] {}",
+                                chrono::Local::now().format("%H:%M:%S"),
+                                content.describe()
+                            );
+                            last_hash = Some(hash);
+                            changes += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let message = e.to_string();
+                    if last_error.as_ref() != Some(&message) {
+                        eprintln!("⚠ Failed to read the clipboard: {}", message);
+                        last_error = Some(message);
+                    }
+                }
+            }
+        }
+        thread::sleep(poll_interval);
+    }
+
+    println!();
+    println!("👋 Stopped after {} clipboard changes.", changes);
+    Ok(())
+}
+
+pub fn start_watcher(db: ClipboardDatabase, key: MasterKey, options: WatcherOptions) -> Result<()> {
+    build_watcher(db, key, options)?.watch()
+}
+
+/// Check the clipboard once, as `clpd start --once`, returning the stored entry
+/// if something new was captured
+pub fn capture_once(
+    db: ClipboardDatabase,
+    key: MasterKey,
+    options: WatcherOptions,
+) -> Result<(CaptureOutcome, Option<ClipboardEntry>)> {
+    let mut watcher = build_watcher(db, key, options)?;
+    let outcome = watcher.check_clipboard()?;
+    let entry = match watcher.last_stored() {
+        Some(id) => watcher.db.read().get_entry(id)?,
+        None => None,
+    };
+    Ok((outcome, entry))
+}
+
+fn build_watcher(
+    db: ClipboardDatabase,
+    key: MasterKey,
+    options: WatcherOptions,
+) -> Result<LocalClipboardWatcher> {
+    let mut watcher = LocalClipboardWatcher::new(db, key, options.max_entries)?
+        .with_max_age(options.max_age)
+        .with_dedupe_window(options.dedupe_window)
+        .with_poll_interval(options.poll_interval)
+        .with_poll_jitter(options.poll_jitter)
+        .with_strip_ansi(options.strip_ansi)
+        .with_normalize(options.normalize)
+        .with_selections(options.selections)
+        .with_max_image_bytes(options.max_image_bytes)
+        .with_image_mode(options.images)
+        .with_swap_rb(options.swap_rb)
+        .with_canonical_images(options.canonical_images)
+        .with_max_rate(options.max_rate)
+        .with_delta(options.delta)
+        .with_on_change_only(options.on_change_only)
+        .with_skip_initial(options.skip_initial)
+        .with_ignore_apps(options.ignore_apps)
+        .with_verbose(options.verbose);
+    if !watcher.ignore_apps.is_empty()
+        && let Err(e) = focused_app()
+    {
+        eprintln!(
+            "⚠ Warning: Can't tell which application has the focus ({:#}), so --ignore-app may not skip anything",
+            e
+        );
+    }
+    if options.events {
+        match ChangeEvents::start(options.selections.includes(ClipboardSelection::Primary)) {
+            Ok(events) => watcher = watcher.with_change_events(Some(events)),
+            Err(e) => eprintln!(
+                "⚠ Warning: No clipboard change notifications ({:#}), polling every {} ms instead",
+                e,
+                options.poll_interval.as_millis()
+            ),
+        }
+    }
+    if options.notify {
+        watcher = watcher.with_notifier(CaptureNotifier::new(options.quiet_hours));
+    }
+    if let Some(command) = options.on_capture {
+        watcher = watcher.with_on_capture(CaptureHook::new(command));
+    }
+    Ok(watcher)
+}
+
+/// An in-memory clipboard for driving the watcher in tests, with a change counter
+/// like those of Windows and macOS
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeClipboard {
+    clipboard: Option<ClipboardContent>,
+    primary: Option<ClipboardContent>,
+    changes: u64,
+    // Reads fail as if another application held the clipboard open
+    locked: bool,
+    // Takes text that isn't valid UTF-8, as Windows and macOS do
+    takes_bytes: bool,
+    raw_text: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl FakeClipboard {
+    /// Put content on a selection, as another application copying it would
+    pub fn copy(&mut self, content: ClipboardContent, selection: ClipboardSelection) {
+        *self.selection(selection) = Some(content);
+        self.changes += 1;
+    }
+
+    /// A clipboard that takes text byte for byte, like those of Windows and macOS
+    pub fn taking_bytes() -> Self {
+        Self {
+            takes_bytes: true,
+            ..Self::default()
+        }
+    }
+
+    /// The bytes last put on the clipboard with `set_text_bytes`
+    pub fn raw_text(&self) -> Option<&[u8]> {
+        self.raw_text.as_deref()
+    }
+
+    pub fn copy_text(&mut self, text: &str) {
+        self.copy(
+            ClipboardContent::Text(text.to_string()),
+            ClipboardSelection::Clipboard,
+        );
+    }
+
+    /// Make reads fail, or work again
+    pub fn lock(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// Empty every selection
+    pub fn clear(&mut self) {
+        self.clipboard = None;
+        self.primary = None;
+        self.changes += 1;
+    }
+
+    fn selection(&mut self, selection: ClipboardSelection) -> &mut Option<ClipboardContent> {
+        match selection {
+            ClipboardSelection::Clipboard => &mut self.clipboard,
+            ClipboardSelection::Primary => &mut self.primary,
+        }
+    }
+}
+
+#[cfg(test)]
+impl ClipboardSource for FakeClipboard {
+    fn get_text(&mut self, selection: ClipboardSelection) -> Result<String, arboard::Error> {
+        if self.locked {
+            return Err(arboard::Error::ClipboardOccupied);
+        }
+        match self.selection(selection) {
+            Some(ClipboardContent::Text(text)) => Ok(text.clone()),
+            _ => Err(arboard::Error::ContentNotAvailable),
+        }
+    }
+
+    fn get_image(
+        &mut self,
+        selection: ClipboardSelection,
+    ) -> Result<arboard::ImageData<'static>, arboard::Error> {
+        if self.locked {
+            return Err(arboard::Error::ClipboardOccupied);
+        }
+        match self.selection(selection) {
+            Some(ClipboardContent::Image(image)) => Ok(image.clone()),
+            _ => Err(arboard::Error::ContentNotAvailable),
+        }
+    }
+
+    fn set_text(
+        &mut self,
+        text: String,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        self.copy(ClipboardContent::Text(text), selection);
+        Ok(())
+    }
+
+    fn set_image(
+        &mut self,
+        image: arboard::ImageData,
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        self.copy(ClipboardContent::Image(image.to_owned_img()), selection);
+        Ok(())
+    }
+
+    fn set_text_bytes(
+        &mut self,
+        bytes: &[u8],
+        selection: ClipboardSelection,
+    ) -> Result<(), arboard::Error> {
+        if !self.takes_bytes {
+            return Err(arboard::Error::ConversionFailure);
+        }
+        self.raw_text = Some(bytes.to_vec());
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        self.copy(ClipboardContent::Text(text), selection);
+        Ok(())
+    }
+
+    fn change_count(&self) -> Option<u64> {
+        Some(self.changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{DecryptedEntry, ImageEncoding};
+    use crate::testutil::{capture_fixture, test_key};
+
+    #[test]
+    fn test_hash_data() {
+        let data = b"test data";
+        let hash = LocalClipboardWatcher::hash_data(data);
+
+        // SHA-256 produces 64 hex characters
+        assert_eq!(hash.len(), 64);
+
+        // Same data produces same hash
+        let hash2 = LocalClipboardWatcher::hash_data(data);
+        assert_eq!(hash, hash2);
+
+        // Different data produces different hash
+        let hash3 = LocalClipboardWatcher::hash_data(b"different data");
+        assert_ne!(hash, hash3);
+    }
+
+    #[test]
+    fn test_recent_hashes_alternating_values() {
+        let mut recent = RecentHashes::new(4);
+        let a = LocalClipboardWatcher::hash_data(b"value a");
+        let b = LocalClipboardWatcher::hash_data(b"value b");
+
+        let mut stored = 0;
+        for hash in [&a, &b, &a, &b, &a, &b] {
+            if !recent.contains(hash) {
+                stored += 1;
+            }
+            recent.push(hash.clone());
+        }
+
+        // Only the first occurrence of each value is treated as new
+        assert_eq!(stored, 2);
+    }
+
+    #[test]
+    fn test_recent_hashes_evicts_oldest() {
+        let mut recent = RecentHashes::new(2);
+        recent.push("a".to_string());
+        recent.push("b".to_string());
+        recent.push("c".to_string());
+
+        assert!(!recent.contains("a"));
+        assert!(recent.contains("b"));
+        assert!(recent.contains("c"));
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("plain"), "plain");
+        assert_eq!(
+            strip_ansi("\x1b[1;31merror\x1b[0m: failed"),
+            "error: failed"
+        );
+        assert_eq!(strip_ansi("\x1b]0;title\x07text"), "text");
+        assert_eq!(
+            strip_ansi("\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"),
+            "link"
+        );
+    }
+
+    #[test]
+    fn test_text_flavor() {
+        let formats = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            text_flavor(&formats(&[
+                "public.utf8-plain-text",
+                "public.html",
+                "com.example.editor.plain-text",
+            ]))
+            .as_deref(),
+            Some("com.example.editor.plain-text")
+        );
+        assert_eq!(
+            text_flavor(&formats(&[
+                "CF_UNICODETEXT",
+                "CF_LOCALE",
+                "Plaintext Notes"
+            ]))
+            .as_deref(),
+            Some("Plaintext Notes")
+        );
+        assert_eq!(
+            text_flavor(&formats(&[
+                "text/html",
+                "TEXT/PLAIN;charset=UTF-8",
+                "text/plain;charset=utf-16"
+            ]))
+            .as_deref(),
+            Some("text/plain;charset=utf-16")
+        );
+        // Only generic or non-plain-text formats
+        assert_eq!(
+            text_flavor(&formats(&["CF_UNICODETEXT", "CF_TEXT", "HTML Format"])),
+            None
+        );
+        assert_eq!(
+            text_flavor(&formats(&["public.rtf", "NSStringPboardType"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_encode_text_flavor() {
+        assert_eq!(encode_text_flavor("Plaintext Notes", "hé"), b"h\xc3\xa9\0");
+        assert_eq!(
+            encode_text_flavor("Editor UnicodeText", "hé"),
+            [b'h', 0, 0xe9, 0, 0, 0]
+        );
+        assert_eq!(
+            encode_text_flavor("text/plain;charset=UTF-16", "h"),
+            [b'h', 0, 0, 0]
+        );
+        assert_eq!(
+            encode_text_flavor("text/plain;charset=utf-16be", "h"),
+            [0, b'h', 0, 0]
+        );
+        assert_eq!(text_flavor(&[]), None);
+
+        // Kept with the entry; older entries have none
+        let mut entry = ClipboardEntry::new(ClipboardContentType::Text, Vec::new(), String::new());
+        entry.text_flavor = Some("com.example.editor.plain-text".to_string());
+        let decoded = ClipboardEntry::from_stored(&bincode::serialize(&entry).unwrap()).unwrap();
+        assert_eq!(decoded.text_flavor, entry.text_flavor);
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary("plain text"));
+        assert!(!looks_binary("fn main() {\n\tprintln!(\"hi\");\r\n}\n"));
+        assert!(!looks_binary("naïve café 日本語 🎉"));
+        assert!(!looks_binary("\x1b[1;31merror\x1b[0m: failed"));
+        assert!(!looks_binary("one stray \0 in a sentence"));
+
+        assert!(looks_binary("\0"));
+        assert!(looks_binary("PK\x03\x04\x14\0\0\0\x08\0"));
+        let lossy = String::from_utf8_lossy(&[
+            0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0, 0, 0, 0x0d,
+        ]);
+        assert!(looks_binary(&lossy));
+    }
+
+    #[test]
+    fn test_binary_text_is_skipped() {
+        let (_dir, _, mut watcher) = capture_fixture();
+
+        assert!(matches!(
+            watcher
+                .process_text("\x7fELF\x02\x01\x01\0\0\0\0\0")
+                .unwrap(),
+            CaptureOutcome::Skipped(_)
+        ));
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 0);
+        assert_eq!(
+            watcher.process_text("real text").unwrap(),
+            CaptureOutcome::Stored
+        );
+    }
+
+    #[test]
+    fn test_ignored_app_is_not_captured() {
+        let (_dir, _, watcher) = capture_fixture();
+        let mut watcher = watcher.with_ignore_apps(vec!["keepass".to_string()]);
+
+        watcher.detect_focus = || Ok(vec!["keepassxc".into(), "KeePassXC".into()]);
+        assert!(matches!(
+            watcher.process_text("hunter2").unwrap(),
+            CaptureOutcome::Skipped(reason) if reason.contains("keepass")
+        ));
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 0);
+
+        // Still on the clipboard once another application has the focus
+        watcher.detect_focus = || Ok(vec!["firefox".into()]);
+        assert_eq!(
+            watcher.process_text("hunter2").unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(
+            watcher.process_text("new text").unwrap(),
+            CaptureOutcome::Stored
+        );
+
+        // Stored when the focused application can't be found out
+        watcher.detect_focus = || anyhow::bail!("no window has the focus");
+        assert_eq!(
+            watcher.process_text("more text").unwrap(),
+            CaptureOutcome::Stored
+        );
+    }
+
+    #[test]
+    fn test_counter_changed() {
+        let mut last = None;
+        assert!(counter_changed(&mut last, Some(5)));
+        assert!(!counter_changed(&mut last, Some(5)));
+        assert!(counter_changed(&mut last, Some(6)));
+        // Platforms without a counter always read the clipboard
+        assert!(counter_changed(&mut last, None));
+        assert!(counter_changed(&mut last, None));
+    }
+
+    #[test]
+    fn test_process_text_strips_ansi() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        let key = MasterKey::from_bytes([7u8; 32]);
+        let mut watcher = LocalClipboardWatcher::<Clipboard>::with_clipboard(
+            None,
+            Arc::new(RwLock::new(db)),
+            key,
+            None,
+        )
+        .with_strip_ansi(true);
+
+        assert_eq!(
+            watcher.process_text("\x1b[32mok\x1b[0m done").unwrap(),
+            CaptureOutcome::Stored
+        );
+
+        let entries = watcher.db.read().list_entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        let stored = crate::crypto::decrypt(&watcher.key, &entries[0].payload).unwrap();
+        assert_eq!(stored, b"ok done");
+        assert_eq!(
+            entries[0].hash,
+            LocalClipboardWatcher::hash_data(b"ok done")
+        );
+    }
+
+    #[test]
+    fn test_process_text_stores_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        let key = MasterKey::from_bytes([7u8; 32]);
+        let mut watcher = LocalClipboardWatcher::<Clipboard>::with_clipboard(
+            None,
+            Arc::new(RwLock::new(db)),
+            key,
+            None,
+        )
+        .with_delta(true);
+
+        let lines: Vec<String> = (0..10)
+            .map(|i| format!("let value_{} = compute({}, {});", i, i, i * 2))
+            .collect();
+        let first = lines.join("\n");
+        let second = first.replace("compute(3,", "compute(30,");
+        let third = second.replace("value_4", "renamed_4");
+        let (first, second, third) = (first.as_str(), second.as_str(), third.as_str());
+        for text in [first, second, third, "unrelated"] {
+            watcher.process_text(text).unwrap();
+        }
+
+        let db = watcher.db.read();
+        let entries = db.list_entries().unwrap();
+        let by_hash = |text: &str| {
+            let hash = LocalClipboardWatcher::hash_data(text.as_bytes());
+            entries.iter().find(|e| e.hash == hash).unwrap()
+        };
+
+        let base = by_hash(first);
+        assert_eq!(base.delta_base, None);
+        // Deltas are always taken against a full entry
+        for text in [second, third] {
+            let entry = by_hash(text);
+            assert_eq!(entry.delta_base.as_ref(), Some(&base.id));
+            assert_eq!(
+                db.decrypt_entry(&watcher.key, entry).unwrap(),
+                text.as_bytes()
+            );
+        }
+        // Too different (and too short) to be worth a delta
+        assert_eq!(by_hash("unrelated").delta_base, None);
+    }
+
+    #[test]
+    fn test_per_entry_keys_setting() {
+        let (_dir, _, mut watcher) = capture_fixture();
+
+        watcher.process_text("single key").unwrap();
+        watcher.db.read().set_per_entry_keys(true).unwrap();
+        watcher.process_text("own key").unwrap();
+
+        let db = watcher.db.read();
+        for entry in db.list_entries().unwrap() {
+            let content = db.decrypt_content(&watcher.key, &entry).unwrap();
+            match content {
+                DecryptedEntry::Text(text) if text == "single key" => {
+                    assert!(!entry.per_entry_key)
+                }
+                DecryptedEntry::Text(text) if text == "own key" => assert!(entry.per_entry_key),
+                other => panic!("unexpected entry {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_prune_applies_limit_before_capturing() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(RwLock::new(
+            ClipboardDatabase::open(dir.path().join("db")).unwrap(),
+        ));
+        let key = MasterKey::from_bytes([7u8; 32]);
+        let mut unlimited =
+            LocalClipboardWatcher::<Clipboard>::with_clipboard(None, db.clone(), key.clone(), None);
+        for i in 0..5 {
+            unlimited.process_text(&format!("entry {}", i)).unwrap();
+        }
+        assert_eq!(unlimited.prune().unwrap(), 0);
+
+        let limited =
+            LocalClipboardWatcher::<Clipboard>::with_clipboard(None, db.clone(), key, Some(2));
+        assert_eq!(limited.prune().unwrap(), 3);
+        assert_eq!(db.read().count_entries().unwrap(), 2);
+        assert_eq!(limited.prune().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_capture_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(RwLock::new(
+            ClipboardDatabase::open(dir.path().join("db")).unwrap(),
+        ));
+        let key = MasterKey::from_bytes([7u8; 32]);
+        let mut watcher =
+            LocalClipboardWatcher::<Clipboard>::with_clipboard(None, db.clone(), key.clone(), None);
+
+        watcher.process_text("one").unwrap();
+        watcher.process_text("one").unwrap();
+        watcher.process_text("two").unwrap();
+        assert_eq!(watcher.process_text("").unwrap(), CaptureOutcome::Empty);
+
+        // A fresh session only knows about "one" from the database
+        let mut restarted = LocalClipboardWatcher::<Clipboard>::with_clipboard(None, db, key, None);
+        restarted.process_text("one").unwrap();
+
+        assert_eq!(
+            watcher.stats(),
+            &CaptureStats {
+                captured: 2,
+                skipped_recent: 1,
+                skipped_existing: 0,
+                skipped_own: 0,
+                read_errors: 0,
+            }
+        );
+        assert_eq!(restarted.stats().skipped_existing, 1);
+        assert!((watcher.stats().dedupe_rate() - 100.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rate_limiter_pauses_and_resumes() {
+        let mut limiter = RateLimiter::new(CaptureRate {
+            count: 3,
+            window: Duration::from_secs(10),
+        });
+        let start = Instant::now();
+
+        // Spread out enough, any number of entries is fine
+        for i in 0..10 {
+            let at = start + Duration::from_secs(4 * i);
+            assert!(!limiter.record_at(at));
+            assert!(!limiter.is_paused_at(at));
+        }
+
+        // The fourth entry within ten seconds trips the cutoff
+        let burst = start + Duration::from_secs(100);
+        for i in 0..3 {
+            assert!(!limiter.record_at(burst + Duration::from_secs(i)));
+        }
+        let tripped = burst + Duration::from_secs(3);
+        assert!(limiter.record_at(tripped));
+        assert!(limiter.is_paused_at(tripped));
+        assert!(limiter.is_paused_at(tripped + RATE_COOLDOWN - Duration::from_secs(1)));
+
+        // After the cooldown capture resumes with a fresh window
+        let resumed = tripped + RATE_COOLDOWN;
+        assert!(!limiter.is_paused_at(resumed));
+        for i in 0..3 {
+            assert!(!limiter.record_at(resumed + Duration::from_secs(i)));
+        }
+    }
+
+    #[test]
+    fn test_clipboard_content_describe() {
+        let text = ClipboardContent::Text("first line\nsecond line".to_string());
+        assert_eq!(text.describe(), "Text, 22 bytes: first line⏎second line");
+
+        let image = ClipboardContent::Image(arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![0; 8].into(),
+        });
+        assert_eq!(image.describe(), "Image 2x1, 8 bytes");
+        assert_ne!(text.hash(), image.hash());
+    }
+
+    #[test]
+    fn test_last_stored_entry() {
+        let (_dir, db, mut watcher) = capture_fixture();
+        let key = test_key();
+        assert_eq!(watcher.last_stored(), None);
+        assert_eq!(watcher.process_text("").unwrap(), CaptureOutcome::Empty);
+        assert_eq!(watcher.last_stored(), None);
+        assert_eq!(watcher.process_text("new").unwrap(), CaptureOutcome::Stored);
+        let newest = db.newest_entries(1).unwrap().remove(0);
+        assert_eq!(watcher.last_stored(), Some(newest.id.as_str()));
+
+        // A one-shot capture of content that's already stored reports no entry
+        let mut watcher = LocalClipboardWatcher::without_clipboard(db, key);
+        assert_eq!(
+            watcher.process_text("new").unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.last_stored(), None);
+    }
+
+    #[test]
+    fn test_usage_counters() {
+        use crate::database::{ClipboardType, UsageCounter};
+
+        let (_dir, db, mut watcher) = capture_fixture();
+        let count = |counter| {
+            db.usage_counts()
+                .unwrap()
+                .map(|counts| counts.into_iter().find(|(c, _)| *c == counter).unwrap().1)
+        };
+
+        // Nothing is counted until switched on
+        watcher.process_text("before").unwrap();
+        assert_eq!(db.usage_counts().unwrap(), None);
+
+        db.set_usage_counting(true).unwrap();
+        watcher.process_text("one").unwrap();
+        watcher.process_text("one").unwrap();
+        watcher.process_text("two").unwrap();
+        assert_eq!(count(UsageCounter::Captures), Some(2));
+
+        let clipboard = ClipboardType::Local(Box::new(watcher));
+        clipboard.record_clipboard_write("hash");
+        assert_eq!(count(UsageCounter::Copies), Some(1));
+        db.count_usage(UsageCounter::Copies);
+        assert_eq!(count(UsageCounter::Copies), Some(2));
+        assert_eq!(count(UsageCounter::Searches), Some(0));
+
+        db.set_usage_counting(false).unwrap();
+        db.count_usage(UsageCounter::Copies);
+        assert_eq!(db.usage_counts().unwrap(), None);
+    }
+
+    #[test]
+    fn test_normalized_text_dedupes() {
+        let (_dir, _, watcher) = capture_fixture();
+        // No dedupe window, so only the stored hashes can match
+        let mut watcher = watcher
+            .with_dedupe_window(0)
+            .with_normalize(Some(TextNormalization::Nfc));
+
+        let nfd = "Cafe\u{301}";
+        let nfc = "Caf\u{e9}";
+        assert_eq!(watcher.process_text(nfd).unwrap(), CaptureOutcome::Stored);
+        assert_eq!(
+            watcher.process_text(nfc).unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(
+            watcher.process_text("CAF\u{c9}").unwrap(),
+            CaptureOutcome::Stored
+        );
+
+        // The text is stored as copied, only the dedupe hash is normalized
+        let entries = watcher.db.read().list_entries().unwrap();
+        let first = entries.iter().find(|e| e.dedupe_hash.is_some()).unwrap();
+        assert_eq!(first.hash, LocalClipboardWatcher::hash_data(nfd.as_bytes()));
+        assert_eq!(
+            crate::crypto::decrypt(&watcher.key, &first.payload).unwrap(),
+            nfd.as_bytes()
+        );
+
+        // Case folding also matches text that differs only in case
+        watcher = watcher.with_normalize(Some(TextNormalization::Casefold));
+        assert_eq!(
+            watcher.process_text("caf\u{e9}").unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert_eq!(
+            watcher.process_text("CAFE\u{301}").unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_skip_initial_content() {
+        let (_dir, _, watcher) = capture_fixture();
+        let mut watcher = watcher.with_strip_ansi(true);
+
+        watcher.mark_seen(&ClipboardContent::Text("\x1b[31msecret\x1b[0m".to_string()));
+        watcher.mark_seen(&ClipboardContent::Image(arboard::ImageData {
+            width: 1,
+            height: 1,
+            bytes: vec![1, 2, 3, 4].into(),
+        }));
+
+        // Still on the clipboard on the next polls
+        assert_eq!(
+            watcher.process_text("\x1b[31msecret\x1b[0m").unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        let image = arboard::ImageData {
+            width: 1,
+            height: 1,
+            bytes: vec![1, 2, 3, 4].into(),
+        };
+        assert_eq!(
+            watcher.process_image_from(&image, None).unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 0);
+
+        assert_eq!(
+            watcher.process_text("copied later").unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_format_list() {
+        let output = "TARGETS\nUTF8_STRING\n\ntext/plain\nUTF8_STRING\r\n  image/png \n";
+        assert_eq!(
+            parse_format_list(output),
+            ["TARGETS", "UTF8_STRING", "text/plain", "image/png"]
+        );
+        assert!(parse_format_list("").is_empty());
+    }
+
+    #[test]
+    fn test_self_writes_window() {
+        let writes = SelfWrites::default();
+        let start = Instant::now();
+        writes.record_at("a", start);
+        writes.record_at("b", start);
+
+        assert!(!writes.take_at("c", start));
+        assert!(writes.take_at("a", start + Duration::from_secs(1)));
+        // Each write is only matched once
+        assert!(!writes.take_at("a", start + Duration::from_secs(1)));
+        // Too late to be the watcher noticing our own write
+        assert!(!writes.take_at("b", start + SELF_WRITE_WINDOW + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_write_in_progress_pauses_reads() {
+        let writes = SelfWrites::default();
+        assert!(!writes.is_writing());
+        let first = writes.begin_write();
+        let second = writes.clone().begin_write();
+        assert!(writes.is_writing());
+        drop(first);
+        // Still paused until every write is done
+        assert!(writes.is_writing());
+        drop(second);
+        assert!(!writes.is_writing());
+    }
+
+    #[test]
+    fn test_announced_writes_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let writes = AnnouncedWrites::new(dir.path().join("clpd").join("last-write"));
+        let start = SystemTime::now();
+        assert!(!writes.take_at("a", start));
+
+        writes.announce_at("a", start).unwrap();
+        assert!(!writes.take_at("b", start));
+        assert!(writes.take_at("a", start + Duration::from_secs(1)));
+        // Only matched once
+        assert!(!writes.take_at("a", start + Duration::from_secs(1)));
+
+        writes.announce_at("a", start).unwrap();
+        assert!(!writes.take_at("a", start + SELF_WRITE_WINDOW + Duration::from_secs(1)));
+
+        fs::write(dir.path().join("clpd").join("last-write"), "garbage").unwrap();
+        assert!(!writes.take_at("garbage", start));
+    }
+
+    #[test]
+    fn test_process_text_ignores_announced_writes() {
+        let (dir, _, watcher) = capture_fixture();
+        let writes = AnnouncedWrites::new(dir.path().join("last-write"));
+        let mut watcher = watcher.with_announced_writes(Some(writes.clone()));
+
+        // `clpd copy` in another process
+        writes
+            .announce(&LocalClipboardWatcher::hash_data(b"copied back"))
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                watcher.process_text("copied back").unwrap(),
+                CaptureOutcome::Duplicate
+            );
+        }
+        assert_eq!(watcher.stats().skipped_own, 1);
+        assert_eq!(watcher.process_text("new").unwrap(), CaptureOutcome::Stored);
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_process_text_ignores_self_writes() {
+        let (_dir, _, watcher) = capture_fixture();
+        let writes = SelfWrites::default();
+        let mut watcher = watcher
+            .with_dedupe_window(1)
+            .with_self_writes(writes.clone());
+
+        watcher.process_text("stored").unwrap();
+        watcher.process_text("other").unwrap();
+
+        // The TUI copies "stored" back; polls keep seeing it until the next copy
+        writes.record(&LocalClipboardWatcher::hash_data(b"stored"));
+        for _ in 0..3 {
+            assert_eq!(
+                watcher.process_text("stored").unwrap(),
+                CaptureOutcome::Duplicate
+            );
+        }
+        assert_eq!(watcher.stats().skipped_own, 1);
+        assert_eq!(watcher.stats().skipped_existing, 0);
+        // "other" is still the most recent value, so it's still deduplicated
+        watcher.process_text("other").unwrap();
+        assert_eq!(watcher.stats().skipped_recent, 1);
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_process_image_rejects_bad_sizes() {
+        let (_dir, _, watcher) = capture_fixture();
+        let mut watcher = watcher.with_max_image_bytes(64);
+
+        // Fewer bytes than the dimensions claim
+        let mismatched = arboard::ImageData {
+            width: 2,
+            height: 2,
+            bytes: vec![0u8; 3].into(),
+        };
+        assert!(matches!(
+            watcher.process_image_from(&mismatched, None).unwrap(),
+            CaptureOutcome::Skipped(_)
+        ));
+
+        // Some applications briefly offer an image without pixels
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let empty = arboard::ImageData {
+                width,
+                height,
+                bytes: Vec::new().into(),
+            };
+            assert!(matches!(
+                watcher.process_image_from(&empty, None).unwrap(),
+                CaptureOutcome::Skipped(_)
+            ));
+        }
+
+        // Dimensions over the cap
+        let oversized = arboard::ImageData {
+            width: 1 << 20,
+            height: 1 << 20,
+            bytes: vec![0u8; 16].into(),
+        };
+        assert!(matches!(
+            watcher.process_image_from(&oversized, None).unwrap(),
+            CaptureOutcome::Skipped(_)
+        ));
+
+        let valid = arboard::ImageData {
+            width: 2,
+            height: 2,
+            bytes: vec![0u8; 16].into(),
+        };
+        assert_eq!(
+            watcher.process_image_from(&valid, None).unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert_eq!(
+            watcher.process_image_from(&valid, None).unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_canonical_image_dedupe() {
+        let (_dir, _, watcher) = capture_fixture();
+        let mut watcher = watcher.with_dedupe_window(1).with_canonical_images(true);
+
+        // The same screenshot with unused alpha filled with 0, then with 255
+        let transparent = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![10, 20, 30, 0, 40, 50, 60, 0].into(),
+        };
+        let opaque = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![10, 20, 30, 255, 40, 50, 60, 255].into(),
+        };
+        let other = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![1, 1, 1, 1, 2, 2, 2, 2].into(),
+        };
+        // With its rows padded to 12 bytes
+        let padded = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![10, 20, 30, 255, 40, 50, 60, 255, 0, 0, 0, 0].into(),
+        };
+        assert_eq!(
+            watcher.process_image_from(&transparent, None).unwrap(),
+            CaptureOutcome::Stored
+        );
+        watcher.process_image_from(&other, None).unwrap();
+        assert_eq!(
+            watcher.process_image_from(&opaque, None).unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(
+            watcher.process_image_from(&padded, None).unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 2);
+
+        // The original pixels are what's stored
+        let entries = watcher.db.read().list_entries().unwrap();
+        let image = entries
+            .iter()
+            .find(|entry| entry.dedupe_hash.is_some())
+            .unwrap();
+        let stored = watcher.db.read().decrypt_content(&watcher.key, image);
+        let Ok(DecryptedEntry::Image(stored)) = stored else {
+            panic!("not an image");
+        };
+        assert_eq!(stored.bytes, transparent.bytes.to_vec());
+
+        // Without the flag they're different images, and padded rows are rejected
+        let (_dir, _, mut watcher) = capture_fixture();
+        watcher.process_image_from(&transparent, None).unwrap();
+        assert_eq!(
+            watcher.process_image_from(&opaque, None).unwrap(),
+            CaptureOutcome::Stored
+        );
+        assert!(matches!(
+            watcher.process_image_from(&padded, None).unwrap(),
+            CaptureOutcome::Skipped(_)
+        ));
+    }
+
+    #[test]
+    fn test_jittered_interval() {
+        let interval = Duration::from_millis(500);
+        assert_eq!(jittered(interval, 0), interval);
+
+        for _ in 0..100 {
+            let sleep = jittered(interval, 20);
+            assert!(sleep >= Duration::from_millis(400));
+            assert!(sleep <= Duration::from_millis(600));
+        }
+    }
+
+    fn fake_watcher(
+        dir: &tempfile::TempDir,
+        max_entries: Option<usize>,
+    ) -> LocalClipboardWatcher<FakeClipboard> {
+        let db = ClipboardDatabase::open(dir.path().join("db")).unwrap();
+        LocalClipboardWatcher::with_clipboard(
+            Some(FakeClipboard::default()),
+            Arc::new(RwLock::new(db)),
+            MasterKey::from_bytes([7u8; 32]),
+            max_entries,
+        )
+    }
+
+    fn fake(watcher: &mut LocalClipboardWatcher<FakeClipboard>) -> &mut FakeClipboard {
+        watcher.clipboard.as_mut().unwrap()
+    }
+
+    fn stored_texts(watcher: &LocalClipboardWatcher<FakeClipboard>) -> Vec<String> {
+        let db = watcher.db.read();
+        let mut texts: Vec<String> = db
+            .list_entries()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.content_type == ClipboardContentType::Text)
+            .map(|entry| String::from_utf8(db.decrypt_entry(&watcher.key, entry).unwrap()).unwrap())
+            .collect();
+        texts.sort();
+        texts
+    }
+
+    #[test]
+    fn test_fake_clipboard_capture_dedupe_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = fake_watcher(&dir, Some(2));
+
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Empty);
+
+        fake(&mut watcher).copy_text("one");
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        // Seen again on every poll until something else is copied
+        assert_eq!(
+            watcher.check_clipboard().unwrap(),
+            CaptureOutcome::Duplicate
+        );
+
+        fake(&mut watcher).copy_text("two");
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        fake(&mut watcher).copy_text("one");
+        assert_eq!(
+            watcher.check_clipboard().unwrap(),
+            CaptureOutcome::Duplicate
+        );
+
+        // The third entry prunes the oldest
+        fake(&mut watcher).copy_text("three");
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        assert_eq!(stored_texts(&watcher), ["three", "two"]);
+
+        let image = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![255; 8].into(),
+        };
+        fake(&mut watcher).copy(
+            ClipboardContent::Image(image),
+            ClipboardSelection::Clipboard,
+        );
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        assert_eq!(watcher.db.read().count_entries().unwrap(), 2);
+        assert_eq!(stored_texts(&watcher), ["three"]);
+
+        fake(&mut watcher).clear();
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Empty);
+
+        assert_eq!(watcher.stats().captured, 4);
+        assert_eq!(watcher.stats().skipped_recent, 2);
+    }
+
+    #[test]
+    fn test_fake_clipboard_on_change_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = fake_watcher(&dir, None).with_on_change_only(true);
+
+        fake(&mut watcher).copy_text("one");
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+
+        // Without the counter moving the clipboard isn't read at all
+        fake(&mut watcher).clipboard = Some(ClipboardContent::Text("unseen".to_string()));
+        assert_eq!(
+            watcher.check_clipboard().unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(stored_texts(&watcher), ["one"]);
+
+        fake(&mut watcher).copy_text("two");
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        assert_eq!(stored_texts(&watcher), ["one", "two"]);
+    }
+
+    #[test]
+    fn test_fake_clipboard_skip_initial_and_selections() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = fake_watcher(&dir, None).with_selections(Selections::Both);
+
+        fake(&mut watcher).copy_text("before start");
+        assert!(watcher.skip_current());
+        assert_eq!(
+            watcher.check_clipboard().unwrap(),
+            CaptureOutcome::Duplicate
+        );
+
+        set_text(
+            fake(&mut watcher),
+            "selected".to_string(),
+            ClipboardSelection::Primary,
+        )
+        .unwrap();
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        assert_eq!(stored_texts(&watcher), ["selected"]);
+    }
+
+    #[test]
+    fn test_thumbnail_mode_downscales_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = fake_watcher(&dir, None).with_image_mode(ImageMode::Thumbnail);
+
+        let (width, height) = (1024, 512);
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % 251) as u8, (i / width) as u8, 128, 255])
+            .collect();
+        let image = arboard::ImageData {
+            width,
+            height,
+            bytes: pixels.into(),
+        };
+        fake(&mut watcher).copy(
+            ClipboardContent::Image(image.clone()),
+            ClipboardSelection::Clipboard,
+        );
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+
+        let entry = watcher.db.read().list_entries().unwrap().remove(0);
+        let stored = entry.image.unwrap();
+        assert_eq!((stored.width, stored.height), (THUMBNAIL_MAX_SIDE, 128));
+        let original = entry.original_image.unwrap();
+        assert_eq!((original.width, original.height), (width, height));
+        assert!(entry.describe().contains("thumbnail"));
+        assert!(entry.thumbnail_warning().is_some());
+
+        // Copying hands out the thumbnail, which reads back the same
+        let plaintext = watcher
+            .db
+            .read()
+            .decrypt_entry(&watcher.key, &entry)
+            .unwrap();
+        let thumbnail = ImageData::from_plaintext(&plaintext).unwrap();
+        assert_eq!(
+            (thumbnail.width, thumbnail.height),
+            (THUMBNAIL_MAX_SIDE, 128)
+        );
+        assert!(thumbnail.is_well_formed());
+        let mut clipboard = FakeClipboard::default();
+        set_image(
+            &mut clipboard,
+            arboard::ImageData {
+                width: thumbnail.width,
+                height: thumbnail.height,
+                bytes: thumbnail.bytes.as_slice().into(),
+            },
+            ClipboardSelection::Clipboard,
+        )
+        .unwrap();
+        let copied = clipboard.get_image(ClipboardSelection::Clipboard).unwrap();
+        assert_eq!(copied.bytes.as_ref(), thumbnail.bytes.as_slice());
+
+        // The full image copied again is a duplicate of the thumbnail entry
+        fake(&mut watcher).clear();
+        watcher.check_clipboard().unwrap();
+        watcher.recent_hashes = RecentHashes::new(DEFAULT_DEDUPE_WINDOW);
+        fake(&mut watcher).copy(
+            ClipboardContent::Image(image),
+            ClipboardSelection::Clipboard,
+        );
+        assert_eq!(
+            watcher.check_clipboard().unwrap(),
+            CaptureOutcome::Duplicate
+        );
+        assert_eq!(watcher.stats().skipped_existing, 1);
+
+        // Images that are small already are stored as they are
+        let small = arboard::ImageData {
+            width: 2,
+            height: 1,
+            bytes: vec![9; 8].into(),
+        };
+        fake(&mut watcher).copy(
+            ClipboardContent::Image(small),
+            ClipboardSelection::Clipboard,
+        );
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        let small = watcher
+            .db
+            .read()
+            .get_entry(watcher.last_stored().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(small.original_image, None);
+        assert_eq!(small.image_encoding, Some(ImageEncoding::Png));
+    }
+
+    #[test]
+    fn test_fake_clipboard_read_errors_are_not_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut watcher = fake_watcher(&dir, None);
+
+        // Genuinely empty
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Empty);
+        assert_eq!(watcher.stats().read_errors, 0);
+        assert!(watcher.last_read_error.is_none());
+
+        fake(&mut watcher).copy_text("hidden");
+        fake(&mut watcher).lock(true);
+        assert!(matches!(
+            ClipboardContent::read(fake(&mut watcher)),
+            Err(arboard::Error::ClipboardOccupied)
+        ));
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Empty);
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Empty);
+        assert_eq!(watcher.stats().read_errors, 2);
+        assert!(
+            watcher
+                .last_read_error
+                .as_deref()
+                .is_some_and(|e| e.contains("held by another party")),
+            "{:?}",
+            watcher.last_read_error
+        );
+        assert!(stored_texts(&watcher).is_empty());
+
+        // Once it can be read again the content is stored as usual
+        fake(&mut watcher).lock(false);
+        assert_eq!(watcher.check_clipboard().unwrap(), CaptureOutcome::Stored);
+        assert!(watcher.last_read_error.is_none());
+        assert_eq!(stored_texts(&watcher), ["hidden"]);
     }
 }